@@ -0,0 +1,360 @@
+/*!
+# Block Models
+
+The `assets/<namespace>/models/block/<name>.json` files. A model is a list of boxes called elements, each with a texture on any of its six faces.
+
+Models can have a `parent`. Textures set by a child replace the parent's and the elements of the closest model that has any are used.
+Faces refer to textures through variables such as `#side` which are looked up in the merged textures until a real texture is found.
+
+Coordinates are in sixteenths of a block. From 0 to 16 on every axis.
+*/
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BlockModelError {
+    #[error("Invalid model {0}: {1}")]
+    InvalidModel(String, serde_json::Error),
+    #[error("Model {0} not found")]
+    MissingModel(String),
+    /// The chain of parents goes from the model back to itself
+    #[error("Model {0} is part of a parent loop: {}", .1.join(" -> "))]
+    ParentLoop(String, Vec<String>),
+}
+/// The directions of a block. Minecraft's north is towards negative Z
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[serde(alias = "bottom")]
+    Down,
+    #[serde(alias = "top")]
+    Up,
+    North,
+    South,
+    West,
+    East,
+}
+impl Direction {
+    /// The axis the direction is along. 0 is X, 1 is Y and 2 is Z
+    pub fn axis(&self) -> usize {
+        match self {
+            Direction::West | Direction::East => 0,
+            Direction::Down | Direction::Up => 1,
+            Direction::North | Direction::South => 2,
+        }
+    }
+    /// Does the direction point towards the positive end of its axis
+    pub fn is_positive(&self) -> bool {
+        matches!(self, Direction::Up | Direction::South | Direction::East)
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+/// A block model file as it is written. See [ResolvedModel] for the model with its parents applied
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct BlockModel {
+    pub parent: Option<String>,
+    #[serde(rename = "ambientocclusion")]
+    pub ambient_occlusion: Option<bool>,
+    /// Texture variables to texture names or other variables
+    #[serde(default)]
+    pub textures: HashMap<String, String>,
+    pub elements: Option<Vec<ModelElement>>,
+}
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ModelElement {
+    /// The lowest corner of the box
+    pub from: [f32; 3],
+    /// The highest corner of the box
+    pub to: [f32; 3],
+    pub rotation: Option<ElementRotation>,
+    /// Is the element shaded by the direction it faces
+    #[serde(default = "default_true")]
+    pub shade: bool,
+    /// Missing faces are not drawn
+    #[serde(default)]
+    pub faces: HashMap<Direction, ElementFace>,
+}
+/// Rotates an element around a single axis
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ElementRotation {
+    pub origin: [f32; 3],
+    pub axis: Axis,
+    /// In degrees. Minecraft only allows steps of 22.5 from -45 to 45
+    pub angle: f32,
+    /// Scales the faces so they still cover the whole block after being rotated
+    #[serde(default)]
+    pub rescale: bool,
+}
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ElementFace {
+    /// The area of the texture. `[x1, y1, x2, y2]` from 0 to 16. Taken from the position of the element if missing
+    pub uv: Option<[f32; 4]>,
+    /// A texture variable such as `#side`
+    pub texture: String,
+    /// The face is hidden when the block in this direction is opaque
+    pub cullface: Option<Direction>,
+    /// Rotates the texture clockwise in steps of 90 degrees
+    #[serde(default)]
+    pub rotation: u32,
+    #[serde(rename = "tintindex", default = "default_tint_index")]
+    pub tint_index: i32,
+}
+fn default_true() -> bool {
+    true
+}
+fn default_tint_index() -> i32 {
+    -1
+}
+impl ElementFace {
+    /// The uv of the face. The one in the file or the one Minecraft works out from the position of the element
+    pub fn uv(&self, direction: Direction, element: &ModelElement) -> [f32; 4] {
+        if let Some(uv) = self.uv {
+            return uv;
+        }
+        let [from_x, from_y, from_z] = element.from;
+        let [to_x, to_y, to_z] = element.to;
+        match direction {
+            Direction::Down => [from_x, 16.0 - to_z, to_x, 16.0 - from_z],
+            Direction::Up => [from_x, from_z, to_x, to_z],
+            Direction::North => [16.0 - to_x, 16.0 - to_y, 16.0 - from_x, 16.0 - from_y],
+            Direction::South => [from_x, 16.0 - to_y, to_x, 16.0 - from_y],
+            Direction::West => [from_z, 16.0 - to_y, to_z, 16.0 - from_y],
+            Direction::East => [16.0 - to_z, 16.0 - to_y, 16.0 - from_z, 16.0 - from_y],
+        }
+    }
+}
+impl BlockModel {
+    pub fn from_json(name: &str, json: &[u8]) -> Result<Self, BlockModelError> {
+        serde_json::from_slice(json)
+            .map_err(|err| BlockModelError::InvalidModel(name.to_string(), err))
+    }
+    /// Applies the parents of the model. `load` reads a model by its name. Such as `minecraft:block/cube_all`
+    ///
+    /// Parents starting with `builtin/` are not files and end the chain
+    pub fn resolve(
+        self,
+        name: &str,
+        mut load: impl FnMut(&str) -> Result<Option<BlockModel>, BlockModelError>,
+    ) -> Result<ResolvedModel, BlockModelError> {
+        let mut chain = vec![(name.to_string(), self)];
+        while let Some(parent) = chain.last().and_then(|(_, model)| model.parent.clone()) {
+            let parent = ModelName::parse(&parent).to_string();
+            if parent.contains("builtin/") {
+                break;
+            }
+            if let Some(start) = chain.iter().position(|(name, _)| *name == parent) {
+                let mut names: Vec<String> = chain[start..]
+                    .iter()
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                names.push(parent.clone());
+                return Err(BlockModelError::ParentLoop(parent, names));
+            }
+            let model =
+                load(&parent)?.ok_or_else(|| BlockModelError::MissingModel(parent.clone()))?;
+            chain.push((parent, model));
+        }
+        let mut resolved = ResolvedModel {
+            ambient_occlusion: true,
+            textures: HashMap::new(),
+            elements: Vec::new(),
+        };
+        let mut elements = None;
+        // From the root parent down so children replace what their parents set
+        for (_, model) in chain.into_iter().rev() {
+            if let Some(ambient_occlusion) = model.ambient_occlusion {
+                resolved.ambient_occlusion = ambient_occlusion;
+            }
+            resolved.textures.extend(model.textures);
+            if model.elements.is_some() {
+                elements = model.elements;
+            }
+        }
+        resolved.elements = elements.unwrap_or_default();
+        Ok(resolved)
+    }
+}
+/// A model with its parents applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedModel {
+    pub ambient_occlusion: bool,
+    pub textures: HashMap<String, String>,
+    pub elements: Vec<ModelElement>,
+}
+impl ResolvedModel {
+    /// Follows texture variables until a texture name is found. None if a variable is never set
+    pub fn texture<'a>(&'a self, reference: &'a str) -> Option<&'a str> {
+        let mut current = reference;
+        // A variable can not go through more variables than there are
+        for _ in 0..=self.textures.len() {
+            let Some(variable) = current.strip_prefix('#') else {
+                return Some(current);
+            };
+            current = self.textures.get(variable)?;
+        }
+        None
+    }
+}
+/// A namespaced name such as `minecraft:block/stone`. Names without a namespace are in `minecraft`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ModelName<'a> {
+    pub namespace: &'a str,
+    pub path: &'a str,
+}
+impl<'a> ModelName<'a> {
+    pub fn parse(name: &'a str) -> Self {
+        match name.split_once(':') {
+            Some((namespace, path)) => Self { namespace, path },
+            None => Self {
+                namespace: "minecraft",
+                path: name,
+            },
+        }
+    }
+    /// Where the model is in a resource pack
+    pub fn model_path(&self) -> String {
+        format!("assets/{}/models/{}.json", self.namespace, self.path)
+    }
+}
+impl std::fmt::Display for ModelName<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CUBE: &str = r##"{
+        "elements": [{
+            "from": [0, 0, 0],
+            "to": [16, 16, 16],
+            "faces": {
+                "down":  { "texture": "#down", "cullface": "down" },
+                "up":    { "texture": "#up", "cullface": "up" },
+                "north": { "texture": "#north", "cullface": "north" },
+                "south": { "texture": "#south", "cullface": "south" },
+                "west":  { "texture": "#west", "cullface": "west" },
+                "east":  { "texture": "#east", "cullface": "east" }
+            }
+        }]
+    }"##;
+    const CUBE_ALL: &str = r##"{
+        "parent": "block/cube",
+        "textures": {
+            "particle": "#all",
+            "down": "#all", "up": "#all", "north": "#all",
+            "east": "#all", "south": "#all", "west": "#all"
+        }
+    }"##;
+    const SLAB: &str = r##"{
+        "parent": "minecraft:block/block",
+        "elements": [{
+            "from": [0, 0, 0],
+            "to": [16, 8, 16],
+            "faces": {
+                "down":  { "uv": [0, 0, 16, 16], "texture": "#bottom", "cullface": "down" },
+                "up":    { "texture": "#top" },
+                "north": { "texture": "#side", "cullface": "north" }
+            }
+        }]
+    }"##;
+
+    fn load(name: &str) -> Result<Option<BlockModel>, BlockModelError> {
+        let json = match name {
+            "minecraft:block/cube" => CUBE,
+            "minecraft:block/cube_all" => CUBE_ALL,
+            "minecraft:block/slab" => SLAB,
+            "minecraft:block/block" => "{}",
+            "minecraft:block/loop" => r#"{"parent": "block/loop"}"#,
+            "minecraft:block/ping" => r#"{"parent": "block/pong"}"#,
+            "minecraft:block/pong" => r#"{"parent": "block/ping"}"#,
+            _ => return Ok(None),
+        };
+        BlockModel::from_json(name, json.as_bytes()).map(Some)
+    }
+
+    #[test]
+    fn test_parent_textures_are_resolved() -> Result<(), BlockModelError> {
+        let stone = BlockModel::from_json(
+            "minecraft:block/stone",
+            br#"{"parent": "minecraft:block/cube_all", "textures": {"all": "minecraft:block/stone"}}"#,
+        )?
+        .resolve("minecraft:block/stone", load)?;
+        assert_eq!(stone.elements.len(), 1);
+        let element = &stone.elements[0];
+        assert_eq!(element.faces.len(), 6);
+        let down = &element.faces[&Direction::Down];
+        assert_eq!(down.cullface, Some(Direction::Down));
+        assert_eq!(stone.texture(&down.texture), Some("minecraft:block/stone"));
+        assert_eq!(stone.texture("#particle"), Some("minecraft:block/stone"));
+        assert_eq!(stone.texture("#missing"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_element_uv_defaults_to_its_position() -> Result<(), BlockModelError> {
+        let slab = BlockModel::from_json(
+            "minecraft:block/stone_slab",
+            br#"{"parent": "block/slab", "textures": {"bottom": "block/stone", "top": "block/stone", "side": "block/stone_slab_side"}}"#,
+        )?
+        .resolve("minecraft:block/stone_slab", load)?;
+        let element = &slab.elements[0];
+        assert_eq!(element.to, [16.0, 8.0, 16.0]);
+        let up = &element.faces[&Direction::Up];
+        assert_eq!(up.cullface, None);
+        assert_eq!(up.tint_index, -1);
+        assert_eq!(up.uv(Direction::Up, element), [0.0, 0.0, 16.0, 16.0]);
+        let north = &element.faces[&Direction::North];
+        // The bottom half of the texture
+        assert_eq!(north.uv(Direction::North, element), [0.0, 8.0, 16.0, 16.0]);
+        assert_eq!(slab.texture(&north.texture), Some("block/stone_slab_side"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_and_looping_parents() {
+        let missing = BlockModel {
+            parent: Some("block/nope".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            missing.resolve("block/test", load),
+            Err(BlockModelError::MissingModel(name)) if name == "minecraft:block/nope"
+        ));
+        let looping = BlockModel {
+            parent: Some("block/loop".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            looping.resolve("block/test", load),
+            Err(BlockModelError::ParentLoop(..))
+        ));
+        let ping = BlockModel {
+            parent: Some("block/ping".to_string()),
+            ..Default::default()
+        };
+        let err = ping.resolve("block/test", load).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Model minecraft:block/ping is part of a parent loop: minecraft:block/ping -> minecraft:block/pong -> minecraft:block/ping"
+        );
+        let generated = BlockModel {
+            parent: Some("builtin/generated".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            generated.resolve("item/stick", load).unwrap().elements,
+            vec![]
+        );
+    }
+}
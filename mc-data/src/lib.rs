@@ -1,3 +1,10 @@
+/*!
+# Minecraft Data
+
+Types for the data files Minecraft keeps in resource packs
+*/
+pub mod block_model;
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
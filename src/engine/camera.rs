@@ -35,6 +35,13 @@ impl Camera {
         }
     }
 
+    pub fn yaw(&self) -> Rad<f32> {
+        self.yaw
+    }
+    pub fn pitch(&self) -> Rad<f32> {
+        self.pitch
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
         let (sin_pitch, cos_pitch) = self.pitch.0.sin_cos();
         let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
@@ -215,4 +222,7 @@ impl CameraUniform {
         self.view_position = camera.position.to_homogeneous().into();
         self.view_proj = (projection.calc_matrix() * camera.calc_matrix()).into()
     }
+    pub fn view_proj(&self) -> glam::Mat4 {
+        glam::Mat4::from_cols_array_2d(&self.view_proj)
+    }
 }
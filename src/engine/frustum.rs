@@ -0,0 +1,86 @@
+/*!
+# Frustum Culling
+
+The six planes of the camera's view are pulled out of the view-projection matrix. Anything completely outside of one of the planes can not be seen and is not drawn.
+*/
+use glam::{Mat4, Vec3, Vec4, Vec4Swizzles};
+
+/// An axis aligned bounding box in world space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    /// Left, right, bottom, top, near and far. The normals point into the frustum
+    planes: [Vec4; 6],
+}
+impl Frustum {
+    /// Extracts the planes from a view-projection matrix whose clip space depth goes from 0 to 1
+    pub fn from_view_proj(view_proj: Mat4) -> Self {
+        let row = |index| view_proj.row(index);
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(2),
+            row(3) - row(2),
+        ]
+        .map(|plane| plane / plane.xyz().length());
+        Self { planes }
+    }
+    /// Is any part of the box inside of the frustum
+    ///
+    /// Only checks the corner of the box furthest along each plane's normal. So some boxes just outside of the corners of the frustum are still counted as inside
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            let furthest = Vec3::select(plane.xyz().cmpge(Vec3::ZERO), aabb.max, aabb.min);
+            plane.xyz().dot(furthest) + plane.w >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frustum() -> Frustum {
+        // Looking down -Z from the origin. Depth from 0 to 1 like wgpu
+        let projection = Mat4::perspective_rh(90f32.to_radians(), 1.0, 0.1, 100.0);
+        Frustum::from_view_proj(projection * Mat4::IDENTITY)
+    }
+    fn cube(center: Vec3) -> Aabb {
+        Aabb::new(center - Vec3::splat(8.0), center + Vec3::splat(8.0))
+    }
+
+    #[test]
+    fn test_boxes_in_front_are_visible() {
+        let frustum = frustum();
+        assert!(frustum.intersects(&cube(Vec3::new(0.0, 0.0, -20.0))));
+        // Partly inside of the left plane
+        assert!(frustum.intersects(&cube(Vec3::new(-25.0, 0.0, -20.0))));
+        // The camera is inside of the box
+        assert!(frustum.intersects(&cube(Vec3::ZERO)));
+    }
+
+    #[test]
+    fn test_boxes_outside_are_culled() {
+        let frustum = frustum();
+        // Behind the camera
+        assert!(!frustum.intersects(&cube(Vec3::new(0.0, 0.0, 20.0))));
+        // Too far to the side
+        assert!(!frustum.intersects(&cube(Vec3::new(-40.0, 0.0, -20.0))));
+        // Past the far plane
+        assert!(!frustum.intersects(&cube(Vec3::new(0.0, 0.0, -120.0))));
+    }
+}
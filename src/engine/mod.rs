@@ -4,14 +4,16 @@ use wgpu::util::DeviceExt;
 // lib.rs
 use cgmath::prelude::*;
 use winit::{
-    event::{MouseButton, WindowEvent},
+    event::WindowEvent,
     keyboard::PhysicalKey,
     window::Window,
 };
 
 mod camera;
+pub mod frustum;
 mod model;
 mod render_types;
+pub mod resource_pack;
 pub mod shapes;
 mod texture;
 pub mod utils;
@@ -19,6 +21,7 @@ pub mod voxel;
 use crate::engine::render_types::{BasicModelVertex, LightUniform, ShaderVertexType};
 
 use self::{
+    frustum::Frustum,
     utils::create_render_pipeline,
     voxel::{
         voxel_pipeline::DrawChunk,
@@ -26,7 +29,9 @@ use self::{
     },
 };
 
+pub use camera::Camera;
 use camera::CameraUniform;
+pub use texture::TextureFiltering;
 
 pub trait SubRenderer {
     fn update(&mut self, base_state: &mut WGPUStateBase, dt: std::time::Duration);
@@ -85,9 +90,16 @@ impl State {
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    required_features: wgpu::Features::empty(),
-
-                    required_limits: wgpu::Limits::default(),
+                    // Sections are drawn with one indirect call per layer when the adapter can
+                    required_features: adapter.features()
+                        & (wgpu::Features::MULTI_DRAW_INDIRECT
+                            | wgpu::Features::INDIRECT_FIRST_INSTANCE),
+
+                    // Every block texture is a layer of one texture array
+                    required_limits: wgpu::Limits {
+                        max_texture_array_layers: adapter.limits().max_texture_array_layers,
+                        ..Default::default()
+                    },
                 },
                 None, // Trace path
             )
@@ -222,7 +234,7 @@ impl State {
             &base,
             &camera_bind_group_layout,
             &light_bind_group_layout,
-        );
+        )?;
         let state = Self {
             base,
             camera,
@@ -242,6 +254,9 @@ impl State {
         Ok(state)
     }
 
+    pub fn camera(&self) -> &camera::Camera {
+        &self.camera
+    }
     pub fn window(&self) -> &Window {
         &self.base.window
     }
@@ -274,11 +289,6 @@ impl State {
                 self.camera_controller.process_scroll(delta);
                 true
             }
-            WindowEvent::MouseInput {
-                button: MouseButton::Left,
-                state,
-                ..
-            } => true,
             _ => false,
         }
     }
@@ -305,6 +315,12 @@ impl State {
             bytemuck::cast_slice(&[self.light_uniform]),
         );
         self.voxel_renderer.update(&mut self.base, dt);
+        let camera_position = self.camera.position;
+        self.voxel_renderer.prepare_draws(
+            &Frustum::from_view_proj(self.camera_uniform.view_proj()),
+            glam::Vec3::new(camera_position.x, camera_position.y, camera_position.z),
+            &self.base,
+        );
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -355,7 +371,6 @@ impl State {
             //   &self.camera_bind_group,
             //    &self.light_bind_group,
             //);
-            render_pass.set_pipeline(&self.voxel_renderer.voxel_pipeline);
             render_pass.draw_chunks(
                 &self.voxel_renderer,
                 &self.camera_bind_group,
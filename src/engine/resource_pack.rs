@@ -0,0 +1,386 @@
+/*!
+# Resource Packs
+
+A resource pack is a directory or a zip archive with a `pack.mcmeta` file at its root and its files under `assets/<namespace>/`.
+
+Packs are loaded into a [ResourcePackStack]. When more than one pack has the same file the pack added last wins, the same as Minecraft's pack list.
+The vanilla assets folder pointed to by `MINECRAFT_ASSETS` has no `pack.mcmeta` so it is opened with [ResourcePack::vanilla_assets] and sits at the bottom of the stack.
+*/
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{debug, warn};
+use zip::ZipArchive;
+
+#[derive(Debug, Error)]
+pub enum ResourcePackError {
+    #[error("IO Error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Invalid zip archive: {0}")]
+    ZipError(#[from] zip::result::ZipError),
+    #[error("{0} has no pack.mcmeta")]
+    MissingPackMeta(PathBuf),
+    #[error("Invalid pack.mcmeta: {0}")]
+    InvalidPackMeta(#[from] serde_json::Error),
+    #[error("Unsupported pack format {0}. Packs from before format {min} keep block textures in textures/blocks", min = ResourcePack::MIN_PACK_FORMAT)]
+    UnsupportedPackFormat(u32),
+}
+/// The `pack.mcmeta` file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PackMcMeta {
+    pub pack: PackMeta,
+}
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PackMeta {
+    /// The version of Minecraft the pack was made for
+    pub pack_format: u32,
+    /// Either a string or a text component
+    #[serde(default)]
+    pub description: serde_json::Value,
+}
+#[derive(Debug)]
+enum PackSource {
+    /// Paths are relative to the directory
+    Directory(PathBuf),
+    /// A vanilla `assets` folder. The `assets/` at the start of paths is dropped
+    Assets(PathBuf),
+    Zip(ZipArchive<File>),
+}
+#[derive(Debug)]
+pub struct ResourcePack {
+    pub name: String,
+    /// None for the vanilla assets
+    pub meta: Option<PackMeta>,
+    source: PackSource,
+}
+impl ResourcePack {
+    /// Format 4 (Minecraft 1.13) moved block textures to `textures/block` and gave them the names that are loaded
+    pub const MIN_PACK_FORMAT: u32 = 4;
+    /// The newest format the texture and model layout is known to match (Minecraft 1.21.4). Newer packs are loaded with a warning
+    pub const MAX_KNOWN_PACK_FORMAT: u32 = 46;
+    /// Opens a directory or a `.zip` pack. Packs older than [ResourcePack::MIN_PACK_FORMAT] are rejected
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ResourcePackError> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let source = if path.is_dir() {
+            PackSource::Directory(path.to_path_buf())
+        } else {
+            PackSource::Zip(ZipArchive::new(File::open(path)?)?)
+        };
+        let mut pack = Self {
+            name,
+            meta: None,
+            source,
+        };
+        let Some(meta) = pack.read("pack.mcmeta")? else {
+            return Err(ResourcePackError::MissingPackMeta(path.to_path_buf()));
+        };
+        let PackMcMeta { pack: meta } = serde_json::from_slice(&meta)?;
+        if meta.pack_format < Self::MIN_PACK_FORMAT {
+            return Err(ResourcePackError::UnsupportedPackFormat(meta.pack_format));
+        }
+        if meta.pack_format > Self::MAX_KNOWN_PACK_FORMAT {
+            warn!(
+                "Resource pack {} has the unknown format {}. Its textures or models may not load",
+                pack.name, meta.pack_format
+            );
+        }
+        debug!(
+            "Opened resource pack {} with format {}",
+            pack.name, meta.pack_format
+        );
+        pack.meta = Some(meta);
+        Ok(pack)
+    }
+    /// The `assets` folder of a Minecraft install. Such as the one `MINECRAFT_ASSETS` points to
+    pub fn vanilla_assets(path: impl Into<PathBuf>) -> Self {
+        Self {
+            name: "vanilla".to_string(),
+            meta: None,
+            source: PackSource::Assets(path.into()),
+        }
+    }
+    /// Reads a file. Paths use `/` and start at the root of the pack. Such as `assets/minecraft/textures/block/dirt.png`
+    ///
+    /// None if the pack does not have the file
+    pub fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>, ResourcePackError> {
+        let file = match &mut self.source {
+            PackSource::Directory(root) => root.join(path),
+            PackSource::Assets(root) => match path.strip_prefix("assets/") {
+                Some(path) => root.join(path),
+                None => return Ok(None),
+            },
+            PackSource::Zip(archive) => {
+                let mut file = match archive.by_name(path) {
+                    Ok(file) => file,
+                    Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+                    Err(err) => return Err(err.into()),
+                };
+                let mut bytes = Vec::with_capacity(file.size() as usize);
+                file.read_to_end(&mut bytes)?;
+                return Ok(Some(bytes));
+            }
+        };
+        match std::fs::read(file) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+    /// The paths of the files directly inside of a directory. `directory` ends with a `/`
+    pub fn list(&self, directory: &str) -> Result<Vec<String>, ResourcePackError> {
+        let root = match &self.source {
+            PackSource::Directory(root) => root.join(directory),
+            PackSource::Assets(root) => match directory.strip_prefix("assets/") {
+                Some(directory) => root.join(directory),
+                None => return Ok(Vec::new()),
+            },
+            PackSource::Zip(archive) => {
+                return Ok(archive
+                    .file_names()
+                    .filter(|name| {
+                        name.strip_prefix(directory)
+                            .is_some_and(|file| !file.is_empty() && !file.contains('/'))
+                    })
+                    .map(str::to_string)
+                    .collect());
+            }
+        };
+        let entries = match std::fs::read_dir(root) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(format!(
+                    "{directory}{}",
+                    entry.file_name().to_string_lossy()
+                ));
+            }
+        }
+        Ok(files)
+    }
+}
+/// A texture and its `.mcmeta` file read out of a pack. See [ResourcePackStack::read_with_mcmeta]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackTexture {
+    pub png: Vec<u8>,
+    /// The animation of the texture. None if the pack has no `.mcmeta` for it
+    pub mcmeta: Option<Vec<u8>>,
+}
+/// Resource packs in order of priority. Packs later in the stack override the files of earlier packs
+#[derive(Debug, Default)]
+pub struct ResourcePackStack {
+    packs: Vec<ResourcePack>,
+}
+impl ResourcePackStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Puts the pack on top of the stack
+    pub fn push(&mut self, pack: ResourcePack) {
+        self.packs.push(pack);
+    }
+    pub fn packs(&self) -> &[ResourcePack] {
+        &self.packs
+    }
+    /// The vanilla assets from `MINECRAFT_ASSETS` followed by every pack in `KAKARA_RESOURCE_PACKS`
+    ///
+    /// `KAKARA_RESOURCE_PACKS` is a list of directories and zip files separated like `PATH`. Packs that fail to open are skipped
+    pub fn from_env() -> Self {
+        let mut stack = Self::new();
+        match std::env::var_os("MINECRAFT_ASSETS") {
+            Some(assets) => stack.push(ResourcePack::vanilla_assets(assets)),
+            None => warn!("MINECRAFT_ASSETS not set"),
+        }
+        if let Some(packs) = std::env::var_os("KAKARA_RESOURCE_PACKS") {
+            for path in std::env::split_paths(&packs) {
+                match ResourcePack::open(&path) {
+                    Ok(pack) => stack.push(pack),
+                    Err(err) => warn!("Skipping resource pack {}: {}", path.display(), err),
+                }
+            }
+        }
+        stack
+    }
+    /// Reads a file from the highest pack that has it
+    pub fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>, ResourcePackError> {
+        for pack in self.packs.iter_mut().rev() {
+            if let Some(bytes) = pack.read(path)? {
+                return Ok(Some(bytes));
+            }
+        }
+        Ok(None)
+    }
+    /// Reads a file and its `.mcmeta` from the highest pack that has the file
+    ///
+    /// The `.mcmeta` of a lower pack is not used. A pack that replaces an animated texture with a still one is not animated
+    pub fn read_with_mcmeta(
+        &mut self,
+        path: &str,
+    ) -> Result<Option<PackTexture>, ResourcePackError> {
+        for pack in self.packs.iter_mut().rev() {
+            if let Some(png) = pack.read(path)? {
+                let mcmeta = pack.read(&format!("{path}.mcmeta"))?;
+                return Ok(Some(PackTexture { png, mcmeta }));
+            }
+        }
+        Ok(None)
+    }
+    /// Every file directly inside of the directory in any pack
+    pub fn list(&self, directory: &str) -> Result<BTreeSet<String>, ResourcePackError> {
+        let mut files = BTreeSet::new();
+        for pack in &self.packs {
+            files.extend(pack.list(directory)?);
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::write::FileOptions;
+
+    use super::*;
+    use crate::test_directory::TestDirectory;
+
+    const PACK_MCMETA: &str = r#"{"pack": {"pack_format": 15, "description": "Test"}}"#;
+
+    fn write_directory_pack(root: &Path, files: &[(&str, &[u8])]) {
+        for (path, bytes) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, bytes).unwrap();
+        }
+    }
+    fn write_zip_pack(path: &Path, files: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, bytes) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(bytes).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_later_packs_override_earlier_packs() -> anyhow::Result<()> {
+        let directory = TestDirectory::new("pack-stack");
+        let base = directory.join("base");
+        write_directory_pack(
+            &base,
+            &[
+                ("pack.mcmeta", PACK_MCMETA.as_bytes()),
+                ("assets/minecraft/textures/block/dirt.png", b"base dirt"),
+                ("assets/minecraft/textures/block/stone.png", b"base stone"),
+            ],
+        );
+        let zip = directory.join("override.zip");
+        write_zip_pack(
+            &zip,
+            &[
+                ("pack.mcmeta", PACK_MCMETA.as_bytes()),
+                ("assets/minecraft/textures/block/dirt.png", b"zip dirt"),
+                ("assets/minecraft/textures/block/sand.png", b"zip sand"),
+                ("assets/minecraft/textures/block/nested/ignored.png", b""),
+            ],
+        );
+        let mut stack = ResourcePackStack::new();
+        stack.push(ResourcePack::open(&base)?);
+        stack.push(ResourcePack::open(&zip)?);
+        assert_eq!(stack.packs()[1].meta.as_ref().unwrap().pack_format, 15);
+
+        let files: Vec<String> = stack
+            .list("assets/minecraft/textures/block/")?
+            .into_iter()
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                "assets/minecraft/textures/block/dirt.png",
+                "assets/minecraft/textures/block/sand.png",
+                "assets/minecraft/textures/block/stone.png",
+            ]
+        );
+        let mut read = |path: &str| stack.read(&format!("assets/minecraft/textures/block/{path}"));
+        assert_eq!(read("dirt.png")?, Some(b"zip dirt".to_vec()));
+        assert_eq!(read("stone.png")?, Some(b"base stone".to_vec()));
+        assert_eq!(read("gravel.png")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_mcmeta_is_required() {
+        let directory = TestDirectory::new("pack-invalid");
+        let missing = directory.join("missing");
+        write_directory_pack(
+            &missing,
+            &[("assets/minecraft/textures/block/dirt.png", b"")],
+        );
+        assert!(matches!(
+            ResourcePack::open(&missing),
+            Err(ResourcePackError::MissingPackMeta(_))
+        ));
+        let invalid = directory.join("invalid.zip");
+        write_zip_pack(&invalid, &[("pack.mcmeta", br#"{"pack": {}}"#)]);
+        assert!(matches!(
+            ResourcePack::open(&invalid),
+            Err(ResourcePackError::InvalidPackMeta(_))
+        ));
+        // Format 3 packs use textures/blocks
+        let old = directory.join("old");
+        write_directory_pack(
+            &old,
+            &[(
+                "pack.mcmeta",
+                br#"{"pack": {"pack_format": 3, "description": ""}}"#,
+            )],
+        );
+        assert!(matches!(
+            ResourcePack::open(&old),
+            Err(ResourcePackError::UnsupportedPackFormat(3))
+        ));
+        // Newer formats are only warned about
+        let new = directory.join("new");
+        write_directory_pack(
+            &new,
+            &[(
+                "pack.mcmeta",
+                br#"{"pack": {"pack_format": 1000, "description": ""}}"#,
+            )],
+        );
+        assert!(ResourcePack::open(&new).is_ok());
+    }
+
+    #[test]
+    fn test_vanilla_assets_drop_the_assets_directory() -> anyhow::Result<()> {
+        let directory = TestDirectory::new("pack-vanilla");
+        write_directory_pack(
+            &directory,
+            &[("minecraft/textures/block/dirt.png", b"dirt")],
+        );
+        let mut pack = ResourcePack::vanilla_assets(directory.to_path_buf());
+        assert_eq!(
+            pack.list("assets/minecraft/textures/block/")?,
+            vec!["assets/minecraft/textures/block/dirt.png"]
+        );
+        assert_eq!(
+            pack.read("assets/minecraft/textures/block/dirt.png")?,
+            Some(b"dirt".to_vec())
+        );
+        Ok(())
+    }
+}
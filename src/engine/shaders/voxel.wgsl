@@ -1,4 +1,5 @@
-// Vertex shader
+// Shared by both vertex formats. The vertex shader is appended from voxel_vertex.wgsl or voxel_packed_vertex.wgsl
+// See crate::engine::voxel::gpu_vertex
 
 struct Camera {
     view_pos: vec4<f32>,
@@ -14,41 +15,30 @@ struct Light {
 @group(2) @binding(0)
 var<uniform> light: Light;
 
-/// crate::engine::voxel::chunk_mesh::BlockVertex
-struct BlockVertex {
-    @location(0) position: vec3<f32>,
-    @location(1) normal: vec3<f32>,
-    @location(2) tex_coords: vec2<f32>,
-}
-
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
     @location(1) world_normal: vec3<f32>,
     @location(2) world_position: vec3<f32>,
-}
-
-@vertex
-fn vs_main(block_vertex: BlockVertex) -> VertexOutput {
-    var out: VertexOutput;
-    out.tex_coords = block_vertex.tex_coords;
-    out.world_normal = block_vertex.normal;
-    let model_space = vec4<f32>(block_vertex.position, 1.0);
-    out.world_position = model_space.xyz;
-    out.clip_position = camera.view_proj * model_space;
-    return out;
+    @location(3) tile: vec4<f32>,
+    @location(4) light: vec2<f32>,
+    @location(5) ao: f32,
+    @location(6) @interpolate(flat) layer: u32,
 }
 
 // Fragment shader
 
 @group(0) @binding(0)
-var t_diffuse: texture_2d<f32>;
+var t_diffuse: texture_2d_array<f32>;
 @group(0)@binding(1)
 var s_diffuse: sampler;
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+fn block_color(in: VertexOutput) -> vec4<f32> {
+    // Greedy quads repeat the texture. Wrap inside of the tile the texture is in
+    let atlas_coords = in.tile.xy + fract(in.tex_coords) * in.tile.zw;
+    // Gradients of the unwrapped coordinates so the wrap does not cause seams
+    let gradient_coords = in.tex_coords * in.tile.zw;
+    let object_color: vec4<f32> = textureSampleGrad(t_diffuse, s_diffuse, atlas_coords, in.layer, dpdx(gradient_coords), dpdy(gradient_coords));
     
     // We don't need (or want) much ambient light, so 0.1 is fine
     let ambient_strength = 0.1;
@@ -64,7 +54,28 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let specular_strength = pow(max(dot(in.world_normal, half_dir), 0.0), 32.0);
     let specular_color = specular_strength * light.color;
 
-    let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+    // Sky and block light scale the light object. Every level is 80% as bright as the one above it
+    let light_level = max(in.light.x, in.light.y) * 15.0;
+    let brightness = max(pow(0.8, 15.0 - light_level), 0.05);
+
+    // Corners next to other blocks are darker
+    let occlusion = mix(0.4, 1.0, in.ao);
+
+    let result = (ambient_color + diffuse_color + specular_color) * brightness * occlusion * object_color.xyz;
 
     return vec4<f32>(result, object_color.a);
-}
\ No newline at end of file
+}
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return block_color(in);
+}
+
+// Cutout blocks such as leaves are either fully solid or fully see through
+@fragment
+fn fs_cutout(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = block_color(in);
+    if color.a < 0.5 {
+        discard;
+    }
+    return vec4<f32>(color.rgb, 1.0);
+}
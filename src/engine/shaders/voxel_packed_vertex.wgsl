@@ -0,0 +1,44 @@
+// Vertex shader
+
+/// crate::engine::voxel::texture_atlas::TextureTile
+struct TextureTile {
+    /// The top left corner followed by the size
+    rect: vec4<f32>,
+    layer: u32,
+}
+/// crate::engine::voxel::texture_atlas::TextureTable
+@group(0) @binding(2)
+var<storage, read> textures: array<TextureTile>;
+
+fn bits(word: u32, shift: u32, count: u32) -> u32 {
+    return (word >> shift) & ((1u << count) - 1u);
+}
+
+/// crate::engine::voxel::gpu_vertex::PackedBlockVertex
+@vertex
+fn vs_main(@location(0) packed: vec3<u32>, @location(6) section_origin: vec3<f32>) -> VertexOutput {
+    var out: VertexOutput;
+    let first = packed.x;
+    let second = packed.y;
+
+    // Positions are stored in sixteenths of a block and half a block up
+    let local = vec3<f32>(f32(bits(first, 0u, 9u)), f32(bits(first, 9u, 9u)), f32(bits(first, 18u, 9u))) / 16.0 - 0.5;
+
+    let normal_id = bits(first, 27u, 3u);
+    var normal = vec3<f32>(0.0);
+    normal[normal_id / 2u] = select(-1.0, 1.0, normal_id % 2u == 1u);
+    out.world_normal = normal;
+
+    out.ao = f32(bits(first, 30u, 2u)) / 3.0;
+    out.light = vec2<f32>(f32(bits(second, 18u, 4u)), f32(bits(second, 22u, 4u))) / 15.0;
+
+    out.tex_coords = vec2<f32>(f32(bits(second, 0u, 9u)), f32(bits(second, 9u, 9u))) / 16.0;
+    let tile = textures[packed.z];
+    out.tile = tile.rect;
+    out.layer = tile.layer;
+
+    let model_space = vec4<f32>(local + section_origin, 1.0);
+    out.world_position = model_space.xyz;
+    out.clip_position = camera.view_proj * model_space;
+    return out;
+}
@@ -0,0 +1,27 @@
+// Vertex shader
+
+/// crate::engine::voxel::chunk_mesh::BlockVertex
+struct BlockVertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) tex_coords: vec2<f32>,
+    @location(3) tile: vec4<f32>,
+    @location(4) light: vec2<f32>,
+    @location(5) ao: f32,
+    @location(7) layer: u32,
+}
+
+@vertex
+fn vs_main(block_vertex: BlockVertex, @location(6) section_origin: vec3<f32>) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = block_vertex.tex_coords;
+    out.tile = block_vertex.tile;
+    out.light = block_vertex.light;
+    out.ao = block_vertex.ao;
+    out.layer = block_vertex.layer;
+    out.world_normal = block_vertex.normal;
+    let model_space = vec4<f32>(block_vertex.position + section_origin, 1.0);
+    out.world_position = model_space.xyz;
+    out.clip_position = camera.view_proj * model_space;
+    return out;
+}
@@ -1,4 +1,5 @@
-use image::GenericImageView;
+use image::{GenericImageView, Rgba, RgbaImage};
+use strum::{Display, EnumString};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -137,4 +138,206 @@ impl Texture {
             sampler,
         })
     }
+    /// Creates a 2D texture array with one layer per image. Every image must be the same size
+    ///
+    /// `mip_levels` includes the full size image. The smaller levels are generated with [mip_chain]
+    pub fn from_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layers: &[image::DynamicImage],
+        mip_levels: u32,
+        filtering: TextureFiltering,
+        label: Option<&str>,
+    ) -> Self {
+        let (width, height) = layers
+            .first()
+            .map(|layer| layer.dimensions())
+            .unwrap_or((1, 1));
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: layers.len().max(1) as u32,
+        };
+        let mip_levels = mip_levels.clamp(1, size.max_mips(wgpu::TextureDimension::D2));
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: mip_levels,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        // A single layer would be viewed as a plain 2D texture by default
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&filtering.sampler_descriptor());
+
+        let texture = Self {
+            texture,
+            view,
+            sampler,
+        };
+        for (index, layer) in layers.iter().enumerate() {
+            texture.write_region(queue, layer.to_rgba8(), 0, 0, index as u32);
+        }
+        texture
+    }
+    /// Writes the image into a layer with its top left corner at `x` and `y`. Every mip level is written
+    ///
+    /// `x` and `y` need to be divisible by two for every mip level after the first
+    pub fn write_region(&self, queue: &wgpu::Queue, image: RgbaImage, x: u32, y: u32, layer: u32) {
+        let mip_levels = self.texture.mip_level_count();
+        for (level, image) in mip_chain(image, mip_levels).iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &self.texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d {
+                        x: x >> level,
+                        y: y >> level,
+                        z: layer,
+                    },
+                },
+                image,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * image.width()),
+                    rows_per_image: Some(image.height()),
+                },
+                wgpu::Extent3d {
+                    width: image.width(),
+                    height: image.height(),
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+}
+/// How block textures are sampled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum TextureFiltering {
+    /// The closest pixel of the closest mip level
+    Nearest,
+    /// Blends between pixels and mip levels when textures are shrunk. Close up textures stay pixelated
+    #[default]
+    Trilinear,
+    /// Trilinear filtering that keeps textures sharp at steep angles. Close up textures are smoothed
+    Anisotropic,
+}
+impl TextureFiltering {
+    /// The highest anisotropy wgpu supports
+    const MAX_ANISOTROPY: u16 = 16;
+    pub fn sampler_descriptor(&self) -> wgpu::SamplerDescriptor<'static> {
+        // Anisotropic filtering requires every filter to be linear
+        let (mag_filter, min_filter, mipmap_filter, anisotropy_clamp) = match self {
+            TextureFiltering::Nearest => (
+                wgpu::FilterMode::Nearest,
+                wgpu::FilterMode::Nearest,
+                wgpu::FilterMode::Nearest,
+                1,
+            ),
+            TextureFiltering::Trilinear => (
+                wgpu::FilterMode::Nearest,
+                wgpu::FilterMode::Linear,
+                wgpu::FilterMode::Linear,
+                1,
+            ),
+            TextureFiltering::Anisotropic => (
+                wgpu::FilterMode::Linear,
+                wgpu::FilterMode::Linear,
+                wgpu::FilterMode::Linear,
+                Self::MAX_ANISOTROPY,
+            ),
+        };
+        wgpu::SamplerDescriptor {
+            label: Some("Block Texture Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter,
+            min_filter,
+            mipmap_filter,
+            anisotropy_clamp,
+            ..Default::default()
+        }
+    }
+}
+/// The image followed by `levels - 1` smaller copies. Each one is half the size of the last
+///
+/// Every 2x2 block of pixels is averaged into one. Colors are weighted by their alpha so see through pixels do not darken the edges of cutout textures
+pub fn mip_chain(image: RgbaImage, levels: u32) -> Vec<RgbaImage> {
+    let mut chain = vec![image];
+    for _ in 1..levels {
+        let previous = chain.last().expect("The chain starts with the image");
+        let width = (previous.width() / 2).max(1);
+        let height = (previous.height() / 2).max(1);
+        let next = RgbaImage::from_fn(width, height, |x, y| {
+            let mut color = [0u32; 3];
+            let mut alpha = 0u32;
+            let mut count = 0u32;
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (px, py) = (x * 2 + dx, y * 2 + dy);
+                if px >= previous.width() || py >= previous.height() {
+                    continue;
+                }
+                let [r, g, b, a] = previous.get_pixel(px, py).0;
+                let a = a as u32;
+                color[0] += r as u32 * a;
+                color[1] += g as u32 * a;
+                color[2] += b as u32 * a;
+                alpha += a;
+                count += 1;
+            }
+            if alpha == 0 {
+                return Rgba([0, 0, 0, 0]);
+            }
+            Rgba([
+                (color[0] / alpha) as u8,
+                (color[1] / alpha) as u8,
+                (color[2] / alpha) as u8,
+                (alpha / count) as u8,
+            ])
+        });
+        chain.push(next);
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mip_chain_averages_pixels() {
+        let mut image = RgbaImage::new(4, 4);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            // Left half red, right half blue. The top right pixel is see through
+            *pixel = if x == 3 && y == 0 {
+                Rgba([255, 255, 255, 0])
+            } else if x < 2 {
+                Rgba([200, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 100, 255])
+            };
+        }
+        let chain = mip_chain(image, 3);
+        assert_eq!(
+            chain
+                .iter()
+                .map(|level| level.dimensions())
+                .collect::<Vec<_>>(),
+            vec![(4, 4), (2, 2), (1, 1)]
+        );
+        assert_eq!(chain[1].get_pixel(0, 0), &Rgba([200, 0, 0, 255]));
+        // The see through pixel lowers the alpha without adding white
+        assert_eq!(chain[1].get_pixel(1, 0), &Rgba([0, 0, 100, 191]));
+        // The partly see through blue counts for less than the red
+        assert_eq!(chain[2].get_pixel(0, 0).0[..3], [106, 0, 46]);
+    }
 }
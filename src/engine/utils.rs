@@ -1,3 +1,21 @@
+/// How a pipeline writes its fragments
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineBlending<'a> {
+    /// The fragment shader entry point
+    pub fragment_entry_point: &'a str,
+    pub blend: wgpu::BlendState,
+    /// Blended geometry should not hide what is drawn behind it after it
+    pub depth_write_enabled: bool,
+}
+impl Default for PipelineBlending<'_> {
+    fn default() -> Self {
+        Self {
+            fragment_entry_point: "fs_main",
+            blend: wgpu::BlendState::REPLACE,
+            depth_write_enabled: true,
+        }
+    }
+}
 pub fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
@@ -7,24 +25,40 @@ pub fn create_render_pipeline(
     shader: wgpu::ShaderModuleDescriptor,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(shader);
-
+    create_blended_render_pipeline(
+        device,
+        layout,
+        color_format,
+        depth_format,
+        vertex_layouts,
+        &shader,
+        PipelineBlending::default(),
+    )
+}
+/// Creates a pipeline from an existing shader module. Used when several pipelines share a shader
+pub fn create_blended_render_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    color_format: wgpu::TextureFormat,
+    depth_format: Option<wgpu::TextureFormat>,
+    vertex_layouts: &[wgpu::VertexBufferLayout],
+    shader: &wgpu::ShaderModule,
+    blending: PipelineBlending,
+) -> wgpu::RenderPipeline {
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some(&format!("{:?}", shader)),
         layout: Some(layout),
         vertex: wgpu::VertexState {
-            module: &shader,
+            module: shader,
             entry_point: "vs_main",
             buffers: vertex_layouts,
         },
         fragment: Some(wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_main",
+            module: shader,
+            entry_point: blending.fragment_entry_point,
             targets: &[Some(wgpu::ColorTargetState {
                 format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
+                blend: Some(blending.blend),
                 write_mask: wgpu::ColorWrites::ALL,
             })],
         }),
@@ -39,7 +73,7 @@ pub fn create_render_pipeline(
         },
         depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
             format,
-            depth_write_enabled: true,
+            depth_write_enabled: blending.depth_write_enabled,
             depth_compare: wgpu::CompareFunction::Less,
             stencil: wgpu::StencilState::default(),
             bias: wgpu::DepthBiasState::default(),
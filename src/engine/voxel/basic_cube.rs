@@ -1,5 +1,6 @@
 use std::{borrow::Cow, iter};
 
+use strum::IntoEnumIterator;
 use tracing::debug;
 use wgpu::util::DeviceExt;
 
@@ -123,6 +124,14 @@ pub fn add_block_model(
     let texture = texture
         .get_coordinates(&texture_atlas.as_ref())
         .expect("Texture not found");
+    // The cube is drawn with a single 2D texture so every face has to be on the same layer
+    let layer = texture[Face::North].layer;
+    if Face::iter().any(|face| texture[face].layer != layer) {
+        anyhow::bail!("The faces of the cube are on different texture array layers");
+    }
+    let Some(layer_image) = texture_atlas.layers.get(layer as usize) else {
+        anyhow::bail!("The texture atlas has no layer {layer}");
+    };
     let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Cube Vertex Buffer"),
         contents: bytemuck::cast_slice(&block_vertices(texture)),
@@ -139,13 +148,7 @@ pub fn add_block_model(
         num_elements: indicies.len() as u32,
         material: 0,
     };
-    let texture = Texture::from_image(
-        device,
-        queue,
-        &texture_atlas.texture,
-        Some("Texture Atlas "),
-        false,
-    )?;
+    let texture = Texture::from_image(device, queue, layer_image, Some("Texture Atlas "), false)?;
     let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
         layout,
         entries: &[
@@ -0,0 +1,296 @@
+/*!
+# Buffer Arena
+
+Section meshes are stored in one large vertex buffer and one large index buffer instead of two small buffers per section.
+A [RangeAllocator] keeps track of which parts of the buffers are free. When a mesh does not fit the buffer is doubled and the old contents are copied over.
+
+Keeping every section in the same buffers means they can all be drawn with a single `multi_draw_indexed_indirect` call per layer.
+*/
+use std::{marker::PhantomData, mem, ops::Range};
+
+use bytemuck::Pod;
+use glam::Vec3;
+
+use super::{
+    chunk_mesh::BlockVertex,
+    gpu_vertex::{GpuBlockVertex, GpuVertex},
+    texture_atlas::TextureTable,
+};
+
+/// Hands out ranges of a fixed size space. Freed ranges are merged with the free ranges they touch
+#[derive(Debug, Clone)]
+pub struct RangeAllocator {
+    size: u32,
+    /// Sorted by start. Two free ranges never touch
+    free: Vec<Range<u32>>,
+}
+impl RangeAllocator {
+    pub fn new(size: u32) -> Self {
+        let mut free = Vec::new();
+        if size > 0 {
+            free.push(0..size);
+        }
+        Self { size, free }
+    }
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+    /// The number of elements that have been handed out
+    pub fn used(&self) -> u32 {
+        self.size
+            - self
+                .free
+                .iter()
+                .map(|range| range.len() as u32)
+                .sum::<u32>()
+    }
+    /// Takes the first free range that is large enough. None if there is no room left
+    pub fn allocate(&mut self, length: u32) -> Option<Range<u32>> {
+        if length == 0 {
+            return Some(0..0);
+        }
+        let index = self
+            .free
+            .iter()
+            .position(|range| range.len() as u32 >= length)?;
+        let range = &mut self.free[index];
+        let start = range.start;
+        range.start += length;
+        if self.free[index].is_empty() {
+            self.free.remove(index);
+        }
+        Some(start..start + length)
+    }
+    /// Gives a range back. It must have come from [RangeAllocator::allocate]
+    pub fn free(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+        let index = self.free.partition_point(|free| free.start < range.start);
+        self.free.insert(index, range);
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start {
+            self.free[index].end = self.free[index + 1].end;
+            self.free.remove(index + 1);
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start {
+            self.free[index - 1].end = self.free[index].end;
+            self.free.remove(index);
+        }
+    }
+    /// Takes the first free range that is large enough. Grows to at least double the size when there is no room left
+    pub fn allocate_or_grow(&mut self, length: u32) -> Range<u32> {
+        if let Some(range) = self.allocate(length) {
+            return range;
+        }
+        self.grow((self.size * 2).max(self.size + length));
+        self.allocate(length)
+            .expect("The grown space has room at the end")
+    }
+    /// Adds free space to the end
+    pub fn grow(&mut self, new_size: u32) {
+        let old_size = self.size;
+        if new_size <= old_size {
+            return;
+        }
+        self.size = new_size;
+        self.free(old_size..new_size);
+    }
+}
+/// A GPU buffer of `T` that is split up with a [RangeAllocator]
+#[derive(Debug)]
+pub struct BufferArena<T> {
+    label: &'static str,
+    usage: wgpu::BufferUsages,
+    buffer: wgpu::Buffer,
+    allocator: RangeAllocator,
+    _marker: PhantomData<T>,
+}
+impl<T: Pod> BufferArena<T> {
+    /// `usage` is what the buffer is used for. Copying is always added so the buffer can grow
+    pub fn new(
+        device: &wgpu::Device,
+        label: &'static str,
+        usage: wgpu::BufferUsages,
+        capacity: u32,
+    ) -> Self {
+        let usage = usage | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC;
+        Self {
+            label,
+            usage,
+            buffer: Self::create_buffer(device, label, usage, capacity),
+            allocator: RangeAllocator::new(capacity),
+            _marker: PhantomData,
+        }
+    }
+    fn create_buffer(
+        device: &wgpu::Device,
+        label: &'static str,
+        usage: wgpu::BufferUsages,
+        capacity: u32,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: capacity as u64 * mem::size_of::<T>() as u64,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+    pub fn allocator(&self) -> &RangeAllocator {
+        &self.allocator
+    }
+    /// Writes the data into a free part of the buffer and returns where it was written in elements
+    ///
+    /// Grows the buffer if there is no room left
+    pub fn insert(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, data: &[T]) -> Range<u32> {
+        let old_size = self.allocator.size();
+        let range = self.allocator.allocate_or_grow(data.len() as u32);
+        if self.allocator.size() != old_size {
+            self.grow(device, queue, old_size);
+        }
+        if !range.is_empty() {
+            queue.write_buffer(
+                &self.buffer,
+                range.start as u64 * mem::size_of::<T>() as u64,
+                bytemuck::cast_slice(data),
+            );
+        }
+        range
+    }
+    /// Frees a range returned by [BufferArena::insert]. The data is left in the buffer until it is overwritten
+    pub fn remove(&mut self, range: Range<u32>) {
+        self.allocator.free(range);
+    }
+    /// Replaces the buffer with one the size of the grown allocator. The first `old_size` elements are copied over
+    fn grow(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, old_size: u32) {
+        let new_size = self.allocator.size();
+        let buffer = Self::create_buffer(device, self.label, self.usage, new_size);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Buffer Arena Grow"),
+        });
+        encoder.copy_buffer_to_buffer(
+            &self.buffer,
+            0,
+            &buffer,
+            0,
+            old_size as u64 * mem::size_of::<T>() as u64,
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+        self.buffer = buffer;
+    }
+}
+/// The vertex and index arenas every section mesh is written into
+#[derive(Debug)]
+pub struct ChunkArena {
+    pub vertices: BufferArena<GpuBlockVertex>,
+    pub indices: BufferArena<u32>,
+    /// The index of every texture. Used to convert the vertices
+    pub textures: TextureTable,
+}
+impl ChunkArena {
+    /// Room for about 256 sections with a few hundred faces each before the first grow
+    const INITIAL_VERTICES: u32 = 1 << 16;
+    pub fn new(device: &wgpu::Device, textures: TextureTable) -> Self {
+        Self {
+            vertices: BufferArena::new(
+                device,
+                "voxel_chunk_vertices",
+                wgpu::BufferUsages::VERTEX,
+                Self::INITIAL_VERTICES,
+            ),
+            indices: BufferArena::new(
+                device,
+                "voxel_chunk_indices",
+                wgpu::BufferUsages::INDEX,
+                Self::INITIAL_VERTICES / 4 * 6,
+            ),
+            textures,
+        }
+    }
+    /// Converts the vertices into the [GpuBlockVertex] format relative to `origin` and writes them into the arena
+    pub fn insert_vertices(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        vertices: &[BlockVertex],
+        origin: Vec3,
+    ) -> Range<u32> {
+        let vertices: Vec<GpuBlockVertex> = vertices
+            .iter()
+            .map(|vertex| GpuBlockVertex::from_block_vertex(vertex, origin, &self.textures))
+            .collect();
+        self.vertices.insert(device, queue, &vertices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocations_do_not_overlap() {
+        let mut allocator = RangeAllocator::new(100);
+        let a = allocator.allocate(30).unwrap();
+        let b = allocator.allocate(30).unwrap();
+        let c = allocator.allocate(40).unwrap();
+        assert_eq!((a.clone(), b.clone(), c.clone()), (0..30, 30..60, 60..100));
+        assert_eq!(allocator.allocate(1), None);
+        assert_eq!(allocator.used(), 100);
+
+        // The freed middle is reused
+        allocator.free(b);
+        assert_eq!(allocator.allocate(40), None);
+        assert_eq!(allocator.allocate(20), Some(30..50));
+        assert_eq!(allocator.used(), 90);
+    }
+
+    #[test]
+    fn test_freed_ranges_merge() {
+        let mut allocator = RangeAllocator::new(90);
+        let ranges: Vec<_> = (0..3).map(|_| allocator.allocate(30).unwrap()).collect();
+        allocator.free(ranges[0].clone());
+        allocator.free(ranges[2].clone());
+        assert_eq!(allocator.allocate(60), None);
+        // Freeing the middle joins all three
+        allocator.free(ranges[1].clone());
+        assert_eq!(allocator.allocate(90), Some(0..90));
+    }
+
+    #[test]
+    fn test_grow_joins_the_free_end() {
+        let mut allocator = RangeAllocator::new(50);
+        allocator.allocate(40).unwrap();
+        allocator.grow(100);
+        assert_eq!(allocator.allocate(60), Some(40..100));
+        assert_eq!(allocator.used(), 100);
+    }
+
+    #[test]
+    fn test_arena_grows_when_full() {
+        let mut allocator = RangeAllocator::new(64);
+        let a = allocator.allocate_or_grow(40);
+        let b = allocator.allocate_or_grow(20);
+        assert_eq!(
+            (a.clone(), b.clone(), allocator.size()),
+            (0..40, 40..60, 64)
+        );
+        // Does not fit in the 4 left at the end. The size is doubled and the free end is used
+        assert_eq!(allocator.allocate_or_grow(30), 60..90);
+        assert_eq!(allocator.size(), 128);
+        // Larger than double the size
+        assert_eq!(allocator.allocate_or_grow(200), 90..290);
+        assert_eq!(allocator.size(), 128 + 200);
+        assert_eq!(allocator.used(), 290);
+
+        // Freed ranges are reused before growing again
+        allocator.free(a);
+        assert_eq!(allocator.allocate_or_grow(30), 0..30);
+        assert_eq!(allocator.allocate_or_grow(0), 0..0);
+        allocator.free(b);
+        assert_eq!(allocator.used(), 30 + 30 + 200);
+        assert_eq!(allocator.allocate_or_grow(30), 30..60);
+        assert_eq!(allocator.size(), 328);
+    }
+}
@@ -1,30 +1,117 @@
-use std::{mem, ops::Add};
+use std::{
+    mem,
+    ops::{Add, Range},
+    sync::Arc,
+};
 
 use bytemuck::{Pod, Zeroable};
-use glam::{I64Vec3, IVec3, UVec3, Vec3};
-use strum::{EnumIs, IntoEnumIterator};
+use glam::{I64Vec3, IVec3, UVec3, Vec2, Vec3};
+use strum::{Display, EnumIs, EnumIter, EnumString, IntoEnumIterator};
 use wgpu::{
-    util::DeviceExt as _, vertex_attr_array, BufferAddress, VertexBufferLayout, VertexStepMode,
+    util::DrawIndexedIndirectArgs, vertex_attr_array, BufferAddress, VertexBufferLayout,
+    VertexStepMode,
 };
 
 use crate::engine::{
+    frustum::Aabb,
     render_types::ShaderVertexType,
     shapes::{Quad, Vertex},
-    voxel::{cube_data::UntexturedQuad, VoxelLocation},
+    voxel::{cube_data::UntexturedQuad, greedy_mesher, VoxelLocation},
 };
 
-use super::{Face, LoadedCubeTexture};
+use super::{
+    buffer_arena::ChunkArena, voxel_model::VoxelModel, Face, LoadedCubeTexture, UVCoordinates,
+};
 const CHUNK_HEIGHT: usize = 16;
 const CHUNK_WIDTH: usize = 16;
 const CHUNK_DEPTH: usize = 16;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, EnumIs)]
+/// How a section is turned into quads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum MeshingMode {
+    /// One quad for every visible face
+    #[default]
+    Naive,
+    /// Merges neighbouring faces with the same texture into larger quads. See [greedy_mesher]
+    Greedy,
+}
+/// How sections are meshed. Stored with the mesh so it is rebuilt the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeshOptions {
+    pub mode: MeshingMode,
+    /// Darkens the corners of faces that are next to other blocks
+    pub ambient_occlusion: bool,
+}
+impl Default for MeshOptions {
+    fn default() -> Self {
+        Self {
+            mode: MeshingMode::default(),
+            ambient_occlusion: true,
+        }
+    }
+}
+impl From<MeshingMode> for MeshOptions {
+    fn from(mode: MeshingMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, EnumIs)]
 pub enum VoxelVisiblity {
     Empty,
+    /// Partly see through. Such as water or stained glass. Blended with what is behind it
     Translucent,
+    /// Pixels are either fully solid or fully see through. Such as leaves or glass
+    Cutout,
     #[default]
     Opaque,
 }
+/// The sub-meshes of a section. Each layer is drawn by its own pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter, Display)]
+pub enum RenderLayer {
+    Opaque,
+    /// Drawn after the opaque layer. Transparent pixels are discarded
+    Cutout,
+    /// Drawn last with alpha blending. Sections are sorted back to front
+    Translucent,
+}
+impl RenderLayer {
+    pub const COUNT: usize = 3;
+    /// The layer a voxel is drawn in. Empty voxels are not drawn
+    pub fn for_visibility(visibility: VoxelVisiblity) -> Option<RenderLayer> {
+        match visibility {
+            VoxelVisiblity::Empty => None,
+            VoxelVisiblity::Translucent => Some(RenderLayer::Translucent),
+            VoxelVisiblity::Cutout => Some(RenderLayer::Cutout),
+            VoxelVisiblity::Opaque => Some(RenderLayer::Opaque),
+        }
+    }
+}
+impl From<RenderLayer> for usize {
+    fn from(layer: RenderLayer) -> Self {
+        layer as usize
+    }
+}
+/// The light levels of a voxel. Both are 0-15
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct VoxelLight {
+    pub sky: u8,
+    pub block: u8,
+}
+impl VoxelLight {
+    /// Open sky with no block light. Used for faces that look outside of the loaded world
+    pub const FULL_SKY: VoxelLight = VoxelLight { sky: 15, block: 0 };
+    pub fn new(sky: u8, block: u8) -> Self {
+        Self { sky, block }
+    }
+    /// The light levels scaled to 0-1 for the shader. Sky light followed by block light
+    pub fn as_vertex_light(&self) -> [f32; 2] {
+        [self.sky as f32 / 15.0, self.block as f32 / 15.0]
+    }
+}
 /// A single Voxel in the world with what faces are visible
 #[derive(Debug, Clone, PartialEq)]
 pub struct RenderVoxel<'a> {
@@ -40,7 +127,10 @@ pub struct Voxel {
     pub visibility: VoxelVisiblity,
     /// None is transparent
     pub textures: Option<LoadedCubeTexture>,
-    // TODO: Custom Models. Update the Face method to use it.
+    /// The light inside of the voxel. Faces next to it are lit with it
+    pub light: VoxelLight,
+    /// Drawn instead of the faces of a cube. See [push_model_faces]
+    pub model: Option<Arc<VoxelModel>>,
 }
 impl Voxel {
     pub fn air(position: Vec3) -> Self {
@@ -48,22 +138,32 @@ impl Voxel {
             position,
             visibility: VoxelVisiblity::Empty,
             textures: None,
+            light: VoxelLight::default(),
+            model: None,
         }
     }
+    /// `textures` is only None for empty voxels. The registry gives visible blocks without textures the missing texture
     pub fn new(
         position: Vec3,
         visibility: VoxelVisiblity,
         textures: Option<LoadedCubeTexture>,
     ) -> Self {
-        if visibility.is_opaque() && textures.is_none() {
-            todo!("Give Coords of missing texture")
-        }
         Self {
             position,
             visibility,
             textures,
+            light: VoxelLight::default(),
+            model: None,
         }
     }
+    pub fn with_model(mut self, model: Option<Arc<VoxelModel>>) -> Self {
+        self.model = model;
+        self
+    }
+    pub fn with_light(mut self, light: VoxelLight) -> Self {
+        self.light = light;
+        self
+    }
     /// Gets the vertices for the given Face. Returns an array of 4 [BlockVertex]
     ///
     /// # Note
@@ -75,7 +175,270 @@ impl Voxel {
         let uv = self.textures.as_ref().map(|t| t[face]).unwrap_or_default();
         let quad =
             Quad::new_quad_from_face(face, self.position + chunk_position, Vec3::splat(1f32), uv);
-        BlockVertex::from_quad(quad)
+        BlockVertex::with_layer(BlockVertex::from_quad(quad), uv.layer)
+    }
+}
+/// A voxel on the border of a neighbouring section
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BorderVoxel {
+    pub visibility: VoxelVisiblity,
+    pub light: VoxelLight,
+}
+/// One 16x16 layer of voxels on the border of a section
+pub type BorderLayer = [BorderVoxel; CHUNK_WIDTH * CHUNK_DEPTH];
+/// The layers of the neighbouring sections that touch a section. Used to cull the faces on the border of the section
+///
+/// Neighbours that are not loaded are treated as empty. So faces towards them are visible
+#[derive(Debug, Clone, Default)]
+pub struct SectionNeighbours {
+    /// Indexed by the face of the section the neighbour is on
+    layers: [Option<Box<BorderLayer>>; 6],
+    /// The opaque voxels one step past the 12 edges of the section. One bit for each voxel along the edge. See [SectionNeighbours::set_outer_opaque]
+    edges: [u16; 12],
+    /// The opaque voxels one step past the 8 corners of the section. One bit for each corner
+    corners: u8,
+}
+impl SectionNeighbours {
+    pub fn set(&mut self, face: Face, layer: Box<BorderLayer>) {
+        self.layers[usize::from(face)] = Some(layer);
+    }
+    pub fn has(&self, face: Face) -> bool {
+        self.layers[usize::from(face)].is_some()
+    }
+    /// The voxel in the neighbour that touches the voxel at `coords` through `face`
+    pub fn get(&self, face: Face, coords: I64Vec3) -> Option<BorderVoxel> {
+        self.layers[usize::from(face)]
+            .as_ref()
+            .map(|layer| layer[border_index(face, coords)])
+    }
+    /// Marks the voxel at `coords` as opaque. It has to be outside of the section on two or three axes. Only ambient occlusion looks at these voxels
+    pub fn set_outer_opaque(&mut self, coords: I64Vec3) {
+        match outer_bit(coords) {
+            Some((None, bit)) => self.corners |= 1 << bit,
+            Some((Some(edge), bit)) => self.edges[edge] |= 1 << bit,
+            None => {}
+        }
+    }
+    /// Is the voxel past an edge or corner of the section opaque. Voxels that were never set are not
+    pub fn is_outer_opaque(&self, coords: I64Vec3) -> bool {
+        match outer_bit(coords) {
+            Some((None, bit)) => self.corners & (1 << bit) != 0,
+            Some((Some(edge), bit)) => self.edges[edge] & (1 << bit) != 0,
+            None => false,
+        }
+    }
+}
+/// The edge and bit of a voxel past an edge of the section. Corners have no edge
+///
+/// None if the voxel is not one step outside of the section on at least two axes
+fn outer_bit(coords: I64Vec3) -> Option<(Option<usize>, u32)> {
+    let mut outside = [false; 3];
+    let mut positive = [false; 3];
+    for axis in 0..3 {
+        match coords[axis] {
+            -1 => outside[axis] = true,
+            16 => (outside[axis], positive[axis]) = (true, true),
+            0..=15 => {}
+            _ => return None,
+        }
+    }
+    match outside {
+        [true, true, true] => Some((
+            None,
+            positive[0] as u32 | (positive[1] as u32) << 1 | (positive[2] as u32) << 2,
+        )),
+        [true, true, false] | [true, false, true] | [false, true, true] => {
+            let inside = outside.iter().position(|outside| !outside)?;
+            let (a, b) = match inside {
+                0 => (1, 2),
+                1 => (0, 2),
+                _ => (0, 1),
+            };
+            let edge = inside * 4 + (positive[a] as usize) * 2 + positive[b] as usize;
+            Some((Some(edge), coords[inside] as u32))
+        }
+        _ => None,
+    }
+}
+/// The index inside of a [BorderLayer] on the `face` side of a section. The axis of the face is ignored
+pub fn border_index(face: Face, coords: I64Vec3) -> usize {
+    let (a, b) = match face.axis() {
+        0 => (coords.y, coords.z),
+        1 => (coords.x, coords.z),
+        _ => (coords.x, coords.y),
+    };
+    ((b as usize & 0xF) << 4) | (a as usize & 0xF)
+}
+/// The coordinates of the voxel at `index` of the [BorderLayer] on the `face` side of a section
+pub fn border_coords(face: Face, index: usize) -> I64Vec3 {
+    let a = (index & 0xF) as i64;
+    let b = ((index >> 4) & 0xF) as i64;
+    let side = if face.is_positive() { 15 } else { 0 };
+    match face.axis() {
+        0 => I64Vec3::new(side, a, b),
+        1 => I64Vec3::new(a, side, b),
+        _ => I64Vec3::new(a, b, side),
+    }
+}
+/// Is the face of the voxel at `index` visible
+///
+/// Faces are visible if the voxel is not empty and the next voxel is not opaque.
+/// See through voxels also hide the faces between them and voxels with the same visibility. So the inside of a pool of water is not drawn.
+/// On the border of the section the next voxel comes from the neighbours. Faces towards missing neighbours are always visible
+///
+/// Voxels with a model have no cube faces. Their model does not fill the block so it never hides the faces next to it inside of the section
+pub fn is_face_visible(
+    voxels: &[Voxel],
+    neighbours: &SectionNeighbours,
+    index: usize,
+    face: Face,
+) -> bool {
+    let current_voxel = &voxels[index];
+    if current_voxel.visibility.is_empty() || current_voxel.model.is_some() {
+        return false;
+    }
+    let coords = I64Vec3::from_index(index);
+    let next_voxel = match coords.position_for_face(face) {
+        Some(next) => {
+            let next = &voxels[next.to_index()];
+            if next.model.is_some() {
+                return true;
+            }
+            Some(next.visibility)
+        }
+        None => neighbours.get(face, coords).map(|voxel| voxel.visibility),
+    };
+    match next_voxel {
+        Some(next_voxel) => {
+            !next_voxel.is_opaque()
+                && (current_voxel.visibility.is_opaque() || next_voxel != current_voxel.visibility)
+        }
+        None => true,
+    }
+}
+/// Pushes the faces of the model of the voxel at `index` onto the layer it is drawn in
+///
+/// Faces with a cullface are hidden by an opaque voxel on that side and lit by it. Other faces are lit by the voxel itself.
+/// Model faces have no ambient occlusion
+pub fn push_model_faces(
+    voxels: &[Voxel],
+    neighbours: &SectionNeighbours,
+    index: usize,
+    chunk_position: Vec3,
+    layers: &mut [RawMeshLayer; RenderLayer::COUNT],
+) {
+    let voxel = &voxels[index];
+    let (Some(model), Some(layer)) = (&voxel.model, RenderLayer::for_visibility(voxel.visibility))
+    else {
+        return;
+    };
+    let coords = I64Vec3::from_index(index);
+    for quad in &model.quads {
+        let light = match quad.cullface {
+            Some(cullface) => {
+                let mut next = coords;
+                next[cullface.axis()] += if cullface.is_positive() { 1 } else { -1 };
+                if is_opaque_at(voxels, neighbours, next) {
+                    continue;
+                }
+                face_light(voxels, neighbours, index, cullface)
+            }
+            None => voxel.light,
+        };
+        let vertices = BlockVertex::from_quad(quad.at(voxel.position + chunk_position));
+        layers[usize::from(layer)].push_quad(
+            BlockVertex::with_layer(BlockVertex::with_light(vertices, light), quad.layer),
+            [3; 4],
+        );
+    }
+}
+/// The light shining on a face. Taken from the voxel the face looks into
+pub fn face_light(
+    voxels: &[Voxel],
+    neighbours: &SectionNeighbours,
+    index: usize,
+    face: Face,
+) -> VoxelLight {
+    let coords = I64Vec3::from_index(index);
+    match coords.position_for_face(face) {
+        Some(next) => voxels[next.to_index()].light,
+        None => neighbours
+            .get(face, coords)
+            .map(|voxel| voxel.light)
+            .unwrap_or(VoxelLight::FULL_SKY),
+    }
+}
+/// Is the voxel at `coords` opaque. Coordinates one step outside of the section are looked up in the neighbours
+///
+/// Voxels past the edges and corners of the section come from [SectionNeighbours::is_outer_opaque]
+fn is_opaque_at(voxels: &[Voxel], neighbours: &SectionNeighbours, coords: I64Vec3) -> bool {
+    let outside = Face::iter()
+        .filter(|face| {
+            let value = coords[face.axis()];
+            if face.is_positive() {
+                value > 15
+            } else {
+                value < 0
+            }
+        })
+        .collect::<Vec<_>>();
+    match outside.as_slice() {
+        [] => voxels[coords.to_index()].visibility.is_opaque(),
+        [face] => neighbours
+            .get(*face, coords)
+            .is_some_and(|voxel| voxel.visibility.is_opaque()),
+        _ => neighbours.is_outer_opaque(coords),
+    }
+}
+/// The ambient occlusion of each corner of a face. In the same order as the vertices of [Quad::new_quad_from_face]
+///
+/// Every corner looks at the two voxels beside it and the voxel diagonal to it in front of the face.
+/// 3 is fully lit and 0 is a corner with both sides blocked
+pub fn face_occlusion(
+    voxels: &[Voxel],
+    neighbours: &SectionNeighbours,
+    index: usize,
+    face: Face,
+) -> [u8; 4] {
+    let mut front = I64Vec3::from_index(index);
+    front[face.axis()] += if face.is_positive() { 1 } else { -1 };
+    let center = Vec3::from_index(index);
+    let quad = Quad::new_quad_from_face(face, center, Vec3::ONE, UVCoordinates::default());
+    let corners = [
+        quad.top_left,
+        quad.top_right,
+        quad.bottom_left,
+        quad.bottom_right,
+    ];
+    let mut occlusion = [3; 4];
+    for (corner, occlusion) in corners.iter().zip(occlusion.iter_mut()) {
+        let mut sides = [I64Vec3::ZERO; 2];
+        for (side, axis) in (0..3).filter(|axis| *axis != face.axis()).enumerate() {
+            sides[side][axis] = if corner.position[axis] > center[axis] {
+                1
+            } else {
+                -1
+            };
+        }
+        let first = is_opaque_at(voxels, neighbours, front + sides[0]);
+        let second = is_opaque_at(voxels, neighbours, front + sides[1]);
+        let diagonal = is_opaque_at(voxels, neighbours, front + sides[0] + sides[1]);
+        *occlusion = if first && second {
+            0
+        } else {
+            3 - (first as u8 + second as u8 + diagonal as u8)
+        };
+    }
+    occlusion
+}
+/// Pushes the indices of a quad. The diagonal is picked so the brighter corners are joined
+///
+/// Otherwise the occlusion is interpolated differently depending on which way the quad was split
+pub fn push_occluded_indices(indices: &mut Vec<u32>, offset: u32, occlusion: [u8; 4]) {
+    if occlusion[0] + occlusion[3] > occlusion[1] + occlusion[2] {
+        UntexturedQuad::push_flipped_indicies(indices, offset);
+    } else {
+        UntexturedQuad::push_indicies(indices, offset);
     }
 }
 /// A visible voxel face in the world
@@ -84,16 +447,55 @@ impl Voxel {
 pub struct BlockVertex {
     pub position: [f32; 3],
     pub normal: [f32; 3],
+    /// Position inside of the texture in tiles. Values past 1 repeat the texture
     pub uv: [f32; 2],
+    /// The area of the texture atlas the texture is in. The top left corner followed by the size
+    pub tile: [f32; 4],
+    /// Sky light followed by block light. Both are 0-1
+    pub light: [f32; 2],
+    /// Ambient occlusion of the corner. 0 is fully occluded and 1 is not occluded
+    pub ao: f32,
+    /// The layer of the texture array the texture is in
+    pub layer: u32,
 }
 impl BlockVertex {
     pub fn from_quad(quad: Quad) -> [BlockVertex; 4] {
-        [
-            BlockVertex::new_from_generic_vertex(quad.top_left),
-            BlockVertex::new_from_generic_vertex(quad.top_right),
-            BlockVertex::new_from_generic_vertex(quad.bottom_left),
-            BlockVertex::new_from_generic_vertex(quad.bottom_right),
-        ]
+        Self::from_repeating_quad(quad, Vec2::ONE)
+    }
+    /// Creates the vertices of a quad whose texture is repeated `repeat` times across and down
+    ///
+    /// The atlas area is taken from the uv coordinates of the quad
+    pub fn from_repeating_quad(quad: Quad, repeat: Vec2) -> [BlockVertex; 4] {
+        Self::repeat_texture(
+            [
+                BlockVertex::new_from_generic_vertex(quad.top_left),
+                BlockVertex::new_from_generic_vertex(quad.top_right),
+                BlockVertex::new_from_generic_vertex(quad.bottom_left),
+                BlockVertex::new_from_generic_vertex(quad.bottom_right),
+            ],
+            repeat,
+        )
+    }
+    /// Converts the atlas uv coordinates of a quad into a tile and the position inside of it
+    pub fn repeat_texture(vertices: [BlockVertex; 4], repeat: Vec2) -> [BlockVertex; 4] {
+        let min = vertices
+            .iter()
+            .fold(Vec2::MAX, |min, v| min.min(Vec2::from(v.uv)));
+        let max = vertices
+            .iter()
+            .fold(Vec2::MIN, |max, v| max.max(Vec2::from(v.uv)));
+        let size = max - min;
+        vertices.map(|mut vertex| {
+            // Texture-less quads have no area
+            let tile_uv = Vec2::select(
+                size.cmpgt(Vec2::ZERO),
+                (Vec2::from(vertex.uv) - min) / size,
+                Vec2::ZERO,
+            );
+            vertex.uv = (tile_uv * repeat).into();
+            vertex.tile = [min.x, min.y, size.x, size.y];
+            vertex
+        })
     }
     pub fn new_from_generic_vertex(generic_vertex: Vertex) -> Self {
         let Vertex {
@@ -105,8 +507,35 @@ impl BlockVertex {
             position: position.into(),
             normal: normal.into(),
             uv: uv.into(),
+            tile: [0.0; 4],
+            light: VoxelLight::FULL_SKY.as_vertex_light(),
+            ao: 1.0,
+            layer: 0,
         }
     }
+    /// Sets the texture array layer of every vertex of a quad
+    pub fn with_layer(vertices: [BlockVertex; 4], layer: u32) -> [BlockVertex; 4] {
+        vertices.map(|mut vertex| {
+            vertex.layer = layer;
+            vertex
+        })
+    }
+    /// Lights every vertex of a quad with the same light
+    pub fn with_light(vertices: [BlockVertex; 4], light: VoxelLight) -> [BlockVertex; 4] {
+        vertices.map(|mut vertex| {
+            vertex.light = light.as_vertex_light();
+            vertex
+        })
+    }
+    /// Sets the ambient occlusion of each vertex. See [face_occlusion]
+    pub fn with_occlusion(vertices: [BlockVertex; 4], occlusion: [u8; 4]) -> [BlockVertex; 4] {
+        let mut index = 0;
+        vertices.map(|mut vertex| {
+            vertex.ao = occlusion[index] as f32 / 3.0;
+            index += 1;
+            vertex
+        })
+    }
 }
 impl Default for BlockVertex {
     fn default() -> Self {
@@ -114,6 +543,10 @@ impl Default for BlockVertex {
             position: [0.0; 3],
             normal: [0.0; 3],
             uv: [0.0; 2],
+            tile: [0.0; 4],
+            light: VoxelLight::FULL_SKY.as_vertex_light(),
+            ao: 1.0,
+            layer: 0,
         }
     }
 }
@@ -121,10 +554,14 @@ impl ShaderVertexType for BlockVertex {
     fn desc() -> wgpu::VertexBufferLayout<'static> {
         use wgpu::VertexAttribute;
 
-        static ATTRIBUTES: [VertexAttribute; 3] = vertex_attr_array![
+        static ATTRIBUTES: [VertexAttribute; 7] = vertex_attr_array![
             0 => Float32x3,
             1 => Float32x3,
             2 => Float32x2,
+            3 => Float32x4,
+            4 => Float32x2,
+            5 => Float32,
+            7 => Uint32,
         ];
 
         VertexBufferLayout {
@@ -134,27 +571,64 @@ impl ShaderVertexType for BlockVertex {
         }
     }
 }
-#[derive(Debug, Clone)]
-pub struct RawChunkMesh {
-    pub position: I64Vec3,
+/// The vertices and indices of one [RenderLayer] of a section
+#[derive(Debug, Clone, Default)]
+pub struct RawMeshLayer {
     pub vertices: Vec<BlockVertex>,
     pub indices: Vec<u32>,
 }
-impl RawChunkMesh {
-    /// Clears the vertices and indices
+impl RawMeshLayer {
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
     pub fn clear(&mut self) {
         self.vertices.clear();
         self.indices.clear();
     }
+    /// Pushes the vertices of a quad. The quad is split along the diagonal picked by [push_occluded_indices]
+    pub fn push_quad(&mut self, vertices: [BlockVertex; 4], occlusion: [u8; 4]) {
+        let vertex_index = self.vertices.len() as u32;
+        self.vertices
+            .extend_from_slice(&BlockVertex::with_occlusion(vertices, occlusion));
+        push_occluded_indices(&mut self.indices, vertex_index, occlusion);
+    }
+}
+#[derive(Debug, Clone)]
+pub struct RawChunkMesh {
+    pub position: I64Vec3,
+    /// The options used when the mesh is rebuilt
+    pub options: MeshOptions,
+    /// Indexed by [RenderLayer]
+    pub layers: [RawMeshLayer; RenderLayer::COUNT],
+}
+impl RawChunkMesh {
+    /// Clears the vertices and indices of every layer
+    pub fn clear(&mut self) {
+        self.layers.iter_mut().for_each(RawMeshLayer::clear);
+    }
+    pub fn layer(&self, layer: RenderLayer) -> &RawMeshLayer {
+        &self.layers[usize::from(layer)]
+    }
+    pub fn layer_mut(&mut self, layer: RenderLayer) -> &mut RawMeshLayer {
+        &mut self.layers[usize::from(layer)]
+    }
+    pub fn number_of_vertices(&self) -> usize {
+        self.layers.iter().map(|layer| layer.vertices.len()).sum()
+    }
     /// Builds a new chunk mesh from a list of voxels
     /// See the `rebuild` method for more info
-    pub fn build(position: I64Vec3, voxels: Vec<Voxel>) -> RawChunkMesh {
+    pub fn build(
+        position: I64Vec3,
+        voxels: Vec<Voxel>,
+        neighbours: &SectionNeighbours,
+        options: MeshOptions,
+    ) -> RawChunkMesh {
         let mut this = Self {
             position: position,
-            vertices: Vec::with_capacity(voxels.len() * 2),
-            indices: Vec::with_capacity(voxels.len() * 3),
+            options,
+            layers: Default::default(),
         };
-        this.rebuild(voxels);
+        this.rebuild(voxels, neighbours);
         this
     }
     /// Rebuilds the chunk mesh from a list of voxels
@@ -162,100 +636,489 @@ impl RawChunkMesh {
     /// and rebuild them from the list of voxels
     /// A list of Voxels is 4096 elements long. So an XYZ coordinate can be converted to an index as long as it is within the range of 0-15
     /// For more info on Voxel Location see the [VoxelLocation] trait
-    pub fn rebuild(&mut self, voxels: Vec<Voxel>) {
+    ///
+    /// Faces on the border are culled against the neighbouring sections
+    pub fn rebuild(&mut self, voxels: Vec<Voxel>, neighbours: &SectionNeighbours) {
         assert_eq!(
             voxels.len(),
             CHUNK_HEIGHT * CHUNK_WIDTH * CHUNK_DEPTH,
             "Invalid Voxel Count"
         );
         self.clear();
-        let mut render_voxels = Vec::with_capacity(voxels.len());
-        for index in 0..voxels.len() {
-            let coords: I64Vec3 = I64Vec3::from_index(index);
-            let current_voxel = &voxels[index];
-
-            let mut faces = Vec::with_capacity(6);
-            // Iterate over each face and check if it is visible
-            // Face is visible if the next voxel is not opaque
-            for face in Face::iter() {
-                let next_voxel = coords
-                    .position_for_face(face)
-                    .map(|c| &voxels[c.to_index()]);
-                if let Some(next_voxel) = next_voxel {
-                    // Opaque is visible
-                    if !next_voxel.visibility.is_opaque() && current_voxel.visibility.is_opaque() {
-                        faces.push(face);
-                    }
-                } else {
-                    faces.push(face);
-                }
-            }
-            if faces.len() > 0 && !current_voxel.visibility.is_empty() {
-                render_voxels.push(RenderVoxel {
-                    voxel: current_voxel,
-                    faces: faces,
-                });
-            }
-        }
-        let mut vertex_index = 0;
         let chunk_position: Vec3 = Vec3::new(
             self.position.x as f32,
             self.position.y as f32,
             self.position.z as f32,
         );
+        match self.options.mode {
+            MeshingMode::Naive => self.build_naive(&voxels, neighbours, chunk_position),
+            MeshingMode::Greedy => greedy_mesher::build(
+                &voxels,
+                neighbours,
+                chunk_position,
+                self.options.ambient_occlusion,
+                &mut self.layers,
+            ),
+        }
+    }
+    /// One quad for every visible face
+    fn build_naive(
+        &mut self,
+        voxels: &[Voxel],
+        neighbours: &SectionNeighbours,
+        chunk_position: Vec3,
+    ) {
+        let mut render_voxels = Vec::with_capacity(voxels.len());
+        for (index, current_voxel) in voxels.iter().enumerate() {
+            // Iterate over each face and check if it is visible
+            let faces: Vec<Face> = Face::iter()
+                .filter(|face| is_face_visible(voxels, neighbours, index, *face))
+                .collect();
+            if !faces.is_empty() {
+                render_voxels.push((
+                    index,
+                    RenderVoxel {
+                        voxel: current_voxel,
+                        faces,
+                    },
+                ));
+            }
+        }
         // Calculate the vertexes and indicies for each render voxel
-        for render_voxel in render_voxels.iter() {
+        for (index, render_voxel) in render_voxels.iter() {
+            let Some(layer) = RenderLayer::for_visibility(render_voxel.voxel.visibility) else {
+                continue;
+            };
             for face in &render_voxel.faces {
-                // TODO Support Custom Models
+                let light = face_light(voxels, neighbours, *index, *face);
+                let occlusion = if self.options.ambient_occlusion {
+                    face_occlusion(voxels, neighbours, *index, *face)
+                } else {
+                    [3; 4]
+                };
                 let face = render_voxel.voxel.face(*face, chunk_position);
-                self.vertices.extend_from_slice(&face);
-                UntexturedQuad::push_indicies(&mut self.indices, vertex_index);
-                vertex_index += 4;
+                self.layer_mut(layer)
+                    .push_quad(BlockVertex::with_light(face, light), occlusion);
             }
         }
+        for (index, voxel) in voxels.iter().enumerate() {
+            if voxel.model.is_some() {
+                push_model_faces(voxels, neighbours, index, chunk_position, &mut self.layers);
+            }
+        }
+    }
+}
+/// One [RenderLayer] of a section on the GPU. The ranges point into the buffers of a [ChunkArena]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkMeshLayer {
+    pub vertices: Range<u32>,
+    pub indices: Range<u32>,
+}
+impl ChunkMeshLayer {
+    /// Writes the layer into the arena. The vertices are stored relative to `origin`
+    pub fn build_layer(
+        raw_layer: &RawMeshLayer,
+        origin: Vec3,
+        arena: &mut ChunkArena,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> ChunkMeshLayer {
+        ChunkMeshLayer {
+            vertices: arena.insert_vertices(device, queue, &raw_layer.vertices, origin),
+            indices: arena.indices.insert(device, queue, &raw_layer.indices),
+        }
+    }
+    pub fn number_of_indices(&self) -> u32 {
+        self.indices.len() as u32
+    }
+    pub fn number_of_vertices(&self) -> u32 {
+        self.vertices.len() as u32
+    }
+    /// The arguments to draw this layer out of the arena. The indices of the layer start at zero so the first vertex is used as the base
+    ///
+    /// `first_instance` is left at zero. It is set to the index of the draw when the [SectionOrigin](super::gpu_vertex::SectionOrigin)s are written
+    pub fn draw_args(&self) -> DrawIndexedIndirectArgs {
+        DrawIndexedIndirectArgs {
+            index_count: self.number_of_indices(),
+            instance_count: 1,
+            first_index: self.indices.start,
+            base_vertex: self.vertices.start as i32,
+            first_instance: 0,
+        }
     }
 }
 #[derive(Debug)]
 pub struct ChunkMesh {
     pub position: I64Vec3,
-    pub vertices: wgpu::Buffer,
-    pub indices: wgpu::Buffer,
-    pub number_of_indices: u32,
+    /// The space the section takes up in the world
+    pub aabb: Aabb,
+    /// Indexed by [RenderLayer]. Empty layers are not uploaded
+    pub layers: [Option<ChunkMeshLayer>; RenderLayer::COUNT],
 }
 impl ChunkMesh {
-    /// Converts a RawChunkMesh into a ChunkMesh this will allocate the memory on the GPU
-    pub fn build_mesh(raw_mesh: RawChunkMesh, device: &wgpu::Device) -> ChunkMesh {
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("voxel_chunk_vertices"),
-            contents: bytemuck::cast_slice(&raw_mesh.vertices),
-            usage: wgpu::BufferUsages::VERTEX,
+    /// Converts a RawChunkMesh into a ChunkMesh by writing its layers into the arena
+    pub fn build_mesh(
+        raw_mesh: RawChunkMesh,
+        arena: &mut ChunkArena,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> ChunkMesh {
+        let origin = raw_mesh.position.as_vec3();
+        let layers = raw_mesh.layers.each_ref().map(|layer| {
+            (!layer.is_empty())
+                .then(|| ChunkMeshLayer::build_layer(layer, origin, arena, device, queue))
         });
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("voxel_chunk_indices"),
-            contents: bytemuck::cast_slice(&raw_mesh.indices),
-            usage: wgpu::BufferUsages::INDEX,
-        });
-
+        // Voxels are centered on their position so the section starts half a block before it
+        let min = origin - Vec3::splat(0.5);
         ChunkMesh {
             position: raw_mesh.position,
-            vertices: vertex_buffer,
-            indices: index_buffer,
-            number_of_indices: raw_mesh.indices.len() as u32,
+            aabb: Aabb::new(min, min + Vec3::splat(CHUNK_WIDTH as f32)),
+            layers,
         }
     }
+    /// Gives the space used by the mesh back to the arena
+    pub fn free(self, arena: &mut ChunkArena) {
+        for layer in self.layers.into_iter().flatten() {
+            arena.vertices.remove(layer.vertices);
+            arena.indices.remove(layer.indices);
+        }
+    }
+    pub fn layer(&self, layer: RenderLayer) -> Option<&ChunkMeshLayer> {
+        self.layers[usize::from(layer)].as_ref()
+    }
+    pub fn number_of_vertices(&self) -> u32 {
+        self.layers
+            .iter()
+            .flatten()
+            .map(|layer| layer.number_of_vertices())
+            .sum()
+    }
+    /// The center of the section in world space
+    pub fn center(&self) -> Vec3 {
+        self.aabb.center()
+    }
+}
+/// The order to draw translucent sections in. Furthest from the camera first so closer sections blend over them
+pub fn back_to_front(centers: impl IntoIterator<Item = Vec3>, camera: Vec3) -> Vec<usize> {
+    let distances: Vec<f32> = centers
+        .into_iter()
+        .map(|center| center.distance_squared(camera))
+        .collect();
+    let mut order: Vec<usize> = (0..distances.len()).collect();
+    order.sort_by(|a, b| distances[*b].total_cmp(&distances[*a]));
+    order
+}
 
-    pub fn render<'pass>(
-        &'pass self,
-        render_pass: &'pass mut wgpu::RenderPass<'pass>,
-        texture: &'pass crate::engine::texture::Texture,
-        light_bind_group: &'pass wgpu::BindGroup,
-        camera_bind_group: &'pass wgpu::BindGroup,
-    ) {
-        render_pass.set_vertex_buffer(0, self.vertices.slice(..));
-        render_pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint32);
-        //render_pass.set_bind_group(0, &material.get_bind_group(), &[]);
-        render_pass.set_bind_group(1, camera_bind_group, &[]);
-        render_pass.set_bind_group(2, light_bind_group, &[]);
-        render_pass.draw_indexed(0..self.number_of_indices, 0, 0..1);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid_section() -> Vec<Voxel> {
+        (0..4096)
+            .map(|index| Voxel {
+                position: Vec3::from_index(index),
+                visibility: VoxelVisiblity::Opaque,
+                textures: None,
+                light: VoxelLight::default(),
+                model: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_border_faces_culled_by_neighbours() {
+        let alone = RawChunkMesh::build(
+            I64Vec3::ZERO,
+            solid_section(),
+            &SectionNeighbours::default(),
+            MeshingMode::Naive.into(),
+        );
+        assert_eq!(alone.layer(RenderLayer::Opaque).vertices.len(), 6 * 256 * 4);
+
+        let opaque = BorderVoxel {
+            visibility: VoxelVisiblity::Opaque,
+            light: VoxelLight::default(),
+        };
+        let mut neighbours = SectionNeighbours::default();
+        for face in Face::iter() {
+            neighbours.set(face, Box::new([opaque; 256]));
+        }
+        // An empty spot above the section only shows the top face below it
+        let mut above = Box::new([opaque; 256]);
+        above[border_index(Face::Top, I64Vec3::new(3, 15, 7))] = BorderVoxel {
+            visibility: VoxelVisiblity::Empty,
+            light: VoxelLight::new(12, 3),
+        };
+        neighbours.set(Face::Top, above);
+        let surrounded = RawChunkMesh::build(
+            I64Vec3::ZERO,
+            solid_section(),
+            &neighbours,
+            MeshingMode::Naive.into(),
+        );
+        assert_eq!(surrounded.layer(RenderLayer::Opaque).vertices.len(), 4);
+        assert_eq!(
+            surrounded.layer(RenderLayer::Opaque).vertices[0].normal,
+            Face::Top.normal()
+        );
+        // Lit by the empty spot
+        assert_eq!(
+            surrounded.layer(RenderLayer::Opaque).vertices[0].light,
+            VoxelLight::new(12, 3).as_vertex_light()
+        );
+    }
+
+    #[test]
+    fn test_corners_next_to_blocks_are_occluded() {
+        // A floor with one block standing on it
+        let voxels: Vec<Voxel> = (0..4096)
+            .map(|index| {
+                let position = Vec3::from_index(index);
+                if position.y == 0.0 || position == Vec3::new(5.0, 1.0, 5.0) {
+                    Voxel {
+                        position,
+                        visibility: VoxelVisiblity::Opaque,
+                        textures: None,
+                        light: VoxelLight::default(),
+                        model: None,
+                    }
+                } else {
+                    Voxel::air(position)
+                }
+            })
+            .collect();
+        let neighbours = SectionNeighbours::default();
+        let open = face_occlusion(
+            &voxels,
+            &neighbours,
+            I64Vec3::new(9, 0, 9).to_index(),
+            Face::Top,
+        );
+        assert_eq!(open, [3; 4]);
+        // Diagonal to the block. Only one corner touches it
+        let diagonal = face_occlusion(
+            &voxels,
+            &neighbours,
+            I64Vec3::new(6, 0, 6).to_index(),
+            Face::Top,
+        );
+        assert_eq!(diagonal.iter().filter(|corner| **corner == 2).count(), 1);
+        assert_eq!(diagonal.iter().filter(|corner| **corner == 3).count(), 3);
+        // Beside the block. Two corners touch it
+        let beside = face_occlusion(
+            &voxels,
+            &neighbours,
+            I64Vec3::new(6, 0, 5).to_index(),
+            Face::Top,
+        );
+        assert_eq!(beside.iter().filter(|corner| **corner == 2).count(), 2);
+
+        let mut indices = Vec::new();
+        push_occluded_indices(&mut indices, 0, diagonal);
+        // The quad is split so the occluded corner is not on the shared edge
+        let dark = diagonal.iter().position(|corner| *corner == 2).unwrap() as u32;
+        assert_eq!(indices.iter().filter(|index| **index == dark).count(), 1);
+
+        let disabled = RawChunkMesh::build(
+            I64Vec3::ZERO,
+            voxels,
+            &neighbours,
+            MeshOptions {
+                mode: MeshingMode::Naive,
+                ambient_occlusion: false,
+            },
+        );
+        assert!(disabled
+            .layer(RenderLayer::Opaque)
+            .vertices
+            .iter()
+            .all(|vertex| vertex.ao == 1.0));
+    }
+
+    #[test]
+    fn test_edges_and_corners_of_the_section_occlude() {
+        let voxels: Vec<Voxel> = (0..4096)
+            .map(|index| {
+                let position = Vec3::from_index(index);
+                if position.y == 0.0 {
+                    Voxel {
+                        position,
+                        visibility: VoxelVisiblity::Opaque,
+                        textures: None,
+                        light: VoxelLight::default(),
+                        model: None,
+                    }
+                } else {
+                    Voxel::air(position)
+                }
+            })
+            .collect();
+        let index = I64Vec3::new(15, 0, 15).to_index();
+        let open = face_occlusion(&voxels, &SectionNeighbours::default(), index, Face::Top);
+        assert_eq!(open, [3; 4]);
+
+        // A block past the vertical edge of the section. Diagonal to the top of the corner voxel
+        let mut neighbours = SectionNeighbours::default();
+        neighbours.set_outer_opaque(I64Vec3::new(16, 1, 16));
+        assert!(neighbours.is_outer_opaque(I64Vec3::new(16, 1, 16)));
+        assert!(!neighbours.is_outer_opaque(I64Vec3::new(16, 2, 16)));
+        assert!(!neighbours.is_outer_opaque(I64Vec3::new(-1, 1, 16)));
+        let shaded = face_occlusion(&voxels, &neighbours, index, Face::Top);
+        assert_eq!(shaded.iter().filter(|corner| **corner == 2).count(), 1);
+
+        // Corners of the section are stored apart from the edges
+        neighbours.set_outer_opaque(I64Vec3::new(-1, 16, -1));
+        assert!(neighbours.is_outer_opaque(I64Vec3::new(-1, 16, -1)));
+        assert!(!neighbours.is_outer_opaque(I64Vec3::new(16, 16, -1)));
+        assert!(!neighbours.is_outer_opaque(I64Vec3::new(0, 16, -1)));
+    }
+
+    #[test]
+    fn test_see_through_voxels_are_split_into_layers() {
+        // Opaque floor. A pool of translucent voxels and a glass block on top of it
+        let voxels: Vec<Voxel> = (0..4096)
+            .map(|index| {
+                let position = Vec3::from_index(index);
+                let visibility = match position {
+                    Vec3 { y: 0.0, .. } => VoxelVisiblity::Opaque,
+                    Vec3 { y: 1.0, x, z } if x < 2.0 && z < 1.0 => VoxelVisiblity::Translucent,
+                    Vec3 {
+                        y: 2.0,
+                        x: 0.0,
+                        z: 0.0,
+                    } => VoxelVisiblity::Cutout,
+                    _ => return Voxel::air(position),
+                };
+                Voxel {
+                    position,
+                    visibility,
+                    textures: None,
+                    light: VoxelLight::default(),
+                    model: None,
+                }
+            })
+            .collect();
+        for mode in [MeshingMode::Naive, MeshingMode::Greedy] {
+            let mesh = RawChunkMesh::build(
+                I64Vec3::ZERO,
+                voxels.clone(),
+                &SectionNeighbours::default(),
+                MeshOptions {
+                    mode,
+                    ambient_occlusion: false,
+                },
+            );
+            // The floor under the pool is still drawn
+            let floor_top = mesh
+                .layer(RenderLayer::Opaque)
+                .vertices
+                .iter()
+                .filter(|vertex| vertex.normal == Face::Top.normal())
+                .count();
+            assert!(floor_top >= 4);
+            // Every side of the glass block
+            assert_eq!(mesh.layer(RenderLayer::Cutout).vertices.len(), 6 * 4);
+            // The faces between the two translucent voxels and the bottoms on the floor are hidden
+            let translucent_faces = mesh.layer(RenderLayer::Translucent).vertices.len() / 4;
+            let expected = match mode {
+                MeshingMode::Naive => 2 * 6 - 2 - 2,
+                // The long sides and the top are merged
+                MeshingMode::Greedy => 5,
+            };
+            assert_eq!(translucent_faces, expected, "{mode}");
+        }
+    }
+
+    #[test]
+    fn test_model_faces_are_culled_by_their_cullface() {
+        use crate::engine::{
+            shapes::Quad,
+            voxel::voxel_model::{ModelQuad, VoxelModel},
+        };
+        // The bottom and top of a slab. Only the bottom has a cullface
+        let face = |face: Face, cullface: Option<Face>| ModelQuad {
+            quad: Quad::new_quad_from_face(
+                face,
+                Vec3::new(0.0, -0.25, 0.0),
+                Vec3::new(1.0, 0.5, 1.0),
+                UVCoordinates::default(),
+            ),
+            layer: 0,
+            cullface,
+        };
+        let slab = Arc::new(VoxelModel {
+            quads: vec![
+                face(Face::Bottom, Some(Face::Bottom)),
+                face(Face::Top, None),
+            ],
+        });
+        let voxels: Vec<Voxel> = (0..4096)
+            .map(|index| {
+                let position = Vec3::from_index(index);
+                match position {
+                    Vec3 { y: 0.0, .. } => Voxel::new(position, VoxelVisiblity::Opaque, None),
+                    Vec3 {
+                        x: 4.0,
+                        y: 1.0,
+                        z: 4.0,
+                    } => Voxel::new(position, VoxelVisiblity::Cutout, None)
+                        .with_model(Some(slab.clone()))
+                        .with_light(VoxelLight::new(9, 2)),
+                    _ => Voxel::air(position),
+                }
+            })
+            .collect();
+        for mode in [MeshingMode::Naive, MeshingMode::Greedy] {
+            let mesh = RawChunkMesh::build(
+                I64Vec3::ZERO,
+                voxels.clone(),
+                &SectionNeighbours::default(),
+                MeshOptions {
+                    mode,
+                    ambient_occlusion: false,
+                },
+            );
+            // The bottom is hidden by the floor. The top is lit by the voxel itself
+            let cutout = &mesh.layer(RenderLayer::Cutout).vertices;
+            assert_eq!(cutout.len(), 4, "{mode}");
+            assert_eq!(cutout[0].normal, Face::Top.normal());
+            assert_eq!(cutout[0].position[1], 1.0);
+            assert_eq!(cutout[0].light, VoxelLight::new(9, 2).as_vertex_light());
+            // The floor under the slab is still drawn
+            let floor_top = mesh
+                .layer(RenderLayer::Opaque)
+                .vertices
+                .iter()
+                .filter(|vertex| vertex.normal == Face::Top.normal())
+                .count();
+            let expected = match mode {
+                MeshingMode::Naive => 256 * 4,
+                // The face under the slab is lit by it so it is not merged with the rest
+                MeshingMode::Greedy => 5 * 4,
+            };
+            assert_eq!(floor_top, expected, "{mode}");
+        }
+    }
+
+    #[test]
+    fn test_back_to_front() {
+        let camera = Vec3::new(0.0, 0.0, 0.0);
+        let centers = [
+            Vec3::new(16.0, 0.0, 0.0),
+            Vec3::new(48.0, 0.0, 0.0),
+            Vec3::new(0.0, -32.0, 0.0),
+        ];
+        assert_eq!(back_to_front(centers, camera), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn test_border_coords_round_trip() {
+        for face in Face::iter() {
+            for index in 0..256 {
+                let coords = border_coords(face, index);
+                assert_eq!(border_index(face, coords), index);
+                assert!(coords.position_for_face(face).is_none());
+            }
+        }
     }
 }
@@ -5,7 +5,7 @@ Contains layout information for basic cubes.
 */
 use std::ops::Add;
 
-use glam::Vec3;
+use glam::{Vec2, Vec3};
 
 use crate::engine::render_types::BasicModelVertex;
 
@@ -38,28 +38,33 @@ impl UntexturedQuad {
     }
     pub fn as_block_voxel_array(&self, uv: UVCoordinates) -> [BlockVertex; 4] {
         let UntexturedQuad { position, normal } = self;
-        [
+        let vertices = [
             BlockVertex {
                 position: position[0],
                 normal: normal[0],
                 uv: uv[0].into(),
+                ..Default::default()
             },
             BlockVertex {
                 position: position[1],
                 normal: normal[1],
                 uv: uv[1].into(),
+                ..Default::default()
             },
             BlockVertex {
                 position: position[2],
                 normal: normal[2],
                 uv: uv[2].into(),
+                ..Default::default()
             },
             BlockVertex {
                 position: position[3],
                 normal: normal[3],
                 uv: uv[3].into(),
+                ..Default::default()
             },
-        ]
+        ];
+        BlockVertex::with_layer(BlockVertex::repeat_texture(vertices, Vec2::ONE), uv.layer)
     }
 
     pub fn push_indicies(indicies: &mut Vec<u32>, offset: u32) {
@@ -70,6 +75,15 @@ impl UntexturedQuad {
         indicies.push(1 + offset);
         indicies.push(2 + offset);
     }
+    /// The same quad split along the other diagonal
+    pub fn push_flipped_indicies(indicies: &mut Vec<u32>, offset: u32) {
+        indicies.push(offset);
+        indicies.push(2 + offset);
+        indicies.push(3 + offset);
+        indicies.push(offset);
+        indicies.push(3 + offset);
+        indicies.push(1 + offset);
+    }
 }
 
 fn add_vec3_to_f32_array(array: &mut [f32; 3], vec: Vec3) {
@@ -0,0 +1,303 @@
+/*!
+# GPU Vertex Formats
+
+The mesher always builds [BlockVertex]s. They are converted into the format picked at build time when they are written into the [ChunkArena](super::buffer_arena::ChunkArena).
+
+- By default the vertices are uploaded as [BlockVertex]s. 64 bytes per vertex
+- With the `packed_vertices` feature they are packed into a [PackedBlockVertex]. 12 bytes per vertex
+
+Both formats store positions relative to the section they are in. The origin of the section is passed to the vertex shader per draw as a [SectionOrigin] instance.
+
+The packed format stores positions and texture coordinates in sixteenths of a block. Which is a pixel of a 16x16 texture, the same steps block models use.
+Normals are rounded to the closest axis so the faces of rotated model elements are shaded like the block faces they are closest to.
+*/
+use std::mem;
+
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec2, Vec3};
+use wgpu::{vertex_attr_array, BufferAddress, VertexBufferLayout, VertexStepMode};
+
+use crate::engine::render_types::ShaderVertexType;
+
+use super::{chunk_mesh::BlockVertex, texture_atlas::TextureTable};
+
+/// The vertex format section meshes are uploaded in
+#[cfg(not(feature = "packed_vertices"))]
+pub type GpuBlockVertex = BlockVertex;
+/// The vertex format section meshes are uploaded in
+#[cfg(feature = "packed_vertices")]
+pub type GpuBlockVertex = PackedBlockVertex;
+
+/// A vertex format a [BlockVertex] can be uploaded as
+pub trait GpuVertex: Pod + ShaderVertexType {
+    /// The vertex shader that reads the format. Appended to `voxel.wgsl`
+    const VERTEX_SHADER: &'static str;
+    /// Converts a vertex in world space into one relative to `origin`
+    ///
+    /// `textures` gives every texture an index for formats that can not store the whole tile
+    fn from_block_vertex(vertex: &BlockVertex, origin: Vec3, textures: &TextureTable) -> Self;
+}
+impl GpuVertex for BlockVertex {
+    const VERTEX_SHADER: &'static str = include_str!("../shaders/voxel_vertex.wgsl");
+
+    fn from_block_vertex(vertex: &BlockVertex, origin: Vec3, _: &TextureTable) -> Self {
+        BlockVertex {
+            position: (Vec3::from(vertex.position) - origin).into(),
+            ..*vertex
+        }
+    }
+}
+/// The position of the section a draw belongs to. One per draw, read as an instance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Zeroable, Pod)]
+#[repr(C)]
+pub struct SectionOrigin {
+    pub origin: [f32; 3],
+}
+impl ShaderVertexType for SectionOrigin {
+    fn desc() -> VertexBufferLayout<'static> {
+        use wgpu::VertexAttribute;
+
+        static ATTRIBUTES: [VertexAttribute; 1] = vertex_attr_array![6 => Float32x3];
+
+        VertexBufferLayout {
+            array_stride: mem::size_of::<Self>() as BufferAddress,
+            step_mode: VertexStepMode::Instance,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+/// A [BlockVertex] packed into three words. Decoded by `voxel_packed_vertex.wgsl`
+///
+/// The first word:
+///
+/// | Bits  | Value                                                 |
+/// |-------|-------------------------------------------------------|
+/// | 0-26  | X, Y and Z inside of the section in sixteenths of a block. 9 bits each |
+/// | 27-29 | Normal. The axis times two plus one if it is positive |
+/// | 30-31 | Ambient occlusion 0-3                                 |
+///
+/// The second word:
+///
+/// | Bits  | Value                                          |
+/// |-------|------------------------------------------------|
+/// | 0-17  | U and V inside of the whole texture in sixteenths. How many times the texture repeats up to this corner. 9 bits each |
+/// | 18-21 | Sky light 0-15                                 |
+/// | 22-25 | Block light 0-15                               |
+///
+/// The third word is the index of the texture in the [TextureTable]
+///
+/// Positions are stored half a block up because voxels are centered on their position
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Zeroable, Pod)]
+#[repr(C)]
+pub struct PackedBlockVertex {
+    pub data: [u32; 3],
+}
+impl PackedBlockVertex {
+    /// Positions and texture coordinates are stored in steps of `1 / STEPS`
+    const STEPS: f32 = 16.0;
+    const POSITION_BITS: u32 = 9;
+    const NORMAL_SHIFT: u32 = 27;
+    const AO_SHIFT: u32 = 30;
+    const UV_BITS: u32 = 9;
+    const SKY_SHIFT: u32 = 18;
+    const BLOCK_SHIFT: u32 = 22;
+
+    /// Undoes [GpuVertex::from_block_vertex]. The opposite of what the shader does
+    ///
+    /// The uv is relative to the whole texture. Even if the vertex used part of it
+    pub fn unpack(&self, origin: Vec3, textures: &TextureTable) -> BlockVertex {
+        let [first, second, texture] = self.data;
+        let bits = |word: u32, shift: u32, count: u32| (word >> shift) & ((1 << count) - 1);
+        let position = Vec3::new(
+            bits(first, 0, Self::POSITION_BITS) as f32,
+            bits(first, Self::POSITION_BITS, Self::POSITION_BITS) as f32,
+            bits(first, Self::POSITION_BITS * 2, Self::POSITION_BITS) as f32,
+        ) / Self::STEPS
+            - Vec3::splat(0.5)
+            + origin;
+        let normal_id = bits(first, Self::NORMAL_SHIFT, 3);
+        let mut normal = [0.0; 3];
+        normal[(normal_id / 2) as usize] = if normal_id % 2 == 1 { 1.0 } else { -1.0 };
+        let tile = textures.tile(texture);
+        BlockVertex {
+            position: position.into(),
+            normal,
+            uv: [
+                bits(second, 0, Self::UV_BITS) as f32 / Self::STEPS,
+                bits(second, Self::UV_BITS, Self::UV_BITS) as f32 / Self::STEPS,
+            ],
+            tile: tile.rect,
+            light: [
+                bits(second, Self::SKY_SHIFT, 4) as f32 / 15.0,
+                bits(second, Self::BLOCK_SHIFT, 4) as f32 / 15.0,
+            ],
+            ao: bits(first, Self::AO_SHIFT, 2) as f32 / 3.0,
+            layer: tile.layer,
+        }
+    }
+}
+impl GpuVertex for PackedBlockVertex {
+    const VERTEX_SHADER: &'static str = include_str!("../shaders/voxel_packed_vertex.wgsl");
+
+    fn from_block_vertex(vertex: &BlockVertex, origin: Vec3, textures: &TextureTable) -> Self {
+        let max_position = ((1 << Self::POSITION_BITS) - 1) as f32;
+        let local = ((Vec3::from(vertex.position) - origin + Vec3::splat(0.5)) * Self::STEPS)
+            .round()
+            .clamp(Vec3::ZERO, Vec3::splat(max_position))
+            .as_uvec3();
+        let normal = Vec3::from(vertex.normal);
+        let abs = normal.abs();
+        let axis = if abs.x >= abs.y.max(abs.z) {
+            0
+        } else if abs.y >= abs.z {
+            1
+        } else {
+            2
+        };
+        let normal_id = axis * 2 + (normal[axis as usize] > 0.0) as u32;
+        let first = local.x
+            | local.y << Self::POSITION_BITS
+            | local.z << (Self::POSITION_BITS * 2)
+            | normal_id << Self::NORMAL_SHIFT
+            | ((vertex.ao * 3.0).round().clamp(0.0, 3.0) as u32) << Self::AO_SHIFT;
+
+        let texture = textures.index_of(vertex.tile, vertex.layer);
+        let [x, y, width, height] = textures.tile(texture).rect;
+        let texture_size = Vec2::new(width, height);
+        // Model faces can use part of the texture. Their uv is moved to be inside of the whole texture
+        let uv = if texture_size.cmpgt(Vec2::ZERO).all() {
+            let offset =
+                (Vec2::new(vertex.tile[0], vertex.tile[1]) - Vec2::new(x, y)) / texture_size;
+            let scale = Vec2::new(vertex.tile[2], vertex.tile[3]) / texture_size;
+            offset + Vec2::from(vertex.uv) * scale
+        } else {
+            Vec2::from(vertex.uv)
+        };
+        let max_uv = ((1 << Self::UV_BITS) - 1) as f32;
+        let uv = (uv * Self::STEPS)
+            .round()
+            .clamp(Vec2::ZERO, Vec2::splat(max_uv))
+            .as_uvec2();
+        let level = |value: f32| (value * 15.0).round().clamp(0.0, 15.0) as u32;
+        let second = uv.x
+            | uv.y << Self::UV_BITS
+            | level(vertex.light[0]) << Self::SKY_SHIFT
+            | level(vertex.light[1]) << Self::BLOCK_SHIFT;
+        Self {
+            data: [first, second, texture],
+        }
+    }
+}
+impl ShaderVertexType for PackedBlockVertex {
+    fn desc() -> VertexBufferLayout<'static> {
+        use wgpu::VertexAttribute;
+
+        static ATTRIBUTES: [VertexAttribute; 1] = vertex_attr_array![0 => Uint32x3];
+
+        VertexBufferLayout {
+            array_stride: mem::size_of::<Self>() as BufferAddress,
+            step_mode: VertexStepMode::Vertex,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::voxel::texture_atlas::{TextureAtlasInfo, TextureRef};
+
+    #[test]
+    fn test_packed_vertex_round_trips() {
+        let texture = TextureRef {
+            name: "stone.png".to_string(),
+            width: 16,
+            height: 16,
+            x: 168,
+            y: 72,
+            layer: 3,
+        };
+        let info = TextureAtlasInfo {
+            textures: [(texture.name.clone(), texture)].into_iter().collect(),
+            width: 256,
+            height: 128,
+            texture_width: 16,
+            texture_height: 16,
+            layers: 4,
+            mip_levels: 1,
+        };
+        let textures = TextureTable::new(&info);
+        let origin = Vec3::new(-32.0, 48.0, 16.0);
+        let vertex = BlockVertex {
+            position: [-32.5, 63.5, 20.5],
+            normal: [0.0, 0.0, -1.0],
+            uv: [3.0, 16.0],
+            tile: [168.0 / 256.0, 72.0 / 128.0, 16.0 / 256.0, 16.0 / 128.0],
+            light: [1.0, 7.0 / 15.0],
+            ao: 2.0 / 3.0,
+            layer: 3,
+        };
+        let packed = PackedBlockVertex::from_block_vertex(&vertex, origin, &textures);
+        let unpacked = packed.unpack(origin, &textures);
+        assert_eq!(unpacked.position, vertex.position);
+        assert_eq!(unpacked.normal, vertex.normal);
+        assert_eq!(unpacked.uv, vertex.uv);
+        assert_eq!(unpacked.tile, vertex.tile);
+        assert_eq!(unpacked.light, vertex.light);
+        assert_eq!(unpacked.ao, vertex.ao);
+        assert_eq!(unpacked.layer, vertex.layer);
+    }
+
+    #[test]
+    fn test_packed_model_vertex_keeps_its_precision() {
+        let texture = TextureRef {
+            name: "oak_planks.png".to_string(),
+            width: 16,
+            height: 16,
+            x: 32,
+            y: 16,
+            layer: 0,
+        };
+        let info = TextureAtlasInfo {
+            textures: [(texture.name.clone(), texture)].into_iter().collect(),
+            width: 64,
+            height: 64,
+            texture_width: 16,
+            texture_height: 16,
+            layers: 1,
+            mip_levels: 1,
+        };
+        let textures = TextureTable::new(&info);
+        let origin = Vec3::ZERO;
+        // The top corner of the side of a bottom slab. It uses the bottom half of the texture
+        let vertex = BlockVertex {
+            position: [3.5, 4.0, 7.5],
+            normal: [1.0, 0.0, 0.0],
+            uv: [1.0, 0.0],
+            tile: [32.0 / 64.0, 24.0 / 64.0, 16.0 / 64.0, 8.0 / 64.0],
+            light: [1.0, 0.0],
+            ao: 1.0,
+            layer: 0,
+        };
+        let packed = PackedBlockVertex::from_block_vertex(&vertex, origin, &textures);
+        let unpacked = packed.unpack(origin, &textures);
+        assert_eq!(unpacked.position, vertex.position);
+        assert_eq!(unpacked.tile, [0.5, 0.25, 0.25, 0.25]);
+        // Halfway down the whole texture
+        assert_eq!(unpacked.uv, [1.0, 0.5]);
+        let atlas_uv = |vertex: &BlockVertex| {
+            Vec2::new(vertex.tile[0], vertex.tile[1])
+                + Vec2::from(vertex.uv) * Vec2::new(vertex.tile[2], vertex.tile[3])
+        };
+        assert_eq!(atlas_uv(&unpacked), atlas_uv(&vertex));
+
+        // A block model pixel is the smallest step
+        let pixel = BlockVertex {
+            position: [3.5 - 1.0 / 16.0, 4.0 + 3.0 / 16.0, 7.5],
+            ..vertex
+        };
+        let unpacked = PackedBlockVertex::from_block_vertex(&pixel, origin, &textures)
+            .unpack(origin, &textures);
+        assert_eq!(unpacked.position, pixel.position);
+    }
+}
@@ -0,0 +1,251 @@
+/*!
+# Greedy Meshing
+
+Merges the visible faces of a section into as few quads as possible.
+
+Every face direction is walked one 16x16 layer at a time. Visible faces in a layer that touch, use the same texture, have the same light and are in the same [RenderLayer] are merged into a rectangle.
+Faces with ambient occlusion that changes across the face are never merged, the shading of the corners would be stretched over the whole rectangle.
+Voxels with a model are not merged. Their faces are pushed as they are after the rest of the section.
+The texture is repeated across the merged quad by the shader so a quad covering 4x2 faces still shows 8 copies of the texture.
+*/
+use glam::{UVec3, Vec2, Vec3};
+use strum::IntoEnumIterator;
+
+use crate::engine::shapes::Quad;
+
+use super::{
+    chunk_mesh::{
+        face_light, face_occlusion, is_face_visible, push_model_faces, BlockVertex, RawMeshLayer,
+        RenderLayer, SectionNeighbours, Voxel, VoxelLight,
+    },
+    Face, UVCoordinates, VoxelLocation,
+};
+const SECTION_SIZE: usize = 16;
+/// The axis the face points along followed by the two axes of its layer
+fn face_axes(face: Face) -> (usize, usize, usize) {
+    match face {
+        Face::West | Face::East => (0, 1, 2),
+        Face::Top | Face::Bottom => (1, 0, 2),
+        Face::North | Face::South => (2, 0, 1),
+    }
+}
+/// A visible face in the mask. Faces are only merged if all of this matches
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MaskFace {
+    layer: RenderLayer,
+    uv: UVCoordinates,
+    light: VoxelLight,
+    occlusion: [u8; 4],
+}
+/// Pushes the merged quads of the voxels onto the layers they are drawn in
+pub fn build(
+    voxels: &[Voxel],
+    neighbours: &SectionNeighbours,
+    chunk_position: Vec3,
+    ambient_occlusion: bool,
+    layers: &mut [RawMeshLayer; RenderLayer::COUNT],
+) {
+    // Every visible face in the current layer
+    let mut mask: [Option<MaskFace>; SECTION_SIZE * SECTION_SIZE] =
+        [None; SECTION_SIZE * SECTION_SIZE];
+    for face in Face::iter() {
+        let (normal_axis, u_axis, v_axis) = face_axes(face);
+        for layer in 0..SECTION_SIZE {
+            let voxel_index = |u: usize, v: usize| {
+                let mut coords = [0u32; 3];
+                coords[normal_axis] = layer as u32;
+                coords[u_axis] = u as u32;
+                coords[v_axis] = v as u32;
+                UVec3::from_array(coords).to_index()
+            };
+            // Step One: Find the visible faces in the layer
+            for v in 0..SECTION_SIZE {
+                for u in 0..SECTION_SIZE {
+                    let index = voxel_index(u, v);
+                    if !is_face_visible(voxels, neighbours, index, face) {
+                        mask[v * SECTION_SIZE + u] = None;
+                        continue;
+                    }
+                    let voxel = &voxels[index];
+                    mask[v * SECTION_SIZE + u] =
+                        RenderLayer::for_visibility(voxel.visibility).map(|layer| MaskFace {
+                            layer,
+                            uv: voxel.textures.as_ref().map(|t| t[face]).unwrap_or_default(),
+                            light: face_light(voxels, neighbours, index, face),
+                            occlusion: if ambient_occlusion {
+                                face_occlusion(voxels, neighbours, index, face)
+                            } else {
+                                [3; 4]
+                            },
+                        });
+                }
+            }
+            // Step Two: Merge faces with the same texture into rectangles
+            for v in 0..SECTION_SIZE {
+                let mut u = 0;
+                while u < SECTION_SIZE {
+                    let Some(key) = mask[v * SECTION_SIZE + u] else {
+                        u += 1;
+                        continue;
+                    };
+                    let occlusion = key.occlusion;
+                    let mergeable = occlusion.iter().all(|corner| *corner == occlusion[0]);
+                    let mut width = 1;
+                    while mergeable
+                        && u + width < SECTION_SIZE
+                        && mask[v * SECTION_SIZE + u + width] == Some(key)
+                    {
+                        width += 1;
+                    }
+                    let mut height = 1;
+                    'grow: while mergeable && v + height < SECTION_SIZE {
+                        let row = (v + height) * SECTION_SIZE;
+                        for offset in 0..width {
+                            if mask[row + u + offset] != Some(key) {
+                                break 'grow;
+                            }
+                        }
+                        height += 1;
+                    }
+                    for row in v..v + height {
+                        let row = row * SECTION_SIZE;
+                        mask[row + u..row + u + width].fill(None);
+                    }
+
+                    let first = voxels[voxel_index(u, v)].position;
+                    let last = voxels[voxel_index(u + width - 1, v + height - 1)].position;
+                    let mut size = Vec3::ONE;
+                    size[u_axis] = width as f32;
+                    size[v_axis] = height as f32;
+                    let quad = Quad::new_quad_from_face(
+                        face,
+                        (first + last) / 2.0 + chunk_position,
+                        size,
+                        key.uv,
+                    );
+                    push_quad(
+                        quad,
+                        key.light,
+                        occlusion,
+                        key.uv.layer,
+                        &mut layers[usize::from(key.layer)],
+                    );
+                    u += width;
+                }
+            }
+        }
+    }
+    for (index, voxel) in voxels.iter().enumerate() {
+        if voxel.model.is_some() {
+            push_model_faces(voxels, neighbours, index, chunk_position, layers);
+        }
+    }
+}
+/// Pushes a quad that repeats its texture once per block it covers
+fn push_quad(
+    quad: Quad,
+    light: VoxelLight,
+    occlusion: [u8; 4],
+    texture_layer: u32,
+    layer: &mut RawMeshLayer,
+) {
+    let repeat = Vec2::new(
+        quad.top_left.position.distance(quad.top_right.position),
+        quad.top_left.position.distance(quad.bottom_left.position),
+    );
+    let vertices = BlockVertex::from_repeating_quad(quad, repeat);
+    layer.push_quad(
+        BlockVertex::with_layer(BlockVertex::with_light(vertices, light), texture_layer),
+        occlusion,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{I64Vec3, Vec2, Vec3};
+
+    use crate::engine::voxel::{
+        chunk_mesh::{
+            MeshingMode, RawChunkMesh, RenderLayer, SectionNeighbours, Voxel, VoxelVisiblity,
+        },
+        LoadedCubeTexture, UVCoordinates, VoxelLocation,
+    };
+
+    fn texture(x: f32) -> Option<LoadedCubeTexture> {
+        Some(LoadedCubeTexture::SingleTexture(UVCoordinates {
+            top_left: Vec2::new(x, 0.0),
+            top_right: Vec2::new(x + 0.5, 0.0),
+            bottom_left: Vec2::new(x, 0.5),
+            bottom_right: Vec2::new(x + 0.5, 0.5),
+            layer: 0,
+        }))
+    }
+    /// A section with the bottom layer filled. Blocks with x < `split` use a different texture
+    fn floor(split: f32) -> Vec<Voxel> {
+        (0..4096)
+            .map(|index| {
+                let position = Vec3::from_index(index);
+                if position.y != 0.0 {
+                    return Voxel::air(position);
+                }
+                let texture = if position.x < split {
+                    texture(0.5)
+                } else {
+                    texture(0.0)
+                };
+                Voxel::new(position, VoxelVisiblity::Opaque, texture)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_greedy_merges_layer() {
+        let naive = RawChunkMesh::build(
+            I64Vec3::ZERO,
+            floor(0.0),
+            &SectionNeighbours::default(),
+            MeshingMode::Naive.into(),
+        );
+        let greedy = RawChunkMesh::build(
+            I64Vec3::ZERO,
+            floor(0.0),
+            &SectionNeighbours::default(),
+            MeshingMode::Greedy.into(),
+        );
+        // Top and bottom are 256 faces each. Every side is 16 faces
+        assert_eq!(
+            naive.layer(RenderLayer::Opaque).vertices.len(),
+            (256 * 2 + 16 * 4) * 4
+        );
+        // One quad per face direction
+        assert_eq!(greedy.layer(RenderLayer::Opaque).vertices.len(), 6 * 4);
+        assert_eq!(greedy.layer(RenderLayer::Opaque).indices.len(), 6 * 6);
+        // The texture is repeated once per block
+        let max_uv = greedy
+            .layer(RenderLayer::Opaque)
+            .vertices
+            .iter()
+            .fold(0f32, |max, v| max.max(v.uv[0]).max(v.uv[1]));
+        assert_eq!(max_uv, 16.0);
+    }
+
+    #[test]
+    fn test_greedy_keeps_textures_apart() {
+        let greedy = RawChunkMesh::build(
+            I64Vec3::ZERO,
+            floor(8.0),
+            &SectionNeighbours::default(),
+            MeshingMode::Greedy.into(),
+        );
+        // Top and bottom are split in two. The West and East sides only have one texture
+        // North and South are split in two
+        assert_eq!(
+            greedy.layer(RenderLayer::Opaque).vertices.len(),
+            (2 + 2 + 1 + 1 + 2 + 2) * 4
+        );
+        for vertex in &greedy.layer(RenderLayer::Opaque).vertices {
+            assert!(vertex.tile[0] == 0.0 || vertex.tile[0] == 0.5);
+            assert_eq!(vertex.tile[2], 0.5);
+        }
+    }
+}
@@ -9,11 +9,15 @@ pub use texture_atlas::{
     UVCoordinates,
 };
 pub mod basic_cube;
+pub mod buffer_arena;
 pub mod chunk_mesh;
 pub mod cube_data;
+pub mod gpu_vertex;
+pub mod greedy_mesher;
 mod location;
 pub mod texture_atlas;
 pub mod voxel_pipeline;
+pub mod voxel_model;
 pub mod voxel_state;
 
 pub use super::shapes::cube::Face;
@@ -28,4 +32,27 @@ impl Face {
             Face::East => cube_data::EAST_FACE,
         }
     }
+    /// The axis the face points along. 0 is X, 1 is Y and 2 is Z
+    pub fn axis(&self) -> usize {
+        match self {
+            Face::West | Face::East => 0,
+            Face::Top | Face::Bottom => 1,
+            Face::North | Face::South => 2,
+        }
+    }
+    /// The face pointing along the axis. The opposite of [Face::axis] and [Face::is_positive]
+    pub fn from_axis(axis: usize, positive: bool) -> Face {
+        match (axis, positive) {
+            (0, false) => Face::West,
+            (0, true) => Face::East,
+            (1, false) => Face::Bottom,
+            (1, true) => Face::Top,
+            (_, false) => Face::South,
+            (_, true) => Face::North,
+        }
+    }
+    /// Does the face point towards the positive end of its axis. Matches [VoxelLocation::position_for_face]
+    pub fn is_positive(&self) -> bool {
+        matches!(self, Face::North | Face::East | Face::Top)
+    }
 }
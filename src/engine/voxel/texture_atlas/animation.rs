@@ -0,0 +1,337 @@
+/*!
+# Animated Textures
+
+Minecraft stores animated textures as a strip of frames with a `.png.mcmeta` file next to it describing the order and timing of the frames.
+
+Only one frame is placed in the atlas. The [TextureAnimator] works out which frame should be showing every tick and the renderer writes it over the texture's slot.
+*/
+use std::time::Duration;
+
+use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+use serde::Deserialize;
+
+use super::{wrap_texture, TextureAtlasBuildError};
+
+/// The `.png.mcmeta` file
+#[derive(Debug, Deserialize)]
+struct McMeta {
+    animation: Option<AnimationMeta>,
+}
+#[derive(Debug, Deserialize)]
+struct AnimationMeta {
+    #[serde(default)]
+    interpolate: bool,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default = "default_frame_time")]
+    frametime: u32,
+    frames: Option<Vec<FrameMeta>>,
+}
+fn default_frame_time() -> u32 {
+    1
+}
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FrameMeta {
+    Index(u32),
+    Frame { index: u32, time: Option<u32> },
+}
+/// A frame of an [Animation]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimationFrame {
+    /// The frame in the strip. Frames are numbered left to right, top to bottom
+    pub index: u32,
+    /// How many ticks the frame is shown for
+    pub ticks: u32,
+}
+/// The order and timing of the frames of an animated texture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    /// Blend each frame into the next one instead of switching at once
+    pub interpolate: bool,
+}
+/// What an animation shows on a tick
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnimationSample {
+    pub frame: u32,
+    pub next: u32,
+    /// How far to blend `frame` into `next`. Always 0 without interpolation
+    pub blend: f32,
+}
+impl Animation {
+    /// Minecraft counts animations in game ticks. 20 per second
+    pub const TICK: Duration = Duration::from_millis(50);
+    /// The frame showing on the tick
+    pub fn sample(&self, tick: u64) -> AnimationSample {
+        let total: u64 = self.frames.iter().map(|frame| frame.ticks as u64).sum();
+        let mut tick = tick % total.max(1);
+        for (index, frame) in self.frames.iter().enumerate() {
+            let ticks = frame.ticks as u64;
+            if tick < ticks {
+                let next = self.frames[(index + 1) % self.frames.len()];
+                return AnimationSample {
+                    frame: frame.index,
+                    next: next.index,
+                    blend: if self.interpolate {
+                        tick as f32 / ticks as f32
+                    } else {
+                        0.0
+                    },
+                };
+            }
+            tick -= ticks;
+        }
+        let index = self.frames.first().map(|frame| frame.index).unwrap_or(0);
+        AnimationSample {
+            frame: index,
+            next: index,
+            blend: 0.0,
+        }
+    }
+}
+/// The frames of an animated texture cut out of its strip
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimatedFrames {
+    pub animation: Animation,
+    /// Every frame in the strip in order
+    pub frames: Vec<DynamicImage>,
+}
+impl AnimatedFrames {
+    /// Reads the animation out of the contents of a `.png.mcmeta` file. None if the file does not describe an animation
+    ///
+    /// Frames are square and as wide as the narrowest side of the strip unless the file says otherwise
+    pub fn from_mcmeta(
+        mcmeta: &str,
+        strip: &DynamicImage,
+    ) -> Result<Option<Self>, TextureAtlasBuildError> {
+        let McMeta { animation } = serde_json::from_str(mcmeta)?;
+        let Some(meta) = animation else {
+            return Ok(None);
+        };
+        let (strip_width, strip_height) = strip.dimensions();
+        let side = strip_width.min(strip_height);
+        let frame_width = meta.width.unwrap_or(side).clamp(1, strip_width.max(1));
+        let frame_height = meta.height.unwrap_or(side).clamp(1, strip_height.max(1));
+        let columns = strip_width / frame_width;
+        let rows = strip_height / frame_height;
+        let frames: Vec<DynamicImage> = (0..columns * rows)
+            .map(|index| {
+                strip.crop_imm(
+                    (index % columns) * frame_width,
+                    (index / columns) * frame_height,
+                    frame_width,
+                    frame_height,
+                )
+            })
+            .collect();
+        let frame_time = meta.frametime.max(1);
+        let order: Vec<AnimationFrame> = match meta.frames {
+            Some(order) => order
+                .into_iter()
+                .map(|frame| match frame {
+                    FrameMeta::Index(index) => AnimationFrame {
+                        index,
+                        ticks: frame_time,
+                    },
+                    FrameMeta::Frame { index, time } => AnimationFrame {
+                        index,
+                        ticks: time.unwrap_or(frame_time).max(1),
+                    },
+                })
+                .filter(|frame| (frame.index as usize) < frames.len())
+                .collect(),
+            None => (0..frames.len() as u32)
+                .map(|index| AnimationFrame {
+                    index,
+                    ticks: frame_time,
+                })
+                .collect(),
+        };
+        if order.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            animation: Animation {
+                frames: order,
+                interpolate: meta.interpolate,
+            },
+            frames,
+        }))
+    }
+    /// The first frame of the animation. The one put in the atlas
+    pub fn first_frame(&self) -> &DynamicImage {
+        &self.frames[self.animation.sample(0).frame as usize]
+    }
+    /// The frame showing for the sample. Blends the two frames when interpolating
+    pub fn render(&self, sample: AnimationSample) -> DynamicImage {
+        let frame = &self.frames[sample.frame as usize];
+        if sample.blend <= 0.0 || sample.frame == sample.next {
+            return frame.clone();
+        }
+        let next = &self.frames[sample.next as usize];
+        let mut blended = DynamicImage::new_rgba8(frame.width(), frame.height());
+        for (x, y, Rgba(from)) in frame.pixels() {
+            let Rgba(to) = next.get_pixel(x, y);
+            let pixel = std::array::from_fn(|channel| {
+                (from[channel] as f32 * (1.0 - sample.blend) + to[channel] as f32 * sample.blend)
+                    .round() as u8
+            });
+            blended.put_pixel(x, y, Rgba(pixel));
+        }
+        blended
+    }
+}
+/// An animated texture that has been placed in the atlas
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimatedTexture {
+    pub name: String,
+    pub frames: AnimatedFrames,
+    /// The area the texture fills including its gutter. Every frame is written over the whole area
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub layer: u32,
+    /// Pixels between the edge of the area and the texture
+    pub offset: u32,
+}
+impl AnimatedTexture {
+    /// The area of the atlas with the sampled frame repeated across it
+    pub fn render(&self, sample: AnimationSample) -> DynamicImage {
+        wrap_texture(
+            &self.frames.render(sample),
+            self.width,
+            self.height,
+            self.offset,
+        )
+    }
+}
+/// Keeps track of which frame every animated texture is showing
+#[derive(Debug, Clone)]
+pub struct TextureAnimator {
+    textures: Vec<AnimatedTexture>,
+    /// What was last written for each texture. Starts at the frame the atlas was built with
+    shown: Vec<AnimationSample>,
+    elapsed: Duration,
+    tick: u64,
+}
+impl TextureAnimator {
+    pub fn new(textures: Vec<AnimatedTexture>) -> Self {
+        let shown = textures
+            .iter()
+            .map(|texture| texture.frames.animation.sample(0))
+            .collect();
+        Self {
+            textures,
+            shown,
+            elapsed: Duration::ZERO,
+            tick: 0,
+        }
+    }
+    pub fn textures(&self) -> &[AnimatedTexture] {
+        &self.textures
+    }
+    /// Moves time forward. Returns the textures whose frame changed and the area to write for each
+    pub fn advance(&mut self, dt: Duration) -> Vec<(&AnimatedTexture, DynamicImage)> {
+        self.elapsed += dt;
+        let tick = (self.elapsed.as_millis() / Animation::TICK.as_millis()) as u64;
+        if tick == self.tick {
+            return Vec::new();
+        }
+        self.tick = tick;
+        self.textures
+            .iter()
+            .zip(&mut self.shown)
+            .filter_map(|(texture, shown)| {
+                let sample = texture.frames.animation.sample(tick);
+                if sample == *shown {
+                    return None;
+                }
+                *shown = sample;
+                Some((texture, texture.render(sample)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x12 strip of three frames. Each frame is a single color
+    fn strip() -> DynamicImage {
+        let mut strip = DynamicImage::new_rgba8(4, 12);
+        for y in 0..12 {
+            for x in 0..4 {
+                strip.put_pixel(x, y, Rgba([(y / 4) as u8 * 100, 0, 0, 255]));
+            }
+        }
+        strip
+    }
+
+    #[test]
+    fn test_mcmeta_frames_and_timing() -> anyhow::Result<()> {
+        let mcmeta =
+            r#"{"animation": {"frametime": 2, "frames": [2, {"index": 0, "time": 5}, 1]}}"#;
+        let animated = AnimatedFrames::from_mcmeta(mcmeta, &strip())?.unwrap();
+        assert_eq!(animated.frames.len(), 3);
+        assert_eq!(animated.frames[0].dimensions(), (4, 4));
+        assert_eq!(
+            animated.first_frame().get_pixel(0, 0),
+            Rgba([200, 0, 0, 255])
+        );
+        let frames: Vec<u32> = (0..11)
+            .map(|tick| animated.animation.sample(tick).frame)
+            .collect();
+        assert_eq!(frames, vec![2, 2, 0, 0, 0, 0, 0, 1, 1, 2, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mcmeta_without_animation() -> anyhow::Result<()> {
+        assert_eq!(
+            AnimatedFrames::from_mcmeta(r#"{"villager": {}}"#, &strip())?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolated_frames_blend() -> anyhow::Result<()> {
+        let mcmeta = r#"{"animation": {"frametime": 4, "interpolate": true}}"#;
+        let animated = AnimatedFrames::from_mcmeta(mcmeta, &strip())?.unwrap();
+        let sample = animated.animation.sample(2);
+        assert_eq!((sample.frame, sample.next, sample.blend), (0, 1, 0.5));
+        assert_eq!(
+            animated.render(sample).get_pixel(1, 1),
+            Rgba([50, 0, 0, 255])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_animator_only_returns_changed_frames() -> anyhow::Result<()> {
+        let frames =
+            AnimatedFrames::from_mcmeta(r#"{"animation": {"frametime": 2}}"#, &strip())?.unwrap();
+        let mut animator = TextureAnimator::new(vec![AnimatedTexture {
+            name: "water_still.png".to_string(),
+            frames,
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 8,
+            layer: 0,
+            offset: 2,
+        }]);
+        // The first frame is already in the atlas
+        assert!(animator.advance(Animation::TICK).is_empty());
+        let changed = animator.advance(Animation::TICK);
+        assert_eq!(changed.len(), 1);
+        let (_, area) = &changed[0];
+        assert_eq!(area.dimensions(), (8, 8));
+        assert_eq!(area.get_pixel(2, 2), Rgba([100, 0, 0, 255]));
+        assert!(animator.advance(Duration::from_millis(10)).is_empty());
+        Ok(())
+    }
+}
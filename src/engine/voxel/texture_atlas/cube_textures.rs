@@ -1,6 +1,7 @@
 use std::{borrow::Cow, ops::Index};
 
 use ahash::HashMap;
+use tracing::warn;
 
 use crate::engine::voxel::Face;
 
@@ -9,36 +10,52 @@ use super::{TextureAtlasInfo, UVCoordinates};
 #[derive(Debug)]
 pub enum CubeTextures<'a> {
     SingleTexture(Cow<'a, str>),
-    MultiTexture(HashMap<Face, Cow<'a, str>>),
+    MultiTexture {
+        textures: HashMap<Face, Cow<'a, str>>,
+        /// Used for the faces not in `textures`. The missing texture if there is none
+        default: Option<Cow<'a, str>>,
+    },
     Transparent,
 }
 impl CubeTextures<'_> {
+    /// Looks up the textures in the atlas. Textures that can not be found are replaced with the missing texture
+    ///
+    /// None if the cube is transparent
     pub fn get_coordinates(&self, texture_atlas: &TextureAtlasInfo) -> Option<LoadedCubeTexture> {
         match self {
-            CubeTextures::SingleTexture(texture) => {
-                let uv = texture_atlas.get_uv_for_texture(texture)?;
-                Some(LoadedCubeTexture::SingleTexture(uv))
-            }
-            CubeTextures::MultiTexture(textures) => {
-                if textures.len() == 6 {
-                    let front = texture_atlas.get_uv_for_texture(textures.get(&Face::North)?)?;
-                    let back = texture_atlas.get_uv_for_texture(textures.get(&Face::South)?)?;
-                    let top = texture_atlas.get_uv_for_texture(textures.get(&Face::Top)?)?;
-                    let bottom = texture_atlas.get_uv_for_texture(textures.get(&Face::Bottom)?)?;
-                    let left = texture_atlas.get_uv_for_texture(textures.get(&Face::West)?)?;
-                    let right = texture_atlas.get_uv_for_texture(textures.get(&Face::East)?)?;
-
-                    Some(LoadedCubeTexture::MultiTextureAllSet {
-                        front,
-                        back,
-                        top,
-                        bottom,
-                        left,
-                        right,
-                    })
-                } else {
-                    todo!("Handle this case")
+            CubeTextures::SingleTexture(texture) => Some(LoadedCubeTexture::SingleTexture(
+                texture_atlas.get_uv_or_missing(texture),
+            )),
+            CubeTextures::MultiTexture { textures, default } => {
+                let get = |face: Face| textures.get(&face).or(default.as_ref());
+                if let [Some(front), Some(back), Some(top), Some(bottom), Some(left), Some(right)] =
+                    [
+                        Face::North,
+                        Face::South,
+                        Face::Top,
+                        Face::Bottom,
+                        Face::West,
+                        Face::East,
+                    ]
+                    .map(get)
+                {
+                    return Some(LoadedCubeTexture::MultiTextureAllSet {
+                        front: texture_atlas.get_uv_or_missing(front),
+                        back: texture_atlas.get_uv_or_missing(back),
+                        top: texture_atlas.get_uv_or_missing(top),
+                        bottom: texture_atlas.get_uv_or_missing(bottom),
+                        left: texture_atlas.get_uv_or_missing(left),
+                        right: texture_atlas.get_uv_or_missing(right),
+                    });
                 }
+                warn!("Cube is missing textures for some faces and has no default. Using the missing texture");
+                Some(LoadedCubeTexture::MultiTexture {
+                    textures: textures
+                        .iter()
+                        .map(|(face, texture)| (*face, texture_atlas.get_uv_or_missing(texture)))
+                        .collect(),
+                    default_texture: texture_atlas.missing_texture(),
+                })
             }
             CubeTextures::Transparent => None,
         }
@@ -94,3 +111,62 @@ impl Index<Face> for LoadedCubeTexture {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::engine::voxel::texture_atlas::{
+        RawImageReference, TextureAtlasBuilder, TextureBackend,
+    };
+
+    fn atlas() -> Arc<TextureAtlasInfo> {
+        let mut builder = TextureAtlasBuilder::new();
+        for name in ["grass_top.png", "dirt.png", "grass_side.png"] {
+            builder.add_texture(RawImageReference {
+                name: name.to_string(),
+                dynamic_image: image::DynamicImage::new_rgba8(16, 16),
+                animation: None,
+            });
+        }
+        builder.build(TextureBackend::Array).unwrap().info
+    }
+
+    #[test]
+    fn test_multi_texture_uses_the_default_for_other_faces() {
+        let atlas = atlas();
+        let textures = CubeTextures::MultiTexture {
+            textures: [
+                (Face::Top, "grass_top.png".into()),
+                (Face::Bottom, "dirt.png".into()),
+            ]
+            .into_iter()
+            .collect(),
+            default: Some("grass_side.png".into()),
+        };
+        let loaded = textures.get_coordinates(&atlas).unwrap();
+        assert_eq!(loaded[Face::Top], atlas.get_uv_or_missing("grass_top.png"));
+        assert_eq!(loaded[Face::Bottom], atlas.get_uv_or_missing("dirt.png"));
+        assert_eq!(
+            loaded[Face::East],
+            atlas.get_uv_or_missing("grass_side.png")
+        );
+    }
+
+    #[test]
+    fn test_missing_faces_and_names_use_the_missing_texture() {
+        let atlas = atlas();
+        let textures = CubeTextures::MultiTexture {
+            textures: [(Face::Top, "grass_topp.png".into())].into_iter().collect(),
+            default: None,
+        };
+        let loaded = textures.get_coordinates(&atlas).unwrap();
+        assert_eq!(loaded[Face::Top], atlas.missing_texture());
+        assert_eq!(loaded[Face::North], atlas.missing_texture());
+        let single = CubeTextures::from("typo.png")
+            .get_coordinates(&atlas)
+            .unwrap();
+        assert_eq!(single[Face::West], atlas.missing_texture());
+    }
+}
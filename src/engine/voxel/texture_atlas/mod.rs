@@ -6,11 +6,22 @@ use std::{
 };
 
 use ahash::{HashMap, HashMapExt};
+use bytemuck::{Pod, Zeroable};
 use glam::{U64Vec2, Vec2};
 use image::{DynamicImage, GenericImage, GenericImageView};
+use strum::{Display, EnumString};
 use thiserror::Error;
 use tracing::{debug, warn};
+
+use crate::engine::resource_pack::{
+    PackTexture, ResourcePack, ResourcePackError, ResourcePackStack,
+};
+
+use self::animation::{AnimatedFrames, AnimatedTexture};
+pub mod animation;
 pub mod cube_textures;
+mod packer;
+pub use packer::SkylinePacker;
 #[derive(Debug, Error)]
 pub enum TextureAtlasBuildError {
     #[error("No file name provided")]
@@ -21,6 +32,20 @@ pub enum TextureAtlasBuildError {
     NoTextures,
     #[error("IO Error: {0}")]
     IoError(#[from] io::Error),
+    #[error("Invalid animation: {0}")]
+    InvalidAnimation(#[from] serde_json::Error),
+    #[error("Resource Pack Error: {0}")]
+    ResourcePack(#[from] ResourcePackError),
+}
+/// How the textures are laid out on the GPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum TextureBackend {
+    /// Every texture is its own layer of a texture array. Textures can not bleed into each other
+    #[default]
+    Array,
+    /// Every texture is stitched into a single image. For adapters that do not support enough texture array layers
+    Atlas,
 }
 #[derive(Debug, PartialEq)]
 pub struct TextureAtlasBuilder {
@@ -30,6 +55,11 @@ pub struct TextureAtlasBuilder {
 }
 
 impl TextureAtlasBuilder {
+    /// Pixels of wrapped texture around every texture in the stitched atlas.
+    /// Lower mip levels average neighbouring pixels so without it textures would bleed into each other
+    pub const ATLAS_GUTTER: u32 = 8;
+    /// The name of the texture used when a texture can not be found. Always added by [Self::build]
+    pub const MISSING_TEXTURE: &'static str = "missing.png";
     pub fn new() -> Self {
         Self {
             textures: Vec::new(),
@@ -37,35 +67,49 @@ impl TextureAtlasBuilder {
             max_height: 0,
         }
     }
+    /// Where block textures are in a resource pack
+    pub const BLOCK_TEXTURES: &'static str = "assets/minecraft/textures/block/";
     /// Loads textures from the minecraft assets folder
-    /// Currently only supports 16x16 block textures
     #[tracing::instrument]
-    pub fn load_from_minecraft_assets() -> Result<TextureAtlas, TextureAtlasBuildError> {
+    pub fn load_from_minecraft_assets(
+        backend: TextureBackend,
+    ) -> Result<TextureAtlas, TextureAtlasBuildError> {
         let Ok(minecraft_assets_location) = std::env::var("MINECRAFT_ASSETS").map(PathBuf::from)
         else {
             warn!("MINECRAFT_ASSETS not set");
             return Err(TextureAtlasBuildError::NoTextures);
         };
-        let blocks = minecraft_assets_location
-            .join("minecraft")
-            .join("textures")
-            .join("block");
-
+        let mut packs = ResourcePackStack::new();
+        packs.push(ResourcePack::vanilla_assets(minecraft_assets_location));
+        Self::load_from_resource_packs(&mut packs, backend)
+    }
+    /// Loads every block texture in the packs. Textures in higher packs replace the ones below them
+    ///
+    /// Textures with a `.png.mcmeta` animation only take up the space of one frame
+    pub fn load_from_resource_packs(
+        packs: &mut ResourcePackStack,
+        backend: TextureBackend,
+    ) -> Result<TextureAtlas, TextureAtlasBuildError> {
+        if packs.packs().is_empty() {
+            warn!("No resource packs were loaded. Every block will use the missing texture");
+        }
         let mut builder = TextureAtlasBuilder::new();
-        for entry in std::fs::read_dir(blocks)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                if path.extension().map(|v| v == "png").unwrap_or(false) {
-                    let texture = RawImageReference::from_file(path)?;
-                    if texture.dynamic_image.width() != 16 || texture.dynamic_image.height() != 16 {
-                        continue;
-                    }
-                    builder.add_texture(texture);
-                }
+        for path in packs.list(Self::BLOCK_TEXTURES)? {
+            if !path.ends_with(".png") {
+                continue;
+            }
+            let Some(PackTexture { png, mcmeta }) = packs.read_with_mcmeta(&path)? else {
+                continue;
+            };
+            let name = path.trim_start_matches(Self::BLOCK_TEXTURES);
+            let mcmeta = mcmeta.map(|mcmeta| String::from_utf8_lossy(&mcmeta).into_owned());
+            // One broken texture should not keep the rest of the packs from loading
+            match RawImageReference::from_bytes(name, &png, mcmeta.as_deref()) {
+                Ok(texture) => builder.add_texture(texture),
+                Err(err) => warn!("Skipping texture {path}: {err}"),
             }
         }
-        builder.build()
+        builder.build(backend)
     }
     pub fn add_texture(&mut self, texture: RawImageReference) {
         let (width, height) = texture.dynamic_image.dimensions();
@@ -73,39 +117,191 @@ impl TextureAtlasBuilder {
         self.max_height = self.max_height.max(height);
         self.textures.push(texture);
     }
-    /// Builds the texture atlas
-    pub fn build(self) -> Result<TextureAtlas, TextureAtlasBuildError> {
-        let number_of_textures = self.textures.len() as u32;
-        let half = (number_of_textures as f32).sqrt().ceil() as u32;
+    /// The width and height of the smallest texture. Limits how many mip levels can be made
+    fn min_size(&self) -> u32 {
+        self.textures
+            .iter()
+            .map(|texture| {
+                texture
+                    .dynamic_image
+                    .width()
+                    .min(texture.dynamic_image.height())
+            })
+            .min()
+            .unwrap_or(1)
+            .max(1)
+    }
+    /// A magenta and black checkerboard. Hard to miss in the world
+    pub fn missing_texture() -> RawImageReference {
+        let magenta = image::Rgba([248, 0, 248, 255]);
+        let black = image::Rgba([0, 0, 0, 255]);
+        let mut image = DynamicImage::new_rgba8(16, 16);
+        for y in 0..16 {
+            for x in 0..16 {
+                image.put_pixel(x, y, if (x < 8) == (y < 8) { magenta } else { black });
+            }
+        }
+        RawImageReference {
+            name: Self::MISSING_TEXTURE.to_string(),
+            dynamic_image: image,
+            animation: None,
+        }
+    }
+    /// Builds the textures with the given backend
+    pub fn build(
+        mut self,
+        backend: TextureBackend,
+    ) -> Result<TextureAtlas, TextureAtlasBuildError> {
+        if !self
+            .textures
+            .iter()
+            .any(|texture| texture.name == Self::MISSING_TEXTURE)
+        {
+            self.add_texture(Self::missing_texture());
+        }
+        match backend {
+            TextureBackend::Array => self.build_array(),
+            TextureBackend::Atlas => self.build_atlas(),
+        }
+    }
+    /// Puts every texture in its own layer. Textures smaller than the largest one are placed in the top left of their layer
+    /// and repeated across the rest of it
+    fn build_array(self) -> Result<TextureAtlas, TextureAtlasBuildError> {
         let mut textures = HashMap::with_capacity(self.textures.len());
-        let image_width = half * self.max_width;
+        let mut layers = Vec::with_capacity(self.textures.len());
+        let mut animations = Vec::new();
+        debug!(
+            "Creating texture array with {} layers of {}x{}",
+            self.textures.len(),
+            self.max_width,
+            self.max_height
+        );
+        for (layer, raw_image) in self.textures.iter().enumerate() {
+            let texture_ref = TextureRef {
+                name: raw_image.name.clone(),
+                width: raw_image.dynamic_image.width(),
+                height: raw_image.dynamic_image.height(),
+                x: 0,
+                y: 0,
+                layer: layer as u32,
+            };
+            textures.insert(raw_image.name.clone(), texture_ref);
+            if let Some(frames) = &raw_image.animation {
+                animations.push(AnimatedTexture {
+                    name: raw_image.name.clone(),
+                    frames: frames.clone(),
+                    x: 0,
+                    y: 0,
+                    width: self.max_width,
+                    height: self.max_height,
+                    layer: layer as u32,
+                    offset: 0,
+                });
+            }
+            layers.push(wrap_texture(
+                &raw_image.dynamic_image,
+                self.max_width,
+                self.max_height,
+                0,
+            ));
+        }
+        let info = Arc::new(TextureAtlasInfo {
+            textures,
+            width: self.max_width,
+            height: self.max_height,
+            texture_width: self.max_width,
+            texture_height: self.max_height,
+            layers: layers.len() as u32,
+            // Down to a single pixel of the smallest texture
+            mip_levels: self.min_size().ilog2() + 1,
+        });
+        Ok(TextureAtlas {
+            info,
+            layers,
+            animations,
+        })
+    }
+    /// Packs every texture into a single image with a [SkylinePacker]. Every texture is surrounded by a [Self::ATLAS_GUTTER]
+    ///
+    /// Slots are rounded up to a multiple of the gutter so every texture starts on a pixel in each mip level
+    fn build_atlas(self) -> Result<TextureAtlas, TextureAtlasBuildError> {
+        let gutter = Self::ATLAS_GUTTER;
+        let slot_size = |image: &DynamicImage| {
+            let (width, height) = image.dimensions();
+            (
+                (width + gutter * 2).next_multiple_of(gutter),
+                (height + gutter * 2).next_multiple_of(gutter),
+            )
+        };
+        // Tallest first keeps the skyline flat
+        let mut order: Vec<usize> = (0..self.textures.len()).collect();
+        order.sort_by_key(|&index| {
+            let (width, height) = slot_size(&self.textures[index].dynamic_image);
+            (std::cmp::Reverse(height), std::cmp::Reverse(width))
+        });
+        let area: u64 = self
+            .textures
+            .iter()
+            .map(|texture| {
+                let (width, height) = slot_size(&texture.dynamic_image);
+                width as u64 * height as u64
+            })
+            .sum();
+        let widest = self
+            .textures
+            .iter()
+            .map(|texture| slot_size(&texture.dynamic_image).0)
+            .max()
+            .unwrap_or(gutter);
+        let image_width = ((area as f64).sqrt().ceil() as u32)
+            .max(widest)
+            .next_power_of_two();
 
-        let image_height = half * self.max_height;
+        let mut packer = SkylinePacker::new(image_width);
+        let mut placed = Vec::with_capacity(self.textures.len());
+        for index in order {
+            let (width, height) = slot_size(&self.textures[index].dynamic_image);
+            let (x, y) = packer
+                .pack(width, height)
+                .expect("The atlas is at least as wide as the widest slot");
+            placed.push((index, x, y));
+        }
+        let image_height = packer.height().max(1);
         debug!(
             "Creating texture atlas with size: {}x{}",
             image_width, image_height
         );
-        // Stitches the textures together
-        let mut x = 0;
-        let mut y = 0;
+        let mut textures = HashMap::with_capacity(self.textures.len());
+        let mut animations = Vec::new();
         let mut result_image = DynamicImage::new_rgba8(image_width, image_height);
         debug!("Stitching textures together");
-        for raw_image in self.textures.iter() {
+        for (index, x, y) in placed {
+            let raw_image = &self.textures[index];
             let texture_ref = TextureRef {
                 name: raw_image.name.clone(),
                 width: raw_image.dynamic_image.width(),
                 height: raw_image.dynamic_image.height(),
-                x: x,
-                y: y,
+                x: x + gutter,
+                y: y + gutter,
+                layer: 0,
             };
             textures.insert(raw_image.name.clone(), texture_ref);
             debug!("Added texture: {} at X: {x} Y: {y}", raw_image.name);
-            result_image.copy_from(&raw_image.dynamic_image, x, y)?;
-            if x + self.max_width >= image_width {
-                y += self.max_height;
-                x = 0;
-            } else {
-                x += self.max_width;
+            // The texture is repeated across the whole slot so the gutter matches the texture wrapping around
+            let (slot_width, slot_height) = slot_size(&raw_image.dynamic_image);
+            let slot = wrap_texture(&raw_image.dynamic_image, slot_width, slot_height, gutter);
+            result_image.copy_from(&slot, x, y)?;
+            if let Some(frames) = &raw_image.animation {
+                animations.push(AnimatedTexture {
+                    name: raw_image.name.clone(),
+                    frames: frames.clone(),
+                    x,
+                    y,
+                    width: slot_width,
+                    height: slot_height,
+                    layer: 0,
+                    offset: gutter,
+                });
             }
         }
         debug!("Created texture atlas with {} textures", textures.len());
@@ -115,38 +311,90 @@ impl TextureAtlasBuilder {
             height: image_height,
             texture_width: self.max_width,
             texture_height: self.max_height,
+            layers: 1,
+            // Stops once the gutter is a single pixel
+            mip_levels: self.min_size().min(gutter).ilog2() + 1,
         });
         Ok(TextureAtlas {
             info: textures,
-            texture: result_image,
+            layers: vec![result_image],
+            animations,
         })
     }
 }
+/// Repeats the texture across an image of the given size. The texture starts `offset` pixels in from the top left
+fn wrap_texture(texture: &DynamicImage, width: u32, height: u32, offset: u32) -> DynamicImage {
+    let (texture_width, texture_height) = texture.dimensions();
+    let mut image = DynamicImage::new_rgba8(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = texture.get_pixel(
+                (x + texture_width * offset - offset) % texture_width,
+                (y + texture_height * offset - offset) % texture_height,
+            );
+            image.put_pixel(x, y, pixel);
+        }
+    }
+    image
+}
 #[derive(Debug, PartialEq)]
 pub struct RawImageReference {
     pub name: String,
+    /// The first frame if the texture is animated
     pub dynamic_image: DynamicImage,
+    pub animation: Option<AnimatedFrames>,
 }
 
 impl RawImageReference {
+    /// Loads the image and the animation in the `.mcmeta` file next to it if there is one
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TextureAtlasBuildError> {
-        let img = image::open(path.as_ref())?;
         let image_name = path
             .as_ref()
             .file_name()
             .and_then(|v| v.to_str().map(|v| v.to_string()))
             .ok_or(TextureAtlasBuildError::NoFileName)?;
-
+        let mcmeta = path.as_ref().with_file_name(format!("{image_name}.mcmeta"));
+        let mcmeta = if mcmeta.is_file() {
+            Some(std::fs::read_to_string(mcmeta)?)
+        } else {
+            None
+        };
+        Self::from_bytes(&image_name, &std::fs::read(path)?, mcmeta.as_deref())
+    }
+    /// Decodes an image. `mcmeta` is the contents of its `.png.mcmeta` file
+    ///
+    /// An animation that can not be read is ignored and the image is loaded as a still texture
+    pub fn from_bytes(
+        name: &str,
+        bytes: &[u8],
+        mcmeta: Option<&str>,
+    ) -> Result<Self, TextureAtlasBuildError> {
+        let img = image::load_from_memory(bytes)?;
+        let animation = match mcmeta {
+            Some(mcmeta) => AnimatedFrames::from_mcmeta(mcmeta, &img).unwrap_or_else(|err| {
+                warn!("Invalid animation for {name}. Loading it as a still image: {err}");
+                None
+            }),
+            None => None,
+        };
+        let dynamic_image = match &animation {
+            Some(animation) => animation.first_frame().clone(),
+            None => img,
+        };
         Ok(Self {
-            name: image_name,
-            dynamic_image: img,
+            name: name.to_string(),
+            dynamic_image,
+            animation,
         })
     }
 }
 #[derive(Debug, PartialEq)]
 pub struct TextureAtlas {
-    pub texture: DynamicImage,
+    /// Every layer of the texture array. The stitched atlas is a single layer
+    pub layers: Vec<DynamicImage>,
     pub info: Arc<TextureAtlasInfo>,
+    /// Where every animated texture was placed
+    pub animations: Vec<AnimatedTexture>,
 }
 impl AsRef<TextureAtlasInfo> for TextureAtlas {
     fn as_ref(&self) -> &TextureAtlasInfo {
@@ -160,11 +408,27 @@ pub struct TextureAtlasInfo {
     pub height: u32,
     pub texture_width: u32,
     pub texture_height: u32,
+    /// The number of layers in the texture array
+    pub layers: u32,
+    /// The number of mip levels that can be generated without textures bleeding into each other
+    pub mip_levels: u32,
 }
 impl TextureAtlasInfo {
     pub fn get_texture(&self, name: &str) -> Option<&TextureRef> {
         self.textures.get(name)
     }
+    /// The coordinates of [TextureAtlasBuilder::MISSING_TEXTURE]. Zero if the atlas was not made by the builder
+    pub fn missing_texture(&self) -> UVCoordinates {
+        self.get_uv_for_texture(TextureAtlasBuilder::MISSING_TEXTURE)
+            .unwrap_or_default()
+    }
+    /// Gets the UV coordinates for a texture. Falls back to the missing texture if there is no texture with the name
+    pub fn get_uv_or_missing(&self, name: &str) -> UVCoordinates {
+        self.get_uv_for_texture(name).unwrap_or_else(|| {
+            warn!("Texture {name} not found. Using the missing texture");
+            self.missing_texture()
+        })
+    }
     /// Gets the UV coordinates for a texture
     /// 4 UV coordinates are returned in the following order:
     /// TOP LEFT, TOP RIGHT, BOTTOM LEFT, BOTTOM RIGHT
@@ -188,15 +452,107 @@ impl TextureAtlasInfo {
             top_right: Vec2::new(x + width, y),
             bottom_left: Vec2::new(x, y + height),
             bottom_right: Vec2::new(x + width, y + height),
+            layer: texture.layer,
         })
     }
 }
+/// A texture as the packed vertex format sees it. Read from a storage buffer by `voxel_packed_vertex.wgsl`
+#[derive(Debug, Clone, Copy, PartialEq, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct TextureTile {
+    /// The top left corner followed by the size
+    pub rect: [f32; 4],
+    /// The layer of the texture array the texture is in
+    pub layer: u32,
+    _padding: [u32; 3],
+}
+impl TextureTile {
+    pub fn new(rect: [f32; 4], layer: u32) -> Self {
+        Self {
+            rect,
+            layer,
+            _padding: [0; 3],
+        }
+    }
+    /// Is `rect` inside of the tile. Allows for the rounding of atlas coordinates
+    fn contains(&self, rect: [f32; 4]) -> bool {
+        const EPSILON: f32 = 1e-5;
+        let [x, y, width, height] = self.rect;
+        rect[0] >= x - EPSILON
+            && rect[1] >= y - EPSILON
+            && rect[0] + rect[2] <= x + width + EPSILON
+            && rect[1] + rect[3] <= y + height + EPSILON
+    }
+}
+/// Gives every texture an index so vertices can refer to a texture with a few bits
+///
+/// Index 0 is an empty tile used by quads without a texture
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureTable {
+    tiles: Vec<TextureTile>,
+    /// The top left corner and layer of a tile to its index
+    indices: HashMap<(u32, u32, u32), u32>,
+}
+impl Default for TextureTable {
+    fn default() -> Self {
+        Self {
+            tiles: vec![TextureTile::default()],
+            indices: HashMap::new(),
+        }
+    }
+}
+impl TextureTable {
+    /// Textures are indexed in name order so the same textures always get the same indices
+    pub fn new(info: &TextureAtlasInfo) -> Self {
+        let mut names: Vec<&String> = info.textures.keys().collect();
+        names.sort();
+        let mut table = Self::default();
+        for name in names {
+            let Some(uv) = info.get_uv_for_texture(name) else {
+                continue;
+            };
+            let size = uv.bottom_right - uv.top_left;
+            let tile = TextureTile::new([uv.top_left.x, uv.top_left.y, size.x, size.y], uv.layer);
+            table
+                .indices
+                .insert(Self::key(tile.rect, tile.layer), table.tiles.len() as u32);
+            table.tiles.push(tile);
+        }
+        table
+    }
+    fn key(rect: [f32; 4], layer: u32) -> (u32, u32, u32) {
+        (rect[0].to_bits(), rect[1].to_bits(), layer)
+    }
+    pub fn tiles(&self) -> &[TextureTile] {
+        &self.tiles
+    }
+    /// The index of the tile that `rect` is in. 0 if it is not in a known texture
+    ///
+    /// Most quads use a whole texture and are found by their top left corner. Model faces that use part of a texture are searched for
+    pub fn index_of(&self, rect: [f32; 4], layer: u32) -> u32 {
+        if let Some(index) = self.indices.get(&Self::key(rect, layer)) {
+            return *index;
+        }
+        self.tiles
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, tile)| tile.layer == layer && tile.contains(rect))
+            .map_or(0, |(index, _)| index as u32)
+    }
+    /// The tile at the index. The empty tile if there is none
+    pub fn tile(&self, index: u32) -> TextureTile {
+        self.tiles.get(index as usize).copied().unwrap_or_default()
+    }
+}
 #[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub struct UVCoordinates {
     pub top_left: Vec2,
     pub top_right: Vec2,
     pub bottom_left: Vec2,
     pub bottom_right: Vec2,
+    /// The layer of the texture array the coordinates are in
+    pub layer: u32,
 }
 
 impl Index<usize> for UVCoordinates {
@@ -254,10 +610,21 @@ impl TextureAtlas {
     ///     Err(e) => eprintln!("Failed to save texture atlas: {}", e),
     /// }
     /// ```
+    ///
+    /// Layers are saved stacked on top of each other
     pub fn save(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
-        self.texture
-            .save(path)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        let (width, height) = self
+            .layers
+            .first()
+            .map(|layer| layer.dimensions())
+            .unwrap_or_default();
+        let mut image = DynamicImage::new_rgba8(width, height * self.layers.len() as u32);
+        for (index, layer) in self.layers.iter().enumerate() {
+            image
+                .copy_from(layer, 0, index as u32 * height)
+                .map_err(io::Error::other)?;
+        }
+        image.save(path).map_err(io::Error::other)
     }
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -267,6 +634,8 @@ pub struct TextureRef {
     pub height: u32,
     pub x: u32,
     pub y: u32,
+    /// The layer of the texture array the texture is in
+    pub layer: u32,
 }
 impl TextureRef {
     pub fn coordinates(&self) -> U64Vec2 {
@@ -282,6 +651,7 @@ mod tests {
     use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
     pub use super::*;
+    use crate::test_directory::TestDirectory;
 
     #[test]
     pub fn test_from_minecraft_assets() -> anyhow::Result<()> {
@@ -305,20 +675,270 @@ mod tests {
             let path = entry.path();
             if path.is_file() {
                 if path.extension().map(|v| v == "png").unwrap_or(false) {
-                    let texture = RawImageReference::from_file(path)?;
-                    if texture.dynamic_image.width() != 16 || texture.dynamic_image.height() != 16 {
-                        continue;
-                    }
-                    builder.add_texture(texture);
+                    builder.add_texture(RawImageReference::from_file(path)?);
                 }
             }
         }
         let now = std::time::Instant::now();
-        let atlas = builder.build()?;
+        let atlas = builder.build(TextureBackend::Atlas)?;
         info!("Built texture atlas in {:?}", now.elapsed());
 
         info!("Time to save texture atlas to disk. This may take a while.");
         atlas.save("atlas.png")?;
         Ok(())
     }
+
+    fn builder() -> TextureAtlasBuilder {
+        let mut builder = TextureAtlasBuilder::new();
+        for (index, name) in ["a.png", "b.png", "c.png"].into_iter().enumerate() {
+            let mut image = DynamicImage::new_rgba8(16, 16);
+            image.put_pixel(0, 0, image::Rgba([index as u8 + 1, 0, 0, 255]));
+            builder.add_texture(RawImageReference {
+                name: name.to_string(),
+                dynamic_image: image,
+                animation: None,
+            });
+        }
+        builder
+    }
+
+    #[test]
+    pub fn test_atlas_gutter_wraps_the_texture() -> anyhow::Result<()> {
+        let atlas = builder().build(TextureBackend::Atlas)?;
+        let texture = atlas.info.get_texture("a.png").unwrap();
+        let image = &atlas.layers[0];
+        let marked = image::Rgba([1, 0, 0, 255]);
+        assert_eq!(image.get_pixel(texture.x, texture.y), marked);
+        // The pixel past the right and bottom edges is the first pixel again
+        assert_eq!(image.get_pixel(texture.x + 16, texture.y), marked);
+        assert_eq!(image.get_pixel(texture.x, texture.y + 16), marked);
+        assert_ne!(image.get_pixel(texture.x + 15, texture.y), marked);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_array_gives_every_texture_a_layer() -> anyhow::Result<()> {
+        let array = builder().build(TextureBackend::Array)?;
+        // The three textures and the missing texture
+        assert_eq!(array.layers.len(), 4);
+        assert_eq!(array.info.mip_levels, 5);
+        assert_eq!(array.info.layers, 4);
+        let mut layers: Vec<u32> = ["a.png", "b.png", "c.png"]
+            .into_iter()
+            .map(|name| {
+                let uv = array.info.get_uv_for_texture(name).unwrap();
+                // The texture fills its whole layer
+                assert_eq!(uv.top_left, Vec2::ZERO);
+                assert_eq!(uv.bottom_right, Vec2::ONE);
+                uv.layer
+            })
+            .collect();
+        layers.push(array.info.missing_texture().layer);
+        layers.sort();
+        assert_eq!(layers, vec![0, 1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_atlas_stitches_into_one_layer() -> anyhow::Result<()> {
+        let atlas = builder().build(TextureBackend::Atlas)?;
+        assert_eq!(atlas.layers.len(), 1);
+        // Two slots across and down. Each is the texture with a gutter on both sides
+        assert_eq!(atlas.layers[0].dimensions(), (64, 64));
+        let table = TextureTable::new(&atlas.info);
+        // The empty tile, the three textures and the missing texture
+        assert_eq!(table.tiles().len(), 5);
+        for name in ["a.png", "b.png", "c.png"] {
+            let uv = atlas.info.get_uv_for_texture(name).unwrap();
+            assert_eq!(uv.layer, 0);
+            let size = uv.bottom_right - uv.top_left;
+            assert_eq!(size, Vec2::splat(0.25));
+            let rect = [uv.top_left.x, uv.top_left.y, size.x, size.y];
+            let index = table.index_of(rect, uv.layer);
+            assert_ne!(index, 0);
+            assert_eq!(table.tile(index), TextureTile::new(rect, 0));
+        }
+        // 16, 8, 4 and 2 pixels. A single pixel would mix the texture with its gutter
+        assert_eq!(atlas.info.mip_levels, 4);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_atlas_packs_mixed_sizes() -> anyhow::Result<()> {
+        let mut builder = builder();
+        for (name, width, height) in [
+            ("big.png", 64, 64),
+            ("strip.png", 16, 32),
+            ("wide.png", 32, 16),
+        ] {
+            let mut image = DynamicImage::new_rgba8(width, height);
+            image.put_pixel(width - 1, height - 1, image::Rgba([255, 0, 0, 255]));
+            builder.add_texture(RawImageReference {
+                name: name.to_string(),
+                dynamic_image: image,
+                animation: None,
+            });
+        }
+        let atlas = builder.build(TextureBackend::Atlas)?;
+        let (width, height) = atlas.layers[0].dimensions();
+        let mut rects: Vec<(u32, u32, u32, u32)> = Vec::new();
+        for texture in atlas.info.textures.values() {
+            // Every texture keeps its own size and its gutter stays inside of the image
+            let gutter = TextureAtlasBuilder::ATLAS_GUTTER;
+            assert!(texture.x >= gutter && texture.y >= gutter);
+            assert!(texture.x + texture.width + gutter <= width);
+            assert!(texture.y + texture.height + gutter <= height);
+            let rect = (
+                texture.x - gutter,
+                texture.y - gutter,
+                texture.width + gutter * 2,
+                texture.height + gutter * 2,
+            );
+            for other in &rects {
+                assert!(
+                    rect.0 >= other.0 + other.2
+                        || other.0 >= rect.0 + rect.2
+                        || rect.1 >= other.1 + other.3
+                        || other.1 >= rect.1 + rect.3
+                );
+            }
+            rects.push(rect);
+        }
+        let strip = atlas.info.get_texture("strip.png").unwrap();
+        assert_eq!((strip.width, strip.height), (16, 32));
+        assert_eq!(
+            atlas.layers[0].get_pixel(strip.x + 15, strip.y + 31),
+            image::Rgba([255, 0, 0, 255])
+        );
+        // Tighter than a grid of 80x80 slots
+        assert!(width * height < 80 * 80 * 6);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_array_limits_mips_to_the_smallest_texture() -> anyhow::Result<()> {
+        let mut builder = builder();
+        builder.add_texture(RawImageReference {
+            name: "big.png".to_string(),
+            dynamic_image: DynamicImage::new_rgba8(32, 64),
+            animation: None,
+        });
+        let array = builder.build(TextureBackend::Array)?;
+        assert_eq!(array.layers[0].dimensions(), (32, 64));
+        assert_eq!(array.info.mip_levels, 5);
+        let uv = array.info.get_uv_for_texture("a.png").unwrap();
+        assert_eq!(uv.bottom_right, Vec2::new(0.5, 0.25));
+        // The smaller texture is repeated across its layer
+        let layer = &array.layers[uv.layer as usize];
+        assert_eq!(layer.get_pixel(16, 48), image::Rgba([1, 0, 0, 255]));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_animated_texture_takes_one_slot() -> anyhow::Result<()> {
+        let strip = DynamicImage::new_rgba8(16, 48);
+        let frames = AnimatedFrames::from_mcmeta(r#"{"animation": {}}"#, &strip)?.unwrap();
+        let mut builder = builder();
+        builder.add_texture(RawImageReference {
+            name: "water_still.png".to_string(),
+            dynamic_image: frames.first_frame().clone(),
+            animation: Some(frames),
+        });
+        let atlas = builder.build(TextureBackend::Atlas)?;
+        let texture = atlas.info.get_texture("water_still.png").unwrap();
+        assert_eq!((texture.width, texture.height), (16, 16));
+        assert_eq!(atlas.animations.len(), 1);
+        let animated = &atlas.animations[0];
+        let gutter = TextureAtlasBuilder::ATLAS_GUTTER;
+        assert_eq!(
+            (animated.x + gutter, animated.y + gutter),
+            (texture.x, texture.y)
+        );
+        assert_eq!((animated.width, animated.height), (32, 32));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_invalid_animation_loads_a_still_image() -> anyhow::Result<()> {
+        let mut png = Vec::new();
+        DynamicImage::new_rgba8(16, 32).write_to(
+            &mut std::io::Cursor::new(&mut png),
+            image::ImageOutputFormat::Png,
+        )?;
+        let texture = RawImageReference::from_bytes("lava_still.png", &png, Some("{ not json"))?;
+        assert!(texture.animation.is_none());
+        assert_eq!(texture.dynamic_image.dimensions(), (16, 32));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_unknown_textures_use_the_missing_texture() -> anyhow::Result<()> {
+        let atlas = builder().build(TextureBackend::Atlas)?;
+        let missing = atlas.info.missing_texture();
+        assert_ne!(missing, UVCoordinates::default());
+        assert_eq!(atlas.info.get_uv_or_missing("typo.png"), missing);
+        assert_ne!(atlas.info.get_uv_or_missing("a.png"), missing);
+        let texture = atlas
+            .info
+            .get_texture(TextureAtlasBuilder::MISSING_TEXTURE)
+            .unwrap();
+        let image = &atlas.layers[0];
+        assert_eq!(
+            image.get_pixel(texture.x, texture.y),
+            image::Rgba([248, 0, 248, 255])
+        );
+        assert_eq!(
+            image.get_pixel(texture.x + 8, texture.y),
+            image::Rgba([0, 0, 0, 255])
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_load_from_resource_packs() -> anyhow::Result<()> {
+        let directory = TestDirectory::new("atlas-packs");
+        let write_png = |pack: &str, name: &str, size: u32| -> anyhow::Result<()> {
+            let path = directory
+                .join(pack)
+                .join("assets/minecraft/textures/block")
+                .join(name);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            DynamicImage::new_rgba8(size, size).save(path)?;
+            Ok(())
+        };
+        write_png("base", "dirt.png", 16)?;
+        write_png("base", "stone.png", 16)?;
+        write_png("hd", "dirt.png", 32)?;
+        std::fs::write(
+            directory.join("hd/assets/minecraft/textures/block/broken.png"),
+            b"not a png",
+        )?;
+        for pack in ["base", "hd"] {
+            std::fs::write(
+                directory.join(pack).join("pack.mcmeta"),
+                r#"{"pack": {"pack_format": 15, "description": ""}}"#,
+            )?;
+        }
+        let mut packs = ResourcePackStack::new();
+        packs.push(ResourcePack::open(directory.join("base"))?);
+        packs.push(ResourcePack::open(directory.join("hd"))?);
+        let atlas =
+            TextureAtlasBuilder::load_from_resource_packs(&mut packs, TextureBackend::Atlas)?;
+        let dirt = atlas.info.get_texture("dirt.png").unwrap();
+        assert_eq!((dirt.width, dirt.height), (32, 32));
+        assert_eq!(atlas.info.get_texture("stone.png").unwrap().width, 16);
+        // The broken texture is skipped instead of failing the whole atlas
+        assert!(atlas.info.get_texture("broken.png").is_none());
+        // Without any packs only the missing texture is loaded
+        let atlas = TextureAtlasBuilder::load_from_resource_packs(
+            &mut ResourcePackStack::new(),
+            TextureBackend::Atlas,
+        )?;
+        assert!(atlas
+            .info
+            .get_texture(TextureAtlasBuilder::MISSING_TEXTURE)
+            .is_some());
+        assert_eq!(atlas.info.textures.len(), 1);
+        Ok(())
+    }
 }
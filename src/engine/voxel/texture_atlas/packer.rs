@@ -0,0 +1,143 @@
+/*!
+# Skyline Packer
+
+Packs rectangles of different sizes into an image of a fixed width.
+
+The packer keeps track of the skyline, the top edge of everything placed so far. Each rectangle is put where it ends up the lowest, leftmost first.
+Packing the tallest rectangles first keeps the skyline flat.
+*/
+
+/// A segment of the skyline. Everything below `y` between `x` and `x + width` is taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    x: u32,
+    y: u32,
+    width: u32,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkylinePacker {
+    width: u32,
+    /// Sorted by x. Covers the whole width
+    skyline: Vec<Segment>,
+}
+impl SkylinePacker {
+    pub fn new(width: u32) -> Self {
+        Self {
+            width,
+            skyline: vec![Segment { x: 0, y: 0, width }],
+        }
+    }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    /// The height of the tallest column. The image needs to be at least this tall
+    pub fn height(&self) -> u32 {
+        self.skyline
+            .iter()
+            .map(|segment| segment.y)
+            .max()
+            .unwrap_or_default()
+    }
+    /// Places a rectangle and returns its top left corner. None if it is wider than the packer
+    pub fn pack(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        if width > self.width {
+            return None;
+        }
+        // The lowest spot. Ties go to the leftmost
+        let (index, y) = (0..self.skyline.len())
+            .filter_map(|index| Some((index, self.fits(index, width)?)))
+            .min_by_key(|(index, y)| (*y, self.skyline[*index].x))?;
+        let x = self.skyline[index].x;
+        self.place(index, x, y + height, width);
+        Some((x, y))
+    }
+    /// The height a rectangle starting at the segment would sit at. None if it runs past the right edge
+    fn fits(&self, index: usize, width: u32) -> Option<u32> {
+        let x = self.skyline[index].x;
+        if x + width > self.width {
+            return None;
+        }
+        let mut y = 0;
+        let mut covered = 0;
+        for segment in &self.skyline[index..] {
+            if covered >= width {
+                break;
+            }
+            y = y.max(segment.y);
+            covered += segment.width - (x + covered).saturating_sub(segment.x).min(segment.width);
+        }
+        Some(y)
+    }
+    /// Raises the skyline under the placed rectangle to `top`
+    fn place(&mut self, index: usize, x: u32, top: u32, width: u32) {
+        let end = x + width;
+        self.skyline.insert(index, Segment { x, y: top, width });
+        // Shrink or remove the segments the rectangle covers
+        let next = index + 1;
+        while next < self.skyline.len() {
+            let segment = &mut self.skyline[next];
+            if segment.x >= end {
+                break;
+            }
+            let segment_end = segment.x + segment.width;
+            if segment_end <= end {
+                self.skyline.remove(next);
+                continue;
+            }
+            segment.width = segment_end - end;
+            segment.x = end;
+            break;
+        }
+        // Merge neighbours at the same height
+        let mut index = 0;
+        while index + 1 < self.skyline.len() {
+            if self.skyline[index].y == self.skyline[index + 1].y {
+                self.skyline[index].width += self.skyline[index + 1].width;
+                self.skyline.remove(index + 1);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlaps(a: (u32, u32, u32, u32), b: (u32, u32, u32, u32)) -> bool {
+        a.0 < b.0 + b.2 && b.0 < a.0 + a.2 && a.1 < b.1 + b.3 && b.1 < a.1 + a.3
+    }
+
+    #[test]
+    fn test_packed_rectangles_do_not_overlap() {
+        let mut packer = SkylinePacker::new(128);
+        let mut sizes = vec![(64, 64), (32, 32), (16, 32), (16, 16), (48, 16), (128, 8)];
+        sizes.extend([(16, 16); 20]);
+        let mut placed: Vec<(u32, u32, u32, u32)> = Vec::new();
+        for (width, height) in sizes {
+            let (x, y) = packer.pack(width, height).unwrap();
+            assert!(x + width <= packer.width());
+            let rect = (x, y, width, height);
+            for other in &placed {
+                assert!(!overlaps(rect, *other), "{rect:?} overlaps {other:?}");
+            }
+            placed.push(rect);
+        }
+        assert!(placed.iter().all(|rect| rect.1 + rect.3 <= packer.height()));
+        // Everything fits in 128x128 so nothing should have been stacked past it
+        assert!(packer.height() <= 128);
+    }
+
+    #[test]
+    fn test_gaps_are_filled() {
+        let mut packer = SkylinePacker::new(64);
+        assert_eq!(packer.pack(32, 32), Some((0, 0)));
+        assert_eq!(packer.pack(16, 16), Some((32, 0)));
+        // The lowest spot is next to the 16x16 rectangle, not on top of it
+        assert_eq!(packer.pack(16, 16), Some((48, 0)));
+        assert_eq!(packer.pack(32, 16), Some((32, 16)));
+        assert_eq!(packer.pack(64, 8), Some((0, 32)));
+        assert_eq!(packer.pack(65, 8), None);
+    }
+}
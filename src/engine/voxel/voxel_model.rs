@@ -0,0 +1,317 @@
+/*!
+# Voxel Models
+
+Turns a [ResolvedModel] into quads that can be placed at any voxel. Used for blocks that are not a full cube. Such as slabs, stairs or torches.
+
+The quads are built once when the block is loaded. Each voxel using the model copies them to its position when the section is meshed.
+Minecraft models are 0-16 on every axis. They are moved so the block is centered on zero like the rest of the voxels.
+*/
+use glam::{Affine3A, Vec2, Vec3};
+use mc_data::block_model::{
+    Axis, BlockModel, BlockModelError, Direction, ElementRotation, ModelElement, ModelName,
+    ResolvedModel,
+};
+use tracing::warn;
+
+use crate::engine::{
+    resource_pack::ResourcePackStack,
+    shapes::{ComplexShape, Quad, Shape},
+};
+
+use super::{Face, TextureAtlasInfo, UVCoordinates};
+
+impl From<Direction> for Face {
+    /// Minecraft's north is negative Z. Which is [Face::South] here
+    fn from(direction: Direction) -> Self {
+        Face::from_axis(direction.axis(), direction.is_positive())
+    }
+}
+/// A face of a model element
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelQuad {
+    /// Relative to the center of the voxel. The uv coordinates are in the atlas
+    pub quad: Quad,
+    /// The layer of the texture array the texture is in
+    pub layer: u32,
+    /// Hidden when the voxel on this side is opaque. Also where the face takes its light from
+    pub cullface: Option<Face>,
+}
+impl ModelQuad {
+    /// The quad moved to the voxel at `position`
+    pub fn at(&self, position: Vec3) -> Quad {
+        let mut quad = self.quad;
+        for vertex in [
+            &mut quad.top_left,
+            &mut quad.top_right,
+            &mut quad.bottom_left,
+            &mut quad.bottom_right,
+        ] {
+            vertex.position += position;
+        }
+        quad
+    }
+}
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VoxelModel {
+    pub quads: Vec<ModelQuad>,
+}
+impl VoxelModel {
+    /// Builds the quads of every element. Textures that are not in the atlas use the missing texture
+    pub fn new(model: &ResolvedModel, atlas: &TextureAtlasInfo) -> Self {
+        let quads = model
+            .elements
+            .iter()
+            .flat_map(|element| element_quads(model, element, atlas))
+            .collect();
+        Self { quads }
+    }
+    /// Reads the model and its parents out of the resource packs. Such as `minecraft:block/stone_slab`
+    pub fn load(
+        packs: &mut ResourcePackStack,
+        name: &str,
+        atlas: &TextureAtlasInfo,
+    ) -> Result<Self, BlockModelError> {
+        let mut load = |name: &str| {
+            let path = ModelName::parse(name).model_path();
+            match packs.read(&path) {
+                Ok(Some(json)) => BlockModel::from_json(name, &json).map(Some),
+                Ok(None) => Ok(None),
+                Err(err) => {
+                    warn!("Failed to read {path}: {err}");
+                    Ok(None)
+                }
+            }
+        };
+        let name = ModelName::parse(name).to_string();
+        let model = load(&name)?.ok_or_else(|| BlockModelError::MissingModel(name.clone()))?;
+        Ok(Self::new(&model.resolve(&name, load)?, atlas))
+    }
+}
+/// The name of a texture in the atlas. `minecraft:block/stone` is `stone.png`
+pub fn atlas_texture_name(texture: &str) -> String {
+    let path = texture.split_once(':').map_or(texture, |(_, path)| path);
+    let path = path.strip_prefix("block/").unwrap_or(path);
+    format!("{path}.png")
+}
+fn element_quads(
+    model: &ResolvedModel,
+    element: &ModelElement,
+    atlas: &TextureAtlasInfo,
+) -> Vec<ModelQuad> {
+    let from = Vec3::from(element.from) / 16.0;
+    let to = Vec3::from(element.to) / 16.0;
+    let size = to - from;
+    let center = (from + to) / 2.0 - Vec3::splat(0.5);
+    // Faces are kept in a map. Sorted so the model is meshed the same way every time
+    let mut directions: Vec<_> = element.faces.iter().collect();
+    directions.sort_by_key(|(direction, _)| Face::from(**direction) as u8);
+    let mut quads = Vec::with_capacity(directions.len());
+    let mut cullfaces = Vec::with_capacity(directions.len());
+    for (direction, element_face) in directions {
+        let face = Face::from(*direction);
+        let texture = match model.texture(&element_face.texture) {
+            Some(texture) => atlas.get_uv_or_missing(&atlas_texture_name(texture)),
+            None => atlas.missing_texture(),
+        };
+        let mut quad = Quad::new_quad_from_face(face, center, size, UVCoordinates::default());
+        let uv = element_face
+            .uv(*direction, element)
+            .map(|value| value / 16.0);
+        for vertex in [
+            &mut quad.top_left,
+            &mut quad.top_right,
+            &mut quad.bottom_left,
+            &mut quad.bottom_right,
+        ] {
+            let local = Vec3::select(
+                size.cmpgt(Vec3::ZERO),
+                (vertex.position + Vec3::splat(0.5) - from) / size,
+                Vec3::ZERO,
+            );
+            let corner = rotate_corner(face_corner(*direction, local), element_face.rotation);
+            let uv = Vec2::new(
+                uv[0] + (uv[2] - uv[0]) * corner.x,
+                uv[1] + (uv[3] - uv[1]) * corner.y,
+            );
+            vertex.uv = atlas_uv(&texture, uv);
+        }
+        quads.push((quad, texture.layer));
+        cullfaces.push(element_face.cullface.map(Face::from));
+    }
+    let transform = element.rotation.map(rotation_transform);
+    let shapes = quads.iter().map(|(quad, _)| *quad).collect();
+    let vertices = match transform {
+        Some(transform) => ComplexShape::with_transform(shapes, transform).vertices(),
+        None => ComplexShape::new(shapes).vertices(),
+    };
+    vertices
+        .chunks_exact(4)
+        .zip(quads)
+        .zip(cullfaces)
+        .map(|((vertices, (_, layer)), cullface)| ModelQuad {
+            quad: Quad::new(vertices[0], vertices[1], vertices[2], vertices[3]),
+            layer,
+            cullface,
+        })
+        .collect()
+}
+/// Where a point of the element is on the face. 0,0 is the top left of the texture and 1,1 the bottom right
+///
+/// `local` is the point inside of the element from 0 to 1 on every axis
+fn face_corner(direction: Direction, local: Vec3) -> Vec2 {
+    match direction {
+        Direction::Down => Vec2::new(local.x, 1.0 - local.z),
+        Direction::Up => Vec2::new(local.x, local.z),
+        Direction::North => Vec2::new(1.0 - local.x, 1.0 - local.y),
+        Direction::South => Vec2::new(local.x, 1.0 - local.y),
+        Direction::West => Vec2::new(local.z, 1.0 - local.y),
+        Direction::East => Vec2::new(1.0 - local.z, 1.0 - local.y),
+    }
+}
+/// Rotates the texture clockwise by `degrees`. The top left of the texture ends up in the top right corner after 90 degrees
+fn rotate_corner(corner: Vec2, degrees: u32) -> Vec2 {
+    (0..(degrees / 90) % 4).fold(corner, |corner, _| Vec2::new(corner.y, 1.0 - corner.x))
+}
+/// A point in the uv of the texture from 0 to 1 moved into the area of the atlas the texture is in
+fn atlas_uv(texture: &UVCoordinates, uv: Vec2) -> Vec2 {
+    let size = texture.bottom_right - texture.top_left;
+    texture.top_left + uv * size
+}
+fn rotation_transform(rotation: ElementRotation) -> Affine3A {
+    let origin = Vec3::from(rotation.origin) / 16.0 - Vec3::splat(0.5);
+    let angle = rotation.angle.to_radians();
+    let (axis, unit) = match rotation.axis {
+        Axis::X => (Vec3::X, 0),
+        Axis::Y => (Vec3::Y, 1),
+        Axis::Z => (Vec3::Z, 2),
+    };
+    let mut scale = Vec3::ONE;
+    if rotation.rescale {
+        // Stretch the other two axes so a diagonal face still reaches the corners of the block
+        scale = Vec3::splat(1.0 / angle.cos());
+        scale[unit] = 1.0;
+    }
+    Affine3A::from_translation(origin)
+        * Affine3A::from_axis_angle(axis, angle)
+        * Affine3A::from_scale(scale)
+        * Affine3A::from_translation(-origin)
+}
+
+#[cfg(test)]
+mod tests {
+    use ahash::{HashMap, HashMapExt};
+
+    use super::{super::texture_atlas::TextureRef, *};
+
+    fn atlas() -> TextureAtlasInfo {
+        let mut textures = HashMap::new();
+        textures.insert(
+            "stone.png".to_string(),
+            TextureRef {
+                name: "stone.png".to_string(),
+                x: 16,
+                y: 0,
+                width: 16,
+                height: 16,
+                layer: 0,
+            },
+        );
+        TextureAtlasInfo {
+            textures,
+            width: 32,
+            height: 16,
+            texture_width: 16,
+            texture_height: 16,
+            layers: 1,
+            mip_levels: 1,
+        }
+    }
+    fn model(json: &str) -> ResolvedModel {
+        BlockModel::from_json("test", json.as_bytes())
+            .unwrap()
+            .resolve("test", |_| Ok(None))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_slab_faces() {
+        let slab = model(
+            r##"{
+                "textures": {"side": "minecraft:block/stone"},
+                "elements": [{
+                    "from": [0, 0, 0],
+                    "to": [16, 8, 16],
+                    "faces": {
+                        "down":  { "texture": "#side", "cullface": "down" },
+                        "up":    { "texture": "#side" },
+                        "north": { "texture": "#side", "cullface": "north" }
+                    }
+                }]
+            }"##,
+        );
+        let model = VoxelModel::new(&slab, &atlas());
+        assert_eq!(model.quads.len(), 3);
+        let top = model
+            .quads
+            .iter()
+            .find(|quad| quad.quad.top_left.normal == Vec3::from(Face::Top.normal()))
+            .unwrap();
+        assert_eq!(top.cullface, None);
+        assert!(top
+            .quad
+            .vertices()
+            .iter()
+            .all(|vertex| vertex.position.y == 0.0));
+        // Minecraft's north is the negative Z side
+        let north = model
+            .quads
+            .iter()
+            .find(|quad| quad.cullface == Some(Face::South))
+            .unwrap();
+        assert!(north
+            .quad
+            .vertices()
+            .iter()
+            .all(|vertex| vertex.position.z == -0.5));
+        // The bottom half of the texture in the right half of the atlas
+        let min = north
+            .quad
+            .vertices()
+            .iter()
+            .fold(Vec2::MAX, |min, vertex| min.min(vertex.uv));
+        let max = north
+            .quad
+            .vertices()
+            .iter()
+            .fold(Vec2::MIN, |max, vertex| max.max(vertex.uv));
+        assert_eq!((min, max), (Vec2::new(0.5, 0.5), Vec2::new(1.0, 1.0)));
+        assert_eq!(north.quad.top_left.uv, Vec2::new(0.5, 0.5));
+    }
+
+    #[test]
+    fn test_rotated_element() {
+        let cross = model(
+            r##"{
+                "textures": {"cross": "block/stone"},
+                "elements": [{
+                    "from": [0.8, 0, 8],
+                    "to": [15.2, 16, 8],
+                    "rotation": {"origin": [8, 8, 8], "axis": "y", "angle": 45, "rescale": true},
+                    "faces": {
+                        "north": { "uv": [0, 0, 16, 16], "texture": "#cross", "rotation": 90 }
+                    }
+                }]
+            }"##,
+        );
+        let model = VoxelModel::new(&cross, &atlas());
+        let quad = model.quads[0].quad;
+        // Rotated onto the diagonal of the block
+        for vertex in quad.vertices() {
+            assert!((vertex.position.x.abs() - vertex.position.z.abs()).abs() < 1e-5);
+            assert!(vertex.position.x.abs() <= 0.5 + 1e-5);
+        }
+        // The texture is rotated a quarter turn. The top left corner shows the bottom left of the texture
+        assert_eq!(quad.top_left.uv, Vec2::new(0.5, 1.0));
+        assert_eq!(rotate_corner(Vec2::new(1.0, 0.0), 90), Vec2::new(0.0, 0.0));
+    }
+}
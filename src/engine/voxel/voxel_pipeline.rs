@@ -1,8 +1,12 @@
+use strum::IntoEnumIterator;
 use tracing::debug;
 
-use super::voxel_state::VoxelRenderer;
+use super::{chunk_mesh::RenderLayer, voxel_state::VoxelRenderer};
 
 pub trait DrawChunk<'a> {
+    /// Draws every layer of the visible chunks with its own pipeline. Opaque first, then cutout and translucent last
+    ///
+    /// Uses the draws written by [VoxelRenderer::prepare_draws]. Each layer is one `multi_draw_indexed_indirect` call when the adapter supports it
     fn draw_chunks(
         &mut self,
         renderer: &'a VoxelRenderer,
@@ -20,15 +24,46 @@ where
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
-        debug!("Rendering Chunks {:#?}", renderer.rendered_chunks.len());
-        for chunk in &renderer.rendered_chunks {
-            
-            self.set_vertex_buffer(0, chunk.vertices.slice(..));
-            self.set_index_buffer(chunk.indices.slice(..), wgpu::IndexFormat::Uint32);
-            self.set_bind_group(0, &renderer.atlas_bind_group, &[]);
-            self.set_bind_group(1, camera_bind_group, &[]);
-            self.set_bind_group(2, light_bind_group, &[]);
-            self.draw_indexed(0..chunk.number_of_indices, 0, 0..1);
+        debug!(
+            "Rendering {} of {} chunks",
+            renderer.visible_chunks.len(),
+            renderer.rendered_chunks.len()
+        );
+        if renderer.draws.is_empty() {
+            return;
+        }
+        self.set_bind_group(0, &renderer.atlas_bind_group, &[]);
+        self.set_bind_group(1, camera_bind_group, &[]);
+        self.set_bind_group(2, light_bind_group, &[]);
+        self.set_vertex_buffer(0, renderer.arena.vertices.buffer().slice(..));
+        self.set_vertex_buffer(1, renderer.origin_buffer.slice(..));
+        self.set_index_buffer(
+            renderer.arena.indices.buffer().slice(..),
+            wgpu::IndexFormat::Uint32,
+        );
+        for layer in RenderLayer::iter() {
+            let draws = renderer.layer_draws[usize::from(layer)].clone();
+            if draws.is_empty() {
+                continue;
+            }
+            self.set_pipeline(&renderer.pipelines[usize::from(layer)]);
+            if renderer.multi_draw {
+                let offset = draws.start as u64
+                    * std::mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>() as u64;
+                self.multi_draw_indexed_indirect(
+                    &renderer.indirect_buffer,
+                    offset,
+                    draws.len() as u32,
+                );
+            } else {
+                for draw in &renderer.draws[draws.start as usize..draws.end as usize] {
+                    self.draw_indexed(
+                        draw.first_index..draw.first_index + draw.index_count,
+                        draw.base_vertex,
+                        draw.first_instance..draw.first_instance + 1,
+                    );
+                }
+            }
         }
     }
 }
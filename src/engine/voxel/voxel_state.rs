@@ -1,15 +1,25 @@
 use crate::engine::{
-    create_render_pipeline, render_types::ShaderVertexType, texture::Texture, SubRenderer,
-    WGPUStateBase,
+    frustum::Frustum,
+    render_types::ShaderVertexType,
+    texture::{Texture, TextureFiltering},
+    utils::{create_blended_render_pipeline, PipelineBlending},
+    SubRenderer, WGPUStateBase,
 };
 
 use super::{
-    chunk_mesh::{BlockVertex, ChunkMesh, RawChunkMesh},
+    buffer_arena::ChunkArena,
+    chunk_mesh::{back_to_front, ChunkMesh, RawChunkMesh, RenderLayer},
+    gpu_vertex::{GpuBlockVertex, GpuVertex, SectionOrigin},
+    texture_atlas::{animation::TextureAnimator, TextureTable},
     TextureAtlas,
 };
 use flume::{Receiver, Sender};
-use glam::{I64Vec3, UVec3};
-use wgpu::PipelineLayout;
+use glam::{I64Vec3, UVec3, Vec3};
+use std::ops::Range;
+use strum::IntoEnumIterator;
+#[cfg(feature = "packed_vertices")]
+use wgpu::util::DeviceExt as _;
+use wgpu::{util::DrawIndexedIndirectArgs, PipelineLayout};
 
 #[derive(Debug)]
 pub enum ChunkUpdates {
@@ -22,15 +32,37 @@ pub enum ChunkUpdates {
 }
 pub struct VoxelRendererConfig {
     pub texture_atlas: TextureAtlas,
+    pub texture_filtering: TextureFiltering,
     pub receiver: Receiver<ChunkUpdates>,
 }
 pub struct VoxelRenderer {
     pub rendered_chunks: Vec<ChunkMesh>,
+    /// Indices into `rendered_chunks` of the sections inside of the camera's view. Updated by [VoxelRenderer::prepare_draws]
+    pub visible_chunks: Vec<usize>,
+    /// The vertex and index buffers every section is written into
+    pub arena: ChunkArena,
+    /// The draw of every visible section layer. Grouped by [RenderLayer]
+    pub draws: Vec<DrawIndexedIndirectArgs>,
+    /// The part of `draws` each [RenderLayer] uses
+    pub layer_draws: [Range<u32>; RenderLayer::COUNT],
+    /// `draws` on the GPU for `multi_draw_indexed_indirect`
+    pub indirect_buffer: wgpu::Buffer,
+    /// The [SectionOrigin] of every draw. Read as an instance so each draw uses the one at its index
+    pub origin_buffer: wgpu::Buffer,
+    /// How many draws fit in `indirect_buffer` and `origin_buffer`
+    indirect_capacity: usize,
+    /// Can every layer be drawn with one `multi_draw_indexed_indirect` call. Otherwise every draw is issued on its own
+    ///
+    /// Draws use `first_instance` to pick their origin so `INDIRECT_FIRST_INSTANCE` is needed as well
+    pub multi_draw: bool,
     /// Updates to the voxel pipeline
     pub receiver: Receiver<ChunkUpdates>,
-    pub voxel_pipeline: wgpu::RenderPipeline,
+    /// Indexed by [RenderLayer]
+    pub pipelines: [wgpu::RenderPipeline; RenderLayer::COUNT],
     pub texture: Texture,
     pub atlas_bind_group: wgpu::BindGroup,
+    /// Writes the current frame of every animated texture into `texture`
+    pub animator: TextureAnimator,
 }
 impl VoxelRenderer {
     pub fn new(
@@ -38,11 +70,20 @@ impl VoxelRenderer {
         wgpu: &WGPUStateBase,
         camera_layout: &wgpu::BindGroupLayout,
         light_layout: &wgpu::BindGroupLayout,
-    ) -> Self {
+    ) -> anyhow::Result<Self> {
         let VoxelRendererConfig {
             texture_atlas,
+            texture_filtering,
             receiver,
         } = config;
+        let max_layers = wgpu.device.limits().max_texture_array_layers;
+        if texture_atlas.info.layers > max_layers {
+            anyhow::bail!(
+                "{} textures do not fit in the {} texture array layers the adapter supports. Set KAKARA_TEXTURE_BACKEND=atlas",
+                texture_atlas.info.layers,
+                max_layers
+            );
+        }
         let rendered_chunks = Vec::with_capacity(32);
         let texture_bind_group_layout =
             wgpu.device
@@ -54,7 +95,7 @@ impl VoxelRenderer {
                             ty: wgpu::BindingType::Texture {
                                 multisampled: false,
                                 sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                                view_dimension: wgpu::TextureViewDimension::D2,
+                                view_dimension: wgpu::TextureViewDimension::D2Array,
                             },
                             count: None,
                         },
@@ -64,6 +105,18 @@ impl VoxelRenderer {
                             ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                             count: None,
                         },
+                        // Only read by the packed vertex format
+                        #[cfg(feature = "packed_vertices")]
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::VERTEX,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
                     ],
                     label: Some("Texture Atlas Group Layout"),
                 });
@@ -76,28 +129,64 @@ impl VoxelRenderer {
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = {
-            let shader = wgpu::ShaderModuleDescriptor {
+        let shader = wgpu
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
                 label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("../shaders/voxel.wgsl").into()),
+                source: wgpu::ShaderSource::Wgsl(
+                    [
+                        include_str!("../shaders/voxel.wgsl"),
+                        GpuBlockVertex::VERTEX_SHADER,
+                    ]
+                    .concat()
+                    .into(),
+                ),
+            });
+        let pipelines = [
+            RenderLayer::Opaque,
+            RenderLayer::Cutout,
+            RenderLayer::Translucent,
+        ]
+        .map(|layer| {
+            let blending = match layer {
+                RenderLayer::Opaque => PipelineBlending::default(),
+                RenderLayer::Cutout => PipelineBlending {
+                    fragment_entry_point: "fs_cutout",
+                    ..Default::default()
+                },
+                RenderLayer::Translucent => PipelineBlending {
+                    blend: wgpu::BlendState::ALPHA_BLENDING,
+                    depth_write_enabled: false,
+                    ..Default::default()
+                },
             };
-            create_render_pipeline(
+            create_blended_render_pipeline(
                 &wgpu.device,
                 &pipeline_layout,
                 wgpu.config.format,
                 Some(Texture::DEPTH_FORMAT),
-                &[BlockVertex::desc()],
-                shader,
+                &[GpuBlockVertex::desc(), SectionOrigin::desc()],
+                &shader,
+                blending,
             )
-        };
-        let texture = Texture::from_image(
+        });
+        let texture = Texture::from_layers(
             &wgpu.device,
             &wgpu.queue,
-            &texture_atlas.texture,
-            Some("Texture Atlas BOI"),
-            false,
-        )
-        .expect("Failed to create texture from image");
+            &texture_atlas.layers,
+            texture_atlas.info.mip_levels,
+            texture_filtering,
+            Some("Block Textures"),
+        );
+        let texture_table = TextureTable::new(&texture_atlas.info);
+        #[cfg(feature = "packed_vertices")]
+        let texture_table_buffer =
+            wgpu.device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Texture Table"),
+                    contents: bytemuck::cast_slice(texture_table.tiles()),
+                    usage: wgpu::BufferUsages::STORAGE,
+                });
 
         let texture_bind_group = wgpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &texture_bind_group_layout,
@@ -110,26 +199,149 @@ impl VoxelRenderer {
                     binding: 1,
                     resource: wgpu::BindingResource::Sampler(&texture.sampler),
                 },
+                #[cfg(feature = "packed_vertices")]
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: texture_table_buffer.as_entire_binding(),
+                },
             ],
             label: Some("cube_bind_group"),
         });
+        let indirect_capacity = 256;
         let renderer = VoxelRenderer {
             rendered_chunks,
+            visible_chunks: Vec::new(),
+            arena: ChunkArena::new(&wgpu.device, texture_table),
+            draws: Vec::new(),
+            layer_draws: Default::default(),
+            indirect_buffer: Self::create_indirect_buffer(&wgpu.device, indirect_capacity),
+            origin_buffer: Self::create_origin_buffer(&wgpu.device, indirect_capacity),
+            indirect_capacity,
+            multi_draw: wgpu.device.features().contains(
+                wgpu::Features::MULTI_DRAW_INDIRECT | wgpu::Features::INDIRECT_FIRST_INSTANCE,
+            ),
             receiver,
-            voxel_pipeline: render_pipeline,
+            pipelines,
             texture,
             atlas_bind_group: texture_bind_group,
+            animator: TextureAnimator::new(texture_atlas.animations),
         };
-        renderer
+        Ok(renderer)
     }
 }
 
+impl VoxelRenderer {
+    fn create_indirect_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("voxel_indirect_draws"),
+            size: (capacity * std::mem::size_of::<DrawIndexedIndirectArgs>()) as u64,
+            usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+    fn create_origin_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("voxel_section_origins"),
+            size: (capacity * std::mem::size_of::<SectionOrigin>()) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+    /// Finds the sections that are inside of the frustum and writes the draws for every layer of them.
+    /// Translucent sections are ordered from furthest to closest to `camera_position`
+    ///
+    /// Must be called after the chunks are updated
+    pub fn prepare_draws(
+        &mut self,
+        frustum: &Frustum,
+        camera_position: Vec3,
+        base_state: &WGPUStateBase,
+    ) {
+        self.visible_chunks.clear();
+        self.visible_chunks.extend(
+            self.rendered_chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, chunk)| frustum.intersects(&chunk.aabb))
+                .map(|(index, _)| index),
+        );
+        self.draws.clear();
+        let mut origins = Vec::with_capacity(self.visible_chunks.len());
+        for layer in RenderLayer::iter() {
+            let mut chunks: Vec<&ChunkMesh> = self
+                .visible_chunks
+                .iter()
+                .map(|index| &self.rendered_chunks[*index])
+                .filter(|chunk| chunk.layer(layer).is_some())
+                .collect();
+            if layer == RenderLayer::Translucent {
+                chunks = back_to_front(chunks.iter().map(|chunk| chunk.center()), camera_position)
+                    .into_iter()
+                    .map(|index| chunks[index])
+                    .collect();
+            }
+            let start = self.draws.len() as u32;
+            for chunk in chunks {
+                let Some(mesh) = chunk.layer(layer) else {
+                    continue;
+                };
+                self.draws.push(DrawIndexedIndirectArgs {
+                    first_instance: self.draws.len() as u32,
+                    ..mesh.draw_args()
+                });
+                origins.push(SectionOrigin {
+                    origin: chunk.position.as_vec3().into(),
+                });
+            }
+            self.layer_draws[usize::from(layer)] = start..self.draws.len() as u32;
+        }
+        if self.draws.is_empty() {
+            return;
+        }
+        if self.draws.len() > self.indirect_capacity {
+            self.indirect_capacity = self.draws.len().next_power_of_two();
+            self.indirect_buffer =
+                Self::create_indirect_buffer(&base_state.device, self.indirect_capacity);
+            self.origin_buffer =
+                Self::create_origin_buffer(&base_state.device, self.indirect_capacity);
+        }
+        base_state
+            .queue
+            .write_buffer(&self.origin_buffer, 0, bytemuck::cast_slice(&origins));
+        if !self.multi_draw {
+            return;
+        }
+        let bytes: Vec<u8> = self
+            .draws
+            .iter()
+            .flat_map(|draw| draw.as_bytes())
+            .copied()
+            .collect();
+        base_state
+            .queue
+            .write_buffer(&self.indirect_buffer, 0, &bytes);
+    }
+}
 impl SubRenderer for VoxelRenderer {
     fn update(&mut self, base_state: &mut WGPUStateBase, dt: std::time::Duration) {
+        for (animated, area) in self.animator.advance(dt) {
+            self.texture.write_region(
+                &base_state.queue,
+                area.to_rgba8(),
+                animated.x,
+                animated.y,
+                animated.layer,
+            );
+        }
         for update in self.receiver.try_iter() {
             match update {
                 ChunkUpdates::Add(chunk) => {
-                    let mesh = ChunkMesh::build_mesh(chunk, &base_state.device);
+                    let mesh = ChunkMesh::build_mesh(
+                        chunk,
+                        &mut self.arena,
+                        &base_state.device,
+                        &base_state.queue,
+                    );
                     self.rendered_chunks.push(mesh);
                 }
                 ChunkUpdates::Remove(location) => {
@@ -138,7 +350,7 @@ impl SubRenderer for VoxelRenderer {
                         .iter()
                         .position(|chunk| chunk.position == location)
                     {
-                        self.rendered_chunks.remove(index);
+                        self.rendered_chunks.remove(index).free(&mut self.arena);
                     }
                 }
                 ChunkUpdates::Update(chunk) => {
@@ -147,8 +359,14 @@ impl SubRenderer for VoxelRenderer {
                         .iter()
                         .position(|c| c.position == chunk.position)
                     {
-                        let mesh = ChunkMesh::build_mesh(chunk, &base_state.device);
-                        self.rendered_chunks[index] = mesh;
+                        let mesh = ChunkMesh::build_mesh(
+                            chunk,
+                            &mut self.arena,
+                            &base_state.device,
+                            &base_state.queue,
+                        );
+                        std::mem::replace(&mut self.rendered_chunks[index], mesh)
+                            .free(&mut self.arena);
                     }
                 }
             }
@@ -156,9 +374,46 @@ impl SubRenderer for VoxelRenderer {
     }
 
     fn debug_info(&self) -> Vec<(String, String)> {
-        vec![(
-            "Rendered Chunks".to_string(),
-            self.rendered_chunks.len().to_string(),
-        )]
+        let vertices: u64 = self
+            .rendered_chunks
+            .iter()
+            .map(|chunk| chunk.number_of_vertices() as u64)
+            .sum();
+        vec![
+            (
+                "Rendered Chunks".to_string(),
+                self.rendered_chunks.len().to_string(),
+            ),
+            ("Rendered Vertices".to_string(), vertices.to_string()),
+            (
+                "Drawn Sections".to_string(),
+                self.visible_chunks.len().to_string(),
+            ),
+            (
+                "Culled Sections".to_string(),
+                (self.rendered_chunks.len() - self.visible_chunks.len()).to_string(),
+            ),
+            (
+                "Arena Vertices".to_string(),
+                format!(
+                    "{} / {}",
+                    self.arena.vertices.allocator().used(),
+                    self.arena.vertices.allocator().size()
+                ),
+            ),
+            (
+                "Vertex Memory".to_string(),
+                format!(
+                    "{} KiB",
+                    self.arena.vertices.allocator().used() as usize
+                        * std::mem::size_of::<GpuBlockVertex>()
+                        / 1024
+                ),
+            ),
+            (
+                "Multi Draw Indirect".to_string(),
+                self.multi_draw.to_string(),
+            ),
+        ]
     }
 }
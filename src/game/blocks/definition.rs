@@ -0,0 +1,475 @@
+/*!
+# Block Definitions
+
+Blocks are described in `.toml` or `.json` files in the [BLOCK_DEFINITIONS_DIRECTORY]. Every file holds a list of blocks.
+
+```toml
+[[blocks]]
+name = "glowstone"
+textures = "glowstone.png"
+hardness = 0.3
+light_emission = 15
+
+[[blocks]]
+name = "stone_slab"
+visibility = "cutout"
+properties = [{ name = "type", type = "enum", values = ["bottom", "top", "double"] }]
+models = [
+    { variant = "type=top", model = "minecraft:block/stone_slab_top" },
+    { model = "minecraft:block/stone_slab" },
+]
+```
+
+Ids are given out in the order the blocks are read. Files are read sorted by their path so the ids stay the same between runs.
+Air is always block zero and is not defined in a file.
+Names without a namespace are in the [DEFAULT_NAMESPACE](super::name::DEFAULT_NAMESPACE). See [BlockName]
+*/
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+use ahash::{HashMap, HashMapExt};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::engine::voxel::{chunk_mesh::VoxelVisiblity, CubeTextures, Face};
+
+use super::{BlockName, BlockProperty, PropertyKind};
+
+/// Where the block definitions are read from
+pub const BLOCK_DEFINITIONS_DIRECTORY: &str = "data/blocks";
+
+#[derive(Debug, Error)]
+pub enum BlockDefinitionError {
+    #[error("Failed to read {0:?}. {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Invalid JSON in {0:?}. {1}")]
+    Json(PathBuf, serde_json::Error),
+    #[error("Invalid TOML in {0:?}. {1}")]
+    Toml(PathBuf, toml::de::Error),
+    #[error("{0:?} is not a .json or .toml file")]
+    UnsupportedFormat(PathBuf),
+    #[error("Block {name} in {path:?} is invalid. {reason}")]
+    Invalid {
+        path: PathBuf,
+        name: BlockName,
+        reason: String,
+    },
+    #[error("Block {name} in {path:?} is already defined in {first:?}")]
+    Duplicate {
+        path: PathBuf,
+        name: BlockName,
+        first: PathBuf,
+    },
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDefinitionFile {
+    #[serde(default)]
+    pub blocks: Vec<BlockDefinition>,
+}
+impl BlockDefinitionFile {
+    /// Parses the file by its extension. Does not validate the blocks
+    pub fn parse(path: &Path, contents: &str) -> Result<Self, BlockDefinitionError> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => serde_json::from_str(contents)
+                .map_err(|err| BlockDefinitionError::Json(path.to_owned(), err)),
+            Some("toml") => toml::from_str(contents)
+                .map_err(|err| BlockDefinitionError::Toml(path.to_owned(), err)),
+            _ => Err(BlockDefinitionError::UnsupportedFormat(path.to_owned())),
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VisibilityDefinition {
+    #[default]
+    Opaque,
+    Cutout,
+    Translucent,
+}
+impl From<VisibilityDefinition> for VoxelVisiblity {
+    fn from(visibility: VisibilityDefinition) -> Self {
+        match visibility {
+            VisibilityDefinition::Opaque => VoxelVisiblity::Opaque,
+            VisibilityDefinition::Cutout => VoxelVisiblity::Cutout,
+            VisibilityDefinition::Translucent => VoxelVisiblity::Translucent,
+        }
+    }
+}
+/// The textures of a cube. Either one texture for every face or a texture per face
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum TextureDefinition {
+    Single(String),
+    /// Faces are named like [Face]. `all` is used for the faces that are not set
+    Faces(FaceTextures),
+}
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaceTextures {
+    pub all: Option<String>,
+    pub north: Option<String>,
+    pub south: Option<String>,
+    pub top: Option<String>,
+    pub bottom: Option<String>,
+    pub west: Option<String>,
+    pub east: Option<String>,
+}
+impl TextureDefinition {
+    pub fn cube_textures(&self) -> CubeTextures<'static> {
+        match self {
+            TextureDefinition::Single(texture) => {
+                CubeTextures::SingleTexture(Cow::Owned(texture.clone()))
+            }
+            TextureDefinition::Faces(faces) => {
+                let mut textures = HashMap::new();
+                for (face, texture) in [
+                    (Face::North, &faces.north),
+                    (Face::South, &faces.south),
+                    (Face::Top, &faces.top),
+                    (Face::Bottom, &faces.bottom),
+                    (Face::West, &faces.west),
+                    (Face::East, &faces.east),
+                ] {
+                    if let Some(texture) = texture {
+                        textures.insert(face, Cow::Owned(texture.clone()));
+                    }
+                }
+                CubeTextures::MultiTexture {
+                    textures,
+                    default: faces.all.clone().map(Cow::Owned),
+                }
+            }
+        }
+    }
+}
+/// The model used by the states that match the variant. See [BlockStates::matches](super::BlockStates::matches)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelVariant {
+    /// Such as `type=top`. Empty matches every state
+    #[serde(default)]
+    pub variant: String,
+    pub model: String,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDefinition {
+    pub name: BlockName,
+    /// Blocks drawn with a model do not need textures
+    pub textures: Option<TextureDefinition>,
+    #[serde(default)]
+    pub visibility: VisibilityDefinition,
+    /// How long the block takes to break
+    #[serde(default = "default_hardness")]
+    pub hardness: f32,
+    /// The block light level the block gives off. 0 to 15
+    #[serde(default)]
+    pub light_emission: u8,
+    #[serde(default)]
+    pub properties: Vec<BlockProperty>,
+    /// The model of every state. Shorthand for a single variant that matches every state
+    pub model: Option<String>,
+    /// The first variant that matches a state is used
+    #[serde(default)]
+    pub models: Vec<ModelVariant>,
+}
+fn default_hardness() -> f32 {
+    1.0
+}
+impl BlockDefinition {
+    /// The most states a block can have. Every combination of the values of its properties is a state
+    pub const MAX_STATES: u32 = 4096;
+    /// The variants followed by their models. `model` is turned into a variant that matches every state
+    pub fn model_variants(&self) -> Vec<(String, String)> {
+        let single = self
+            .model
+            .iter()
+            .map(|model| (String::new(), model.clone()));
+        self.models
+            .iter()
+            .map(|variant| (variant.variant.clone(), variant.model.clone()))
+            .chain(single)
+            .collect()
+    }
+    /// Checks the values that can not be checked while parsing. The error is the reason
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name == BlockName::air() {
+            return Err("air is always the first block and can not be defined".to_owned());
+        }
+        if self.light_emission > 15 {
+            return Err(format!(
+                "The light emission {} is above 15",
+                self.light_emission
+            ));
+        }
+        if !self.hardness.is_finite() || self.hardness < 0.0 {
+            return Err(format!("The hardness {} is not positive", self.hardness));
+        }
+        if self.model.is_some() && !self.models.is_empty() {
+            return Err("Only one of model and models can be set".to_owned());
+        }
+        for (index, property) in self.properties.iter().enumerate() {
+            if let PropertyKind::Int { min, max } = property.kind {
+                if min > max {
+                    return Err(format!(
+                        "The property {} has a min {min} above its max {max}",
+                        property.name
+                    ));
+                }
+            }
+            if property.value_count() == 0 {
+                return Err(format!("The property {} has no values", property.name));
+            }
+            if self.properties[..index]
+                .iter()
+                .any(|other| other.name == property.name)
+            {
+                return Err(format!("The property {} is defined twice", property.name));
+            }
+        }
+        let states = self.properties.iter().try_fold(1u32, |states, property| {
+            states
+                .checked_mul(property.value_count())
+                .filter(|states| *states <= Self::MAX_STATES)
+        });
+        if states.is_none() {
+            return Err(format!(
+                "The properties have more than {} states",
+                Self::MAX_STATES
+            ));
+        }
+        for variant in &self.models {
+            for pair in variant.variant.split(',').filter(|pair| !pair.is_empty()) {
+                let Some((name, value)) = pair.split_once('=') else {
+                    return Err(format!("The variant {} is not name=value", variant.variant));
+                };
+                let Some(property) = self
+                    .properties
+                    .iter()
+                    .find(|property| property.name == name)
+                else {
+                    return Err(format!(
+                        "The variant {} uses the unknown property {name}",
+                        variant.variant
+                    ));
+                };
+                if property.index_of(value).is_none() {
+                    return Err(format!(
+                        "The variant {} uses {value} which is not a value of {name}",
+                        variant.variant
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+/// Reads and validates every `.json` and `.toml` file in the directory. Other files are skipped
+///
+/// The blocks are in the order they will be registered. See the [module](self) docs
+pub fn load_block_definitions(
+    directory: impl AsRef<Path>,
+) -> Result<Vec<BlockDefinition>, BlockDefinitionError> {
+    let directory = directory.as_ref();
+    let io_error = |err| BlockDefinitionError::Io(directory.to_owned(), err);
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(directory).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.is_file()
+            && matches!(
+                path.extension().and_then(|extension| extension.to_str()),
+                Some("json" | "toml")
+            )
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| BlockDefinitionError::Io(path.clone(), err))?;
+        let file = BlockDefinitionFile::parse(&path, &contents)?;
+        files.push((path, file));
+    }
+    validate_definitions(files)
+}
+/// Validates the blocks of every file and puts them in one list. Names must be unique across the files
+pub fn validate_definitions(
+    files: Vec<(PathBuf, BlockDefinitionFile)>,
+) -> Result<Vec<BlockDefinition>, BlockDefinitionError> {
+    let mut defined: HashMap<BlockName, PathBuf> = HashMap::new();
+    let mut blocks = Vec::new();
+    for (path, file) in files {
+        for block in file.blocks {
+            block
+                .validate()
+                .map_err(|reason| BlockDefinitionError::Invalid {
+                    path: path.clone(),
+                    name: block.name.clone(),
+                    reason,
+                })?;
+            if let Some(first) = defined.get(&block.name) {
+                return Err(BlockDefinitionError::Duplicate {
+                    path,
+                    name: block.name,
+                    first: first.clone(),
+                });
+            }
+            defined.insert(block.name.clone(), path.clone());
+            blocks.push(block);
+        }
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_directory::TestDirectory;
+
+    fn parse(path: &str, contents: &str) -> Result<Vec<BlockDefinition>, BlockDefinitionError> {
+        let path = PathBuf::from(path);
+        let file = BlockDefinitionFile::parse(&path, contents)?;
+        validate_definitions(vec![(path, file)])
+    }
+
+    #[test]
+    fn test_toml_and_json_match() {
+        let toml = parse(
+            "blocks.toml",
+            r#"
+            [[blocks]]
+            name = "log"
+            textures = { all = "log.png", top = "log_top.png" }
+            hardness = 2.0
+            properties = [{ name = "axis", type = "enum", values = ["x", "y", "z"] }]
+            models = [{ variant = "axis=x", model = "block/log_x" }, { model = "block/log" }]
+            "#,
+        )
+        .unwrap();
+        let json = parse(
+            "blocks.json",
+            r#"{"blocks": [{
+                "name": "log",
+                "textures": {"all": "log.png", "top": "log_top.png"},
+                "hardness": 2.0,
+                "properties": [{"name": "axis", "type": "enum", "values": ["x", "y", "z"]}],
+                "models": [{"variant": "axis=x", "model": "block/log_x"}, {"model": "block/log"}]
+            }]}"#,
+        )
+        .unwrap();
+        for blocks in [toml, json] {
+            let log = &blocks[0];
+            assert_eq!(log.hardness, 2.0);
+            assert_eq!(log.visibility, VisibilityDefinition::Opaque);
+            assert_eq!(
+                log.properties[0].kind,
+                PropertyKind::Enum {
+                    values: vec!["x".into(), "y".into(), "z".into()]
+                }
+            );
+            assert_eq!(
+                log.model_variants(),
+                vec![
+                    ("axis=x".to_owned(), "block/log_x".to_owned()),
+                    (String::new(), "block/log".to_owned())
+                ]
+            );
+            let CubeTextures::MultiTexture { textures, default } =
+                log.textures.as_ref().unwrap().cube_textures()
+            else {
+                panic!("Expected a texture per face");
+            };
+            assert_eq!(
+                textures.get(&Face::Top).map(|top| top.as_ref()),
+                Some("log_top.png")
+            );
+            assert_eq!(default.as_deref(), Some("log.png"));
+        }
+    }
+
+    #[test]
+    fn test_invalid_definitions() {
+        for (contents, reason) in [
+            ("name = \"lamp\"\nlight_emission = 16", "above 15"),
+            ("name = \"sand\"\nhardness = -1.0", "not positive"),
+            ("name = \"air\"", "first block"),
+            (
+                "name = \"slab\"\nmodels = [{ variant = \"half=top\", model = \"block/slab\" }]",
+                "unknown property half",
+            ),
+            (
+                "name = \"slab\"\nproperties = [{ name = \"half\", type = \"enum\", values = [] }]",
+                "has no values",
+            ),
+            (
+                "name = \"stairs\"\nproperties = [{ name = \"step\", type = \"int\", min = 5, max = 2 }]",
+                "min 5 above its max 2",
+            ),
+            (
+                &format!(
+                    "name = \"wide\"\nproperties = [{}]",
+                    (0..4)
+                        .map(|index| format!(
+                            "{{ name = \"p{index}\", type = \"int\", min = 0, max = 255 }}"
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                "more than 4096 states",
+            ),
+        ] {
+            let err = parse("blocks.toml", &format!("[[blocks]]\n{contents}")).unwrap_err();
+            let message = err.to_string();
+            assert!(
+                matches!(err, BlockDefinitionError::Invalid { .. }) && message.contains(reason),
+                "{message}"
+            );
+        }
+        let err = parse(
+            "blocks.toml",
+            "[[blocks]]\nname = \"dirt\"\nvisibilty = \"cutout\"",
+        )
+        .unwrap_err();
+        assert!(matches!(err, BlockDefinitionError::Toml(..)), "{err}");
+        let err = parse("blocks.yaml", "").unwrap_err();
+        assert!(matches!(err, BlockDefinitionError::UnsupportedFormat(_)));
+    }
+
+    #[test]
+    fn test_load_directory_in_order() {
+        let directory = TestDirectory::new("blocks");
+        std::fs::write(
+            directory.join("b.json"),
+            r#"{"blocks": [{"name": "glass", "visibility": "cutout"}]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("a.toml"),
+            "[[blocks]]\nname = \"stone\"\n[[blocks]]\nname = \"dirt\"",
+        )
+        .unwrap();
+        std::fs::write(directory.join("README.md"), "Not a block").unwrap();
+        let names: Vec<_> = load_block_definitions(&directory)
+            .unwrap()
+            .into_iter()
+            .map(|block| block.name.to_string())
+            .collect();
+        assert_eq!(names, ["kakara:stone", "kakara:dirt", "kakara:glass"]);
+
+        std::fs::write(
+            directory.join("c.toml"),
+            "[[blocks]]\nname = \"kakara:dirt\"",
+        )
+        .unwrap();
+        let err = load_block_definitions(&directory).unwrap_err();
+        assert!(
+            matches!(&err, BlockDefinitionError::Duplicate { first, .. } if first.ends_with("a.toml")),
+            "{err}"
+        );
+    }
+}
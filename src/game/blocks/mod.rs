@@ -1,22 +1,60 @@
 use std::sync::Arc;
 
 use ahash::{HashMap, HashMapExt};
+use tracing::warn;
 
-use crate::engine::voxel::{
-    texture_atlas::{TextureAtlas, TextureAtlasInfo},
-    CubeTextures, LoadedCubeTexture,
+use crate::engine::{
+    resource_pack::ResourcePackStack,
+    voxel::{
+        chunk_mesh::VoxelVisiblity,
+        texture_atlas::{TextureAtlas, TextureAtlasInfo},
+        voxel_model::VoxelModel,
+        CubeTextures, LoadedCubeTexture,
+    },
 };
+
+use definition::TextureDefinition;
+pub use definition::{load_block_definitions, BlockDefinition, BLOCK_DEFINITIONS_DIRECTORY};
+pub use name::BlockName;
+pub use state::{BlockProperty, BlockStates, PropertyKind};
+pub mod definition;
+pub mod name;
+pub mod state;
 #[derive(Debug)]
 pub struct Block {
     id: usize,
-    name: &'static str,
+    name: BlockName,
     textures: Option<CubeTextures<'static>>,
+    /// Variants such as `type=top` followed by the block model of the states that match. Such as `minecraft:block/stone_slab_top`
+    ///
+    /// The first matching variant is used. See [BlockStates::matches]
+    models: Vec<(String, String)>,
+}
+impl Block {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    pub fn name(&self) -> &BlockName {
+        &self.name
+    }
 }
 #[derive(Debug)]
 pub struct BlockRegistery {
     pub index: usize,
-    pub blocks: HashMap<String, Block>,
+    pub blocks: HashMap<BlockName, Block>,
+    /// The name of every block. Indexed by the block id
+    pub names: Vec<BlockName>,
+    /// What is stored in the world. See [BlockStates]
+    pub states: BlockStates,
     pub textures: HashMap<usize, LoadedCubeTexture>,
+    /// States that are drawn with a model instead of a cube. Filled by [BlockRegistery::load_models]
+    pub models: HashMap<u32, Arc<VoxelModel>>,
+    /// Indexed by the block id
+    pub light_emissions: Vec<u8>,
+    /// How long a block takes to break. Indexed by the block id
+    pub hardnesses: Vec<f32>,
+    /// Indexed by the block id
+    pub visibilities: Vec<VoxelVisiblity>,
     pub texture_atlas_info: Arc<TextureAtlasInfo>,
 }
 impl BlockRegistery {
@@ -24,48 +62,240 @@ impl BlockRegistery {
         Self {
             index: 0,
             blocks: HashMap::new(),
+            names: Vec::new(),
+            states: BlockStates::default(),
             textures: HashMap::new(),
+            models: HashMap::new(),
+            light_emissions: Vec::new(),
+            hardnesses: Vec::new(),
+            visibilities: Vec::new(),
             texture_atlas_info,
         }
     }
-    pub fn get_texture_for_id(&self, id: usize) -> Option<LoadedCubeTexture> {
-        self.textures.get(&id).cloned()
+    /// The block with the name in its default state. Names without a namespace are in the default namespace
+    pub fn default_state(&self, name: &str) -> Option<u32> {
+        let name = BlockName::parse(name).ok()?;
+        self.states.default_state(self.blocks.get(&name)?.id)
+    }
+    pub fn block_for_state(&self, state: u32) -> Option<&Block> {
+        let name = self.names.get(self.states.block(state)?)?;
+        self.blocks.get(name)
+    }
+    /// Visible blocks without a texture get the missing texture
+    pub fn get_texture_for_state(&self, state: u32) -> Option<LoadedCubeTexture> {
+        if let Some(texture) = self
+            .states
+            .block(state)
+            .and_then(|block| self.textures.get(&block))
+        {
+            return Some(texture.clone());
+        }
+        if self.visibility(state).is_empty() {
+            return None;
+        }
+        Some(LoadedCubeTexture::SingleTexture(
+            self.texture_atlas_info.missing_texture(),
+        ))
+    }
+    pub fn get_model_for_state(&self, state: u32) -> Option<Arc<VoxelModel>> {
+        self.models.get(&state).cloned()
+    }
+    /// The block light level the block gives off. Unknown states give off no light
+    pub fn light_emission(&self, state: u32) -> u8 {
+        self.states
+            .block(state)
+            .and_then(|block| self.light_emissions.get(block))
+            .copied()
+            .unwrap_or(0)
+    }
+    /// How long the block takes to break. Unknown states take no time
+    pub fn hardness(&self, state: u32) -> f32 {
+        self.states
+            .block(state)
+            .and_then(|block| self.hardnesses.get(block))
+            .copied()
+            .unwrap_or(0.0)
+    }
+    /// How the block is drawn and culled. State zero is air. Unknown states are opaque
+    pub fn visibility(&self, state: u32) -> VoxelVisiblity {
+        if state == 0 {
+            return VoxelVisiblity::Empty;
+        }
+        self.states
+            .block(state)
+            .and_then(|block| self.visibilities.get(block))
+            .copied()
+            .unwrap_or_default()
+    }
+    pub fn register_block(&mut self, name: BlockName, textures: Option<CubeTextures<'static>>) {
+        self.register_block_with_properties(name, textures, VoxelVisiblity::Opaque, Vec::new());
     }
-    pub fn register_block(&mut self, name: &'static str, textures: Option<CubeTextures<'static>>) {
+    /// Registers a block with a state for every combination of the values of its properties. See [BlockStates]
+    pub fn register_block_with_properties(
+        &mut self,
+        name: BlockName,
+        textures: Option<CubeTextures<'static>>,
+        visibility: VoxelVisiblity,
+        properties: Vec<BlockProperty>,
+    ) {
         self.blocks.insert(
-            name.to_owned(),
+            name.clone(),
             Block {
                 id: self.index,
-                name,
+                name: name.clone(),
                 textures,
+                models: Vec::new(),
             },
         );
+        self.names.push(name);
+        self.states.push(properties);
+        self.light_emissions.push(0);
+        self.hardnesses.push(0.0);
+        self.visibilities.push(visibility);
         self.index += 1;
     }
+    /// Registers a block read from a data file. The models are read by [BlockRegistery::load_models]
+    ///
+    /// The definition should already be validated. See [load_block_definitions]
+    pub fn register_definition(&mut self, definition: &BlockDefinition) {
+        self.register_block_with_properties(
+            definition.name.clone(),
+            definition
+                .textures
+                .as_ref()
+                .map(TextureDefinition::cube_textures),
+            definition.visibility.into(),
+            definition.properties.clone(),
+        );
+        if let Some(block) = self.blocks.get_mut(&definition.name) {
+            block.models = definition.model_variants();
+        }
+        let id = self.index - 1;
+        self.light_emissions[id] = definition.light_emission.min(15);
+        self.hardnesses[id] = definition.hardness;
+    }
+    /// Reads the models of every state out of the resource packs. States whose model can not be loaded are drawn as a cube with the missing texture
+    pub fn load_models(&mut self, packs: &mut ResourcePackStack) {
+        // Many states share a model
+        let mut loaded: HashMap<&str, Option<Arc<VoxelModel>>> = HashMap::new();
+        for block in self.blocks.values() {
+            if block.models.is_empty() {
+                continue;
+            }
+            for state in self.states.states_of(block.id) {
+                let Some((_, name)) = block
+                    .models
+                    .iter()
+                    .find(|(variant, _)| self.states.matches(state, variant))
+                else {
+                    warn!(
+                        "No model for {}[{}]",
+                        block.name,
+                        self.states.describe(state)
+                    );
+                    continue;
+                };
+                let model = loaded.entry(name.as_str()).or_insert_with(|| {
+                    VoxelModel::load(packs, name, &self.texture_atlas_info)
+                        .map(Arc::new)
+                        .inspect_err(|err| {
+                            warn!("Failed to load the model of {}: {err}", block.name)
+                        })
+                        .ok()
+                });
+                if let Some(model) = model {
+                    self.models.insert(state, model.clone());
+                }
+            }
+        }
+    }
+    /// Looks up the textures of every block in the atlas
+    ///
+    /// Visible blocks without textures or a model are warned about once and drawn with the missing texture
     pub fn load_textures(&mut self) {
         for (_, block) in &self.blocks {
-            let Some(textures) = block.textures.as_ref() else {
-                continue;
+            let texture = match block.textures.as_ref() {
+                Some(textures) => textures.get_coordinates(&self.texture_atlas_info),
+                None if !block.models.is_empty() => None,
+                // Air is registered like any other block. Its state is the one that is empty
+                None if self
+                    .states
+                    .default_state(block.id)
+                    .is_none_or(|state| self.visibility(state).is_empty()) =>
+                {
+                    None
+                }
+                None => {
+                    warn!("{} has no textures. Using the missing texture", block.name);
+                    Some(LoadedCubeTexture::SingleTexture(
+                        self.texture_atlas_info.missing_texture(),
+                    ))
+                }
             };
-            let texture = textures.get_coordinates(&self.texture_atlas_info);
             if let Some(texture) = texture {
                 self.textures.insert(block.id, texture);
             }
         }
     }
 }
-pub fn create_block_registery(atlas: Arc<TextureAtlasInfo>) -> BlockRegistery {
+/// Air followed by the blocks in the order they are defined. See [definition]
+pub fn create_block_registery(
+    atlas: Arc<TextureAtlasInfo>,
+    definitions: &[BlockDefinition],
+) -> BlockRegistery {
     let mut registery = BlockRegistery::new(atlas);
-    registery.register_block("air", None);
-    registery.register_block("stone_bricks", Some("stone_bricks.png".into()));
-    registery.register_block("dirt", Some("dirt.png".into()));
-    registery.register_block(
-        "cracked_stone_bricks",
-        Some("cracked_stone_bricks.png".into()),
-    );
-    registery.register_block("diamond_ore", Some("diamond_ore.png".into()));
-    registery.register_block("iron_ore", Some("iron_ore.png".into()));
-
+    registery.register_block(BlockName::air(), None);
+    for definition in definitions {
+        registery.register_definition(definition);
+    }
     registery.load_textures();
     registery
 }
+/// A registry with the blocks in `data/blocks/default.toml` and no textures
+#[cfg(test)]
+pub(crate) fn test_block_registery() -> BlockRegistery {
+    test_block_registery_from(include_str!("../../../data/blocks/default.toml"))
+}
+/// A registry with the blocks of a TOML definition file and no textures
+#[cfg(test)]
+pub(crate) fn test_block_registery_from(definitions: &str) -> BlockRegistery {
+    let path = std::path::PathBuf::from("test.toml");
+    let file = definition::BlockDefinitionFile::parse(&path, definitions).unwrap();
+    let definitions = definition::validate_definitions(vec![(path, file)]).unwrap();
+    create_block_registery(
+        Arc::new(TextureAtlasInfo {
+            textures: Default::default(),
+            width: 16,
+            height: 16,
+            texture_width: 16,
+            texture_height: 16,
+            layers: 1,
+            mip_levels: 1,
+        }),
+        &definitions,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_visible_blocks_without_textures_use_the_missing_texture() {
+        let registry = test_block_registery_from(
+            r#"
+            [[blocks]]
+            name = "untextured"
+            "#,
+        );
+        let missing =
+            LoadedCubeTexture::SingleTexture(registry.texture_atlas_info.missing_texture());
+        let id = registry.blocks[&BlockName::parse("untextured").unwrap()].id;
+        assert_eq!(registry.textures.get(&id), Some(&missing));
+        let state = registry.default_state("untextured").unwrap();
+        assert_eq!(registry.get_texture_for_state(state), Some(missing));
+        // Air has nothing to draw
+        assert!(!registry.textures.contains_key(&0));
+        assert_eq!(registry.get_texture_for_state(0), None);
+    }
+}
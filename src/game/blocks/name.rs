@@ -0,0 +1,99 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The namespace of block names that do not have one
+pub const DEFAULT_NAMESPACE: &str = "kakara";
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid block name {name:?}. {reason}")]
+pub struct InvalidBlockName {
+    pub name: String,
+    pub reason: &'static str,
+}
+/// A namespaced block name. Such as `kakara:stone_bricks`
+///
+/// Written as `namespace:path`. Names without a namespace are in [DEFAULT_NAMESPACE]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BlockName {
+    pub namespace: String,
+    pub path: String,
+}
+impl BlockName {
+    /// Air is always the first block
+    pub fn air() -> Self {
+        Self {
+            namespace: DEFAULT_NAMESPACE.to_owned(),
+            path: "air".to_owned(),
+        }
+    }
+    /// Namespaces may use `a-z`, `0-9`, `_`, `-` and `.`. Paths may also use `/`
+    pub fn parse(name: &str) -> Result<Self, InvalidBlockName> {
+        let invalid = |reason| InvalidBlockName {
+            name: name.to_owned(),
+            reason,
+        };
+        let (namespace, path) = name.split_once(':').unwrap_or((DEFAULT_NAMESPACE, name));
+        if namespace.is_empty() || path.is_empty() {
+            return Err(invalid("The namespace and path can not be empty"));
+        }
+        let valid = |c: char| matches!(c, 'a'..='z' | '0'..='9' | '_' | '-' | '.');
+        if !namespace.chars().all(valid) {
+            return Err(invalid("The namespace may only use a-z, 0-9, _, - and ."));
+        }
+        if !path.chars().all(|c| valid(c) || c == '/') {
+            return Err(invalid("The path may only use a-z, 0-9, _, -, . and /"));
+        }
+        Ok(Self {
+            namespace: namespace.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+}
+impl fmt::Display for BlockName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.path)
+    }
+}
+impl FromStr for BlockName {
+    type Err = InvalidBlockName;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::parse(name)
+    }
+}
+impl TryFrom<String> for BlockName {
+    type Error = InvalidBlockName;
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Self::parse(&name)
+    }
+}
+impl From<BlockName> for String {
+    fn from(name: BlockName) -> Self {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_block_names() {
+        let dirt = BlockName::parse("dirt").unwrap();
+        assert_eq!(dirt, BlockName::parse("kakara:dirt").unwrap());
+        assert_eq!(dirt.to_string(), "kakara:dirt");
+        let slab = BlockName::parse("minecraft:stone_slab").unwrap();
+        assert_eq!(
+            (slab.namespace.as_str(), slab.path.as_str()),
+            ("minecraft", "stone_slab")
+        );
+        assert!(BlockName::parse("mod:ores/tin_ore").is_ok());
+        for invalid in ["", "kakara:", ":dirt", "Dirt", "a:b:c", "mod/x:dirt"] {
+            assert!(BlockName::parse(invalid).is_err(), "{invalid}");
+        }
+        let json: BlockName = serde_json::from_str("\"glass\"").unwrap();
+        assert_eq!(serde_json::to_string(&json).unwrap(), "\"kakara:glass\"");
+    }
+}
@@ -0,0 +1,291 @@
+/*!
+# Block States
+
+Blocks can have properties. Such as the axis of a log, the facing of a stair or the half of a slab.
+Every combination of the values of a block's properties is a block state with its own id. The state id is what a [BlockStore](crate::world::block_store::BlockStore) holds.
+
+The states of a block follow each other. The state id is the first state of the block plus the values of its properties counted like the digits of a number, the last property being the lowest digit.
+Blocks without properties have a single state.
+*/
+use std::{borrow::Cow, ops::Range};
+
+use serde::{Deserialize, Serialize};
+
+/// The values a [BlockProperty] can have
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PropertyKind {
+    Bool,
+    /// Every number from min to max
+    Int {
+        min: u8,
+        max: u8,
+    },
+    /// One of the names. The first is the default
+    Enum {
+        values: Vec<String>,
+    },
+}
+/// A property of a block. Such as `axis` with the values `x`, `y` and `z`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockProperty {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: PropertyKind,
+}
+impl BlockProperty {
+    /// The number of values the property can have
+    pub fn value_count(&self) -> u32 {
+        match &self.kind {
+            PropertyKind::Bool => 2,
+            PropertyKind::Int { min, max } => max.saturating_sub(*min) as u32 + 1,
+            PropertyKind::Enum { values } => values.len() as u32,
+        }
+    }
+    /// The name of the value at `index`. As written in a variant
+    pub fn value(&self, index: u32) -> Option<Cow<'_, str>> {
+        if index >= self.value_count() {
+            return None;
+        }
+        Some(match &self.kind {
+            PropertyKind::Bool => Cow::Borrowed(if index == 0 { "false" } else { "true" }),
+            PropertyKind::Int { min, .. } => Cow::Owned((*min as u32 + index).to_string()),
+            PropertyKind::Enum { values } => Cow::Borrowed(&values[index as usize]),
+        })
+    }
+    /// The index of the value with the name
+    pub fn index_of(&self, value: &str) -> Option<u32> {
+        match &self.kind {
+            PropertyKind::Bool => match value {
+                "false" => Some(0),
+                "true" => Some(1),
+                _ => None,
+            },
+            PropertyKind::Int { min, max } => value
+                .parse::<u8>()
+                .ok()
+                .filter(|value| (min..=max).contains(&value))
+                .map(|value| (value - min) as u32),
+            PropertyKind::Enum { values } => values
+                .iter()
+                .position(|name| *name == value)
+                .map(|index| index as u32),
+        }
+    }
+}
+/// The states of every block
+#[derive(Debug, Clone, Default)]
+pub struct BlockStates {
+    /// The block of every state. Indexed by the state id
+    blocks: Vec<usize>,
+    /// The first state of every block. Indexed by the block id
+    first_states: Vec<u32>,
+    /// Indexed by the block id
+    properties: Vec<Vec<BlockProperty>>,
+}
+impl BlockStates {
+    /// Adds the states of the next block. Returns the first of them
+    pub fn push(&mut self, properties: Vec<BlockProperty>) -> u32 {
+        let block = self.first_states.len();
+        let first = self.blocks.len() as u32;
+        let count: u32 = properties.iter().map(BlockProperty::value_count).product();
+        self.blocks
+            .extend(std::iter::repeat_n(block, count as usize));
+        self.first_states.push(first);
+        self.properties.push(properties);
+        first
+    }
+    /// The number of states of every block
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+    /// The block the state belongs to
+    pub fn block(&self, state: u32) -> Option<usize> {
+        self.blocks.get(state as usize).copied()
+    }
+    /// Every state of the block
+    pub fn states_of(&self, block: usize) -> Range<u32> {
+        let Some(first) = self.first_states.get(block).copied() else {
+            return 0..0;
+        };
+        let end = self
+            .first_states
+            .get(block + 1)
+            .copied()
+            .unwrap_or(self.blocks.len() as u32);
+        first..end
+    }
+    /// The state with the first value of every property
+    pub fn default_state(&self, block: usize) -> Option<u32> {
+        self.first_states.get(block).copied()
+    }
+    pub fn properties(&self, block: usize) -> &[BlockProperty] {
+        self.properties
+            .get(block)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+    /// The index of the value of every property of the state
+    pub fn values(&self, state: u32) -> Option<Vec<u32>> {
+        let block = self.block(state)?;
+        let mut offset = state - self.first_states[block];
+        let properties = self.properties(block);
+        let mut values = vec![0; properties.len()];
+        for (value, property) in values.iter_mut().zip(properties).rev() {
+            *value = offset % property.value_count();
+            offset /= property.value_count();
+        }
+        Some(values)
+    }
+    /// The state of the block with the values. None if a value is out of range
+    pub fn state(&self, block: usize, values: &[u32]) -> Option<u32> {
+        let properties = self.properties(block);
+        if values.len() != properties.len() {
+            return None;
+        }
+        let mut offset = 0;
+        for (value, property) in values.iter().zip(properties) {
+            if *value >= property.value_count() {
+                return None;
+            }
+            offset = offset * property.value_count() + value;
+        }
+        Some(self.default_state(block)? + offset)
+    }
+    /// The name of the value of a property of the state
+    pub fn property(&self, state: u32, name: &str) -> Option<Cow<'_, str>> {
+        let block = self.block(state)?;
+        let index = self
+            .properties(block)
+            .iter()
+            .position(|property| property.name == name)?;
+        self.properties(block)[index].value(self.values(state)?[index])
+    }
+    /// The same block with one property changed
+    pub fn with_property(&self, state: u32, name: &str, value: &str) -> Option<u32> {
+        let block = self.block(state)?;
+        let properties = self.properties(block);
+        let index = properties
+            .iter()
+            .position(|property| property.name == name)?;
+        let mut values = self.values(state)?;
+        values[index] = properties[index].index_of(value)?;
+        self.state(block, &values)
+    }
+    /// The properties of the state as Minecraft writes them in a variant. Such as `facing=north,half=top`
+    pub fn describe(&self, state: u32) -> String {
+        let (Some(block), Some(values)) = (self.block(state), self.values(state)) else {
+            return String::new();
+        };
+        self.properties(block)
+            .iter()
+            .zip(values)
+            .filter_map(|(property, value)| {
+                Some(format!("{}={}", property.name, property.value(value)?))
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+    /// Does the state have every value of a variant. Such as `type=top`. An empty variant matches every state
+    pub fn matches(&self, state: u32, variant: &str) -> bool {
+        variant
+            .split(',')
+            .filter(|pair| !pair.is_empty())
+            .all(|pair| match pair.split_once('=') {
+                Some((name, value)) => self.property(state, name).as_deref() == Some(value),
+                None => false,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::blocks::test_block_registery;
+
+    fn property(name: &str, kind: PropertyKind) -> BlockProperty {
+        BlockProperty {
+            name: name.to_string(),
+            kind,
+        }
+    }
+    fn values(values: &[&str]) -> PropertyKind {
+        PropertyKind::Enum {
+            values: values.iter().map(|value| value.to_string()).collect(),
+        }
+    }
+    fn states() -> BlockStates {
+        let mut states = BlockStates::default();
+        states.push(Vec::new());
+        states.push(vec![
+            property("facing", values(&["north", "south", "west", "east"])),
+            property("half", values(&["bottom", "top"])),
+        ]);
+        states.push(vec![
+            property("level", PropertyKind::Int { min: 1, max: 3 }),
+            property("lit", PropertyKind::Bool),
+        ]);
+        states
+    }
+
+    #[test]
+    fn test_every_combination_has_a_state() {
+        let states = states();
+        assert_eq!(states.len(), 1 + 4 * 2 + 3 * 2);
+        assert_eq!(states.states_of(1), 1..9);
+        assert_eq!(states.states_of(2), 9..15);
+        for block in 0..3 {
+            for state in states.states_of(block) {
+                assert_eq!(states.block(state), Some(block));
+                let values = states.values(state).unwrap();
+                assert_eq!(states.state(block, &values), Some(state));
+            }
+        }
+        assert_eq!(states.block(15), None);
+        assert_eq!(states.state(1, &[4, 0]), None);
+    }
+
+    #[test]
+    fn test_properties_by_name() {
+        let states = states();
+        let stair = states.default_state(1).unwrap();
+        assert_eq!(states.describe(stair), "facing=north,half=bottom");
+        let top = states.with_property(stair, "half", "top").unwrap();
+        let east = states.with_property(top, "facing", "east").unwrap();
+        assert_eq!(states.describe(east), "facing=east,half=top");
+        assert!(states.matches(east, "half=top"));
+        assert!(states.matches(east, ""));
+        assert!(!states.matches(east, "half=top,facing=west"));
+        assert_eq!(states.with_property(stair, "half", "middle"), None);
+
+        let lamp = states.default_state(2).unwrap();
+        assert_eq!(states.describe(lamp), "level=1,lit=false");
+        let lit = states.with_property(lamp, "lit", "true").unwrap();
+        let level = states.with_property(lit, "level", "3").unwrap();
+        assert_eq!(states.property(level, "level").as_deref(), Some("3"));
+        assert_eq!(states.with_property(lamp, "level", "4"), None);
+        assert_eq!(states.describe(0), "");
+    }
+
+    #[test]
+    fn test_registry_states() {
+        let registry = test_block_registery();
+        // Blocks without properties keep the same ids
+        assert_eq!(registry.default_state("air"), Some(0));
+        assert_eq!(registry.default_state("dirt"), Some(2));
+        let slab = registry.default_state("stone_slab").unwrap();
+        let top = registry.states.with_property(slab, "type", "top").unwrap();
+        assert_eq!(
+            registry
+                .block_for_state(top)
+                .map(|block| block.name().to_string()),
+            Some("kakara:stone_slab".to_owned())
+        );
+        assert_eq!(registry.visibility(top), registry.visibility(slab));
+        assert!(registry.visibility(top).is_cutout());
+        assert_eq!(registry.states.len(), registry.index + 2);
+    }
+}
@@ -1,9 +1,16 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use flume::Sender;
+use flume::{Receiver, Sender};
+use tracing::{debug, error, info};
 
 use crate::{
-    engine::voxel::voxel_state::ChunkUpdates,
+    engine::{
+        voxel::{
+            chunk_mesh::{MeshOptions, MeshingMode},
+            voxel_state::ChunkUpdates,
+        },
+        Camera, TextureFiltering,
+    },
     world::{
         position::{RawPosition, RawRotation},
         World,
@@ -12,11 +19,81 @@ use crate::{
 
 use self::blocks::BlockRegistery;
 pub mod blocks;
+/// How often the world follows the player and checks for chunks finished by the workers
+pub const WORLD_TICK: Duration = Duration::from_millis(50);
+/// How far away the player can break and place blocks
+pub const PLAYER_REACH: f64 = 6.0;
 
 pub struct GameState {
     pub world: World,
     pub player: Player,
     pub game: Arc<Game>,
+    /// Camera movements sent by the renderer
+    pub player_movement: Receiver<PlayerMovement>,
+    /// Clicks sent by the renderer
+    pub player_actions: Receiver<PlayerAction>,
+}
+impl GameState {
+    /// Runs the world until a message is sent on `shutdown` or the sender is dropped
+    ///
+    /// Dirty chunks are saved every [GameSettings::autosave_interval] and once more before returning
+    pub async fn run(mut self, shutdown: Receiver<()>) {
+        self.world.update_meshes(
+            &self.player,
+            self.game.clone(),
+            self.game.chunk_queue.clone(),
+        );
+        let mut autosave = tokio::time::interval(self.game.game_settings.autosave_interval);
+        // The first tick completes immediately
+        autosave.tick().await;
+        let mut tick = tokio::time::interval(WORLD_TICK);
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {
+                    self.update_player();
+                    self.world.tick(&self.player, self.game.clone(), &self.game.chunk_queue);
+                }
+                Ok(action) = self.player_actions.recv_async() => self.handle_action(action),
+                _ = autosave.tick() => self.save_world(),
+                _ = shutdown.recv_async() => break,
+            }
+        }
+        self.save_world();
+    }
+    /// Moves the player to the last position sent by the renderer
+    fn update_player(&mut self) {
+        if let Some(movement) = self.player_movement.try_iter().last() {
+            self.player.position = movement.position;
+            self.player.rotation = movement.rotation;
+        }
+    }
+    /// Breaks or places a block where the camera was looking when the player clicked. The change is shown right away
+    fn handle_action(&mut self, action: PlayerAction) {
+        let changed = match action {
+            PlayerAction::BreakBlock(camera) => {
+                self.world
+                    .break_block(camera.eye(), camera.rotation.direction(), PLAYER_REACH)
+            }
+            PlayerAction::PlaceBlock(camera) => self.world.place_block(
+                camera.eye(),
+                camera.rotation.direction(),
+                PLAYER_REACH,
+                self.player.selected_block,
+            ),
+        };
+        if let Some(position) = changed {
+            debug!("{action:?} changed {position:?}");
+            self.world
+                .apply_block_changes(&self.game, &self.game.chunk_queue);
+        }
+    }
+    fn save_world(&mut self) {
+        match self.world.save_dirty_chunks() {
+            Ok(0) => {}
+            Ok(saved) => info!("Saved {} chunks", saved),
+            Err(err) => error!("Failed to save the world: {}", err),
+        }
+    }
 }
 pub struct Game {
     pub block_registery: BlockRegistery,
@@ -26,8 +103,91 @@ pub struct Game {
 pub struct Player {
     pub position: RawPosition,
     pub rotation: RawRotation,
+    /// The block state placed by [PlayerAction::PlaceBlock]
+    pub selected_block: u32,
+}
+
+/// Where the camera is. Sent from the renderer to the world
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayerMovement {
+    pub position: RawPosition,
+    pub rotation: RawRotation,
+}
+impl PlayerMovement {
+    /// The camera in block coordinates. Blocks are drawn centered on their position so the camera is half a block off
+    pub fn eye(&self) -> RawPosition {
+        RawPosition::new(
+            self.position.x + 0.5,
+            self.position.y + 0.5,
+            self.position.z + 0.5,
+        )
+    }
+}
+/// A click sent from the renderer to the world. Holds the camera at the time of the click
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlayerAction {
+    /// Breaks the block the camera is looking at
+    BreakBlock(PlayerMovement),
+    /// Places [Player::selected_block] against the face the camera is looking at
+    PlaceBlock(PlayerMovement),
+}
+impl From<&Camera> for PlayerMovement {
+    fn from(camera: &Camera) -> Self {
+        let position = camera.position;
+        Self {
+            position: RawPosition::new(position.x as f64, position.y as f64, position.z as f64),
+            rotation: RawRotation::new(camera.yaw().0, camera.pitch().0),
+        }
+    }
 }
 
 pub struct GameSettings {
     pub chunk_render_distance: usize,
+    /// How many chunks past the render distance a chunk has to be before it stops being rendered.
+    ///
+    /// Chunks twice this far past the render distance are unloaded.
+    /// Keeps chunks from being reloaded over and over while the player walks along a chunk border
+    pub chunk_unload_hysteresis: usize,
+    /// How sections are meshed
+    pub meshing_mode: MeshingMode,
+    /// Darkens the corners of blocks that are next to other blocks
+    pub ambient_occlusion: bool,
+    /// How block textures are sampled
+    pub texture_filtering: TextureFiltering,
+    /// How often chunks that have changed are written to disk
+    pub autosave_interval: Duration,
+}
+impl GameSettings {
+    /// Chunks further away than this stop being rendered
+    pub fn chunk_hide_distance(&self) -> i64 {
+        (self.chunk_render_distance + self.chunk_unload_hysteresis) as i64
+    }
+    /// Chunks further away than this are saved and removed from memory
+    pub fn chunk_unload_distance(&self) -> i64 {
+        (self.chunk_render_distance + self.chunk_unload_hysteresis * 2) as i64
+    }
+    /// The options new meshes are built with
+    pub fn mesh_options(&self) -> MeshOptions {
+        MeshOptions {
+            mode: self.meshing_mode,
+            ambient_occlusion: self.ambient_occlusion,
+        }
+    }
+}
+/// A game with the default blocks, a render distance of one chunk and updates that go nowhere
+#[cfg(test)]
+pub(crate) fn test_game() -> Arc<Game> {
+    let (sender, _) = flume::unbounded();
+    Arc::new(Game {
+        block_registery: blocks::test_block_registery(),
+        game_settings: GameSettings {
+            chunk_render_distance: 1,
+            chunk_unload_hysteresis: 1,
+            meshing_mode: Default::default(),
+            ambient_occlusion: true,
+            texture_filtering: Default::default(),
+            autosave_interval: Duration::from_secs(30),
+        },
+        chunk_queue: sender,
+    })
 }
@@ -1,11 +1,21 @@
 #![feature(hash_extract_if)]
-use std::{sync::Arc, time::Instant};
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use anyhow::Context;
 use engine::{
-    voxel::{texture_atlas::TextureAtlasBuilder, voxel_state::VoxelRendererConfig},
+    resource_pack::ResourcePackStack,
+    voxel::{
+        texture_atlas::{TextureAtlasBuilder, TextureBackend},
+        voxel_state::VoxelRendererConfig,
+    },
     State,
 };
-use game::GameState;
+use game::{GameState, PlayerAction, PlayerMovement};
+use tracing::error;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use winit::{
     event::*,
@@ -15,7 +25,11 @@ use winit::{
 };
 pub mod engine;
 pub mod game;
+#[cfg(test)]
+mod test_directory;
 pub mod world;
+/// The directory the world is saved in
+const WORLD_DIRECTORY: &str = "world";
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::registry()
@@ -28,37 +42,107 @@ async fn main() -> anyhow::Result<()> {
     run().await?;
     Ok(())
 }
-pub fn load_assets_start_internal_server() -> anyhow::Result<(VoxelRendererConfig, GameState)> {
+pub fn load_assets_start_internal_server() -> anyhow::Result<(
+    VoxelRendererConfig,
+    GameState,
+    flume::Sender<PlayerMovement>,
+    flume::Sender<PlayerAction>,
+)> {
     let (sender, receiver) = flume::bounded(100);
-    let texture_atlas = TextureAtlasBuilder::load_from_minecraft_assets()?;
-    let block_registery = game::blocks::create_block_registery(texture_atlas.info.clone());
+    // Only the latest position matters. Movements are dropped while the world is behind
+    let (movement_sender, movement_receiver) = flume::bounded(1);
+    let (action_sender, action_receiver) = flume::bounded(16);
+    // The stitched atlas is for adapters that can not fit every texture in a texture array
+    let texture_backend: TextureBackend =
+        env_setting("KAKARA_TEXTURE_BACKEND")?.unwrap_or_default();
+    let mut resource_packs = ResourcePackStack::from_env();
+    let texture_atlas =
+        TextureAtlasBuilder::load_from_resource_packs(&mut resource_packs, texture_backend)?;
+    let block_definitions =
+        game::blocks::load_block_definitions(game::blocks::BLOCK_DEFINITIONS_DIRECTORY)?;
+    let mut block_registery =
+        game::blocks::create_block_registery(texture_atlas.info.clone(), &block_definitions);
+    block_registery.load_models(&mut resource_packs);
     let game = game::Game {
         block_registery,
         game_settings: game::GameSettings {
             chunk_render_distance: 4,
+            chunk_unload_hysteresis: 2,
+            meshing_mode: env_setting("KAKARA_MESHING")?.unwrap_or_default(),
+            ambient_occlusion: env_setting::<Toggle>("KAKARA_AMBIENT_OCCLUSION")?
+                .is_none_or(|toggle| toggle.0),
+            texture_filtering: env_setting("KAKARA_TEXTURE_FILTERING")?.unwrap_or_default(),
+            autosave_interval: Duration::from_secs(30),
         },
         chunk_queue: sender,
     };
 
-    let world = world::World::new();
+    // Only used when the world is created
+    let seed = env_setting("KAKARA_SEED")?;
+    let world = world::World::open(WORLD_DIRECTORY, seed, &game.block_registery)?;
+    // The block the player places
+    let selected_block: String =
+        env_setting("KAKARA_SELECTED_BLOCK")?.unwrap_or_else(|| "stone_bricks".to_owned());
+    let selected_block = game
+        .block_registery
+        .default_state(&selected_block)
+        .with_context(|| {
+            format!("The selected block {selected_block:?} does not exist. Set KAKARA_SELECTED_BLOCK to a block that does")
+        })?;
     let player = game::Player {
         position: world::position::RawPosition::new(0.0, 0.0, 0.0),
         rotation: world::position::RawRotation::new(0.0, 0.0),
+        selected_block,
     };
+    let texture_filtering = game.game_settings.texture_filtering;
     let game_state = game::GameState {
         world,
         player,
         game: Arc::new(game),
+        player_movement: movement_receiver,
+        player_actions: action_receiver,
     };
 
     Ok((
         VoxelRendererConfig {
             texture_atlas,
+            texture_filtering,
             receiver,
         },
         game_state,
+        movement_sender,
+        action_sender,
     ))
 }
+/// Reads a setting from the environment. None if it is not set
+///
+/// A value that does not parse is an error instead of silently using the default
+fn env_setting<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    std::env::var(name)
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .with_context(|| format!("{name} {value:?} is not a valid value"))
+        })
+        .transpose()
+}
+/// An on or off setting. Accepts 1, 0, true and false
+struct Toggle(bool);
+impl FromStr for Toggle {
+    type Err = std::str::ParseBoolError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(Self(true)),
+            "0" => Ok(Self(false)),
+            _ => s.parse().map(Self),
+        }
+    }
+}
 pub async fn run() -> anyhow::Result<()> {
     let event_loop = EventLoop::new()?;
     let window = WindowBuilder::new()
@@ -70,14 +154,10 @@ pub async fn run() -> anyhow::Result<()> {
     window.set_cursor_visible(false);
     window.set_cursor_position(winit::dpi::PhysicalPosition::new(0, 0))?;
 
-    let (config, mut game_state) = load_assets_start_internal_server()?;
-    tokio::spawn(async move {
-        game_state.world.update_meshes(
-            &game_state.player,
-            game_state.game.clone(),
-            game_state.game.chunk_queue.clone(),
-        );
-    });
+    let (config, game_state, player_movement, player_actions) =
+        load_assets_start_internal_server()?;
+    let (shutdown_sender, shutdown_receiver) = flume::bounded(1);
+    let mut world_task = Some(tokio::spawn(game_state.run(shutdown_receiver)));
     let mut state = State::new(window, config).await?;
     let mut last_render_time = Instant::now();
     event_loop.run(move |event, window_loop| {
@@ -87,6 +167,18 @@ pub async fn run() -> anyhow::Result<()> {
                     state.window().request_redraw();
                 }
             }
+            Event::LoopExiting => {
+                let _ = shutdown_sender.send(());
+                if let Some(world_task) = world_task.take() {
+                    // Wait for the world to finish saving before the process exits
+                    let result = tokio::task::block_in_place(|| {
+                        tokio::runtime::Handle::current().block_on(world_task)
+                    });
+                    if let Err(err) = result {
+                        error!("World task failed: {}", err);
+                    }
+                }
+            }
         Event::WindowEvent {
             ref event,
             window_id,
@@ -106,6 +198,17 @@ pub async fn run() -> anyhow::Result<()> {
                 window_loop.exit();
             }
         }
+        WindowEvent::MouseInput { state: ElementState::Pressed, button, .. } => {
+            let camera = PlayerMovement::from(state.camera());
+            let action = match button {
+                MouseButton::Left => Some(PlayerAction::BreakBlock(camera)),
+                MouseButton::Right => Some(PlayerAction::PlaceBlock(camera)),
+                _ => None,
+            };
+            if let Some(action) = action {
+                let _ = player_actions.try_send(action);
+            }
+        }
         WindowEvent::Resized(physical_size) => {
             state.resize(*physical_size);
         }
@@ -114,6 +217,7 @@ pub async fn run() -> anyhow::Result<()> {
             let dt = now - last_render_time;
             last_render_time = now;
             state.update(dt);
+            let _ = player_movement.try_send(PlayerMovement::from(state.camera()));
             //println!("FPS: {}", 1.0 / dt.as_secs_f64());
             match state.render() {
                 Ok(_) => {}
@@ -0,0 +1,39 @@
+/*!
+# Test Directories
+
+Tests that read and write files get their own directory in the system temp directory. It is deleted when the test ends, even if an assert fails.
+*/
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// An empty directory that is deleted when dropped
+#[derive(Debug)]
+pub struct TestDirectory(PathBuf);
+impl TestDirectory {
+    /// The name must be unique across the tests as they run at the same time
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("kakara-{name}-{}", std::process::id()));
+        // Left over from a run that was killed
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("Failed to create the test directory");
+        Self(path)
+    }
+}
+impl Deref for TestDirectory {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+impl AsRef<Path> for TestDirectory {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+impl Drop for TestDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,199 @@
+/*!
+# Block Ids
+
+Chunks store block state ids. The ids depend on the order blocks are registered in, so a saved world keeps the blocks it was saved with in `block_ids.json`.
+
+```json
+{"blocks": [{"name": "kakara:air"}, {"name": "kakara:stone_slab", "properties": [{"name": "type", "type": "enum", "values": ["bottom", "top", "double"]}]}]}
+```
+
+When the world is opened with a registry that gives out different ids, every saved chunk is rewritten with the new ids and the file is replaced.
+The remap is done so it can be stopped at any point
+1. Every region is rewritten to a new file next to it. The saved regions are not touched
+2. The new id map is written to `block_ids.migrating.json`. From here on the remap is finished the next time the world is opened
+3. The new region files are moved over the saved ones
+4. `block_ids.migrating.json` is moved over `block_ids.json`
+
+Values are matched by the names of the properties. Properties that were added get their first value. Blocks that no longer exist become air.
+*/
+use std::{fs::File, io::Write, path::Path};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::game::blocks::{BlockName, BlockProperty, BlockRegistery, BlockStates};
+
+use super::WorldError;
+
+pub const BLOCK_IDS_FILE: &str = "block_ids.json";
+/// The id map of a remap that has rewritten every region but has not moved them over the saved ones yet
+pub const MIGRATION_FILE: &str = "block_ids.migrating.json";
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedBlock {
+    pub name: BlockName,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<BlockProperty>,
+}
+/// The blocks of a world in the order of their ids
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct BlockIdMap {
+    pub blocks: Vec<SavedBlock>,
+}
+impl BlockIdMap {
+    pub fn from_registry(registry: &BlockRegistery) -> Self {
+        let blocks = registry
+            .names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| SavedBlock {
+                name: name.clone(),
+                properties: registry.states.properties(id).to_vec(),
+            })
+            .collect();
+        Self { blocks }
+    }
+    /// Loads the id map from the world directory. Returns None if it does not exist
+    pub fn load(directory: impl AsRef<Path>) -> Result<Option<Self>, WorldError> {
+        let path = directory.as_ref().join(BLOCK_IDS_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(path)?;
+        Ok(Some(serde_json::from_reader(file)?))
+    }
+    pub fn save(&self, directory: impl AsRef<Path>) -> Result<(), WorldError> {
+        self.write(directory.as_ref(), BLOCK_IDS_FILE)
+    }
+    /// Marks a remap to this map as in progress. Every region must already be rewritten
+    pub fn save_migration(&self, directory: impl AsRef<Path>) -> Result<(), WorldError> {
+        self.write(directory.as_ref(), MIGRATION_FILE)
+    }
+    pub fn migration_in_progress(directory: impl AsRef<Path>) -> bool {
+        directory.as_ref().join(MIGRATION_FILE).exists()
+    }
+    /// Makes the map of the remap in progress the map of the world
+    pub fn finish_migration(directory: impl AsRef<Path>) -> Result<(), WorldError> {
+        let directory = directory.as_ref();
+        std::fs::rename(
+            directory.join(MIGRATION_FILE),
+            directory.join(BLOCK_IDS_FILE),
+        )?;
+        Ok(())
+    }
+    /// Writes to a temporary file first so a stopped write never leaves a partial map behind
+    fn write(&self, directory: &Path, file_name: &str) -> Result<(), WorldError> {
+        let path = directory.join(file_name);
+        let temporary = path.with_extension("json.tmp");
+        let mut file = File::create(&temporary)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.flush()?;
+        file.sync_all()?;
+        std::fs::rename(temporary, path)?;
+        Ok(())
+    }
+    /// The new state id of every saved state id. None if every id is still the same
+    pub fn remap(&self, registry: &BlockRegistery) -> Option<Vec<u32>> {
+        let mut saved_states = BlockStates::default();
+        for block in &self.blocks {
+            saved_states.push(block.properties.clone());
+        }
+        let mut remap = Vec::with_capacity(saved_states.len());
+        for (block, saved) in self.blocks.iter().enumerate() {
+            let Some(new_block) = registry.blocks.get(&saved.name).map(|block| block.id()) else {
+                warn!(
+                    "Block {} no longer exists. Replacing it with air",
+                    saved.name
+                );
+                remap.extend(saved_states.states_of(block).map(|_| 0));
+                continue;
+            };
+            for state in saved_states.states_of(block) {
+                let values = saved_states.values(state).unwrap_or_default();
+                let new_values: Vec<u32> = registry
+                    .states
+                    .properties(new_block)
+                    .iter()
+                    .map(|property| {
+                        saved
+                            .properties
+                            .iter()
+                            .zip(&values)
+                            .find(|(saved, _)| saved.name == property.name)
+                            .and_then(|(saved, value)| property.index_of(&saved.value(*value)?))
+                            .unwrap_or(0)
+                    })
+                    .collect();
+                let new_state = registry
+                    .states
+                    .state(new_block, &new_values)
+                    .or_else(|| registry.states.default_state(new_block))
+                    .unwrap_or(0);
+                remap.push(new_state);
+            }
+        }
+        let unchanged = remap
+            .iter()
+            .enumerate()
+            .all(|(state, new_state)| state as u32 == *new_state);
+        (!unchanged).then_some(remap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::blocks::{test_block_registery, test_block_registery_from};
+
+    const SLAB: &str = r#"
+    [[blocks]]
+    name = "stone_slab"
+    properties = [{ name = "type", type = "enum", values = ["bottom", "top", "double"] }]
+    "#;
+
+    #[test]
+    fn test_same_registry_keeps_ids() {
+        let registry = test_block_registery();
+        let map = BlockIdMap::from_registry(&registry);
+        assert_eq!(map.blocks[0].name, BlockName::air());
+        assert_eq!(map.remap(&registry), None);
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(serde_json::from_str::<BlockIdMap>(&json).unwrap(), map);
+    }
+
+    #[test]
+    fn test_states_follow_their_names() {
+        let old = test_block_registery_from(&format!(
+            "[[blocks]]\nname = \"dirt\"\n[[blocks]]\nname = \"sand\"\n{SLAB}"
+        ));
+        let new = test_block_registery_from(
+            r#"
+            [[blocks]]
+            name = "glass"
+            [[blocks]]
+            name = "stone_slab"
+            properties = [
+                { name = "waterlogged", type = "bool" },
+                { name = "type", type = "enum", values = ["top", "bottom", "double"] },
+            ]
+            [[blocks]]
+            name = "dirt"
+            "#,
+        );
+        let remap = BlockIdMap::from_registry(&old).remap(&new).unwrap();
+        assert_eq!(remap.len(), old.states.len());
+        let id = |registry: &BlockRegistery, name: &str| registry.default_state(name).unwrap();
+        assert_eq!(remap[0], 0);
+        assert_eq!(remap[id(&old, "dirt") as usize], id(&new, "dirt"));
+        // Sand was removed
+        assert_eq!(remap[id(&old, "sand") as usize], 0);
+        let old_slab = id(&old, "stone_slab");
+        for value in ["bottom", "top", "double"] {
+            let old_state = old.states.with_property(old_slab, "type", value).unwrap();
+            let new_state = remap[old_state as usize];
+            assert_eq!(
+                new.states.describe(new_state),
+                format!("waterlogged=false,type={value}")
+            );
+        }
+    }
+}
@@ -0,0 +1,479 @@
+/*!
+# Block Store
+
+The blocks of a [Section](super::chunk::Section) are stored as indices into a per section palette.
+
+The width of the indices grows with the palette
+- A single block type needs no indices at all. Most sections are entirely air or stone
+- Up to 16 block types use 4 bit indices
+- Up to 256 block types use 8 bit indices
+- Anything more uses 16 bit indices
+*/
+use std::{
+    fmt::Debug,
+    io::{self, Read, Write},
+    ops::Index,
+};
+
+use super::chunk::Block;
+
+pub const SECTION_VOLUME: usize = 16 * 16 * 16;
+
+/// Written before the block store to say how it is stored
+const EMPTY_STORE: u8 = 0;
+/// Every block id written as a little endian u32. Written by chunk format 1 and is only read now
+const FLAT_STORE: u8 = 1;
+const SINGLE_STORE: u8 = 2;
+const PALETTE_STORE: u8 = 3;
+
+#[derive(Debug, Clone)]
+enum PaletteIndices {
+    /// Every block is the first entry of the palette
+    Single,
+    /// Two indices per byte. The even index is in the low nibble
+    Half(Box<[u8; SECTION_VOLUME / 2]>),
+    Byte(Box<[u8; SECTION_VOLUME]>),
+    Short(Box<[u16; SECTION_VOLUME]>),
+}
+impl PaletteIndices {
+    /// The smallest indices that can address a palette of the given length
+    fn for_palette_length(length: usize) -> Self {
+        match length {
+            0..=1 => Self::Single,
+            2..=16 => Self::Half(Box::new([0; SECTION_VOLUME / 2])),
+            17..=256 => Self::Byte(Box::new([0; SECTION_VOLUME])),
+            _ => Self::Short(Box::new([0; SECTION_VOLUME])),
+        }
+    }
+    fn bits(&self) -> u8 {
+        match self {
+            Self::Single => 0,
+            Self::Half(_) => 4,
+            Self::Byte(_) => 8,
+            Self::Short(_) => 16,
+        }
+    }
+    /// The number of palette entries that can be addressed
+    fn capacity(&self) -> usize {
+        1 << self.bits()
+    }
+    #[inline]
+    fn get(&self, index: usize) -> usize {
+        match self {
+            Self::Single => 0,
+            Self::Half(indices) => ((indices[index >> 1] >> ((index & 1) * 4)) & 0xF) as usize,
+            Self::Byte(indices) => indices[index] as usize,
+            Self::Short(indices) => indices[index] as usize,
+        }
+    }
+    #[inline]
+    fn set(&mut self, index: usize, value: usize) {
+        debug_assert!(value < self.capacity(), "Palette index out of range");
+        match self {
+            Self::Single => {}
+            Self::Half(indices) => {
+                let shift = (index & 1) * 4;
+                let byte = &mut indices[index >> 1];
+                *byte = (*byte & !(0xF << shift)) | ((value as u8) << shift);
+            }
+            Self::Byte(indices) => indices[index] = value as u8,
+            Self::Short(indices) => indices[index] = value as u16,
+        }
+    }
+}
+/// Which palette entries match a filter. Palettes that fit in 8 bit indices are kept on the stack
+enum PaletteMask {
+    Small([u64; 4]),
+    Large(Vec<u64>),
+}
+impl PaletteMask {
+    fn new(palette: &[Block], filter: impl Fn(Block) -> bool) -> Self {
+        let mut mask = if palette.len() <= 256 {
+            Self::Small([0; 4])
+        } else {
+            Self::Large(vec![0; palette.len().div_ceil(64)])
+        };
+        let words = match &mut mask {
+            Self::Small(words) => words.as_mut_slice(),
+            Self::Large(words) => words.as_mut_slice(),
+        };
+        for (index, block) in palette.iter().enumerate() {
+            if filter(*block) {
+                words[index >> 6] |= 1 << (index & 63);
+            }
+        }
+        mask
+    }
+    fn words(&self) -> &[u64] {
+        match self {
+            Self::Small(words) => words,
+            Self::Large(words) => words,
+        }
+    }
+    #[inline]
+    fn contains(&self, index: usize) -> bool {
+        (self.words()[index >> 6] >> (index & 63)) & 1 == 1
+    }
+    fn is_empty(&self) -> bool {
+        self.words().iter().all(|word| *word == 0)
+    }
+}
+/// The blocks of a single section
+///
+/// Indexed the same way as [VoxelLocation](crate::engine::voxel::VoxelLocation). Use [BlockStore::set] to change a block
+#[derive(Clone)]
+pub struct BlockStore {
+    /// Block types that are or were in the section. Never empty
+    palette: Vec<Block>,
+    indices: PaletteIndices,
+}
+impl BlockStore {
+    /// A store where every block is the same
+    pub fn filled(block: Block) -> Self {
+        Self {
+            palette: vec![block],
+            indices: PaletteIndices::Single,
+        }
+    }
+    #[inline]
+    pub fn get(&self, index: usize) -> Block {
+        self.palette[self.indices.get(index)]
+    }
+    /// Sets the block at the index. Returning the block that was there
+    pub fn set(&mut self, index: usize, block: Block) -> Block {
+        let palette_index = match self.palette.iter().position(|b| *b == block) {
+            Some(palette_index) => palette_index,
+            None => {
+                if self.palette.len() == self.indices.capacity() {
+                    // Blocks that are no longer used might still be in the palette
+                    self.compact();
+                }
+                self.palette.push(block);
+                if self.palette.len() > self.indices.capacity() {
+                    self.resize_indices();
+                }
+                self.palette.len() - 1
+            }
+        };
+        let old = self.indices.get(index);
+        self.indices.set(index, palette_index);
+        self.palette[old]
+    }
+    pub fn iter(&self) -> impl Iterator<Item = Block> + '_ {
+        (0..SECTION_VOLUME).map(|index| self.get(index))
+    }
+    /// If every block in the store is the same block it is returned
+    pub fn single_block(&self) -> Option<Block> {
+        match self.indices {
+            PaletteIndices::Single => Some(self.palette[0]),
+            _ => None,
+        }
+    }
+    /// The number of bits used for each block. 0 means every block is the same
+    pub fn bits_per_block(&self) -> u8 {
+        self.indices.bits()
+    }
+    pub fn palette(&self) -> &[Block] {
+        &self.palette
+    }
+    /// Counts the blocks matching the filter. Only checking each palette entry once
+    pub fn count_matching(&self, filter: impl Fn(Block) -> bool) -> usize {
+        if let PaletteIndices::Single = self.indices {
+            return if filter(self.palette[0]) {
+                SECTION_VOLUME
+            } else {
+                0
+            };
+        }
+        let matching = PaletteMask::new(&self.palette, filter);
+        if matching.is_empty() {
+            return 0;
+        }
+        (0..SECTION_VOLUME)
+            .filter(|index| matching.contains(self.indices.get(*index)))
+            .count()
+    }
+    /// Checks if any block matches the filter. Only checking each palette entry once
+    pub fn any_matching(&self, filter: impl Fn(Block) -> bool) -> bool {
+        if let PaletteIndices::Single = self.indices {
+            return filter(self.palette[0]);
+        }
+        let matching = PaletteMask::new(&self.palette, filter);
+        if matching.is_empty() {
+            return false;
+        }
+        (0..SECTION_VOLUME).any(|index| matching.contains(self.indices.get(index)))
+    }
+    /// Removes palette entries that are no longer used and shrinks the indices if possible
+    pub fn compact(&mut self) {
+        if let PaletteIndices::Single = self.indices {
+            return;
+        }
+        let mut remap = vec![usize::MAX; self.palette.len()];
+        let mut palette = Vec::with_capacity(self.palette.len());
+        for index in 0..SECTION_VOLUME {
+            let old = self.indices.get(index);
+            if remap[old] == usize::MAX {
+                remap[old] = palette.len();
+                palette.push(self.palette[old]);
+            }
+        }
+        let mut indices = PaletteIndices::for_palette_length(palette.len());
+        for index in 0..SECTION_VOLUME {
+            indices.set(index, remap[self.indices.get(index)]);
+        }
+        self.palette = palette;
+        self.indices = indices;
+    }
+    /// Changes every block of the store. Blocks that end up the same share a palette entry
+    pub fn remap(&mut self, mut map: impl FnMut(Block) -> Block) {
+        let mut palette: Vec<Block> = Vec::with_capacity(self.palette.len());
+        let remap: Vec<usize> = self
+            .palette
+            .iter()
+            .map(|block| {
+                let block = map(*block);
+                palette.iter().position(|b| *b == block).unwrap_or_else(|| {
+                    palette.push(block);
+                    palette.len() - 1
+                })
+            })
+            .collect();
+        let merged = palette.len() != self.palette.len();
+        self.palette = palette;
+        if merged {
+            let mut indices = PaletteIndices::for_palette_length(self.palette.len());
+            for index in 0..SECTION_VOLUME {
+                indices.set(index, remap[self.indices.get(index)]);
+            }
+            self.indices = indices;
+        }
+    }
+    /// Moves to the smallest indices that fit the palette
+    fn resize_indices(&mut self) {
+        let mut indices = PaletteIndices::for_palette_length(self.palette.len());
+        for index in 0..SECTION_VOLUME {
+            indices.set(index, self.indices.get(index));
+        }
+        self.indices = indices;
+    }
+
+    /// Writes the store with a byte saying how it is stored followed by the data
+    ///
+    /// Palettes are written as the bits per block, the number of entries as a u16, each block id as a u32 then the raw indices.
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut store = self.clone();
+        store.compact();
+        if let Some(block) = store.single_block() {
+            if block == Block::Block(0) {
+                return writer.write_all(&[EMPTY_STORE]);
+            }
+            writer.write_all(&[SINGLE_STORE])?;
+            return write_block(writer, block);
+        }
+        writer.write_all(&[PALETTE_STORE, store.indices.bits()])?;
+        writer.write_all(&(store.palette.len() as u16).to_le_bytes())?;
+        for block in &store.palette {
+            write_block(writer, *block)?;
+        }
+        match &store.indices {
+            PaletteIndices::Single => unreachable!("Single stores are written above"),
+            PaletteIndices::Half(indices) => writer.write_all(indices.as_slice()),
+            PaletteIndices::Byte(indices) => writer.write_all(indices.as_slice()),
+            PaletteIndices::Short(indices) => {
+                for index in indices.iter() {
+                    writer.write_all(&index.to_le_bytes())?;
+                }
+                Ok(())
+            }
+        }
+    }
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        match read_u8(reader)? {
+            EMPTY_STORE => Ok(Self::default()),
+            SINGLE_STORE => Ok(Self::filled(read_block(reader)?)),
+            FLAT_STORE => {
+                let mut store = Self::default();
+                for index in 0..SECTION_VOLUME {
+                    store.set(index, read_block(reader)?);
+                }
+                Ok(store)
+            }
+            PALETTE_STORE => {
+                let bits = read_u8(reader)?;
+                let mut length = [0u8; 2];
+                reader.read_exact(&mut length)?;
+                let length = u16::from_le_bytes(length) as usize;
+                let mut palette = Vec::with_capacity(length);
+                for _ in 0..length {
+                    palette.push(read_block(reader)?);
+                }
+                let mut indices = PaletteIndices::for_palette_length(length);
+                if indices.bits() != bits {
+                    return Err(invalid_data(format!(
+                        "{bits} bits per block does not match a palette of {length}"
+                    )));
+                }
+                match &mut indices {
+                    PaletteIndices::Single => {}
+                    PaletteIndices::Half(indices) => reader.read_exact(indices.as_mut_slice())?,
+                    PaletteIndices::Byte(indices) => reader.read_exact(indices.as_mut_slice())?,
+                    PaletteIndices::Short(indices) => {
+                        let mut index = [0u8; 2];
+                        for value in indices.iter_mut() {
+                            reader.read_exact(&mut index)?;
+                            *value = u16::from_le_bytes(index);
+                        }
+                    }
+                }
+                let store = Self { palette, indices };
+                if (0..SECTION_VOLUME).any(|index| store.indices.get(index) >= length) {
+                    return Err(invalid_data("Palette index out of range".to_owned()));
+                }
+                Ok(store)
+            }
+            kind => Err(invalid_data(format!("Unknown block store kind {kind}"))),
+        }
+    }
+}
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+fn write_block(writer: &mut impl Write, block: Block) -> io::Result<()> {
+    let Block::Block(id) = block;
+    writer.write_all(&id.to_le_bytes())
+}
+fn read_block(reader: &mut impl Read) -> io::Result<Block> {
+    let mut id = [0u8; 4];
+    reader.read_exact(&mut id)?;
+    Ok(Block::Block(u32::from_le_bytes(id)))
+}
+impl Index<usize> for BlockStore {
+    type Output = Block;
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.palette[self.indices.get(index)]
+    }
+}
+impl Default for BlockStore {
+    fn default() -> Self {
+        Self::filled(Block::Block(0))
+    }
+}
+/// Two stores are equal if they contain the same blocks. No matter how they are stored
+impl PartialEq for BlockStore {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+impl Eq for BlockStore {}
+impl Debug for BlockStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockStore")
+            .field("palette", &self.palette)
+            .field("bits_per_block", &self.bits_per_block())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_palette_grows() {
+        let mut store = BlockStore::default();
+        assert_eq!(store.bits_per_block(), 0);
+        assert_eq!(store.set(10, Block::Block(5)), Block::Block(0));
+        assert_eq!(store.bits_per_block(), 4);
+        assert_eq!(store[10], Block::Block(5));
+        assert_eq!(store[11], Block::Block(0));
+
+        for id in 0..300u32 {
+            store.set(id as usize, Block::Block(id + 1));
+        }
+        assert_eq!(store.bits_per_block(), 16);
+        for id in 0..300u32 {
+            assert_eq!(store.get(id as usize), Block::Block(id + 1));
+        }
+        assert_eq!(store.get(4095), Block::Block(0));
+        assert_eq!(store.count_matching(|Block::Block(id)| id > 250), 50);
+        assert!(store.any_matching(|b| b == Block::Block(300)));
+        assert!(!store.any_matching(|b| b == Block::Block(1000)));
+        assert!(BlockStore::default().any_matching(|b| b == Block::Block(0)));
+    }
+
+    #[test]
+    fn test_compact_shrinks() {
+        let mut store = BlockStore::default();
+        for index in 0..SECTION_VOLUME {
+            store.set(index, Block::Block((index % 20) as u32));
+        }
+        assert_eq!(store.bits_per_block(), 8);
+        for index in 0..SECTION_VOLUME {
+            store.set(index, Block::Block(3));
+        }
+        store.compact();
+        assert_eq!(store.single_block(), Some(Block::Block(3)));
+        assert_eq!(
+            store.count_matching(|b| b == Block::Block(3)),
+            SECTION_VOLUME
+        );
+    }
+
+    #[test]
+    fn test_unused_entries_are_reclaimed() {
+        let mut store = BlockStore::default();
+        store.set(1, Block::Block(1));
+        // Without reclaiming unused entries this would overflow a 16 bit palette
+        for id in 2..100_000u32 {
+            store.set(0, Block::Block(id));
+        }
+        assert_eq!(store.get(0), Block::Block(99_999));
+        assert_eq!(store.get(1), Block::Block(1));
+        assert!(store.palette().len() <= 16);
+    }
+
+    #[test]
+    fn test_remap_merges_blocks() {
+        let mut store = BlockStore::default();
+        for index in 0..SECTION_VOLUME {
+            store.set(index, Block::Block((index % 3) as u32));
+        }
+        store.remap(|Block::Block(id)| Block::Block(id + 10));
+        assert_eq!(store.get(4), Block::Block(11));
+        // 11 and 12 both become 20
+        store.remap(|Block::Block(id)| Block::Block(if id == 10 { 10 } else { 20 }));
+        assert_eq!(store.palette(), &[Block::Block(10), Block::Block(20)]);
+        assert_eq!(store.get(0), Block::Block(10));
+        assert_eq!(store.get(4), Block::Block(20));
+        assert_eq!(store.get(5), Block::Block(20));
+        assert_eq!(store.bits_per_block(), 4);
+    }
+
+    #[test]
+    fn test_read_write() -> io::Result<()> {
+        let mut stores = vec![BlockStore::default(), BlockStore::filled(Block::Block(7))];
+        for bits in [4, 8, 16] {
+            let mut store = BlockStore::default();
+            let types = (1usize << bits) / 2 + 1;
+            for index in 0..SECTION_VOLUME {
+                store.set(index, Block::Block((index % types) as u32));
+            }
+            assert_eq!(store.bits_per_block(), bits.min(16) as u8);
+            stores.push(store);
+        }
+        for store in stores {
+            let mut data = Vec::new();
+            store.write_to(&mut data)?;
+            let read = BlockStore::read_from(&mut data.as_slice())?;
+            assert_eq!(read, store);
+            assert_eq!(read.bits_per_block(), store.bits_per_block());
+        }
+        Ok(())
+    }
+}
@@ -1,28 +1,60 @@
 use core::num;
 use std::{
     fmt::Debug,
-    ops::{Index, IndexMut},
+    io::{self, Read, Write},
     sync::Arc,
 };
 
-use glam::Vec3;
+use ahash::HashMap;
+use glam::{I64Vec3, Vec3};
 use noise::{NoiseFn, Perlin};
 use rand::Rng;
 
 use crate::{
     engine::voxel::{
-        chunk_mesh::{Voxel, VoxelVisiblity},
-        VoxelLocation,
+        chunk_mesh::{
+            border_coords, border_index, BorderLayer, BorderVoxel, RawChunkMesh, SectionNeighbours,
+            Voxel, VoxelLight, VoxelVisiblity,
+        },
+        Face, VoxelLocation,
     },
-    game::Game,
+    game::{blocks::BlockRegistery, Game},
 };
 
-use super::position::{BlockPosition, ChunkPosition};
+pub use super::block_store::BlockStore;
+use super::{
+    light::SectionLight,
+    position::{BlockPosition, ChunkPosition},
+    seed::WorldSeed,
+};
+/// Every section of a chunk. One bit per section
+pub const ALL_SECTIONS: u16 = u16::MAX;
+/// The section and the sections above and below it. One bit per section
+fn section_and_edges(section: usize) -> u16 {
+    ((0b111u32 << section) >> 1) as u16
+}
+/// The blocks [Chunk::generate] fills columns with
+pub const GENERATED_BLOCKS: [&str; 6] = [
+    "stone_bricks",
+    "dirt",
+    "cracked_stone_bricks",
+    "diamond_ore",
+    "iron_ore",
+    "glowstone",
+];
 // 16x16x256
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     pub position: ChunkPosition,
     pub sections: Vec<Section>,
+    /// The chunk has changed since it was last written to disk
+    pub needs_saving: bool,
+    /// The light of the chunk has been calculated. Blocks set before this do not need light updates
+    pub lit: bool,
+    /// Blocks that changed since the light was last updated. In world space
+    pub light_updates: Vec<BlockPosition>,
+    /// Changes every time a section is marked dirty. Meshes built by a worker from an older revision are out of date
+    pub revision: u32,
 }
 impl Chunk {
     pub fn new(position: ChunkPosition) -> Self {
@@ -32,41 +64,271 @@ impl Chunk {
             section.position = i as u8;
             sections.push(section);
         }
-        Self { position, sections }
+        Self {
+            position,
+            sections,
+            needs_saving: false,
+            lit: false,
+            light_updates: Vec::new(),
+            revision: 0,
+        }
     }
     pub fn generate_test_chunk() -> Self {
         let mut chunk = Self::new(ChunkPosition::new(0, 0));
         for i in 0..16 {
-            chunk.sections[i].block_store[0] = Block::Block(1);
+            chunk.sections[i].block_store.set(0, Block::Block(1));
         }
         chunk
     }
+    /// Sets the block state at the position
     pub fn set_block(&mut self, position: BlockPosition, block: u32) {
-        let section = position.section();
-
-        let section = &mut self.sections[section];
-        section.dirty = true;
-        let index = position.relative_block() as usize;
-        section.block_store[index] = block.into();
+        self.needs_saving = true;
+        self.set_generated_block(position, block);
+    }
+    /// Sets the block state at the position without marking the chunk as needing to be saved
+    ///
+    /// Used for terrain that is generated again from the seed
+    pub fn set_generated_block(&mut self, position: BlockPosition, block: u32) {
+        let index = position.relative_block();
+        self.sections[position.section()]
+            .block_store
+            .set(index, block.into());
+        self.mark_block_dirty(position);
+        if self.lit {
+            self.light_updates.push(BlockPosition::new(
+                self.position.x * 16 + (position.x & 0xF),
+                position.y,
+                self.position.z * 16 + (position.z & 0xF),
+            ));
+        }
+    }
+    /// Marks the section of the block as dirty
+    ///
+    /// The sections above and below cull their faces against the block so they are marked if the block is on their border
+    pub fn mark_block_dirty(&mut self, position: BlockPosition) {
+        let section_index = position.section();
+        self.mark_section_dirty(section_index);
+        match position.y & 0xF {
+            0 if section_index > 0 => self.mark_section_dirty(section_index - 1),
+            15 if section_index + 1 < self.sections.len() => {
+                self.mark_section_dirty(section_index + 1)
+            }
+            _ => {}
+        }
+    }
+    /// Marks a section as needing its mesh rebuilt
+    pub fn mark_section_dirty(&mut self, section: usize) {
+        self.sections[section].dirty = true;
+        self.revision = self.revision.wrapping_add(1);
+    }
+    /// The layers touching a section. Sections above and below come from this chunk. The sides come from `neighbours`
+    pub fn section_neighbours(
+        &self,
+        section: usize,
+        neighbours: &ChunkNeighbours,
+        registry: &BlockRegistery,
+    ) -> SectionNeighbours {
+        let mut section_neighbours = SectionNeighbours::default();
+        if let Some(above) = self.sections.get(section + 1) {
+            section_neighbours.set(Face::Top, above.border_layer(Face::Bottom, registry));
+        }
+        if let Some(below) = section.checked_sub(1).map(|below| &self.sections[below]) {
+            section_neighbours.set(Face::Bottom, below.border_layer(Face::Top, registry));
+        }
+        for face in ChunkNeighbours::SIDES {
+            if let Some(layer) = neighbours.layer(face, section) {
+                section_neighbours.set(face, Box::new(*layer));
+            }
+        }
+        // Past the top and bottom edges. These are in the sections above and below in the chunks next to this one
+        for face in ChunkNeighbours::SIDES {
+            for (vertical, y) in [(section.checked_sub(1), -1), (Some(section + 1), 16)] {
+                let Some(layer) = vertical.and_then(|vertical| neighbours.layer(face, vertical))
+                else {
+                    continue;
+                };
+                for along in 0..16 {
+                    let mut coords = I64Vec3::new(along, y, along);
+                    coords[face.axis()] = if face.is_positive() { 16 } else { -1 };
+                    if layer[border_index(face, coords)].visibility.is_opaque() {
+                        section_neighbours.set_outer_opaque(coords);
+                    }
+                }
+            }
+        }
+        // Past the vertical edges and the corners. These are in the chunks diagonal to this one
+        let bottom = section as i64 * 16;
+        for (x_face, z_face) in ChunkNeighbours::CORNERS {
+            let x = if x_face.is_positive() { 16 } else { -1 };
+            let z = if z_face.is_positive() { 16 } else { -1 };
+            for y in -1..=16 {
+                if neighbours.is_corner_opaque(x_face, z_face, bottom + y) {
+                    section_neighbours.set_outer_opaque(I64Vec3::new(x, y, z));
+                }
+            }
+        }
+        section_neighbours
     }
 
-    pub(crate) fn generate(position: ChunkPosition, noise: &Perlin) -> Chunk {
+    /// The position of the bottom corner of a section
+    pub fn section_position(&self, section: usize) -> BlockPosition {
+        BlockPosition::new(
+            self.position.x * 16,
+            section as i64 * 16,
+            self.position.z * 16,
+        )
+    }
+    /// Builds the meshes of every section that has blocks. Marking every section as clean
+    pub fn build_meshes(
+        &mut self,
+        game: Arc<Game>,
+        neighbours: &ChunkNeighbours,
+    ) -> Vec<RawChunkMesh> {
+        self.build_section_meshes(game, neighbours, ALL_SECTIONS)
+    }
+    /// Builds the meshes of the given sections that have blocks. One bit per section
+    pub fn build_section_meshes(
+        &mut self,
+        game: Arc<Game>,
+        neighbours: &ChunkNeighbours,
+        sections: u16,
+    ) -> Vec<RawChunkMesh> {
+        let mut meshes = Vec::new();
+        for index in (0..self.sections.len()).filter(|index| sections & (1 << index) != 0) {
+            let position = self.section_position(index);
+            let section = &mut self.sections[index];
+            section.dirty = false;
+            if !section.contains_non_air_blocks() {
+                continue;
+            }
+            let section_neighbours =
+                self.section_neighbours(index, neighbours, &game.block_registery);
+            let section = &self.sections[index];
+            meshes.push(RawChunkMesh::build(
+                position.into(),
+                section.get_voxels(game.clone()),
+                &section_neighbours,
+                game.game_settings.mesh_options(),
+            ));
+        }
+        meshes
+    }
+    /// The sections that need their meshes rebuilt. One bit per section
+    pub fn dirty_sections(&self) -> u16 {
+        self.sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| section.dirty)
+            .fold(0, |sections, (index, _)| sections | 1 << index)
+    }
+    /// The sections of the chunk on the `face` side of this one that are meshed differently now that this chunk is loaded. One bit per section
+    ///
+    /// Chunks that are not loaded are open sky. So only the sections next to a border that is not open sky change.
+    /// The sections above and below are included as the border shades their edges
+    pub fn sections_facing(&self, face: Face, registry: &BlockRegistery) -> u16 {
+        let open_sky = BorderVoxel {
+            visibility: VoxelVisiblity::Empty,
+            light: VoxelLight::FULL_SKY,
+        };
+        self.sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| {
+                section
+                    .border_layer(face, registry)
+                    .iter()
+                    .any(|voxel| *voxel != open_sky)
+            })
+            .fold(0, |sections, (index, _)| {
+                sections | section_and_edges(index)
+            })
+    }
+    /// The sections of the chunk diagonal to this one whose corners are shaded by this chunk. One bit per section
+    pub fn sections_shaded_diagonally(
+        &self,
+        x_face: Face,
+        z_face: Face,
+        registry: &BlockRegistery,
+    ) -> u16 {
+        // The column of this chunk that touches the diagonal chunk
+        let x = if x_face.is_positive() { 15 } else { 0 };
+        let z = if z_face.is_positive() { 15 } else { 0 };
+        self.sections
+            .iter()
+            .enumerate()
+            .filter(|(_, section)| {
+                (0..16).any(|y| {
+                    section.block_store[I64Vec3::new(x, y, z).to_index()]
+                        .visibility(registry)
+                        .is_opaque()
+                })
+            })
+            .fold(0, |sections, (index, _)| {
+                sections | section_and_edges(index)
+            })
+    }
+    /// Changes the id of every block in the chunk. Used when the block ids of a saved world no longer match the registry
+    pub fn remap_blocks(&mut self, mut map: impl FnMut(u32) -> u32) {
+        for section in &mut self.sections {
+            section
+                .block_store
+                .remap(|Block::Block(id)| Block::Block(map(id)));
+        }
+    }
+    /// Generates the chunk. The same seed, noise and position will always generate the same chunk
+    ///
+    /// Each column is filled with one of [GENERATED_BLOCKS]. Blocks missing from the registry generate as air
+    pub(crate) fn generate(
+        position: ChunkPosition,
+        seed: WorldSeed,
+        noise: &Perlin,
+        registry: &BlockRegistery,
+    ) -> Chunk {
         let mut chunk = Self::new(position);
-        let mut rng = rand::thread_rng();
+        let mut rng = seed.chunk_rng(position);
+        let blocks = GENERATED_BLOCKS.map(|name| registry.default_state(name).unwrap_or(0));
 
         for x in 0..16 {
             for z in 0..16 {
-                let number = rng.gen_range(0..6u32);
-                let height = (noise.get([x as f64 / 16.0, z as f64 / 16.0]) * 16.0) as i64 + 32;
+                let block = blocks[rng.gen_range(0..blocks.len())];
+                // Sample in world space so terrain continues across chunk borders
+                let world_x = (position.x * 16 + x) as f64;
+                let world_z = (position.z * 16 + z) as f64;
+                let height = (noise.get([world_x / 16.0, world_z / 16.0]) * 16.0) as i64 + 32;
                 for y in 0..height {
                     let position = BlockPosition::new(x, y, z);
 
-                    chunk.set_block(position, number.into());
+                    chunk.set_generated_block(position, block);
                 }
             }
         }
         chunk
     }
+    /// Writes the sections of the chunk. The position is not written as it is stored by the region file
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&[self.sections.len() as u8])?;
+        for section in &self.sections {
+            section.write_to(writer)?;
+        }
+        Ok(())
+    }
+    /// Reads a chunk written by [Chunk::write_to]
+    pub fn read_from(position: ChunkPosition, reader: &mut impl Read) -> io::Result<Self> {
+        let mut number_of_sections = [0u8; 1];
+        reader.read_exact(&mut number_of_sections)?;
+        let mut chunk = Self::new(position);
+        for _ in 0..number_of_sections[0] {
+            let section = Section::read_from(reader)?;
+            let Some(slot) = chunk.sections.get_mut(section.position as usize) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid section position {}", section.position),
+                ));
+            };
+            *slot = section;
+        }
+        Ok(chunk)
+    }
 }
 // 16x16x16
 #[derive(Clone, PartialEq, Eq)]
@@ -78,41 +340,29 @@ pub struct Section {
     pub dirty: bool,
     pub building: bool,
     pub block_store: BlockStore,
+    /// Not saved. Calculated when the chunk is loaded
+    pub light: SectionLight,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Block {
-    /// Points to a regular block in its natural state. No modifcations
+    /// A block state. The block and the values of its properties are looked up in [BlockStates](crate::game::blocks::BlockStates)
     Block(u32),
     //Ref(u32)
 }
+impl Block {
+    /// State zero is air. Every other state is looked up in the registry
+    pub fn visibility(&self, registry: &BlockRegistery) -> VoxelVisiblity {
+        match self {
+            Block::Block(id) => registry.visibility(*id),
+        }
+    }
+}
 impl From<u32> for Block {
     fn from(i: u32) -> Self {
         Self::Block(i)
     }
 }
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct BlockStore {
-    pub blocks: Box<[Block; 16 * 16 * 16]>,
-}
-impl Index<usize> for BlockStore {
-    type Output = Block;
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.blocks[index]
-    }
-}
-impl IndexMut<usize> for BlockStore {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.blocks[index]
-    }
-}
-impl Default for BlockStore {
-    fn default() -> Self {
-        Self {
-            blocks: Box::new([Block::Block(0); 16 * 16 * 16]),
-        }
-    }
-}
 impl Debug for Section {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Section")
@@ -130,42 +380,154 @@ impl Default for Section {
             dirty: false,
             building: false,
             block_store: BlockStore::default(),
+            light: SectionLight::default(),
         }
     }
 }
 impl Section {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&[self.position])?;
+        self.block_store.write_to(writer)
+    }
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut position = [0u8; 1];
+        reader.read_exact(&mut position)?;
+        Ok(Self {
+            position: position[0],
+            block_store: BlockStore::read_from(reader)?,
+            ..Default::default()
+        })
+    }
     /// Block Zero is always the air block. No matter how the game loads it is a forced air block
     pub fn number_of_non_air_blocks(&self) -> usize {
         self.block_store
-            .blocks
-            .iter()
-            .filter(|b| !matches!(b, Block::Block(0)))
-            .count()
+            .count_matching(|b| !matches!(b, Block::Block(0)))
     }
     pub fn contains_non_air_blocks(&self) -> bool {
         self.block_store
-            .blocks
-            .iter()
-            .any(|b| !matches!(b, Block::Block(0)))
+            .any_matching(|b| !matches!(b, Block::Block(0)))
+    }
+    /// The visibility and light of the 16x16 layer of blocks on the `face` side of the section
+    pub fn border_layer(&self, face: Face, registry: &BlockRegistery) -> Box<BorderLayer> {
+        let mut layer = Box::new([BorderVoxel::default(); 256]);
+        for (index, voxel) in layer.iter_mut().enumerate() {
+            let block_index = border_coords(face, index).to_index();
+            *voxel = BorderVoxel {
+                visibility: self.block_store[block_index].visibility(registry),
+                light: self.light.voxel_light(block_index),
+            };
+        }
+        layer
     }
     pub fn get_voxels(&self, game: Arc<Game>) -> Vec<Voxel> {
         let mut voxels = Vec::with_capacity(16 * 16 * 16);
-        for (index, block) in self.block_store.blocks.iter().enumerate() {
+        for (index, block) in self.block_store.iter().enumerate() {
             #[allow(irrefutable_let_patterns)] // This is just future proofing code
-            if let Block::Block(id) = *block {
+            if let Block::Block(id) = block {
                 let position: Vec3 = Vec3::from_index(index);
-                if id == 0 {
-                    voxels.push(Voxel::air(position));
-                    continue;
+                let voxel = if id == 0 {
+                    Voxel::air(position)
                 } else {
-                    voxels.push(Voxel::new(
+                    Voxel::new(
                         position,
-                        VoxelVisiblity::Opaque,
-                        game.block_registery.get_texture_for_id(id as usize),
-                    ));
-                }
+                        block.visibility(&game.block_registery),
+                        game.block_registery.get_texture_for_state(id),
+                    )
+                    .with_model(game.block_registery.get_model_for_state(id))
+                };
+                voxels.push(voxel.with_light(self.light.voxel_light(index)));
             }
         }
         voxels
     }
 }
+/// The border layers of the loaded chunks next to a chunk
+///
+/// Taken before a chunk is meshed so the meshing does not need access to the world
+#[derive(Debug, Clone, Default)]
+pub struct ChunkNeighbours {
+    /// Indexed by the face of the chunk the neighbour is on. One layer for each section
+    sides: [Option<Vec<BorderLayer>>; 6],
+    /// The opaque blocks of the column touching the chunk in each of the diagonal chunks. Indexed like [ChunkNeighbours::CORNERS]. Used for ambient occlusion
+    corners: [Option<Vec<bool>>; 4],
+}
+impl ChunkNeighbours {
+    /// The faces that can have a neighbouring chunk
+    pub const SIDES: [Face; 4] = [Face::North, Face::South, Face::East, Face::West];
+    /// The diagonal chunks. The chunk on the x side then the z side
+    pub const CORNERS: [(Face, Face); 4] = [
+        (Face::East, Face::North),
+        (Face::East, Face::South),
+        (Face::West, Face::North),
+        (Face::West, Face::South),
+    ];
+    pub fn collect(
+        chunks: &HashMap<ChunkPosition, Chunk>,
+        position: ChunkPosition,
+        registry: &BlockRegistery,
+    ) -> Self {
+        let mut neighbours = Self::default();
+        for face in Self::SIDES {
+            let Some(chunk) = chunks.get(&position.neighbour(face)) else {
+                continue;
+            };
+            let layers = chunk
+                .sections
+                .iter()
+                .map(|section| *section.border_layer(face.opposite(), registry))
+                .collect();
+            neighbours.sides[usize::from(face)] = Some(layers);
+        }
+        for (corner, (x_face, z_face)) in Self::CORNERS.into_iter().enumerate() {
+            let Some(chunk) = chunks.get(&position.neighbour(x_face).neighbour(z_face)) else {
+                continue;
+            };
+            // The column of the diagonal chunk that touches this one
+            let x = if x_face.is_positive() { 0 } else { 15 };
+            let z = if z_face.is_positive() { 0 } else { 15 };
+            let column = chunk
+                .sections
+                .iter()
+                .flat_map(|section| {
+                    (0..16).map(move |y| {
+                        section.block_store[I64Vec3::new(x, y, z).to_index()]
+                            .visibility(registry)
+                            .is_opaque()
+                    })
+                })
+                .collect();
+            neighbours.corners[corner] = Some(column);
+        }
+        neighbours
+    }
+    /// Was the diagonal chunk loaded when the neighbours were collected
+    pub fn is_corner_loaded(&self, x_face: Face, z_face: Face) -> bool {
+        Self::corner_index(x_face, z_face).is_some_and(|corner| self.corners[corner].is_some())
+    }
+    /// Is the block at `y` in the column of the diagonal chunk touching this chunk opaque. Missing chunks are empty
+    pub fn is_corner_opaque(&self, x_face: Face, z_face: Face, y: i64) -> bool {
+        let Some(column) =
+            Self::corner_index(x_face, z_face).and_then(|corner| self.corners[corner].as_ref())
+        else {
+            return false;
+        };
+        usize::try_from(y)
+            .ok()
+            .and_then(|y| column.get(y))
+            .is_some_and(|opaque| *opaque)
+    }
+    fn corner_index(x_face: Face, z_face: Face) -> Option<usize> {
+        Self::CORNERS
+            .iter()
+            .position(|corner| *corner == (x_face, z_face))
+    }
+    /// Was the chunk on the `face` side loaded when the neighbours were collected
+    pub fn is_loaded(&self, face: Face) -> bool {
+        self.sides[usize::from(face)].is_some()
+    }
+    pub fn layer(&self, face: Face, section: usize) -> Option<&BorderLayer> {
+        self.sides[usize::from(face)]
+            .as_ref()
+            .and_then(|layers| layers.get(section))
+    }
+}
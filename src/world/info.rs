@@ -0,0 +1,28 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{seed::WorldSeed, WorldError};
+
+pub const WORLD_INFO_FILE: &str = "world.json";
+/// Information about a world that is saved next to its region files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorldInfo {
+    pub seed: WorldSeed,
+}
+impl WorldInfo {
+    /// Loads the world info from the world directory. Returns None if it does not exist
+    pub fn load(directory: impl AsRef<Path>) -> Result<Option<Self>, WorldError> {
+        let path = directory.as_ref().join(WORLD_INFO_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = std::fs::File::open(path)?;
+        Ok(Some(serde_json::from_reader(file)?))
+    }
+    pub fn save(&self, directory: impl AsRef<Path>) -> Result<(), WorldError> {
+        let file = std::fs::File::create(directory.as_ref().join(WORLD_INFO_FILE))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+/*!
+# Lighting
+
+Every block stores a sky light and a block light level from 0 to 15.
+
+Sky light starts at 15 above the highest opaque block of every column and travels straight down without losing any light.
+Block light starts at blocks that give off light. Both lose one level for every block they travel sideways and are stopped by opaque blocks.
+
+Light is spread with a breadth first flood fill. Removing light floods outwards clearing every block that was lit by the removed light, the blocks on the edge of the cleared area then spread their light back in.
+*/
+use std::collections::VecDeque;
+
+use ahash::HashMap;
+use strum::IntoEnumIterator;
+
+use crate::{
+    engine::voxel::{chunk_mesh::VoxelLight, Face},
+    game::blocks::BlockRegistery,
+};
+
+use super::{
+    chunk::{Block, Chunk, ChunkNeighbours},
+    mark_sections_around,
+    position::{BlockPosition, ChunkPosition},
+};
+pub const MAX_LIGHT: u8 = 15;
+/// The height of the world in blocks
+const WORLD_HEIGHT: i64 = 256;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightKind {
+    Sky,
+    Block,
+}
+impl LightKind {
+    pub const ALL: [LightKind; 2] = [LightKind::Sky, LightKind::Block];
+}
+/// 4096 values from 0-15. Two values are packed into every byte. The even index is in the low nibble
+#[derive(Clone, PartialEq, Eq)]
+pub struct NibbleArray(Box<[u8; 2048]>);
+impl Default for NibbleArray {
+    fn default() -> Self {
+        Self(Box::new([0; 2048]))
+    }
+}
+impl std::fmt::Debug for NibbleArray {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("NibbleArray").finish_non_exhaustive()
+    }
+}
+impl NibbleArray {
+    pub fn get(&self, index: usize) -> u8 {
+        let byte = self.0[index >> 1];
+        if index & 1 == 0 {
+            byte & 0xF
+        } else {
+            byte >> 4
+        }
+    }
+    pub fn set(&mut self, index: usize, value: u8) {
+        let byte = &mut self.0[index >> 1];
+        if index & 1 == 0 {
+            *byte = (*byte & 0xF0) | (value & 0xF);
+        } else {
+            *byte = (*byte & 0x0F) | ((value & 0xF) << 4);
+        }
+    }
+    pub fn clear(&mut self) {
+        self.0.fill(0);
+    }
+}
+/// The light of every block in a section. Light is not saved. It is recalculated when a chunk is loaded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SectionLight {
+    pub sky: NibbleArray,
+    pub block: NibbleArray,
+}
+impl SectionLight {
+    pub fn get(&self, kind: LightKind, index: usize) -> u8 {
+        match kind {
+            LightKind::Sky => self.sky.get(index),
+            LightKind::Block => self.block.get(index),
+        }
+    }
+    pub fn set(&mut self, kind: LightKind, index: usize, value: u8) {
+        match kind {
+            LightKind::Sky => self.sky.set(index, value),
+            LightKind::Block => self.block.set(index, value),
+        }
+    }
+    pub fn voxel_light(&self, index: usize) -> VoxelLight {
+        VoxelLight::new(self.sky.get(index), self.block.get(index))
+    }
+}
+/// Blocks that light can be spread through. Positions are in world space
+pub trait LightVolume {
+    /// None if the block is not loaded. Light does not travel into unloaded blocks
+    fn block(&self, position: BlockPosition) -> Option<Block>;
+    fn light(&self, position: BlockPosition, kind: LightKind) -> u8;
+    /// Sets the light of a loaded block. Sections that show the block are marked as dirty if the light changed
+    fn set_light(&mut self, position: BlockPosition, kind: LightKind, value: u8);
+}
+fn in_world(position: BlockPosition) -> bool {
+    (0..WORLD_HEIGHT).contains(&position.y)
+}
+/// A single chunk. Used to light a chunk before it is added to the world
+impl LightVolume for Chunk {
+    fn block(&self, position: BlockPosition) -> Option<Block> {
+        if position.chunk() != self.position || !in_world(position) {
+            return None;
+        }
+        Some(self.sections[position.section()].block_store[position.relative_block()])
+    }
+    fn light(&self, position: BlockPosition, kind: LightKind) -> u8 {
+        if position.chunk() != self.position || !in_world(position) {
+            return 0;
+        }
+        self.sections[position.section()]
+            .light
+            .get(kind, position.relative_block())
+    }
+    fn set_light(&mut self, position: BlockPosition, kind: LightKind, value: u8) {
+        if position.chunk() != self.position || !in_world(position) {
+            return;
+        }
+        let section = position.section();
+        let index = position.relative_block();
+        if self.sections[section].light.get(kind, index) == value {
+            return;
+        }
+        self.sections[section].light.set(kind, index, value);
+        self.mark_block_dirty(position);
+    }
+}
+/// Every loaded chunk of the world. Light spreads across chunk borders
+pub struct LoadedChunks<'a>(pub &'a mut HashMap<ChunkPosition, Chunk>);
+impl LightVolume for LoadedChunks<'_> {
+    fn block(&self, position: BlockPosition) -> Option<Block> {
+        self.0.get(&position.chunk())?.block(position)
+    }
+    fn light(&self, position: BlockPosition, kind: LightKind) -> u8 {
+        self.0
+            .get(&position.chunk())
+            .map(|chunk| chunk.light(position, kind))
+            .unwrap_or(0)
+    }
+    fn set_light(&mut self, position: BlockPosition, kind: LightKind, value: u8) {
+        let Some(chunk) = self.0.get_mut(&position.chunk()) else {
+            return;
+        };
+        if chunk.light(position, kind) == value {
+            return;
+        }
+        chunk.set_light(position, kind, value);
+        mark_sections_around(self.0, position);
+    }
+}
+/// The light a block spreads to its neighbour in the direction of `face`
+fn spread_level(kind: LightKind, face: Face, level: u8) -> u8 {
+    if kind == LightKind::Sky && face == Face::Bottom && level == MAX_LIGHT {
+        MAX_LIGHT
+    } else {
+        level.saturating_sub(1)
+    }
+}
+/// Spreads the light of every block in the queue to its neighbours
+pub fn propagate(
+    volume: &mut impl LightVolume,
+    kind: LightKind,
+    registry: &BlockRegistery,
+    mut queue: VecDeque<BlockPosition>,
+) {
+    while let Some(position) = queue.pop_front() {
+        let level = volume.light(position, kind);
+        if level <= 1 {
+            continue;
+        }
+        for face in Face::iter() {
+            let next = position.offset(face);
+            let Some(block) = volume.block(next) else {
+                continue;
+            };
+            if block.visibility(registry).is_opaque() {
+                continue;
+            }
+            let next_level = spread_level(kind, face, level);
+            if volume.light(next, kind) < next_level {
+                volume.set_light(next, kind, next_level);
+                queue.push_back(next);
+            }
+        }
+    }
+}
+/// Clears the light that came from the blocks in the queue. Each entry is the block and the light it had before it was cleared
+///
+/// The blocks that can spread light back into the cleared area are pushed onto `relight`
+pub fn remove(
+    volume: &mut impl LightVolume,
+    kind: LightKind,
+    registry: &BlockRegistery,
+    mut queue: VecDeque<(BlockPosition, u8)>,
+    relight: &mut VecDeque<BlockPosition>,
+) {
+    while let Some((position, old_level)) = queue.pop_front() {
+        for face in Face::iter() {
+            let next = position.offset(face);
+            let Some(block) = volume.block(next) else {
+                continue;
+            };
+            let level = volume.light(next, kind);
+            if level == 0 {
+                continue;
+            }
+            if level < old_level || spread_level(kind, face, old_level) == level {
+                volume.set_light(next, kind, 0);
+                queue.push_back((next, level));
+                let emission = emission(kind, block, registry);
+                if emission > 0 {
+                    volume.set_light(next, kind, emission);
+                    relight.push_back(next);
+                }
+            } else {
+                relight.push_back(next);
+            }
+        }
+    }
+}
+fn emission(kind: LightKind, block: Block, registry: &BlockRegistery) -> u8 {
+    match (kind, block) {
+        (LightKind::Block, Block::Block(id)) => registry.light_emission(id),
+        (LightKind::Sky, _) => 0,
+    }
+}
+/// Updates the light around blocks that have changed
+pub fn update_blocks(
+    volume: &mut impl LightVolume,
+    registry: &BlockRegistery,
+    changed: &[BlockPosition],
+) {
+    for kind in LightKind::ALL {
+        let mut removal = VecDeque::new();
+        let mut relight = VecDeque::new();
+        for &position in changed {
+            let Some(block) = volume.block(position) else {
+                continue;
+            };
+            let old_level = volume.light(position, kind);
+            if old_level > 0 {
+                volume.set_light(position, kind, 0);
+                removal.push_back((position, old_level));
+            }
+            // Neighbours shine into the block if it is no longer opaque
+            for face in Face::iter() {
+                relight.push_back(position.offset(face));
+            }
+            let emission = emission(kind, block, registry);
+            if emission > 0 {
+                volume.set_light(position, kind, emission);
+                relight.push_back(position);
+            }
+            let top_of_world = position.y == WORLD_HEIGHT - 1;
+            if kind == LightKind::Sky && top_of_world && !block.visibility(registry).is_opaque() {
+                volume.set_light(position, kind, MAX_LIGHT);
+                relight.push_back(position);
+            }
+        }
+        remove(volume, kind, registry, removal, &mut relight);
+        propagate(volume, kind, registry, relight);
+    }
+}
+/// Calculates the light of a chunk that is not in the world yet. Light from other chunks is added by [stitch_chunk]
+pub fn light_chunk(chunk: &mut Chunk, registry: &BlockRegistery) {
+    for section in &mut chunk.sections {
+        section.light.sky.clear();
+        section.light.block.clear();
+    }
+    let origin = BlockPosition::new(chunk.position.x * 16, 0, chunk.position.z * 16);
+    // Sky light shines down every column until it hits an opaque block
+    let mut queue = VecDeque::new();
+    for x in 0..16 {
+        for z in 0..16 {
+            for y in (0..WORLD_HEIGHT).rev() {
+                let position = BlockPosition::new(origin.x + x, y, origin.z + z);
+                if chunk
+                    .block(position)
+                    .is_some_and(|b| b.visibility(registry).is_opaque())
+                {
+                    break;
+                }
+                chunk.set_light(position, LightKind::Sky, MAX_LIGHT);
+                queue.push_back(position);
+            }
+        }
+    }
+    propagate(chunk, LightKind::Sky, registry, queue);
+
+    let mut queue = VecDeque::new();
+    for (section_index, section) in chunk.sections.iter().enumerate() {
+        let emits = section
+            .block_store
+            .palette()
+            .iter()
+            .any(|block| emission(LightKind::Block, *block, registry) > 0);
+        if !emits {
+            continue;
+        }
+        for (index, block) in section.block_store.iter().enumerate() {
+            let level = emission(LightKind::Block, block, registry);
+            if level > 0 {
+                let relative = BlockPosition::from_relative_block(index);
+                queue.push_back((
+                    BlockPosition::new(
+                        origin.x + relative.x,
+                        section_index as i64 * 16 + relative.y,
+                        origin.z + relative.z,
+                    ),
+                    level,
+                ));
+            }
+        }
+    }
+    let queue = queue
+        .into_iter()
+        .map(|(position, level)| {
+            chunk.set_light(position, LightKind::Block, level);
+            position
+        })
+        .collect();
+    propagate(chunk, LightKind::Block, registry, queue);
+    chunk.lit = true;
+}
+/// Spreads light between a chunk that was just added to the world and the chunks next to it
+pub fn stitch_chunk(
+    chunks: &mut HashMap<ChunkPosition, Chunk>,
+    position: ChunkPosition,
+    registry: &BlockRegistery,
+) {
+    let origin = BlockPosition::new(position.x * 16, 0, position.z * 16);
+    let mut queue = VecDeque::new();
+    for face in ChunkNeighbours::SIDES {
+        if !chunks.contains_key(&position.neighbour(face)) {
+            continue;
+        }
+        for a in 0..16 {
+            let border = match face {
+                Face::North => (a, 15),
+                Face::South => (a, 0),
+                Face::East => (15, a),
+                _ => (0, a),
+            };
+            for y in 0..WORLD_HEIGHT {
+                let inside = BlockPosition::new(origin.x + border.0, y, origin.z + border.1);
+                queue.push_back(inside);
+                queue.push_back(inside.offset(face));
+            }
+        }
+    }
+    if queue.is_empty() {
+        return;
+    }
+    let mut volume = LoadedChunks(chunks);
+    for kind in LightKind::ALL {
+        propagate(&mut volume, kind, registry, queue.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ahash::HashMapExt;
+
+    use crate::game::blocks::{test_block_registery, BlockRegistery};
+
+    use super::*;
+
+    fn glowstone(registry: &BlockRegistery) -> u32 {
+        (0..registry.states.len() as u32)
+            .find(|id| registry.light_emission(*id) == MAX_LIGHT)
+            .unwrap()
+    }
+    /// A chunk with a floor of stone at y 0-9
+    fn floor_chunk(position: ChunkPosition) -> Chunk {
+        let mut chunk = Chunk::new(position);
+        for x in 0..16 {
+            for z in 0..16 {
+                for y in 0..10 {
+                    chunk.set_block(BlockPosition::new(x, y, z), 1);
+                }
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn test_nibble_array() {
+        let mut nibbles = NibbleArray::default();
+        nibbles.set(0, 15);
+        nibbles.set(1, 7);
+        nibbles.set(4095, 3);
+        assert_eq!(nibbles.get(0), 15);
+        assert_eq!(nibbles.get(1), 7);
+        assert_eq!(nibbles.get(4095), 3);
+        nibbles.set(0, 0);
+        assert_eq!(nibbles.get(1), 7);
+    }
+
+    #[test]
+    fn test_sky_light_stops_at_opaque_blocks() {
+        let registry = test_block_registery();
+        let mut chunk = floor_chunk(ChunkPosition::new(0, 0));
+        light_chunk(&mut chunk, &registry);
+        assert_eq!(
+            chunk.light(BlockPosition::new(3, 200, 3), LightKind::Sky),
+            15
+        );
+        assert_eq!(
+            chunk.light(BlockPosition::new(3, 10, 3), LightKind::Sky),
+            15
+        );
+        assert_eq!(chunk.light(BlockPosition::new(3, 9, 3), LightKind::Sky), 0);
+        // Dig a hole with a roof. Light spreads sideways into it
+        chunk.set_block(BlockPosition::new(3, 9, 3), 0);
+        chunk.set_block(BlockPosition::new(3, 8, 3), 0);
+        chunk.set_block(BlockPosition::new(4, 8, 3), 0);
+        update_blocks(
+            &mut chunk,
+            &registry,
+            &[
+                BlockPosition::new(3, 9, 3),
+                BlockPosition::new(3, 8, 3),
+                BlockPosition::new(4, 8, 3),
+            ],
+        );
+        assert_eq!(chunk.light(BlockPosition::new(3, 8, 3), LightKind::Sky), 15);
+        assert_eq!(chunk.light(BlockPosition::new(4, 8, 3), LightKind::Sky), 14);
+        // Cover it back up
+        chunk.set_block(BlockPosition::new(3, 9, 3), 1);
+        update_blocks(&mut chunk, &registry, &[BlockPosition::new(3, 9, 3)]);
+        assert_eq!(chunk.light(BlockPosition::new(3, 8, 3), LightKind::Sky), 0);
+        assert_eq!(chunk.light(BlockPosition::new(4, 8, 3), LightKind::Sky), 0);
+    }
+
+    #[test]
+    fn test_block_light_is_added_and_removed() {
+        let registry = test_block_registery();
+        let glowstone = glowstone(&registry);
+        let mut chunk = floor_chunk(ChunkPosition::new(0, 0));
+        light_chunk(&mut chunk, &registry);
+        let light_position = BlockPosition::new(8, 10, 8);
+        chunk.set_block(light_position, glowstone);
+        update_blocks(&mut chunk, &registry, &[light_position]);
+        assert_eq!(chunk.light(light_position, LightKind::Block), 15);
+        assert_eq!(
+            chunk.light(BlockPosition::new(8, 11, 8), LightKind::Block),
+            14
+        );
+        assert_eq!(
+            chunk.light(BlockPosition::new(11, 10, 8), LightKind::Block),
+            12
+        );
+        // The floor is opaque
+        assert_eq!(
+            chunk.light(BlockPosition::new(8, 9, 8), LightKind::Block),
+            0
+        );
+
+        chunk.set_block(light_position, 0);
+        update_blocks(&mut chunk, &registry, &[light_position]);
+        assert_eq!(chunk.light(light_position, LightKind::Block), 0);
+        assert_eq!(
+            chunk.light(BlockPosition::new(11, 10, 8), LightKind::Block),
+            0
+        );
+        // Sky light fills the spot back in
+        assert_eq!(chunk.light(light_position, LightKind::Sky), 15);
+    }
+
+    #[test]
+    fn test_light_crosses_chunks() {
+        let registry = test_block_registery();
+        let glowstone = glowstone(&registry);
+        let mut chunks = HashMap::new();
+        for x in 0..2 {
+            let position = ChunkPosition::new(x, 0);
+            let mut chunk = floor_chunk(position);
+            // Roof over both chunks so only block light is seen
+            for bx in 0..16 {
+                for bz in 0..16 {
+                    chunk.set_block(BlockPosition::new(bx, 12, bz), 1);
+                }
+            }
+            if x == 0 {
+                chunk.set_block(BlockPosition::new(14, 10, 5), glowstone);
+            }
+            light_chunk(&mut chunk, &registry);
+            chunks.insert(position, chunk);
+            stitch_chunk(&mut chunks, position, &registry);
+        }
+        let volume = LoadedChunks(&mut chunks);
+        assert_eq!(
+            volume.light(BlockPosition::new(14, 10, 5), LightKind::Block),
+            15
+        );
+        assert_eq!(
+            volume.light(BlockPosition::new(16, 10, 5), LightKind::Block),
+            13
+        );
+        assert_eq!(
+            volume.light(BlockPosition::new(18, 11, 5), LightKind::Block),
+            10
+        );
+        assert_eq!(
+            volume.light(BlockPosition::new(18, 11, 5), LightKind::Sky),
+            0
+        );
+    }
+}
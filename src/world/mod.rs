@@ -1,26 +1,66 @@
-use std::sync::Arc;
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
 use ahash::{HashMap, HashMapExt};
 use flume::Sender;
+use glam::Vec3;
 use noise::Perlin;
-use tracing::{debug, info};
+use thiserror::Error;
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    engine::voxel::{chunk_mesh::RawChunkMesh, voxel_state::ChunkUpdates},
-    game::{Game, Player},
+    engine::voxel::{chunk_mesh::RawChunkMesh, voxel_state::ChunkUpdates, Face},
+    game::{blocks::BlockRegistery, Game, Player},
 };
 
 use self::{
-    chunk::Chunk,
-    position::{BlockPosition, ChunkPosition},
+    block_ids::BlockIdMap,
+    chunk::{Block, Chunk, ChunkNeighbours},
+    info::WorldInfo,
+    light::{LightVolume, LoadedChunks},
+    position::{BlockPosition, ChunkPosition, RawPosition},
+    raycast::RaycastHit,
+    region::{RegionError, RegionStorage},
+    seed::WorldSeed,
+    workers::{ChunkJobContext, ChunkWorkers, FinishedJob, GeneratedChunk, RemeshedChunk},
 };
 
+pub mod block_ids;
+pub mod block_store;
 pub mod chunk;
+pub mod info;
+pub mod light;
 pub mod position;
+pub mod raycast;
+pub mod region;
+pub mod seed;
+pub mod workers;
+#[derive(Debug, Error)]
+pub enum WorldError {
+    #[error("Region Error: {0}")]
+    RegionError(#[from] RegionError),
+    #[error("IO Error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Invalid world info: {0}")]
+    InvalidWorldInfo(#[from] serde_json::Error),
+}
 #[derive(Debug)]
 pub struct World {
+    pub seed: WorldSeed,
+    /// Built from the seed
     pub noise: Perlin,
+    /// Where chunks are saved. None means the world only lives in memory
+    ///
+    /// Shared with the workers so saved chunks are read off of the world thread
+    pub storage: Option<Arc<Mutex<RegionStorage>>>,
     pub chunks: HashMap<ChunkPosition, Chunk>,
+    /// Chunks that are not loaded yet are built by the workers
+    pub workers: ChunkWorkers,
+    /// The chunk the loaded area was last centered on
+    pub center: Option<ChunkPosition>,
     // ChunkMeshes are 16x16x16 meaning they need 3 points to define a position
     // The Block posistion is the bottom left corner of the chunk
     // Meshes are 16x16x16 meaning that they have multiple meshes for each chunk
@@ -29,29 +69,317 @@ pub struct World {
 }
 
 impl World {
-    pub fn new() -> Self {
-        let noise = Perlin::default();
+    pub fn new(seed: WorldSeed) -> Self {
+        let noise = seed.height_noise();
 
         Self {
+            seed,
             noise,
+            storage: None,
             chunks: HashMap::new(),
+            workers: ChunkWorkers::default(),
+            center: None,
             built_meshes: HashMap::new(),
             meshes_being_rendered: HashMap::new(),
         }
     }
 
-    pub fn generate_test_world() -> Self {
-        let mut world = Self::new();
+    /// Opens a world that is saved in the given directory
+    ///
+    /// The seed is only used if the world is being created. Otherwise the saved seed is used. If no seed is given a random one is picked
+    ///
+    /// Saved chunks are rewritten if the registry gives out different block ids than the world was saved with. See [block_ids]
+    pub fn open(
+        directory: impl AsRef<Path>,
+        seed: Option<WorldSeed>,
+        registry: &BlockRegistery,
+    ) -> Result<Self, WorldError> {
+        let mut storage = RegionStorage::open(directory)?;
+        let info = match WorldInfo::load(&storage.directory)? {
+            Some(info) => {
+                if seed.is_some_and(|seed| seed != info.seed) {
+                    warn!("World already exists. Using its seed {}", info.seed);
+                }
+                info
+            }
+            None => {
+                let info = WorldInfo {
+                    seed: seed.unwrap_or_else(WorldSeed::random),
+                };
+                info.save(&storage.directory)?;
+                info
+            }
+        };
+        if BlockIdMap::migration_in_progress(&storage.directory) {
+            // Every region was rewritten before the world was closed
+            storage.replace_rewritten_regions()?;
+            BlockIdMap::finish_migration(&storage.directory)?;
+            info!("Finished the block id remap that was in progress");
+        } else {
+            storage.remove_rewritten_regions()?;
+        }
+        // Worlds saved before the id map was added used the ids of the default blocks
+        let map = BlockIdMap::from_registry(registry);
+        if let Some(remap) =
+            BlockIdMap::load(&storage.directory)?.and_then(|saved| saved.remap(registry))
+        {
+            let rewritten = storage.rewrite_saved_chunks(|chunk| {
+                chunk.remap_blocks(|id| remap.get(id as usize).copied().unwrap_or(0))
+            })?;
+            map.save_migration(&storage.directory)?;
+            storage.replace_rewritten_regions()?;
+            BlockIdMap::finish_migration(&storage.directory)?;
+            info!("Block ids changed. Rewrote {rewritten} saved chunks");
+        } else {
+            map.save(&storage.directory)?;
+        }
+        info!(
+            "Opened world at {:?} with seed {}",
+            storage.directory, info.seed
+        );
+        Ok(Self {
+            storage: Some(Arc::new(Mutex::new(storage))),
+            ..Self::new(info.seed)
+        })
+    }
+    /// Picks up the chunks the workers have finished and starts jobs for the next closest chunks
+    ///
+    /// Never blocks. Meshes of finished chunks in render distance are sent to the renderer.
+    /// The chunks next to a finished chunk are remeshed by the workers so their faces towards it are culled
+    pub fn poll_chunk_workers(
+        &mut self,
+        player: &Player,
+        game: Arc<Game>,
+        sender: &Sender<ChunkUpdates>,
+    ) {
+        for job in self.workers.finished() {
+            match job {
+                FinishedJob::Generated(generated) => {
+                    self.insert_generated_chunk(*generated, &game, sender)
+                }
+                FinishedJob::Remeshed(remeshed) => self.apply_remesh(remeshed, &game, sender),
+            }
+        }
+        let context = ChunkJobContext {
+            seed: self.seed,
+            noise: self.noise,
+            game,
+            storage: self.storage.clone(),
+        };
+        let chunks = &self.chunks;
+        self.workers
+            .dispatch(player.position, &context, |position| {
+                ChunkNeighbours::collect(chunks, position, &context.game.block_registery)
+            });
+        self.start_remeshes(&context.game);
+    }
+    /// Loads a chunk built by a worker and sends its meshes to the renderer
+    fn insert_generated_chunk(
+        &mut self,
+        generated: GeneratedChunk,
+        game: &Arc<Game>,
+        sender: &Sender<ChunkUpdates>,
+    ) {
+        let GeneratedChunk {
+            chunk,
+            meshes,
+            neighbours,
+        } = generated;
+        let position = chunk.position;
+        debug!("Chunk {:?} finished with {} meshes", position, meshes.len());
+        // The player may have walked away while the chunk was being built
+        let render_distance = game.game_settings.chunk_render_distance as i64;
+        let visible = self
+            .center
+            .is_none_or(|center| position.distance(&center) <= render_distance);
+        for mesh in meshes {
+            if visible {
+                self.meshes_being_rendered
+                    .insert(mesh.position.into(), mesh.clone());
+                sender.send(ChunkUpdates::Add(mesh)).unwrap();
+            } else {
+                self.built_meshes.insert(mesh.position.into(), mesh);
+            }
+        }
+        self.chunks.insert(position, chunk);
+        light::stitch_chunk(&mut self.chunks, position, &game.block_registery);
+        let registry = &game.block_registery;
+        let mut remesh_self = 0;
+        let mut remesh_neighbours = Vec::new();
+        let chunk = &self.chunks[&position];
+        for face in ChunkNeighbours::SIDES {
+            let Some(neighbour) = self.chunks.get(&position.neighbour(face)) else {
+                continue;
+            };
+            // The neighbour was meshed without this chunk so its faces towards it are not culled
+            remesh_neighbours.push((neighbour.position, chunk.sections_facing(face, registry)));
+            // The neighbour finished while this chunk was being built
+            if !neighbours.is_loaded(face) {
+                remesh_self |= neighbour.sections_facing(face.opposite(), registry);
+            }
+        }
+        // The diagonal chunks only shade the corners of each other
+        for (x_face, z_face) in ChunkNeighbours::CORNERS {
+            let Some(neighbour) = self
+                .chunks
+                .get(&position.neighbour(x_face).neighbour(z_face))
+            else {
+                continue;
+            };
+            remesh_neighbours.push((
+                neighbour.position,
+                chunk.sections_shaded_diagonally(x_face, z_face, registry),
+            ));
+            if !neighbours.is_corner_loaded(x_face, z_face) {
+                remesh_self |= neighbour.sections_shaded_diagonally(
+                    x_face.opposite(),
+                    z_face.opposite(),
+                    registry,
+                );
+            }
+        }
+        for (neighbour, sections) in remesh_neighbours {
+            self.queue_remesh(neighbour, sections);
+        }
+        // Light that spread in from the neighbours is picked up from the dirty sections
+        self.queue_remesh(position, remesh_self);
+    }
+    /// Queues sections of a loaded chunk to be rebuilt on a worker. One bit per section
+    ///
+    /// Sections that are already dirty are included so they are not also rebuilt on the world thread. See [World::start_remeshes]
+    fn queue_remesh(&mut self, position: ChunkPosition, sections: u16) {
+        let Some(chunk) = self.chunks.get_mut(&position) else {
+            return;
+        };
+        let sections = sections | chunk.dirty_sections();
+        for (index, section) in chunk.sections.iter_mut().enumerate() {
+            if sections & (1 << index) != 0 {
+                section.dirty = false;
+            }
+        }
+        self.workers.queue_remesh(position, sections);
+    }
+    /// Starts the queued remeshes on the workers that are free
+    ///
+    /// Every change to a chunk up to now is included. Meshes still being built for it are out of date and are redone once they finish. See [World::apply_remesh]
+    fn start_remeshes(&mut self, game: &Arc<Game>) {
+        while let Some((position, sections)) = self.workers.next_remesh() {
+            let Some(chunk) = self.chunks.get(&position) else {
+                // Unloaded while the remesh was queued
+                continue;
+            };
+            let neighbours =
+                ChunkNeighbours::collect(&self.chunks, position, &game.block_registery);
+            self.workers
+                .remesh(chunk, sections, neighbours, game.clone());
+        }
+    }
+    /// Replaces the meshes of a chunk with the ones built by a worker
+    ///
+    /// Chunks that changed while the worker was meshing them are remeshed again
+    fn apply_remesh(
+        &mut self,
+        remeshed: RemeshedChunk,
+        game: &Arc<Game>,
+        sender: &Sender<ChunkUpdates>,
+    ) {
+        let RemeshedChunk {
+            position,
+            revision,
+            sections,
+            meshes,
+        } = remeshed;
+        let Some(chunk) = self.chunks.get(&position) else {
+            // Unloaded while the worker was meshing it
+            return;
+        };
+        if chunk.revision != revision {
+            self.queue_remesh(position, sections);
+            return;
+        }
+        let render_distance = game.game_settings.chunk_render_distance as i64;
+        let in_render_distance = self
+            .center
+            .is_none_or(|center| position.distance(&center) <= render_distance);
+        let mut meshes: HashMap<BlockPosition, RawChunkMesh> = meshes
+            .into_iter()
+            .map(|mesh| (mesh.position.into(), mesh))
+            .collect();
+        for index in (0..chunk.sections.len()).filter(|index| sections & (1 << index) != 0) {
+            let section_position = chunk.section_position(index);
+            let mesh = meshes.remove(&section_position);
+            if self.meshes_being_rendered.contains_key(&section_position) {
+                match mesh {
+                    Some(mesh) => {
+                        self.meshes_being_rendered
+                            .insert(section_position, mesh.clone());
+                        sender.send(ChunkUpdates::Update(mesh)).unwrap();
+                    }
+                    None => {
+                        self.meshes_being_rendered.remove(&section_position);
+                        sender
+                            .send(ChunkUpdates::Remove(section_position.into()))
+                            .unwrap();
+                    }
+                }
+            } else if let Some(mesh) = mesh {
+                if in_render_distance {
+                    self.built_meshes.remove(&section_position);
+                    self.meshes_being_rendered
+                        .insert(section_position, mesh.clone());
+                    sender.send(ChunkUpdates::Add(mesh)).unwrap();
+                } else {
+                    self.built_meshes.insert(section_position, mesh);
+                }
+            } else {
+                self.built_meshes.remove(&section_position);
+            }
+        }
+    }
+    /// Writes every chunk that has changed since it was last saved
+    ///
+    /// Returns the number of chunks that were written
+    pub fn save_dirty_chunks(&mut self) -> Result<usize, RegionError> {
+        let Some(storage) = self.storage.as_ref() else {
+            return Ok(0);
+        };
+        let mut storage = lock_storage(storage);
+        let mut saved = 0;
+        for chunk in self.chunks.values_mut().filter(|c| c.needs_saving) {
+            storage.save_chunk(chunk)?;
+            chunk.needs_saving = false;
+            saved += 1;
+        }
+        storage.flush()?;
+        Ok(saved)
+    }
+
+    pub fn generate_test_world(registry: &BlockRegistery) -> Self {
+        let mut world = Self::new(WorldSeed::default());
         for x in -1..1 {
             for z in -1..1 {
                 let position = ChunkPosition::new(x, z);
-                world
-                    .chunks
-                    .insert(position, Chunk::generate(position, &world.noise));
+                world.chunks.insert(
+                    position,
+                    Chunk::generate(position, world.seed, &world.noise, registry),
+                );
             }
         }
         world
     }
+    /// Called every [WORLD_TICK](crate::game::WORLD_TICK)
+    ///
+    /// The loaded area is only re-evaluated once the player has entered a different chunk
+    pub fn tick(&mut self, player: &Player, game: Arc<Game>, sender: &Sender<ChunkUpdates>) {
+        let player_chunk = player.position.chunk();
+        if self.center != Some(player_chunk) {
+            debug!("Player entered chunk {:?}", player_chunk);
+            self.update_loaded_chunks(player_chunk, &game, sender);
+        }
+        self.update_light(&game.block_registery);
+        self.rebuild_dirty_sections(&game, sender);
+        self.poll_chunk_workers(player, game, sender);
+    }
     /// Updates the meshes that are being rendered
     /// Currently we are cloning the meshes to send them to the renderer.The renderer converts these raw meshes into the the wgpu Buffers
     pub fn update_meshes(
@@ -60,92 +388,845 @@ impl World {
         game: Arc<Game>,
         sender: Sender<ChunkUpdates>,
     ) {
-        // Step One: Get the chunks that need to be rendered
-        let player_chunk = player.position.chunk();
-        let mut chunks_needing_rendering = Vec::with_capacity(
-            (game.game_settings.chunk_render_distance * 2)
-                * (game.game_settings.chunk_render_distance * 2),
-        );
-        let render_distance = game.game_settings.chunk_render_distance as i64;
-        for x in -render_distance..render_distance {
-            for z in -render_distance..render_distance {
-                let position = ChunkPosition::new(x + player_chunk.x, z + player_chunk.z);
-                if !self.chunks.contains_key(&position) {
-                    // TODO: This will cause a lot of blocking so we should open a task and have a "callback"
-                    self.chunks
-                        .insert(position, Chunk::generate(position, &self.noise));
+        self.update_loaded_chunks(player.position.chunk(), &game, &sender);
+        self.update_light(&game.block_registery);
+        self.rebuild_dirty_sections(&game, &sender);
+        self.poll_chunk_workers(player, game, &sender);
+        info!("Updated Meshes");
+    }
+    /// Centers the loaded area on `center`
+    ///
+    /// Chunks in render distance are shown or requested from the workers.
+    /// Chunks past [GameSettings::chunk_hide_distance](crate::game::GameSettings::chunk_hide_distance) stop being rendered and chunks past [GameSettings::chunk_unload_distance](crate::game::GameSettings::chunk_unload_distance) are saved and dropped
+    fn update_loaded_chunks(
+        &mut self,
+        center: ChunkPosition,
+        game: &Arc<Game>,
+        sender: &Sender<ChunkUpdates>,
+    ) {
+        self.center = Some(center);
+        let settings = &game.game_settings;
+        let render_distance = settings.chunk_render_distance as i64;
+        // Step One: Show the chunks in render distance. The ones that are not loaded are built by the workers
+        for x in -render_distance..=render_distance {
+            for z in -render_distance..=render_distance {
+                let position = ChunkPosition::new(x + center.x, z + center.z);
+                if self.chunks.contains_key(&position) {
+                    self.show_chunk(position, game, sender);
+                } else {
+                    self.workers.request(position);
                 }
-                chunks_needing_rendering.push(position);
             }
         }
-        // Step Two: Remove the meshes that are should not be rendered
+        self.workers
+            .retain_pending(|position| position.distance(&center) <= render_distance);
+        // Step Two: Stop rendering the meshes that are too far away. They are kept in case the player comes back
+        let hide_distance = settings.chunk_hide_distance();
         self.meshes_being_rendered
-            .extract_if(|k, _| chunks_needing_rendering.contains(&k.chunk()))
+            .extract_if(|k, _| k.chunk().distance(&center) > hide_distance)
             .for_each(|(location, mesh)| {
-                sender
-                    .send(ChunkUpdates::Remove(location.clone().into()))
-                    .unwrap();
+                sender.send(ChunkUpdates::Remove(location.into())).unwrap();
                 self.built_meshes.insert(location, mesh);
             });
-        // Step Three: Build Meshes the need to be rendered. Update the ones that are already being rendered
-        for position in &chunks_needing_rendering {
-            for y in 0usize..16 {
-                // TODO no errors
-                let chunk = self.chunks.get_mut(&position).expect("Chunk not found");
-                let section = &mut chunk.sections[y];
-
-                let section_position =
-                    BlockPosition::new(position.x * 16, y as i64 * 16, position.z * 16);
-                debug!(
-                    "Chunk Position {:?} will be rendered with {} non air blocks",
-                    section_position,
-                    section.number_of_non_air_blocks()
-                );
-                if !section.contains_non_air_blocks() {
-                    debug!("Skipping section because it has no non air blocks");
+        // Step Three: Unload the chunks that are even further away
+        let unload_distance = settings.chunk_unload_distance();
+        let far_chunks: Vec<_> = self
+            .chunks
+            .keys()
+            .filter(|position| position.distance(&center) > unload_distance)
+            .copied()
+            .collect();
+        for position in far_chunks {
+            self.unload_chunk(position);
+        }
+    }
+    /// Sends the meshes of a loaded chunk to the renderer. Cached meshes are reused if the section has not changed
+    fn show_chunk(
+        &mut self,
+        position: ChunkPosition,
+        game: &Arc<Game>,
+        sender: &Sender<ChunkUpdates>,
+    ) {
+        let Some(chunk) = self.chunks.get(&position) else {
+            return;
+        };
+        let neighbours = ChunkNeighbours::collect(&self.chunks, position, &game.block_registery);
+        let mut cleaned = Vec::new();
+        for (index, section) in chunk.sections.iter().enumerate() {
+            let section_position = chunk.section_position(index);
+            if self.meshes_being_rendered.contains_key(&section_position) {
+                // Changes are picked up by rebuild_dirty_sections
+                continue;
+            }
+            cleaned.push(index);
+            if !section.contains_non_air_blocks() {
+                self.built_meshes.remove(&section_position);
+                continue;
+            }
+            let mesh = match self.built_meshes.remove(&section_position) {
+                Some(mesh) if !section.dirty => mesh,
+                Some(mut mesh) => {
+                    mesh.rebuild(
+                        section.get_voxels(game.clone()),
+                        &chunk.section_neighbours(index, &neighbours, &game.block_registery),
+                    );
+                    mesh
+                }
+                None => RawChunkMesh::build(
+                    section_position.into(),
+                    section.get_voxels(game.clone()),
+                    &chunk.section_neighbours(index, &neighbours, &game.block_registery),
+                    game.game_settings.mesh_options(),
+                ),
+            };
+            self.meshes_being_rendered
+                .insert(section_position, mesh.clone());
+            sender.send(ChunkUpdates::Add(mesh)).unwrap();
+        }
+        if let Some(chunk) = self.chunks.get_mut(&position) {
+            for index in cleaned {
+                chunk.sections[index].dirty = false;
+            }
+        }
+    }
+    /// Rebuilds the meshes of the sections that have changed since they were last meshed
+    ///
+    /// Cached meshes of hidden chunks are dropped. They are rebuilt when the chunk is shown again
+    fn rebuild_dirty_sections(&mut self, game: &Arc<Game>, sender: &Sender<ChunkUpdates>) {
+        let render_distance = game.game_settings.chunk_render_distance as i64;
+        let dirty_chunks: Vec<ChunkPosition> = self
+            .chunks
+            .values()
+            .filter(|chunk| chunk.sections.iter().any(|section| section.dirty))
+            .map(|chunk| chunk.position)
+            .collect();
+        for position in dirty_chunks {
+            let in_render_distance = self
+                .center
+                .is_some_and(|center| position.distance(&center) <= render_distance);
+            let neighbours =
+                ChunkNeighbours::collect(&self.chunks, position, &game.block_registery);
+            let chunk = &self.chunks[&position];
+            for (index, section) in chunk.sections.iter().enumerate() {
+                if !section.dirty {
                     continue;
                 }
+                let section_position = chunk.section_position(index);
+                let has_blocks = section.contains_non_air_blocks();
                 if let Some(mesh) = self.meshes_being_rendered.get_mut(&section_position) {
-                    if section.dirty {
-                        // Dirty section so rebuild the mesh
-                        mesh.rebuild(section.get_voxels(game.clone()));
-                        section.dirty = false;
-                    }
-                    sender.send(ChunkUpdates::Update(mesh.clone())).unwrap();
-                } else if let Some(mut mesh) = self.built_meshes.remove(&section_position) {
-                    // Already built so just add it to the being rendered
-                    if section.dirty {
-                        // Dirty section so rebuild the mesh
-                        mesh.rebuild(section.get_voxels(game.clone()));
-                        section.dirty = false;
+                    if has_blocks {
+                        mesh.rebuild(
+                            section.get_voxels(game.clone()),
+                            &chunk.section_neighbours(index, &neighbours, &game.block_registery),
+                        );
+                        sender.send(ChunkUpdates::Update(mesh.clone())).unwrap();
+                    } else {
+                        self.meshes_being_rendered.remove(&section_position);
+                        sender
+                            .send(ChunkUpdates::Remove(section_position.into()))
+                            .unwrap();
                     }
-                    self.meshes_being_rendered
-                        .insert(section_position, mesh.clone());
-                    sender.send(ChunkUpdates::Add(mesh)).unwrap();
-                } else {
-                    // Basically the mesh is not built already so add it.
+                } else if in_render_distance && has_blocks {
+                    // The section was empty before
                     let mesh = RawChunkMesh::build(
                         section_position.into(),
                         section.get_voxels(game.clone()),
+                        &chunk.section_neighbours(index, &neighbours, &game.block_registery),
+                        game.game_settings.mesh_options(),
                     );
-                    section.dirty = false;
                     self.meshes_being_rendered
                         .insert(section_position, mesh.clone());
                     sender.send(ChunkUpdates::Add(mesh)).unwrap();
+                } else {
+                    self.built_meshes.remove(&section_position);
+                }
+            }
+            if let Some(chunk) = self.chunks.get_mut(&position) {
+                for section in &mut chunk.sections {
+                    section.dirty = false;
                 }
             }
         }
-        info!("Updated Meshes");
+    }
+    /// Sets a block in a loaded chunk. Returns false if the chunk is not loaded
+    ///
+    /// Blocks on the border of a section also cause the sections next to it to be re-meshed
+    pub fn set_block(&mut self, position: BlockPosition, block: u32) -> bool {
+        let chunk_position = position.chunk();
+        let Some(chunk) = self.chunks.get_mut(&chunk_position) else {
+            return false;
+        };
+        chunk.set_block(position, block);
+        mark_sections_around(&mut self.chunks, position);
+        true
+    }
+    /// Breaks the first block along the ray. Returns where the block was
+    pub fn break_block(
+        &mut self,
+        origin: RawPosition,
+        direction: Vec3,
+        reach: f64,
+    ) -> Option<BlockPosition> {
+        let hit = self.raycast(origin, direction, reach)?;
+        self.set_block(hit.position, 0).then_some(hit.position)
+    }
+    /// Places a block against the face of the first block along the ray. Returns where the block was placed
+    ///
+    /// Nothing is placed if the space is not air or is outside of the loaded world
+    pub fn place_block(
+        &mut self,
+        origin: RawPosition,
+        direction: Vec3,
+        reach: f64,
+        block: u32,
+    ) -> Option<BlockPosition> {
+        let hit = self.raycast(origin, direction, reach)?;
+        let position = hit.position.offset(hit.face);
+        if self.get_block(position) != Some(Block::Block(0)) {
+            return None;
+        }
+        self.set_block(position, block).then_some(position)
+    }
+    /// Updates the light and the meshes of the sections changed by [World::set_block] without waiting for the next tick
+    ///
+    /// Only the changed sections and the sections next to them are re-meshed
+    pub fn apply_block_changes(&mut self, game: &Arc<Game>, sender: &Sender<ChunkUpdates>) {
+        self.update_light(&game.block_registery);
+        self.rebuild_dirty_sections(game, sender);
+    }
+    /// The block at the position. None if its chunk is not loaded or it is above or below the world
+    pub fn get_block(&self, position: BlockPosition) -> Option<Block> {
+        self.chunks.get(&position.chunk())?.block(position)
+    }
+    /// The first block along the ray that is not air. Chunks that are not loaded are seen through. See [raycast]
+    pub fn raycast(
+        &self,
+        origin: RawPosition,
+        direction: Vec3,
+        max_distance: f64,
+    ) -> Option<RaycastHit> {
+        raycast::raycast(origin, direction, max_distance, |position| {
+            self.get_block(position)
+                .is_some_and(|block| block != Block::Block(0))
+        })
+    }
+    /// Spreads the light changes caused by blocks that were set since the last update
+    pub fn update_light(&mut self, registry: &BlockRegistery) {
+        let changed: Vec<BlockPosition> = self
+            .chunks
+            .values_mut()
+            .flat_map(|chunk| chunk.light_updates.drain(..))
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+        light::update_blocks(&mut LoadedChunks(&mut self.chunks), registry, &changed);
+    }
+    /// Saves the chunk if it has changed then drops it and its cached meshes
+    ///
+    /// Chunks that fail to save stay loaded so their changes are not lost
+    fn unload_chunk(&mut self, position: ChunkPosition) {
+        let Some(chunk) = self.chunks.get(&position) else {
+            return;
+        };
+        if chunk.needs_saving {
+            let Some(storage) = self.storage.as_ref() else {
+                // The world only lives in memory
+                return;
+            };
+            if let Err(err) = lock_storage(storage).save_chunk(chunk) {
+                error!(
+                    "Failed to save chunk {:?}. Keeping it loaded: {}",
+                    position, err
+                );
+                return;
+            }
+        }
+        debug!("Unloading chunk {:?}", position);
+        for index in 0..chunk.sections.len() {
+            self.built_meshes.remove(&chunk.section_position(index));
+        }
+        self.chunks.remove(&position);
     }
 }
+/// Marks the sections of the neighbouring chunks that touch a block as dirty
+///
+/// Blocks on the x or z border of a chunk are culled against and lit by the chunk next to it.
+/// Blocks on the edges of a section also shade the corners of the sections diagonal to it
+fn mark_sections_around(chunks: &mut HashMap<ChunkPosition, Chunk>, position: BlockPosition) {
+    let chunk_position = position.chunk();
+    let section = position.section();
+    let mut sections = vec![section];
+    match position.y & 0xF {
+        0 if section > 0 => sections.push(section - 1),
+        15 if section + 1 < 16 => sections.push(section + 1),
+        _ => {}
+    }
+    let x_face = match position.x & 0xF {
+        0 => Some(Face::West),
+        15 => Some(Face::East),
+        _ => None,
+    };
+    let z_face = match position.z & 0xF {
+        0 => Some(Face::South),
+        15 => Some(Face::North),
+        _ => None,
+    };
+    let mut touching = Vec::with_capacity(3);
+    touching.extend(x_face.map(|face| chunk_position.neighbour(face)));
+    touching.extend(z_face.map(|face| chunk_position.neighbour(face)));
+    if let (Some(x_face), Some(z_face)) = (x_face, z_face) {
+        touching.push(chunk_position.neighbour(x_face).neighbour(z_face));
+    }
+    for neighbour in touching {
+        if let Some(neighbour) = chunks.get_mut(&neighbour) {
+            for section in &sections {
+                neighbour.mark_section_dirty(*section);
+            }
+        }
+    }
+}
+/// Workers only read from the storage so a job that panicked while holding the lock did not leave it half written
+fn lock_storage(storage: &Mutex<RegionStorage>) -> MutexGuard<'_, RegionStorage> {
+    storage
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
 
 #[cfg(test)]
 mod tests {
+    use glam::I64Vec3;
+
+    use crate::{
+        game::{
+            blocks::{test_block_registery, test_block_registery_from},
+            test_game,
+        },
+        test_directory::TestDirectory,
+        world::chunk::ALL_SECTIONS,
+    };
+
     use super::*;
 
     #[test]
     fn test_chunk_generation() {
-        let world = World::generate_test_world();
+        let world = World::generate_test_world(&test_block_registery());
         println!("{:?}", world.chunks)
     }
+
+    #[test]
+    fn test_generation_is_deterministic() {
+        let position = ChunkPosition::new(7, -3);
+        let world = World::new(WorldSeed::new(1234));
+        let other_world = World::new(WorldSeed::new(1234));
+        let registry = test_block_registery();
+        let chunk = Chunk::generate(position, world.seed, &world.noise, &registry);
+        let same_chunk = Chunk::generate(position, other_world.seed, &other_world.noise, &registry);
+        assert_eq!(chunk.sections, same_chunk.sections);
+        // Generated terrain is not saved until it is changed
+        assert!(!chunk.needs_saving);
+
+        let different_seed = World::new(WorldSeed::new(4321));
+        let different_chunk = Chunk::generate(
+            position,
+            different_seed.seed,
+            &different_seed.noise,
+            &registry,
+        );
+        assert_ne!(chunk.sections, different_chunk.sections);
+    }
+
+    #[test]
+    fn test_generated_blocks_are_picked_by_name() {
+        let registry = test_block_registery_from(
+            "[[blocks]]\nname = \"glass\"\n[[blocks]]\nname = \"iron_ore\"\n[[blocks]]\nname = \"dirt\"",
+        );
+        let world = World::new(WorldSeed::new(1234));
+        let chunk = Chunk::generate(
+            ChunkPosition::new(0, 0),
+            world.seed,
+            &world.noise,
+            &registry,
+        );
+        let generated = [
+            0,
+            registry.default_state("iron_ore").unwrap(),
+            registry.default_state("dirt").unwrap(),
+        ];
+        let mut found = Vec::new();
+        for x in 0..16 {
+            for z in 0..16 {
+                let Some(Block::Block(state)) = chunk.block(BlockPosition::new(x, 0, z)) else {
+                    panic!("Block outside of the chunk");
+                };
+                assert!(generated.contains(&state), "Generated state {state}");
+                found.push(state);
+            }
+        }
+        assert!(found.contains(&generated[1]) && found.contains(&generated[2]));
+    }
+
+    #[test]
+    fn test_chunk_seeds_are_unique() {
+        let seed = WorldSeed::new(0);
+        let mut seeds = ahash::HashSet::default();
+        for x in -16..16 {
+            for z in -16..16 {
+                assert!(seeds.insert(seed.chunk_seed(ChunkPosition::new(x, z))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_border_blocks_dirty_neighbours() {
+        let mut world = World::generate_test_world(&test_block_registery());
+        for chunk in world.chunks.values_mut() {
+            chunk
+                .sections
+                .iter_mut()
+                .for_each(|section| section.dirty = false);
+        }
+        // The west edge of chunk 0,0 touches chunk -1,0
+        assert!(world.set_block(BlockPosition::new(0, 20, 5), 0));
+        assert!(world.chunks[&ChunkPosition::new(0, 0)].sections[1].dirty);
+        assert!(world.chunks[&ChunkPosition::new(-1, 0)].sections[1].dirty);
+        assert!(!world.chunks[&ChunkPosition::new(0, -1)].sections[1].dirty);
+        // The bottom of section 2 touches section 1
+        assert!(world.set_block(BlockPosition::new(5, 32, -5), 0));
+        let chunk = &world.chunks[&ChunkPosition::new(0, -1)];
+        assert!(chunk.sections[1].dirty && chunk.sections[2].dirty);
+        assert!(!world.set_block(BlockPosition::new(100, 0, 0), 1));
+        // The corner of a section shades the sections diagonal to it
+        for chunk in world.chunks.values_mut() {
+            chunk
+                .sections
+                .iter_mut()
+                .for_each(|section| section.dirty = false);
+        }
+        assert!(world.set_block(BlockPosition::new(0, 31, 0), 0));
+        let diagonal = &world.chunks[&ChunkPosition::new(-1, -1)];
+        assert!(diagonal.sections[1].dirty && diagonal.sections[2].dirty);
+        assert!(!diagonal.sections[0].dirty);
+    }
+
+    #[test]
+    fn test_section_neighbours_include_edges_and_corners() {
+        let registry = test_block_registery();
+        let mut world = World::generate_test_world(&registry);
+        for block in [1, 0] {
+            world.set_block(BlockPosition::new(0, 16, 0), block);
+            let opaque = block != 0;
+            // Past the vertical edge of the diagonal chunk
+            let position = ChunkPosition::new(-1, -1);
+            let neighbours = ChunkNeighbours::collect(&world.chunks, position, &registry);
+            let chunk = &world.chunks[&position];
+            let section = chunk.section_neighbours(1, &neighbours, &registry);
+            assert_eq!(section.is_outer_opaque(I64Vec3::new(16, 0, 16)), opaque);
+            let below = chunk.section_neighbours(0, &neighbours, &registry);
+            assert_eq!(below.is_outer_opaque(I64Vec3::new(16, 16, 16)), opaque);
+            // Past the top edge of the chunk on the side
+            let position = ChunkPosition::new(-1, 0);
+            let neighbours = ChunkNeighbours::collect(&world.chunks, position, &registry);
+            let below = world.chunks[&position].section_neighbours(0, &neighbours, &registry);
+            assert_eq!(below.is_outer_opaque(I64Vec3::new(16, 16, 0)), opaque);
+        }
+    }
+
+    #[test]
+    fn test_block_ids_are_remapped_on_open() -> anyhow::Result<()> {
+        let directory = TestDirectory::new("world-ids");
+        let old =
+            test_block_registery_from("[[blocks]]\nname = \"dirt\"\n[[blocks]]\nname = \"ice\"");
+        let new =
+            test_block_registery_from("[[blocks]]\nname = \"ice\"\n[[blocks]]\nname = \"dirt\"");
+        let position = BlockPosition::new(-20, 5, 40);
+        {
+            let mut world = World::open(&directory, Some(WorldSeed::new(1)), &old)?;
+            let mut chunk = Chunk::new(position.chunk());
+            chunk.set_block(position, old.default_state("ice").unwrap());
+            chunk.needs_saving = true;
+            world.chunks.insert(chunk.position, chunk);
+            assert_eq!(world.save_dirty_chunks()?, 1);
+        }
+        let world = World::open(&directory, None, &new)?;
+        let chunk = workers::load_saved_chunk(world.storage.as_deref(), position.chunk()).unwrap();
+        let Block::Block(id) = chunk.sections[0].block_store[position.relative_block()];
+        assert_eq!(id, new.default_state("ice").unwrap());
+        assert_eq!(
+            BlockIdMap::load(&directory)?,
+            Some(BlockIdMap::from_registry(&new))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stopped_block_id_remap_is_finished_on_open() -> anyhow::Result<()> {
+        let directory = TestDirectory::new("world-ids-stopped");
+        let old =
+            test_block_registery_from("[[blocks]]\nname = \"dirt\"\n[[blocks]]\nname = \"ice\"");
+        let new =
+            test_block_registery_from("[[blocks]]\nname = \"ice\"\n[[blocks]]\nname = \"dirt\"");
+        let position = BlockPosition::new(3, 5, -7);
+        let ice_at = |world: &World| {
+            let chunk =
+                workers::load_saved_chunk(world.storage.as_deref(), position.chunk()).unwrap();
+            let Block::Block(id) = chunk.sections[0].block_store[position.relative_block()];
+            id
+        };
+        {
+            let mut world = World::open(&directory, Some(WorldSeed::new(1)), &old)?;
+            let mut chunk = Chunk::new(position.chunk());
+            chunk.set_block(position, old.default_state("ice").unwrap());
+            world.chunks.insert(chunk.position, chunk);
+            world.save_dirty_chunks()?;
+        }
+        let region = position.chunk().region();
+        let remap = BlockIdMap::from_registry(&old).remap(&new).unwrap();
+        {
+            // Rewritten regions without the new map are discarded
+            let mut storage = RegionStorage::open(&directory)?;
+            storage.rewrite_saved_chunks(|chunk| chunk.remap_blocks(|id| remap[id as usize]))?;
+            assert!(storage.rewritten_region_path(region).exists());
+        }
+        let world = World::open(&directory, None, &old)?;
+        assert_eq!(ice_at(&world), old.default_state("ice").unwrap());
+        let storage = lock_storage(world.storage.as_ref().unwrap());
+        assert!(!storage.rewritten_region_path(region).exists());
+        drop(storage);
+        drop(world);
+        {
+            // Stopped after the new map was written
+            let mut storage = RegionStorage::open(&directory)?;
+            storage.rewrite_saved_chunks(|chunk| chunk.remap_blocks(|id| remap[id as usize]))?;
+            BlockIdMap::from_registry(&new).save_migration(&directory)?;
+        }
+        let world = World::open(&directory, None, &new)?;
+        assert_eq!(ice_at(&world), new.default_state("ice").unwrap());
+        assert!(!BlockIdMap::migration_in_progress(&directory));
+        assert_eq!(
+            BlockIdMap::load(&directory)?,
+            Some(BlockIdMap::from_registry(&new))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_break_and_place_update_only_changed_sections() {
+        let (sender, receiver) = flume::unbounded();
+        let game = test_game();
+        let mut world = World::new(WorldSeed::default());
+        for x in 0..2 {
+            let position = ChunkPosition::new(x, 0);
+            let mut chunk = Chunk::new(position);
+            for x in 0..16 {
+                for z in 0..16 {
+                    chunk.set_block(BlockPosition::new(position.x * 16 + x, 16, z), 1);
+                }
+            }
+            world.chunks.insert(position, chunk);
+        }
+        for position in [ChunkPosition::new(0, 0), ChunkPosition::new(1, 0)] {
+            world.show_chunk(position, &game, &sender);
+        }
+        assert_eq!(receiver.drain().count(), 2);
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let updated = |receiver: &flume::Receiver<ChunkUpdates>| {
+            let mut updated: Vec<_> = receiver
+                .drain()
+                .map(|update| match update {
+                    ChunkUpdates::Update(mesh) => mesh.position,
+                    other => panic!("Expected an update. Got {other:?}"),
+                })
+                .collect();
+            updated.sort_by_key(|position| position.x);
+            updated
+        };
+
+        // On the border of both chunks and of sections 0 and 1
+        let origin = RawPosition::new(15.5, 20.5, 5.5);
+        let broken = BlockPosition::new(15, 16, 5);
+        assert_eq!(world.break_block(origin, down, 6.0), Some(broken));
+        assert_eq!(world.get_block(broken), Some(Block::Block(0)));
+        world.apply_block_changes(&game, &sender);
+        assert_eq!(
+            updated(&receiver),
+            [I64Vec3::new(0, 16, 0), I64Vec3::new(16, 16, 0)]
+        );
+        // Nothing left to hit below the hole
+        assert_eq!(world.break_block(origin, down, 6.0), None);
+
+        let origin = RawPosition::new(3.5, 20.5, 3.5);
+        let placed = BlockPosition::new(3, 17, 3);
+        assert_eq!(world.place_block(origin, down, 6.0, 2), Some(placed));
+        assert_eq!(world.get_block(placed), Some(Block::Block(2)));
+        world.apply_block_changes(&game, &sender);
+        assert_eq!(updated(&receiver), [I64Vec3::new(0, 16, 0)]);
+        // Out of reach
+        assert_eq!(world.place_block(origin, down, 2.0, 2), None);
+        // The ray starts inside of the layer so the block would go where the placed block is
+        let inside = RawPosition::new(3.5, 16.5, 3.5);
+        assert_eq!(world.place_block(inside, down, 6.0, 3), None);
+        assert_eq!(world.get_block(placed), Some(Block::Block(2)));
+        // The west face of the layer is on the border of the loaded world
+        let outside = RawPosition::new(-0.5, 16.5, 5.5);
+        let east = Vec3::new(1.0, 0.0, 0.0);
+        assert_eq!(
+            world
+                .raycast(outside, east, 6.0)
+                .map(|hit| (hit.position, hit.face)),
+            Some((BlockPosition::new(0, 16, 5), Face::West))
+        );
+        assert_eq!(world.place_block(outside, east, 6.0, 3), None);
+        assert_eq!(world.get_block(BlockPosition::new(-1, 16, 5)), None);
+    }
+
+    /// A player standing in the middle of a chunk
+    fn player_in_chunk(x: i64, z: i64) -> Player {
+        Player {
+            position: RawPosition::new(x as f64 * 16.0 + 8.0, 40.0, z as f64 * 16.0 + 8.0),
+            rotation: position::RawRotation::new(0.0, 0.0),
+            selected_block: 1,
+        }
+    }
+    /// A chunk with a single layer of blocks in its second section
+    fn layer_chunk(position: ChunkPosition) -> Chunk {
+        let mut chunk = Chunk::new(position);
+        for x in 0..16 {
+            for z in 0..16 {
+                chunk.set_generated_block(
+                    BlockPosition::new(position.x * 16 + x, 16, position.z * 16 + z),
+                    1,
+                );
+            }
+        }
+        chunk
+    }
+    /// The adds and removes of the section at `position`. Chunks finished by the workers are ignored
+    fn section_updates(
+        receiver: &flume::Receiver<ChunkUpdates>,
+        position: BlockPosition,
+    ) -> Vec<&'static str> {
+        let position: I64Vec3 = position.into();
+        receiver
+            .drain()
+            .filter_map(|update| match update {
+                ChunkUpdates::Add(mesh) if mesh.position == position => Some("add"),
+                ChunkUpdates::Remove(removed) if removed == position => Some("remove"),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_chunks_in_render_distance_are_loaded() {
+        let (sender, receiver) = flume::unbounded();
+        let game = test_game();
+        let mut world = World::new(WorldSeed::default());
+        let loaded = ChunkPosition::new(1, -1);
+        world.chunks.insert(loaded, layer_chunk(loaded));
+        world.tick(&player_in_chunk(0, 0), game.clone(), &sender);
+        assert_eq!(world.center, Some(ChunkPosition::new(0, 0)));
+        for x in -2..=2 {
+            for z in -2..=2 {
+                let position = ChunkPosition::new(x, z);
+                let in_range = x.abs() <= 1 && z.abs() <= 1;
+                assert_eq!(
+                    world.workers.is_queued(position),
+                    in_range && position != loaded,
+                    "{position:?}"
+                );
+            }
+        }
+        let section = world.chunks[&loaded].section_position(1);
+        assert!(world.meshes_being_rendered.contains_key(&section));
+        assert_eq!(section_updates(&receiver, section), ["add"]);
+        // Chunks that have not started are dropped once they are out of range
+        world.tick(&player_in_chunk(10, 0), game, &sender);
+        assert!(world.workers.number_pending() <= 9);
+        assert!(world.workers.is_queued(ChunkPosition::new(11, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_chunks_are_hidden_then_unloaded_past_the_hysteresis() {
+        let (sender, receiver) = flume::unbounded();
+        let game = test_game();
+        // Render distance 1. Hidden past 2 chunks and unloaded past 3
+        assert_eq!(game.game_settings.chunk_hide_distance(), 2);
+        assert_eq!(game.game_settings.chunk_unload_distance(), 3);
+        let mut world = World::new(WorldSeed::default());
+        let position = ChunkPosition::new(0, 0);
+        world.chunks.insert(position, layer_chunk(position));
+        let section = world.chunks[&position].section_position(1);
+        world.tick(&player_in_chunk(0, 0), game.clone(), &sender);
+        assert_eq!(section_updates(&receiver, section), ["add"]);
+
+        // Still inside of the hysteresis band
+        world.tick(&player_in_chunk(2, 0), game.clone(), &sender);
+        assert!(section_updates(&receiver, section).is_empty());
+        assert!(world.meshes_being_rendered.contains_key(&section));
+
+        world.tick(&player_in_chunk(3, 0), game.clone(), &sender);
+        assert_eq!(section_updates(&receiver, section), ["remove"]);
+        assert!(world.built_meshes.contains_key(&section));
+        assert!(world.chunks.contains_key(&position));
+
+        world.tick(&player_in_chunk(4, 0), game, &sender);
+        assert!(!world.chunks.contains_key(&position));
+        assert!(!world.built_meshes.contains_key(&section));
+        assert!(section_updates(&receiver, section).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hidden_chunks_are_shown_again() {
+        let (sender, receiver) = flume::unbounded();
+        let game = test_game();
+        let mut world = World::new(WorldSeed::default());
+        let position = ChunkPosition::new(0, 0);
+        world.chunks.insert(position, layer_chunk(position));
+        let section = world.chunks[&position].section_position(1);
+        world.tick(&player_in_chunk(0, 0), game.clone(), &sender);
+        world.tick(&player_in_chunk(3, 0), game.clone(), &sender);
+        assert_eq!(section_updates(&receiver, section), ["add", "remove"]);
+
+        // Coming back before the chunk is unloaded reuses its mesh
+        world.tick(&player_in_chunk(1, 0), game, &sender);
+        assert_eq!(section_updates(&receiver, section), ["add"]);
+        assert!(world.meshes_being_rendered.contains_key(&section));
+        assert!(!world.built_meshes.contains_key(&section));
+        assert!(world.chunks.contains_key(&position));
+    }
+
+    /// Picks up finished jobs until a remesh of `position` is finished
+    async fn wait_for_remesh(world: &mut World, position: ChunkPosition) -> RemeshedChunk {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+        loop {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "Remesh never finished"
+            );
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            // Only one job is ever running
+            if let Some(job) = world.workers.finished().pop() {
+                match job {
+                    FinishedJob::Remeshed(remeshed) if remeshed.position == position => {
+                        return remeshed
+                    }
+                    other => panic!("Unexpected job {other:?}"),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_neighbours_of_finished_chunks_are_remeshed_on_the_workers() {
+        let (sender, receiver) = flume::unbounded();
+        let game = test_game();
+        let mut world = World::new(WorldSeed::default());
+        let position = ChunkPosition::new(0, 0);
+        world.chunks.insert(position, layer_chunk(position));
+        let section: I64Vec3 = world.chunks[&position].section_position(1).into();
+        let player = player_in_chunk(0, 0);
+        world.tick(&player, game.clone(), &sender);
+        receiver.drain().for_each(drop);
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+        let mut remeshed = false;
+        let mut updates = Vec::new();
+        while world.chunks.len() < 9 || world.workers.number_in_flight() > 0 {
+            assert!(std::time::Instant::now() < deadline, "Chunks never loaded");
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            world.tick(&player, game.clone(), &sender);
+            remeshed |= world.workers.is_remeshing(position);
+            updates.extend(receiver.drain().filter_map(|update| match update {
+                ChunkUpdates::Update(mesh) if mesh.position == section => Some("update"),
+                ChunkUpdates::Add(mesh) if mesh.position == section => Some("add"),
+                ChunkUpdates::Remove(removed) if removed == section => Some("remove"),
+                _ => None,
+            }));
+        }
+        assert!(remeshed);
+        assert!(!updates.is_empty());
+        assert!(
+            updates.iter().all(|update| *update == "update"),
+            "{updates:?}"
+        );
+    }
+
+    #[test]
+    fn test_only_sections_next_to_a_new_chunk_are_remeshed() {
+        let registry = test_block_registery();
+        let mut chunk = layer_chunk(ChunkPosition::new(0, 0));
+        light::light_chunk(&mut chunk, &registry);
+        // The layer and the dark section below it. Everything above is open sky
+        assert_eq!(chunk.sections_facing(Face::East, &registry), 0b111);
+        assert_eq!(
+            chunk.sections_shaded_diagonally(Face::West, Face::South, &registry),
+            0b111
+        );
+        assert_eq!(
+            Chunk::new(ChunkPosition::new(0, 0)).sections_shaded_diagonally(
+                Face::West,
+                Face::South,
+                &registry
+            ),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_out_of_date_remeshes_are_redone() {
+        let (sender, receiver) = flume::unbounded();
+        let game = test_game();
+        let mut world = World::new(WorldSeed::default());
+        let position = ChunkPosition::new(0, 0);
+        world.chunks.insert(position, layer_chunk(position));
+        world.show_chunk(position, &game, &sender);
+        receiver.drain().for_each(drop);
+
+        world.queue_remesh(position, ALL_SECTIONS);
+        world.start_remeshes(&game);
+        assert!(world.workers.is_remeshing(position));
+        // Placed while the worker is meshing the chunk
+        assert!(world.set_block(BlockPosition::new(3, 17, 3), 2));
+        world.apply_block_changes(&game, &sender);
+        assert_eq!(receiver.drain().count(), 1);
+
+        let out_of_date = wait_for_remesh(&mut world, position).await;
+        assert_ne!(out_of_date.revision, world.chunks[&position].revision);
+        world.apply_remesh(out_of_date, &game, &sender);
+        assert!(receiver.is_empty());
+        assert!(world.workers.is_remesh_queued(position));
+        world.start_remeshes(&game);
+        assert!(world.workers.is_remeshing(position));
+
+        let remeshed = wait_for_remesh(&mut world, position).await;
+        assert_eq!(remeshed.revision, world.chunks[&position].revision);
+        world.apply_remesh(remeshed, &game, &sender);
+        let updates: Vec<_> = receiver.drain().collect();
+        assert!(
+            matches!(updates.as_slice(), [ChunkUpdates::Update(mesh)] if mesh.position == BlockPosition::new(0, 16, 0).into()),
+            "{updates:?}"
+        );
+    }
+
+    #[test]
+    fn test_chunk_neighbours_match_borders() {
+        let registry = test_block_registery();
+        let world = World::generate_test_world(&registry);
+        let position = ChunkPosition::new(-1, -1);
+        let neighbours = ChunkNeighbours::collect(&world.chunks, position, &registry);
+        assert!(neighbours.is_loaded(Face::North) && neighbours.is_loaded(Face::East));
+        assert!(!neighbours.is_loaded(Face::South) && !neighbours.is_loaded(Face::West));
+        let north = &world.chunks[&position.neighbour(Face::North)];
+        for section in 0..16 {
+            assert_eq!(
+                neighbours.layer(Face::North, section),
+                Some(
+                    north.sections[section]
+                        .border_layer(Face::South, &registry)
+                        .as_ref()
+                )
+            );
+        }
+    }
 }
@@ -1,6 +1,8 @@
 use derive_more::From;
 use glam::{I64Vec3, UVec3, Vec3};
 
+use crate::engine::voxel::Face;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, From)]
 pub struct ChunkPosition {
     pub x: i64,
@@ -10,6 +12,50 @@ impl ChunkPosition {
     pub fn new(x: i64, z: i64) -> Self {
         Self { x, z }
     }
+    /// The region file this chunk is stored in
+    pub fn region(&self) -> RegionPosition {
+        RegionPosition {
+            x: self.x >> 5,
+            z: self.z >> 5,
+        }
+    }
+    /// The horizontal distance squared from the center of the chunk to the position
+    pub fn distance_squared(&self, position: &RawPosition) -> f64 {
+        let x = (self.x * 16 + 8) as f64 - position.x;
+        let z = (self.z * 16 + 8) as f64 - position.z;
+        x * x + z * z
+    }
+    /// The chunk on the other side of the face. Top and Bottom return the same chunk
+    pub fn neighbour(&self, face: Face) -> Self {
+        match face {
+            Face::North => Self::new(self.x, self.z + 1),
+            Face::South => Self::new(self.x, self.z - 1),
+            Face::East => Self::new(self.x + 1, self.z),
+            Face::West => Self::new(self.x - 1, self.z),
+            Face::Top | Face::Bottom => *self,
+        }
+    }
+    /// The number of chunks between the two positions along the furthest axis
+    pub fn distance(&self, other: &ChunkPosition) -> i64 {
+        (self.x - other.x).abs().max((self.z - other.z).abs())
+    }
+    /// The index of this chunk inside of its region. Will be 0-1023
+    pub fn region_index(&self) -> usize {
+        let x = (self.x & 0x1F) as usize;
+        let z = (self.z & 0x1F) as usize;
+        (z << 5) | x
+    }
+}
+/// A region is 32x32 chunks stored in a single file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, From)]
+pub struct RegionPosition {
+    pub x: i64,
+    pub z: i64,
+}
+impl RegionPosition {
+    pub fn new(x: i64, z: i64) -> Self {
+        Self { x, z }
+    }
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq, From, Hash)]
 pub struct BlockPosition {
@@ -36,6 +82,7 @@ macro_rules! convert_position_type {
 convert_position_type!(BlockPosition => UVec3 as u32);
 convert_position_type!(BlockPosition => I64Vec3 as i64);
 convert_position_type!(BlockPosition => Vec3 as f32);
+convert_position_type!(I64Vec3 => BlockPosition as i64);
 
 impl BlockPosition {
     pub fn chunk(&self) -> ChunkPosition {
@@ -44,6 +91,17 @@ impl BlockPosition {
             z: self.z >> 4,
         }
     }
+    /// The block on the other side of the face. Matches [ChunkPosition::neighbour]
+    pub fn offset(&self, face: Face) -> Self {
+        match face {
+            Face::North => Self::new(self.x, self.y, self.z + 1),
+            Face::South => Self::new(self.x, self.y, self.z - 1),
+            Face::East => Self::new(self.x + 1, self.y, self.z),
+            Face::West => Self::new(self.x - 1, self.y, self.z),
+            Face::Top => Self::new(self.x, self.y + 1, self.z),
+            Face::Bottom => Self::new(self.x, self.y - 1, self.z),
+        }
+    }
     pub fn section(&self) -> usize {
         (self.y as usize >> 4)
     }
@@ -120,6 +178,12 @@ impl RawRotation {
     pub fn new(yaw: f32, pitch: f32) -> Self {
         Self { yaw, pitch }
     }
+    /// The direction the rotation looks in. Matches the camera
+    pub fn direction(&self) -> Vec3 {
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        Vec3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw)
+    }
 }
 
 impl Into<[f32; 2]> for RawRotation {
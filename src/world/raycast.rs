@@ -0,0 +1,196 @@
+/*!
+# Raycasting
+
+Walks a ray through the block grid one block at a time. The next block is always the one whose border the ray reaches first, so no block the ray touches is skipped.
+See "A Fast Voxel Traversal Algorithm for Ray Tracing" by Amanatides and Woo.
+*/
+use glam::{DVec3, Vec3};
+
+use crate::engine::voxel::Face;
+
+use super::position::{BlockPosition, RawPosition};
+
+/// The block a ray hit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+    pub position: BlockPosition,
+    /// The face of the block the ray entered through. The block on the other side is where a block would be placed
+    ///
+    /// If the ray starts inside of the block it is the face the ray would have entered through
+    pub face: Face,
+    /// Where the ray touched the block
+    pub point: RawPosition,
+    /// From the origin to the point
+    pub distance: f64,
+}
+/// Walks from `origin` along `direction` until `is_solid` returns true or the ray is longer than `max_distance`
+///
+/// None if nothing was hit, the direction has no length or `max_distance` is not finite
+pub fn raycast(
+    origin: RawPosition,
+    direction: Vec3,
+    max_distance: f64,
+    mut is_solid: impl FnMut(BlockPosition) -> bool,
+) -> Option<RaycastHit> {
+    // An endless ray would never stop walking through unloaded chunks
+    if !max_distance.is_finite() {
+        return None;
+    }
+    let direction = direction.as_dvec3().try_normalize()?;
+    let origin = DVec3::new(origin.x, origin.y, origin.z);
+    let mut block = origin.floor().as_i64vec3();
+    let step = direction.signum().as_i64vec3();
+    // The distance along the ray to the next border on every axis
+    let mut next_border = DVec3::ZERO;
+    // The distance along the ray between two borders on every axis
+    let mut border_distance = DVec3::ZERO;
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            next_border[axis] = f64::INFINITY;
+            border_distance[axis] = f64::INFINITY;
+            continue;
+        }
+        let border = if direction[axis] > 0.0 {
+            block[axis] as f64 + 1.0
+        } else {
+            block[axis] as f64
+        };
+        next_border[axis] = (border - origin[axis]) / direction[axis];
+        border_distance[axis] = 1.0 / direction[axis].abs();
+    }
+    let longest = (0..3)
+        .max_by(|a, b| direction[*a].abs().total_cmp(&direction[*b].abs()))
+        .unwrap_or(0);
+    let mut face = Face::from_axis(longest, direction[longest] < 0.0);
+    let mut distance = 0.0;
+    while distance <= max_distance {
+        let position = BlockPosition::from(block);
+        if is_solid(position) {
+            let point = origin + direction * distance;
+            return Some(RaycastHit {
+                position,
+                face,
+                point: RawPosition::new(point.x, point.y, point.z),
+                distance,
+            });
+        }
+        let axis = (0..3)
+            .min_by(|a, b| next_border[*a].total_cmp(&next_border[*b]))
+            .unwrap_or(0);
+        distance = next_border[axis];
+        next_border[axis] += border_distance[axis];
+        block[axis] += step[axis];
+        // Moving towards positive enters the block through its negative face
+        face = Face::from_axis(axis, step[axis] < 0);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{
+        chunk::{Block, Chunk},
+        position::ChunkPosition,
+        seed::WorldSeed,
+        World,
+    };
+
+    fn cast(
+        origin: [f64; 3],
+        direction: [f32; 3],
+        max_distance: f64,
+        solid: &[BlockPosition],
+    ) -> Option<RaycastHit> {
+        raycast(
+            origin.into(),
+            Vec3::from(direction),
+            max_distance,
+            |position| solid.contains(&position),
+        )
+    }
+
+    #[test]
+    fn test_straight_rays() {
+        let wall = BlockPosition::new(5, 0, 0);
+        let hit = cast([0.5, 0.5, 0.5], [1.0, 0.0, 0.0], 10.0, &[wall]).unwrap();
+        assert_eq!(hit.position, wall);
+        assert_eq!(hit.face, Face::West);
+        assert_eq!(hit.distance, 4.5);
+        assert_eq!(hit.point, RawPosition::new(5.0, 0.5, 0.5));
+        assert_eq!(hit.position.offset(hit.face), BlockPosition::new(4, 0, 0));
+        // Too far away
+        assert_eq!(cast([0.5, 0.5, 0.5], [1.0, 0.0, 0.0], 4.0, &[wall]), None);
+
+        let floor = BlockPosition::new(0, -3, 0);
+        let hit = cast([0.5, 0.5, 0.5], [0.0, -1.0, 0.0], 10.0, &[floor]).unwrap();
+        assert_eq!((hit.position, hit.face), (floor, Face::Top));
+        assert_eq!(cast([0.5, 0.5, 0.5], [0.0; 3], 10.0, &[floor]), None);
+        for max_distance in [f64::INFINITY, f64::NAN] {
+            assert_eq!(
+                cast([0.5, 0.5, 0.5], [0.0, -1.0, 0.0], max_distance, &[floor]),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_ray_starting_inside_a_block() {
+        let block = BlockPosition::new(0, 0, 0);
+        let hit = cast([0.5, 0.5, 0.5], [0.0, 0.0, -1.0], 10.0, &[block]).unwrap();
+        assert_eq!(
+            (hit.position, hit.face, hit.distance),
+            (block, Face::North, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_rays_cross_chunk_and_section_borders() {
+        let mut world = World::new(WorldSeed::default());
+        for (x, z) in [(0, 0), (-1, 0), (0, -1), (-1, -1)] {
+            let position = ChunkPosition::new(x, z);
+            world.chunks.insert(position, Chunk::new(position));
+        }
+        // From section 1 of chunk 0,0 into section 0 of chunk -1,-1
+        let target = BlockPosition::new(-3, 14, -3);
+        assert!(world.set_block(target, 1));
+        let origin = RawPosition::new(2.5, 19.5, 2.5);
+        let hit = world
+            .raycast(origin, Vec3::new(-1.0, -1.0, -1.0), 20.0)
+            .unwrap();
+        assert_eq!(hit.position, target);
+        assert_eq!(
+            (hit.position.chunk(), hit.position.section()),
+            (ChunkPosition::new(-1, -1), 0)
+        );
+        // The diagonal enters through a corner. One of the three faces is picked
+        assert!(matches!(hit.face, Face::Top | Face::North | Face::East));
+        assert_eq!(
+            world.raycast(origin, Vec3::new(-1.0, -1.0, -1.0), 7.0),
+            None
+        );
+
+        // Along the border of the chunks. Exactly through the corner of 4 blocks
+        let mut visited = Vec::new();
+        let origin = RawPosition::new(-1.5, 15.5, 0.5);
+        let hit = raycast(origin, Vec3::new(1.0, 1.0, 0.0), 2.9, |position| {
+            visited.push(position);
+            false
+        });
+        assert_eq!(hit, None);
+        // Only one block is entered at a time
+        for pair in visited.windows(2) {
+            let [a, b] = pair else { unreachable!() };
+            assert_eq!((a.x - b.x).abs() + (a.y - b.y).abs() + (a.z - b.z).abs(), 1);
+        }
+        assert_eq!(visited.first(), Some(&BlockPosition::new(-2, 15, 0)));
+        assert_eq!(visited.last(), Some(&BlockPosition::new(0, 17, 0)));
+        let wall = BlockPosition::new(0, 17, 0);
+        assert!(world.set_block(wall, 2));
+        let hit = world
+            .raycast(origin, Vec3::new(1.0, 1.0, 0.0), 2.9)
+            .unwrap();
+        assert_eq!(hit.position, wall);
+        assert_eq!(world.get_block(wall), Some(Block::Block(2)));
+    }
+}
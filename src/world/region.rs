@@ -0,0 +1,401 @@
+/*!
+# Region Files
+
+Chunks are persisted in region files. Each region file holds 32x32 chunks.
+
+The file starts with an offset table of 1024 entries. Each entry is 8 bytes
+- The sector the chunk starts at as a little endian u32. 0 means the chunk has never been saved
+- The length of the chunk data in bytes as a little endian u32
+
+Chunk data is stored in 4 KiB sectors after the table. The data is a single format version byte followed by the zlib compressed output of [Chunk::write_to]
+*/
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use ahash::{HashMap, HashMapExt};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use thiserror::Error;
+use tracing::debug;
+
+use super::{
+    chunk::Chunk,
+    position::{ChunkPosition, RegionPosition},
+};
+
+pub const SECTOR_SIZE: usize = 4096;
+pub const CHUNKS_PER_REGION: usize = 32 * 32;
+const ENTRY_SIZE: usize = 8;
+/// The offset table takes up the first two sectors of the file
+const HEADER_SECTORS: usize = (CHUNKS_PER_REGION * ENTRY_SIZE) / SECTOR_SIZE;
+/// Version 2 added paletted block stores. Version 1 chunks can still be read
+pub const CHUNK_FORMAT_VERSION: u8 = 2;
+
+#[derive(Debug, Error)]
+pub enum RegionError {
+    #[error("IO Error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Region file {0:?} is corrupted. {1}")]
+    Corrupted(PathBuf, &'static str),
+    #[error("Unsupported chunk format version {0}")]
+    UnsupportedVersion(u8),
+}
+type Result<T> = std::result::Result<T, RegionError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct RegionEntry {
+    sector: u32,
+    length: u32,
+}
+impl RegionEntry {
+    fn is_empty(&self) -> bool {
+        self.sector == 0
+    }
+    fn number_of_sectors(&self) -> usize {
+        sectors_needed(self.length as usize)
+    }
+}
+fn sectors_needed(length: usize) -> usize {
+    length.div_ceil(SECTOR_SIZE)
+}
+/// A single open region file
+#[derive(Debug)]
+pub struct RegionFile {
+    path: PathBuf,
+    file: File,
+    entries: Box<[RegionEntry; CHUNKS_PER_REGION]>,
+    /// Which sectors of the file are in use. The header sectors are always in use
+    used_sectors: Vec<bool>,
+}
+impl RegionFile {
+    /// Opens a region file. Creating it if it does not exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let file_length = file.metadata()?.len() as usize;
+        let mut entries = Box::new([RegionEntry::default(); CHUNKS_PER_REGION]);
+        if file_length < HEADER_SECTORS * SECTOR_SIZE {
+            if file_length != 0 {
+                return Err(RegionError::Corrupted(path, "Missing offset table"));
+            }
+            file.write_all(&[0u8; HEADER_SECTORS * SECTOR_SIZE])?;
+        } else {
+            let mut header = vec![0u8; HEADER_SECTORS * SECTOR_SIZE];
+            file.read_exact(&mut header)?;
+            for (entry, bytes) in entries.iter_mut().zip(header.chunks_exact(ENTRY_SIZE)) {
+                entry.sector = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+                entry.length = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+            }
+        }
+        let total_sectors = sectors_needed(file_length).max(HEADER_SECTORS);
+        let mut used_sectors = vec![false; total_sectors];
+        used_sectors[..HEADER_SECTORS].fill(true);
+        for entry in entries.iter().filter(|e| !e.is_empty()) {
+            let start = entry.sector as usize;
+            let end = start + entry.number_of_sectors();
+            if start < HEADER_SECTORS || end > total_sectors {
+                return Err(RegionError::Corrupted(
+                    path,
+                    "Chunk points outside of the file",
+                ));
+            }
+            used_sectors[start..end].fill(true);
+        }
+        Ok(Self {
+            path,
+            file,
+            entries,
+            used_sectors,
+        })
+    }
+    pub fn contains_chunk(&self, position: ChunkPosition) -> bool {
+        !self.entries[position.region_index()].is_empty()
+    }
+    /// Reads a chunk from the region. Returns None if it has never been saved
+    pub fn read_chunk(&mut self, position: ChunkPosition) -> Result<Option<Chunk>> {
+        let entry = self.entries[position.region_index()];
+        if entry.is_empty() {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; entry.length as usize];
+        self.file
+            .seek(SeekFrom::Start(entry.sector as u64 * SECTOR_SIZE as u64))?;
+        self.file.read_exact(&mut data)?;
+        let Some((version, compressed)) = data.split_first() else {
+            return Err(RegionError::Corrupted(self.path.clone(), "Empty chunk"));
+        };
+        if !(1..=CHUNK_FORMAT_VERSION).contains(version) {
+            return Err(RegionError::UnsupportedVersion(*version));
+        }
+        let chunk = Chunk::read_from(position, &mut ZlibDecoder::new(compressed))?;
+        Ok(Some(chunk))
+    }
+    /// Writes a chunk to the region. Reusing its current sectors if it still fits
+    pub fn write_chunk(&mut self, chunk: &Chunk) -> Result<()> {
+        let mut data = vec![CHUNK_FORMAT_VERSION];
+        let mut encoder = ZlibEncoder::new(&mut data, Compression::fast());
+        chunk.write_to(&mut encoder)?;
+        encoder.finish()?;
+
+        let index = chunk.position.region_index();
+        let old_entry = self.entries[index];
+        let needed = sectors_needed(data.len());
+        let sector = if !old_entry.is_empty() && old_entry.number_of_sectors() >= needed {
+            let start = old_entry.sector as usize;
+            self.used_sectors[start + needed..start + old_entry.number_of_sectors()].fill(false);
+            start
+        } else {
+            if !old_entry.is_empty() {
+                let start = old_entry.sector as usize;
+                self.used_sectors[start..start + old_entry.number_of_sectors()].fill(false);
+            }
+            self.allocate_sectors(needed)
+        };
+        debug!(
+            "Writing chunk {:?} to sector {} of {:?}",
+            chunk.position, sector, self.path
+        );
+        let entry = RegionEntry {
+            sector: sector as u32,
+            length: data.len() as u32,
+        };
+        // Pad to the sector so the file always ends on a sector boundary
+        data.resize(needed * SECTOR_SIZE, 0);
+        self.file
+            .seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64))?;
+        self.file.write_all(&data)?;
+
+        self.entries[index] = entry;
+        let mut entry_bytes = [0u8; ENTRY_SIZE];
+        entry_bytes[0..4].copy_from_slice(&entry.sector.to_le_bytes());
+        entry_bytes[4..8].copy_from_slice(&entry.length.to_le_bytes());
+        self.file
+            .seek(SeekFrom::Start((index * ENTRY_SIZE) as u64))?;
+        self.file.write_all(&entry_bytes)?;
+        Ok(())
+    }
+    /// Finds the first run of free sectors large enough. Growing the file if none exist
+    fn allocate_sectors(&mut self, needed: usize) -> usize {
+        let mut run_start = HEADER_SECTORS;
+        let mut run_length = 0;
+        for (sector, used) in self.used_sectors.iter().enumerate().skip(HEADER_SECTORS) {
+            if *used {
+                run_start = sector + 1;
+                run_length = 0;
+                continue;
+            }
+            run_length += 1;
+            if run_length == needed {
+                break;
+            }
+        }
+        let end = run_start + needed;
+        if end > self.used_sectors.len() {
+            self.used_sectors.resize(end, false);
+        }
+        self.used_sectors[run_start..end].fill(true);
+        run_start
+    }
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+/// The region files of a world. Region files are opened when first accessed and are kept open
+#[derive(Debug)]
+pub struct RegionStorage {
+    pub directory: PathBuf,
+    regions: HashMap<RegionPosition, RegionFile>,
+}
+impl RegionStorage {
+    /// Opens the region storage. Creating the directory if it does not exist
+    pub fn open(directory: impl AsRef<Path>) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            regions: HashMap::new(),
+        })
+    }
+    pub fn region_path(&self, region: RegionPosition) -> PathBuf {
+        self.directory
+            .join(format!("r.{}.{}.kr", region.x, region.z))
+    }
+    /// Gets an open region file. If create is false and the file does not exist None is returned
+    fn region(&mut self, region: RegionPosition, create: bool) -> Result<Option<&mut RegionFile>> {
+        if !self.regions.contains_key(&region) {
+            let path = self.region_path(region);
+            if !create && !path.exists() {
+                return Ok(None);
+            }
+            self.regions.insert(region, RegionFile::open(path)?);
+        }
+        Ok(self.regions.get_mut(&region))
+    }
+    /// Loads a chunk from disk. Returns None if the chunk has never been saved
+    pub fn load_chunk(&mut self, position: ChunkPosition) -> Result<Option<Chunk>> {
+        match self.region(position.region(), false)? {
+            Some(region) => region.read_chunk(position),
+            None => Ok(None),
+        }
+    }
+    pub fn save_chunk(&mut self, chunk: &Chunk) -> Result<()> {
+        let region = self
+            .region(chunk.position.region(), true)?
+            .expect("Region is always created");
+        region.write_chunk(chunk)
+    }
+    /// Where [RegionStorage::rewrite_saved_chunks] writes the new version of a region
+    pub fn rewritten_region_path(&self, region: RegionPosition) -> PathBuf {
+        self.region_path(region).with_extension("kr.new")
+    }
+    /// Every region that has a file in the directory
+    pub fn saved_regions(&self) -> Result<Vec<RegionPosition>> {
+        self.regions_with_suffix(".kr")
+    }
+    /// Every region that has a rewritten file in the directory
+    pub fn rewritten_regions(&self) -> Result<Vec<RegionPosition>> {
+        self.regions_with_suffix(".kr.new")
+    }
+    fn regions_with_suffix(&self, suffix: &str) -> Result<Vec<RegionPosition>> {
+        let mut regions = Vec::new();
+        for entry in std::fs::read_dir(&self.directory)? {
+            let name = entry?.file_name();
+            let Some(coordinates) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("r.")?.strip_suffix(suffix))
+            else {
+                continue;
+            };
+            if let Some((x, z)) = coordinates.split_once('.') {
+                if let (Ok(x), Ok(z)) = (x.parse(), z.parse()) {
+                    regions.push(RegionPosition::new(x, z));
+                }
+            }
+        }
+        Ok(regions)
+    }
+    /// Reads every saved chunk, changes it and writes it to a new file next to its region. Returns the number of chunks that were rewritten
+    ///
+    /// The saved regions are left untouched. Call [RegionStorage::replace_rewritten_regions] once every region has been rewritten
+    pub fn rewrite_saved_chunks(&mut self, mut update: impl FnMut(&mut Chunk)) -> Result<usize> {
+        // Left over from a rewrite that was stopped part way through
+        self.remove_rewritten_regions()?;
+        let mut rewritten = 0;
+        for region in self.saved_regions()? {
+            let mut new_region = RegionFile::open(self.rewritten_region_path(region))?;
+            for index in 0..CHUNKS_PER_REGION {
+                let position = ChunkPosition::new(
+                    region.x * 32 + (index & 0x1F) as i64,
+                    region.z * 32 + (index >> 5) as i64,
+                );
+                let Some(mut chunk) = self.load_chunk(position)? else {
+                    continue;
+                };
+                update(&mut chunk);
+                new_region.write_chunk(&chunk)?;
+                rewritten += 1;
+            }
+            new_region.flush()?;
+        }
+        Ok(rewritten)
+    }
+    /// Moves the files written by [RegionStorage::rewrite_saved_chunks] over the saved regions
+    ///
+    /// Can be called again if it was stopped part way through
+    pub fn replace_rewritten_regions(&mut self) -> Result<()> {
+        self.flush()?;
+        self.regions.clear();
+        for region in self.rewritten_regions()? {
+            std::fs::rename(self.rewritten_region_path(region), self.region_path(region))?;
+        }
+        Ok(())
+    }
+    /// Deletes the files written by [RegionStorage::rewrite_saved_chunks]
+    pub fn remove_rewritten_regions(&self) -> Result<()> {
+        for region in self.rewritten_regions()? {
+            std::fs::remove_file(self.rewritten_region_path(region))?;
+        }
+        Ok(())
+    }
+    /// Flushes all open region files to disk
+    pub fn flush(&mut self) -> Result<()> {
+        for region in self.regions.values_mut() {
+            region.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_directory::TestDirectory,
+        world::{chunk::Block, position::BlockPosition},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_chunk_round_trip() -> anyhow::Result<()> {
+        let directory = TestDirectory::new("region-round-trip");
+        let mut chunk = Chunk::new(ChunkPosition::new(-3, 40));
+        chunk.set_block(BlockPosition::new(1, 2, 3), 4);
+        chunk.set_block(BlockPosition::new(15, 200, 15), 2);
+        {
+            let mut storage = RegionStorage::open(&directory)?;
+            storage.save_chunk(&chunk)?;
+            storage.flush()?;
+        }
+        let mut storage = RegionStorage::open(&directory)?;
+        let loaded = storage
+            .load_chunk(chunk.position)?
+            .expect("Chunk was saved");
+        for (loaded, section) in loaded.sections.iter().zip(&chunk.sections) {
+            assert_eq!(loaded.block_store, section.block_store);
+        }
+        assert!(!loaded.needs_saving);
+        assert_eq!(
+            loaded.sections[12].block_store[BlockPosition::new(15, 200, 15).relative_block()],
+            Block::Block(2)
+        );
+        assert!(storage.load_chunk(ChunkPosition::new(-4, 40))?.is_none());
+        assert!(storage.load_chunk(ChunkPosition::new(100, 100))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_reuses_sectors() -> anyhow::Result<()> {
+        let directory = TestDirectory::new("region-rewrite");
+        let mut storage = RegionStorage::open(&directory)?;
+        let mut chunk = Chunk::new(ChunkPosition::new(0, 0));
+        for x in 0..16 {
+            for z in 0..16 {
+                chunk.set_block(BlockPosition::new(x, 0, z), (x * z) as u32);
+            }
+        }
+        storage.save_chunk(&chunk)?;
+        storage.save_chunk(&Chunk::new(ChunkPosition::new(1, 0)))?;
+        let length = std::fs::metadata(storage.region_path(RegionPosition::new(0, 0)))?.len();
+        chunk.set_block(BlockPosition::new(0, 0, 0), 3);
+        storage.save_chunk(&chunk)?;
+        let new_length = std::fs::metadata(storage.region_path(RegionPosition::new(0, 0)))?.len();
+        assert_eq!(length, new_length);
+
+        let loaded = storage
+            .load_chunk(chunk.position)?
+            .expect("Chunk was saved");
+        assert_eq!(
+            loaded.sections[0].block_store,
+            chunk.sections[0].block_store
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use std::{fmt::Display, str::FromStr};
+
+use noise::Perlin;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use super::position::ChunkPosition;
+
+/// The seed every part of world generation is derived from
+///
+/// The same seed will always generate the same world
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct WorldSeed(pub u64);
+impl WorldSeed {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+    pub fn random() -> Self {
+        Self(rand::thread_rng().gen())
+    }
+    /// Creates the noise function used for terrain height
+    pub fn height_noise(&self) -> Perlin {
+        Perlin::new((self.0 ^ (self.0 >> 32)) as u32)
+    }
+    /// The seed for the RNG of a single chunk
+    pub fn chunk_seed(&self, position: ChunkPosition) -> u64 {
+        // Large odd constants keep neighbouring chunks from having similar seeds
+        let x = (position.x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let z = (position.z as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+        splitmix64(self.0 ^ x ^ z.rotate_left(32))
+    }
+    /// An RNG that is unique to the chunk and will always produce the same values for this seed
+    ///
+    /// ChaCha is used because its output is guaranteed to stay the same between versions of rand
+    pub fn chunk_rng(&self, position: ChunkPosition) -> ChaCha8Rng {
+        ChaCha8Rng::seed_from_u64(self.chunk_seed(position))
+    }
+}
+/// Finalizer from SplitMix64. Spreads every input bit over the whole output
+fn splitmix64(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+impl From<u64> for WorldSeed {
+    fn from(seed: u64) -> Self {
+        Self(seed)
+    }
+}
+impl Display for WorldSeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl FromStr for WorldSeed {
+    type Err = std::num::ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
@@ -0,0 +1,456 @@
+/*!
+# Chunk Workers
+
+Loading, generating and meshing chunks is done on tokio's blocking thread pool so the world is never stalled by new terrain or disk reads.
+
+Requested chunks wait in a queue until a worker is free. The closest chunks to the player are always started first.
+Loaded chunks are remeshed by the workers when the chunks next to them change. Remeshes wait in their own queue and only take the workers that are left once the requested chunks have started. See [ChunkWorkers::queue_remesh]
+Finished chunks are sent back over a channel and picked up by the world with [ChunkWorkers::finished]. A job that panics frees its worker and its chunk can be requested again
+*/
+use std::{
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+};
+
+use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
+use flume::{Receiver, Sender};
+use noise::Perlin;
+use tracing::{debug, error};
+
+use crate::{engine::voxel::chunk_mesh::RawChunkMesh, game::Game};
+
+use super::{
+    chunk::{Chunk, ChunkNeighbours},
+    light,
+    position::{ChunkPosition, RawPosition},
+    region::RegionStorage,
+    seed::WorldSeed,
+};
+/// A chunk that a worker has finished along with the meshes of its non empty sections
+#[derive(Debug)]
+pub struct GeneratedChunk {
+    pub chunk: Chunk,
+    pub meshes: Vec<RawChunkMesh>,
+    /// The neighbours the meshes were culled against
+    pub neighbours: ChunkNeighbours,
+}
+/// The meshes of the non empty sections of a loaded chunk. Built by [ChunkWorkers::remesh]
+#[derive(Debug)]
+pub struct RemeshedChunk {
+    pub position: ChunkPosition,
+    /// The [Chunk::revision] the meshes were built from
+    pub revision: u32,
+    /// The sections that were remeshed. One bit per section
+    pub sections: u16,
+    pub meshes: Vec<RawChunkMesh>,
+}
+/// A job that a worker has finished
+#[derive(Debug)]
+pub enum FinishedJob {
+    Generated(Box<GeneratedChunk>),
+    Remeshed(RemeshedChunk),
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobKind {
+    Generate,
+    Remesh,
+}
+/// How a job ended
+#[derive(Debug)]
+enum JobResult {
+    Finished(FinishedJob),
+    /// The job panicked
+    Failed(JobKind, ChunkPosition),
+}
+/// Everything a worker needs to load, generate and mesh a chunk
+#[derive(Clone)]
+pub struct ChunkJobContext {
+    pub seed: WorldSeed,
+    pub noise: Perlin,
+    pub game: Arc<Game>,
+    /// Saved chunks are read from here before generating them. None means the world only lives in memory
+    pub storage: Option<Arc<Mutex<RegionStorage>>>,
+}
+#[derive(Debug)]
+pub struct ChunkWorkers {
+    /// Chunks waiting for a worker
+    pending: HashSet<ChunkPosition>,
+    /// Chunks a worker is currently building
+    in_flight: HashSet<ChunkPosition>,
+    /// Loaded chunks waiting for a worker to remesh them. With the sections to remesh
+    queued_remeshes: HashMap<ChunkPosition, u16>,
+    /// Loaded chunks a worker is currently remeshing
+    remeshing: HashSet<ChunkPosition>,
+    max_in_flight: usize,
+    sender: Sender<JobResult>,
+    receiver: Receiver<JobResult>,
+}
+impl Default for ChunkWorkers {
+    /// One worker per available core
+    fn default() -> Self {
+        let workers = std::thread::available_parallelism()
+            .map(|v| v.get())
+            .unwrap_or(4);
+        Self::new(workers)
+    }
+}
+impl ChunkWorkers {
+    pub fn new(max_in_flight: usize) -> Self {
+        let (sender, receiver) = flume::unbounded();
+        Self {
+            pending: HashSet::new(),
+            in_flight: HashSet::new(),
+            queued_remeshes: HashMap::new(),
+            remeshing: HashSet::new(),
+            max_in_flight: max_in_flight.max(1),
+            sender,
+            receiver,
+        }
+    }
+    /// Is the chunk waiting for or being built by a worker
+    pub fn is_queued(&self, position: ChunkPosition) -> bool {
+        self.pending.contains(&position) || self.in_flight.contains(&position)
+    }
+    /// Queues a chunk to be built. Returns false if it is already queued
+    pub fn request(&mut self, position: ChunkPosition) -> bool {
+        if self.in_flight.contains(&position) {
+            return false;
+        }
+        self.pending.insert(position)
+    }
+    /// Drops the pending chunks that `keep` returns false for. Chunks already being built are not affected
+    pub fn retain_pending(&mut self, mut keep: impl FnMut(ChunkPosition) -> bool) {
+        self.pending.retain(|position| keep(*position));
+    }
+    pub fn number_pending(&self) -> usize {
+        self.pending.len()
+    }
+    /// The number of jobs being run. Including remeshes
+    pub fn number_in_flight(&self) -> usize {
+        self.in_flight.len() + self.remeshing.len()
+    }
+    pub fn is_remeshing(&self, position: ChunkPosition) -> bool {
+        self.remeshing.contains(&position)
+    }
+    /// Queues sections of a loaded chunk to be remeshed. One bit per section
+    ///
+    /// Sections queued for the same chunk are remeshed by a single job
+    pub fn queue_remesh(&mut self, position: ChunkPosition, sections: u16) {
+        if sections != 0 {
+            *self.queued_remeshes.entry(position).or_default() |= sections;
+        }
+    }
+    pub fn is_remesh_queued(&self, position: ChunkPosition) -> bool {
+        self.queued_remeshes.contains_key(&position)
+    }
+    /// Takes the next queued remesh if a worker is free. Called after [ChunkWorkers::dispatch] so the requested chunks start first
+    ///
+    /// Chunks that are already being remeshed stay queued until their job is finished
+    pub fn next_remesh(&mut self) -> Option<(ChunkPosition, u16)> {
+        if self.number_in_flight() >= self.max_in_flight {
+            return None;
+        }
+        let position = self
+            .queued_remeshes
+            .keys()
+            .find(|position| !self.remeshing.contains(position))
+            .copied()?;
+        self.queued_remeshes.remove_entry(&position)
+    }
+    /// Starts jobs for the pending chunks closest to `center` until every worker is busy
+    ///
+    /// `neighbours` is called before a job is started. They are used to cull the faces on the sides of the chunk.
+    /// The worker loads the chunk if it has been saved. Otherwise it is generated
+    pub fn dispatch(
+        &mut self,
+        center: RawPosition,
+        context: &ChunkJobContext,
+        mut neighbours: impl FnMut(ChunkPosition) -> ChunkNeighbours,
+    ) {
+        let free_workers = self.max_in_flight.saturating_sub(self.number_in_flight());
+        if free_workers == 0 || self.pending.is_empty() {
+            return;
+        }
+        let mut closest: Vec<_> = self.pending.iter().copied().collect();
+        closest.sort_by(|a, b| {
+            a.distance_squared(&center)
+                .total_cmp(&b.distance_squared(&center))
+        });
+        for position in closest.into_iter().take(free_workers) {
+            self.pending.remove(&position);
+            let neighbours = neighbours(position);
+            let context = context.clone();
+            debug!("Starting chunk job for {:?}", position);
+            self.spawn_job(JobKind::Generate, position, move || {
+                let mut chunk = load_saved_chunk(context.storage.as_deref(), position)
+                    .unwrap_or_else(|| {
+                        Chunk::generate(
+                            position,
+                            context.seed,
+                            &context.noise,
+                            &context.game.block_registery,
+                        )
+                    });
+                light::light_chunk(&mut chunk, &context.game.block_registery);
+                let meshes = chunk.build_meshes(context.game, &neighbours);
+                FinishedJob::Generated(Box::new(GeneratedChunk {
+                    chunk,
+                    meshes,
+                    neighbours,
+                }))
+            });
+        }
+    }
+    /// Rebuilds the meshes of a loaded chunk from a copy of it. Started right away so the chunk is not left with out of date faces
+    ///
+    /// Returns false if the chunk is already being remeshed. That job is out of date if the [Chunk::revision] has changed since
+    pub fn remesh(
+        &mut self,
+        chunk: &Chunk,
+        sections: u16,
+        neighbours: ChunkNeighbours,
+        game: Arc<Game>,
+    ) -> bool {
+        if self.remeshing.contains(&chunk.position) {
+            return false;
+        }
+        let mut chunk = chunk.clone();
+        debug!("Starting remesh job for {:?}", chunk.position);
+        self.spawn_job(JobKind::Remesh, chunk.position, move || {
+            let meshes = chunk.build_section_meshes(game, &neighbours, sections);
+            FinishedJob::Remeshed(RemeshedChunk {
+                position: chunk.position,
+                revision: chunk.revision,
+                sections,
+                meshes,
+            })
+        });
+        true
+    }
+    /// Runs the job on the blocking thread pool. The chunk stays in flight until the result is picked up by [ChunkWorkers::finished]
+    fn spawn_job(
+        &mut self,
+        kind: JobKind,
+        position: ChunkPosition,
+        job: impl FnOnce() -> FinishedJob + Send + 'static,
+    ) {
+        match kind {
+            JobKind::Generate => self.in_flight.insert(position),
+            JobKind::Remesh => self.remeshing.insert(position),
+        };
+        let sender = self.sender.clone();
+        tokio::task::spawn_blocking(move || {
+            // The panic is still printed by the panic hook. It is caught so the worker is freed
+            let result = match std::panic::catch_unwind(AssertUnwindSafe(job)) {
+                Ok(finished) => JobResult::Finished(finished),
+                Err(_) => JobResult::Failed(kind, position),
+            };
+            // The world being dropped is the only reason this would fail
+            let _ = sender.send(result);
+        });
+    }
+    /// Takes every job that has been finished since the last call. Never blocks
+    ///
+    /// Chunks whose job failed are dropped. They are requested again the next time the loaded area changes
+    pub fn finished(&mut self) -> Vec<FinishedJob> {
+        let mut finished = Vec::new();
+        for result in self.receiver.try_iter() {
+            let (kind, position) = match &result {
+                JobResult::Finished(job) => match job {
+                    FinishedJob::Generated(generated) => {
+                        (JobKind::Generate, generated.chunk.position)
+                    }
+                    FinishedJob::Remeshed(remeshed) => (JobKind::Remesh, remeshed.position),
+                },
+                JobResult::Failed(kind, position) => {
+                    error!("{:?} job for {:?} panicked", kind, position);
+                    (*kind, *position)
+                }
+            };
+            match kind {
+                JobKind::Generate => self.in_flight.remove(&position),
+                JobKind::Remesh => self.remeshing.remove(&position),
+            };
+            if let JobResult::Finished(job) = result {
+                finished.push(job);
+            }
+        }
+        finished
+    }
+}
+/// Loads a chunk from disk if it has been saved. Errors are logged and the chunk will be regenerated
+pub(super) fn load_saved_chunk(
+    storage: Option<&Mutex<RegionStorage>>,
+    position: ChunkPosition,
+) -> Option<Chunk> {
+    match super::lock_storage(storage?).load_chunk(position) {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            error!(
+                "Failed to load chunk {:?}. Regenerating it: {}",
+                position, err
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        game::test_game,
+        world::{chunk::ALL_SECTIONS, position::BlockPosition},
+    };
+
+    use super::*;
+
+    fn context() -> ChunkJobContext {
+        let seed = WorldSeed::new(1);
+        ChunkJobContext {
+            seed,
+            noise: seed.height_noise(),
+            game: test_game(),
+            storage: None,
+        }
+    }
+    /// Waits until every job in flight has been picked up. Failed jobs are picked up without a result
+    async fn wait_for_jobs(workers: &mut ChunkWorkers) -> Vec<FinishedJob> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut finished = Vec::new();
+        while workers.number_in_flight() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            finished.extend(workers.finished());
+        }
+        finished
+    }
+
+    #[tokio::test]
+    async fn test_closest_chunks_start_first() {
+        let mut workers = ChunkWorkers::new(2);
+        let positions = [
+            ChunkPosition::new(5, 5),
+            ChunkPosition::new(0, 1),
+            ChunkPosition::new(-3, 0),
+            ChunkPosition::new(0, 0),
+        ];
+        for position in positions {
+            assert!(workers.request(position));
+        }
+        assert!(!workers.request(ChunkPosition::new(0, 0)));
+        workers.dispatch(RawPosition::new(8.0, 0.0, 8.0), &context(), |_| {
+            ChunkNeighbours::default()
+        });
+        assert_eq!(workers.number_in_flight(), 2);
+        assert_eq!(workers.number_pending(), 2);
+        assert!(workers.in_flight.contains(&ChunkPosition::new(0, 0)));
+        assert!(workers.in_flight.contains(&ChunkPosition::new(0, 1)));
+        // Chunks being built can not be requested again
+        assert!(!workers.request(ChunkPosition::new(0, 1)));
+        assert!(workers.is_queued(ChunkPosition::new(0, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_is_capped() {
+        let mut workers = ChunkWorkers::new(1);
+        let context = context();
+        for x in 0..3 {
+            workers.request(ChunkPosition::new(x, 0));
+        }
+        let center = RawPosition::new(8.0, 0.0, 8.0);
+        workers.dispatch(center, &context, |_| ChunkNeighbours::default());
+        workers.dispatch(center, &context, |_| ChunkNeighbours::default());
+        assert_eq!(workers.number_in_flight(), 1);
+        assert_eq!(workers.number_pending(), 2);
+
+        let finished = wait_for_jobs(&mut workers).await;
+        let [FinishedJob::Generated(generated)] = finished.as_slice() else {
+            panic!("Expected one generated chunk. Got {finished:?}");
+        };
+        assert_eq!(generated.chunk.position, ChunkPosition::new(0, 0));
+        assert!(!generated.meshes.is_empty());
+        // The finished chunk freed its worker
+        assert!(!workers.is_queued(ChunkPosition::new(0, 0)));
+        assert_eq!(workers.number_in_flight(), 0);
+        workers.dispatch(center, &context, |_| ChunkNeighbours::default());
+        assert!(workers.in_flight.contains(&ChunkPosition::new(1, 0)));
+        assert_eq!(workers.number_pending(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job_frees_its_worker() {
+        let mut workers = ChunkWorkers::new(1);
+        let position = ChunkPosition::new(2, 2);
+        workers.spawn_job(JobKind::Generate, position, || panic!("Broken chunk"));
+        assert!(workers.is_queued(position));
+        assert!(wait_for_jobs(&mut workers).await.is_empty());
+        assert_eq!(workers.number_in_flight(), 0);
+        assert!(workers.request(position));
+    }
+
+    #[tokio::test]
+    async fn test_remesh_uses_a_copy_of_the_chunk() {
+        let mut workers = ChunkWorkers::new(1);
+        let game = test_game();
+        let mut chunk = Chunk::new(ChunkPosition::new(3, -2));
+        chunk.set_block(BlockPosition::new(48, 40, -30), 1);
+        assert!(workers.remesh(
+            &chunk,
+            ALL_SECTIONS,
+            ChunkNeighbours::default(),
+            game.clone()
+        ));
+        assert!(!workers.remesh(&chunk, ALL_SECTIONS, ChunkNeighbours::default(), game));
+        assert!(workers.is_remeshing(chunk.position));
+        // Remeshes take up a worker
+        workers.request(ChunkPosition::new(0, 0));
+        workers.dispatch(RawPosition::new(8.0, 0.0, 8.0), &context(), |_| {
+            ChunkNeighbours::default()
+        });
+        assert_eq!(workers.number_pending(), 1);
+        // Changes after the remesh started are not part of it
+        chunk.set_block(BlockPosition::new(48, 0, -30), 1);
+
+        let finished = wait_for_jobs(&mut workers).await;
+        let [FinishedJob::Remeshed(remeshed)] = finished.as_slice() else {
+            panic!("Expected one remeshed chunk. Got {finished:?}");
+        };
+        assert_eq!(remeshed.position, chunk.position);
+        assert_ne!(remeshed.revision, chunk.revision);
+        assert_eq!(remeshed.meshes.len(), 1);
+        assert_eq!(
+            remeshed.meshes[0].position,
+            chunk.section_position(2).into()
+        );
+        assert!(!workers.is_remeshing(chunk.position));
+    }
+
+    #[tokio::test]
+    async fn test_queued_remeshes_wait_for_a_free_worker() {
+        let mut workers = ChunkWorkers::new(1);
+        let position = ChunkPosition::new(1, 1);
+        workers.queue_remesh(position, 0b0010);
+        workers.queue_remesh(position, 0b0100);
+        workers.queue_remesh(ChunkPosition::new(2, 2), 0);
+        assert!(!workers.is_remesh_queued(ChunkPosition::new(2, 2)));
+        // Requested chunks start first
+        workers.request(ChunkPosition::new(0, 0));
+        workers.dispatch(RawPosition::new(8.0, 0.0, 8.0), &context(), |_| {
+            ChunkNeighbours::default()
+        });
+        assert_eq!(workers.next_remesh(), None);
+        wait_for_jobs(&mut workers).await;
+        assert_eq!(workers.next_remesh(), Some((position, 0b0110)));
+        assert!(!workers.is_remesh_queued(position));
+
+        // Chunks being remeshed wait for their job to finish
+        let mut workers = ChunkWorkers::new(2);
+        let chunk = Chunk::new(position);
+        assert!(workers.remesh(&chunk, 1, ChunkNeighbours::default(), test_game()));
+        workers.queue_remesh(position, 1);
+        assert_eq!(workers.next_remesh(), None);
+        wait_for_jobs(&mut workers).await;
+        assert_eq!(workers.next_remesh(), Some((position, 1)));
+    }
+}